@@ -1,31 +1,57 @@
 #[derive(PartialEq, Debug, Clone)]
 pub enum Expr {
     Number(i64),
+    Str(String),
     VarRef(String),
     OpCall(String, Box<Expr>, Box<Expr>),
     FunCall(Box<Expr>, Vec<Expr>),
     Cast(Box<Expr>, Ty),
-    Alloc(String),
+    Alloc(String, Ty),
     Assign(String, Box<Expr>),
+    New(String, Vec<Expr>),
+    FieldRef(Box<Expr>, String),
+    FieldSet(Box<Expr>, String, Box<Expr>),
+    While(Box<Expr>, Vec<Expr>),
+    If(Box<Expr>, Vec<Expr>, Vec<Expr>),
+    Return(Box<Expr>),
 }
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum Declaration {
+    Struct(Struct),
     Extern(Extern),
     Function(Function),
 }
 
 impl Declaration {
-    pub fn split(mut decls: Vec<Declaration>) -> (Vec<Extern>, Vec<Function>) {
+    pub fn split(mut decls: Vec<Declaration>) -> (Vec<Struct>, Vec<Extern>, Vec<Function>) {
+        let mut structs = vec![];
         let mut externs = vec![];
         let mut funcs = vec![];
         while let Some(decl) = decls.pop() {
             match decl {
+                Declaration::Struct(x) => structs.push(x),
                 Declaration::Extern(x) => externs.push(x),
                 Declaration::Function(x) => funcs.push(x),
             }
         }
-        (externs, funcs)
+        (structs, externs, funcs)
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Struct {
+    pub name: String,
+    pub fields: Vec<Param>,
+}
+
+impl Struct {
+    /// Returns the index and the type of the field
+    pub fn field(&self, name: &str) -> Option<(usize, &Ty)> {
+        self.fields
+            .iter()
+            .position(|x| x.name == name)
+            .map(|idx| (idx, &self.fields[idx].ty))
     }
 }
 
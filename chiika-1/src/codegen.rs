@@ -8,9 +8,13 @@ use std::collections::HashMap;
 pub struct CodeGen<'run, 'ictx: 'run> {
     ast: Vec<ast::Function>,
     signatures: HashMap<String, ast::FunTy>,
+    structs: HashMap<String, ast::Struct>,
+    struct_types: HashMap<String, inkwell::types::StructType<'ictx>>,
     context: &'ictx inkwell::context::Context,
     module: &'run inkwell::module::Module<'ictx>,
     builder: &'run inkwell::builder::Builder<'ictx>,
+    // Print the progress (off in the REPL of chiika-2)
+    verbose: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -21,6 +25,8 @@ enum LlvmValue<'ictx> {
     Opaque(inkwell::values::PointerValue<'ictx>),
     Func(inkwell::values::FunctionValue<'ictx>, ast::FunTy),
     FuncPtr(inkwell::values::PointerValue<'ictx>, ast::FunTy),
+    // Pointer to a heap-allocated struct and the name of the struct
+    Struct(inkwell::values::PointerValue<'ictx>, String),
 }
 
 impl<'ictx> LlvmValue<'ictx> {
@@ -31,51 +37,74 @@ impl<'ictx> LlvmValue<'ictx> {
             LlvmValue::Opaque(x) => x.into(),
             LlvmValue::Func(x, _) => x.as_global_value().as_basic_value_enum(),
             LlvmValue::FuncPtr(x, _) => x.into(),
+            LlvmValue::Struct(x, _) => x.into(),
         }
     }
 
-    fn into_integer(self, t: inkwell::types::IntType<'ictx>) -> inkwell::values::IntValue<'ictx> {
-        let ptr = match self {
-            LlvmValue::Int(x) | LlvmValue::Any(x) => return x,
-            LlvmValue::Opaque(x) => x,
-            LlvmValue::Func(x, _) => x.as_global_value().as_pointer_value(),
-            LlvmValue::FuncPtr(x, _) => x,
-        };
-        ptr.const_to_int(t)
-    }
-
     fn expect_int(self) -> Result<inkwell::values::IntValue<'ictx>> {
         match self {
             LlvmValue::Int(x) => Ok(x),
             _ => Err(anyhow!("expected int but got {:?}", self)),
         }
     }
+
+    /// Wrap `v` with the same variant as `self`
+    fn rewrap(&self, v: inkwell::values::BasicValueEnum<'ictx>) -> LlvmValue<'ictx> {
+        match self {
+            LlvmValue::Int(_) => LlvmValue::Int(v.into_int_value()),
+            LlvmValue::Any(_) => LlvmValue::Any(v.into_int_value()),
+            LlvmValue::Opaque(_) => LlvmValue::Opaque(v.into_pointer_value()),
+            LlvmValue::Func(_, fun_ty) | LlvmValue::FuncPtr(_, fun_ty) => {
+                LlvmValue::FuncPtr(v.into_pointer_value(), fun_ty.clone())
+            }
+            LlvmValue::Struct(_, name) => LlvmValue::Struct(v.into_pointer_value(), name.clone()),
+        }
+    }
+
+    fn expect_struct(self) -> Result<(inkwell::values::PointerValue<'ictx>, String)> {
+        match self {
+            LlvmValue::Struct(x, name) => Ok((x, name)),
+            _ => Err(anyhow!("expected struct but got {:?}", self)),
+        }
+    }
 }
 
 pub fn run(ast: Vec<ast::Declaration>) -> Result<()> {
-    let (externs, funcs) = ast::Declaration::split(ast);
-    let sigs = gather_sigs(&externs, &funcs);
-
     let context = inkwell::context::Context::create();
-    let module = context.create_module("main");
-    let builder = context.create_builder();
-    let code_gen = CodeGen::new(funcs, sigs, &context, &module, &builder);
-    code_gen.gen_declares(&externs);
-    code_gen.gen_program()?;
+    let module = gen_module(&context, ast, true)?;
     log("Finished compilation.");
-    code_gen.module.verify().unwrap();
-    code_gen
-        .module
+    module
         .print_to_file("../a.ll")
         .map_err(|llvm_str| anyhow!("{}", llvm_str.to_string()))?;
     log("Wrote a.ll.");
-    code_gen
-        .module
-        .write_bitcode_to_path(std::path::Path::new("../a.bc"));
+    module.write_bitcode_to_path(std::path::Path::new("../a.bc"));
     log("Wrote a.bc.");
     Ok(())
 }
 
+/// Compile the program into a verified module (also used by the REPL of
+/// chiika-2 to JIT-compile it). With `verbose`, the functions and the
+/// expressions are printed while compiled
+pub fn gen_module(
+    context: &inkwell::context::Context,
+    ast: Vec<ast::Declaration>,
+    verbose: bool,
+) -> Result<inkwell::module::Module<'_>> {
+    let (structs, externs, funcs) = ast::Declaration::split(ast);
+    let sigs = gather_sigs(&externs, &funcs);
+
+    let module = context.create_module("main");
+    let builder = context.create_builder();
+    let mut code_gen = CodeGen::new(funcs, sigs, structs, context, &module, &builder, verbose);
+    code_gen.gen_struct_types();
+    code_gen.gen_declares(&externs);
+    code_gen.gen_program()?;
+    module
+        .verify()
+        .map_err(|llvm_str| anyhow!("{}", llvm_str.to_string()))?;
+    Ok(module)
+}
+
 fn gather_sigs(externs: &[ast::Extern], funcs: &[ast::Function]) -> HashMap<String, ast::FunTy> {
     let tys = externs
         .iter()
@@ -88,16 +117,27 @@ impl<'run, 'ictx: 'run> CodeGen<'run, 'ictx> {
     fn new(
         ast: Vec<ast::Function>,
         signatures: HashMap<String, ast::FunTy>,
+        structs: Vec<ast::Struct>,
         context: &'ictx inkwell::context::Context,
         module: &'run inkwell::module::Module<'ictx>,
         builder: &'run inkwell::builder::Builder<'ictx>,
+        verbose: bool,
     ) -> CodeGen<'run, 'ictx> {
         CodeGen {
             ast,
             signatures,
+            structs: structs.into_iter().map(|x| (x.name.clone(), x)).collect(),
+            struct_types: HashMap::new(),
             context,
             module,
             builder,
+            verbose,
+        }
+    }
+
+    fn log(&self, msg: impl Into<String>) {
+        if self.verbose {
+            log(msg);
         }
     }
 
@@ -121,6 +161,11 @@ impl<'run, 'ictx: 'run> CodeGen<'run, 'ictx> {
                 "int" => self.context.i64_type().into(),
                 "$ENV" => self.context.i8_type().ptr_type(Default::default()).into(),
                 "$FUTURE" => self.context.i8_type().ptr_type(Default::default()).into(),
+                "array" => self.context.i8_type().ptr_type(Default::default()).into(),
+                "string" => self.context.i8_type().ptr_type(Default::default()).into(),
+                _ if self.structs.contains_key(name) => {
+                    self.context.i8_type().ptr_type(Default::default()).into()
+                }
                 _ => panic!("unknown chiika-1 type `{:?}'", ty),
             },
             ast::Ty::Fun(x) => self.llvm_fn_type(x).ptr_type(Default::default()).into(),
@@ -136,9 +181,13 @@ impl<'run, 'ictx: 'run> CodeGen<'run, 'ictx> {
             ast::Ty::Raw(name) => match &name[..] {
                 "int" => LlvmValue::Int(v.try_into().map_err(|_| anyhow!("not int"))?),
                 "$any" => LlvmValue::Any(v.try_into().map_err(|_| anyhow!("not int(any)"))?),
-                "$ENV" | "$FUTURE" => {
+                "$ENV" | "$FUTURE" | "array" | "string" => {
                     LlvmValue::Opaque(v.try_into().map_err(|_| anyhow!("not {:?}: {:?}", ty, v))?)
                 }
+                _ if self.structs.contains_key(name) => LlvmValue::Struct(
+                    v.try_into().map_err(|_| anyhow!("not {:?}: {:?}", ty, v))?,
+                    name.clone(),
+                ),
                 _ => panic!("unknown chiika-1 type to cast: `{:?}', value: {:?}", ty, v),
             },
             ast::Ty::Fun(fun_ty) => LlvmValue::FuncPtr(
@@ -151,22 +200,59 @@ impl<'run, 'ictx: 'run> CodeGen<'run, 'ictx> {
 
     /// Cast LlvmValue to `ty`
     fn recast(&self, v: LlvmValue<'ictx>, ty: &ast::Ty) -> Result<LlvmValue<'ictx>> {
-        let vv = match (v, ty) {
-            (LlvmValue::Any(n), ast::Ty::Fun(_)) => {
+        let vv = match v {
+            LlvmValue::Any(n) if self.llvm_type(ty).is_pointer_type() => {
                 let t = self.context.i8_type().ptr_type(Default::default());
-                self.builder.build_int_to_ptr(n, t, "f").into()
-            }
-            (v, _) => {
-                if *ty == ast::Ty::Raw("$any".to_string()) {
-                    v.into_integer(self.context.i64_type()).into()
-                } else {
-                    v.into_arg_value()
-                }
+                self.builder.build_int_to_ptr(n, t, "p")?.into()
             }
+            v if *ty == ast::Ty::Raw("$any".to_string()) => self.to_integer(v)?.into(),
+            v => v.into_arg_value(),
         };
         self.cast(vv, ty)
     }
 
+    /// Convert a value into `$any` (i.e. 64-bit integer)
+    fn to_integer(&self, v: LlvmValue<'ictx>) -> Result<inkwell::values::IntValue<'ictx>> {
+        let ptr = match v {
+            LlvmValue::Int(x) | LlvmValue::Any(x) => return Ok(x),
+            LlvmValue::Opaque(x) | LlvmValue::FuncPtr(x, _) | LlvmValue::Struct(x, _) => x,
+            LlvmValue::Func(x, _) => x.as_global_value().as_pointer_value(),
+        };
+        Ok(self
+            .builder
+            .build_ptr_to_int(ptr, self.context.i64_type(), "any")?)
+    }
+
+    fn gen_struct_types(&mut self) {
+        for name in self.structs.keys() {
+            let t = self.context.opaque_struct_type(name);
+            self.struct_types.insert(name.clone(), t);
+        }
+        for (name, t) in &self.struct_types {
+            let field_types = self.structs[name]
+                .fields
+                .iter()
+                .map(|x| self.llvm_type(&x.ty))
+                .collect::<Vec<_>>();
+            t.set_body(&field_types, false);
+        }
+    }
+
+    /// Returns the LLVM struct type, the index and the type of the field
+    fn struct_field(
+        &self,
+        struct_name: &str,
+        field_name: &str,
+    ) -> Result<(inkwell::types::StructType<'ictx>, u32, ast::Ty)> {
+        let (idx, ty) = self.structs[struct_name]
+            .field(field_name)
+            .context(format!(
+                "struct {} has no field `{}'",
+                struct_name, field_name
+            ))?;
+        Ok((self.struct_types[struct_name], idx as u32, ty.clone()))
+    }
+
     fn gen_declares(&self, externs: &[ast::Extern]) {
         for ext in externs {
             let arg_types = ext
@@ -184,7 +270,7 @@ impl<'run, 'ictx: 'run> CodeGen<'run, 'ictx> {
             self.create_func(func);
         }
         for func in &self.ast {
-            log(format!("Compiling function {}", &func.name));
+            self.log(format!("Compiling function {}", &func.name));
             self.gen_func(func)?;
         }
         Ok(())
@@ -208,33 +294,74 @@ impl<'run, 'ictx: 'run> CodeGen<'run, 'ictx> {
         for i in 0..stmts.len() {
             let v = self.gen_expr(func, &mut lvars, &stmts[i])?;
             if i == stmts.len() - 1 {
-                self.builder.build_return(Some(&v.into_arg_value()));
+                self.builder.build_return(Some(&v.into_arg_value()))?;
             }
         }
         Ok(())
     }
 
+    /// Generate stmts and returns the value of the last one (or 0 if empty)
+    fn gen_block(
+        &self,
+        func: &ast::Function,
+        lvars: &mut HashMap<String, (inkwell::values::PointerValue<'ictx>, ast::Ty)>,
+        stmts: &[ast::Expr],
+    ) -> Result<LlvmValue<'ictx>> {
+        let mut v = self.llvm_int(0);
+        for stmt in stmts {
+            v = self.gen_expr(func, lvars, stmt)?;
+        }
+        Ok(v)
+    }
+
+    /// Generate a branch of `if` and jump to `merge_block`.
+    /// Returns the value and the last block of the branch, or None if the
+    /// branch ends with `return`
+    fn gen_branch(
+        &self,
+        func: &ast::Function,
+        lvars: &mut HashMap<String, (inkwell::values::PointerValue<'ictx>, ast::Ty)>,
+        stmts: &[ast::Expr],
+        merge_block: inkwell::basic_block::BasicBlock<'ictx>,
+    ) -> Result<Option<(LlvmValue<'ictx>, inkwell::basic_block::BasicBlock<'ictx>)>> {
+        let v = self.gen_block(func, lvars, stmts)?;
+        if let Some(ast::Expr::Return(_)) = stmts.last() {
+            self.builder.build_unreachable()?;
+            return Ok(None);
+        }
+        let end_block = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(merge_block)?;
+        Ok(Some((v, end_block)))
+    }
+
+    /// Returns a value which is never used (e.g. the value of `return`.)
+    /// Typed as the return value so that it can be the last stmt of `func`
+    fn dummy_value(&self, func: &ast::Function) -> Result<LlvmValue<'ictx>> {
+        self.cast(self.llvm_type(&func.ret_ty).const_zero(), &func.ret_ty)
+    }
+
     fn gen_expr(
         &self,
         func: &ast::Function,
-        lvars: &mut HashMap<String, inkwell::values::PointerValue<'ictx>>,
+        lvars: &mut HashMap<String, (inkwell::values::PointerValue<'ictx>, ast::Ty)>,
         expr: &ast::Expr,
     ) -> Result<LlvmValue<'ictx>> {
-        log(format!("- {:?}", expr));
+        self.log(format!("- {:?}", expr));
         let v = match expr {
             ast::Expr::Number(n) => self.llvm_int(*n as u64),
+            ast::Expr::Str(s) => {
+                let g = self.builder.build_global_string_ptr(s, "str")?;
+                LlvmValue::Opaque(g.as_pointer_value())
+            }
             ast::Expr::VarRef(s) => {
                 if let Some(idx) = func.params.iter().position(|param| param.name == *s) {
                     let param = &func.params[idx];
                     let f = self.module.get_function(&func.name).unwrap();
                     let v = f.get_nth_param(idx as u32).unwrap();
                     self.cast(v, &param.ty)?
-                } else if let Some(ptr) = lvars.get(s) {
-                    let n = self
-                        .builder
-                        .build_load(self.context.i64_type(), ptr.clone(), "n")
-                        .into_int_value();
-                    LlvmValue::Int(n)
+                } else if let Some((ptr, ty)) = lvars.get(s) {
+                    let v = self.builder.build_load(self.llvm_type(ty), *ptr, s)?;
+                    self.cast(v, ty)?
                 } else {
                     let f = self
                         .module
@@ -251,8 +378,8 @@ impl<'run, 'ictx: 'run> CodeGen<'run, 'ictx> {
                 let l = self.gen_expr(func, lvars, lhs)?.expect_int()?;
                 let r = self.gen_expr(func, lvars, rhs)?.expect_int()?;
                 LlvmValue::Int(match &op[..] {
-                    "+" => self.builder.build_int_add(l, r, "result"),
-                    "-" => self.builder.build_int_sub(l, r, "result"),
+                    "+" => self.builder.build_int_add(l, r, "result")?,
+                    "-" => self.builder.build_int_sub(l, r, "result")?,
                     _ => {
                         let pred = match &op[..] {
                             "==" => inkwell::IntPredicate::EQ,
@@ -263,9 +390,9 @@ impl<'run, 'ictx: 'run> CodeGen<'run, 'ictx> {
                             ">=" => inkwell::IntPredicate::SGE,
                             _ => return Err(anyhow!("unknown binop `{}'", op)),
                         };
-                        let i1 = self.builder.build_int_compare(pred, l, r, "result");
+                        let i1 = self.builder.build_int_compare(pred, l, r, "result")?;
                         self.builder
-                            .build_int_cast(i1, self.context.i64_type(), "cast")
+                            .build_int_z_extend(i1, self.context.i64_type(), "cast")?
                     }
                 })
             }
@@ -280,7 +407,7 @@ impl<'run, 'ictx: 'run> CodeGen<'run, 'ictx> {
                 let (x, fun_ty) = match self.gen_expr(func, lvars, func_expr)? {
                     LlvmValue::Func(f, fun_ty) => (
                         self.builder
-                            .build_direct_call(f, &args, "result")
+                            .build_direct_call(f, &args, "result")?
                             .try_as_basic_value()
                             .unwrap_left(),
                         fun_ty,
@@ -289,7 +416,7 @@ impl<'run, 'ictx: 'run> CodeGen<'run, 'ictx> {
                         let ftype = self.llvm_fn_type(&fun_ty);
                         (
                             self.builder
-                                .build_indirect_call(ftype, fptr, &args, "result")
+                                .build_indirect_call(ftype, fptr, &args, "result")?
                                 .try_as_basic_value()
                                 .unwrap_left(),
                             fun_ty,
@@ -303,19 +430,151 @@ impl<'run, 'ictx: 'run> CodeGen<'run, 'ictx> {
                 let v = self.gen_expr(func, lvars, expr)?;
                 self.recast(v, ty)?
             }
-            ast::Expr::Alloc(name) => {
-                let ptr = self.builder.build_alloca(self.context.i64_type(), name);
-                lvars.insert(name.clone(), ptr);
+            ast::Expr::Alloc(name, ty) => {
+                // Put allocas at the beginning of the function so that allocs
+                // in a loop do not consume the stack
+                let f = self.module.get_function(&func.name).unwrap();
+                let entry = f.get_first_basic_block().unwrap();
+                let alloca_builder = self.context.create_builder();
+                match entry.get_first_instruction() {
+                    Some(inst) => alloca_builder.position_before(&inst),
+                    None => alloca_builder.position_at_end(entry),
+                }
+                let ptr = alloca_builder.build_alloca(self.llvm_type(ty), name)?;
+                lvars.insert(name.clone(), (ptr, ty.clone()));
                 self.llvm_int(0)
             }
             ast::Expr::Assign(name, rhs) => {
                 let v = self.gen_expr(func, lvars, rhs)?;
-                let ptr = lvars
+                let (ptr, _) = lvars
                     .get(name)
-                    .expect(&format!("unknown variable `{}'", name));
-                self.builder.build_store(ptr.clone(), v.into_arg_value());
+                    .with_context(|| format!("unknown variable `{}'", name))?;
+                self.builder.build_store(*ptr, v.into_arg_value())?;
                 self.llvm_int(0)
             }
+            ast::Expr::New(name, arg_exprs) => {
+                let struct_ty = *self
+                    .struct_types
+                    .get(name)
+                    .with_context(|| format!("unknown struct `{}'", name))?;
+                if arg_exprs.len() != self.structs[name].fields.len() {
+                    return Err(anyhow!("wrong number of fields for {}: {:?}", name, expr));
+                }
+                let ptr = self
+                    .builder
+                    .build_malloc(struct_ty, name)
+                    .map_err(|e| anyhow!("{}", e))?;
+                for (i, arg_expr) in arg_exprs.iter().enumerate() {
+                    let v = self.gen_expr(func, lvars, arg_expr)?;
+                    let field_ptr = self
+                        .builder
+                        .build_struct_gep(struct_ty, ptr, i as u32, "field")
+                        .map_err(|_| anyhow!("failed to get field {} of {}", i, name))?;
+                    self.builder.build_store(field_ptr, v.into_arg_value())?;
+                }
+                LlvmValue::Struct(ptr, name.clone())
+            }
+            ast::Expr::FieldRef(obj_expr, field_name) => {
+                let (ptr, struct_name) = self.gen_expr(func, lvars, obj_expr)?.expect_struct()?;
+                let (struct_ty, idx, ty) = self.struct_field(&struct_name, field_name)?;
+                let field_ptr = self
+                    .builder
+                    .build_struct_gep(struct_ty, ptr, idx, "field")
+                    .map_err(|_| anyhow!("failed to get field {}", field_name))?;
+                let v = self
+                    .builder
+                    .build_load(self.llvm_type(&ty), field_ptr, field_name)?;
+                self.cast(v, &ty)?
+            }
+            ast::Expr::FieldSet(obj_expr, field_name, rhs) => {
+                let (ptr, struct_name) = self.gen_expr(func, lvars, obj_expr)?.expect_struct()?;
+                let (struct_ty, idx, _) = self.struct_field(&struct_name, field_name)?;
+                let v = self.gen_expr(func, lvars, rhs)?;
+                let field_ptr = self
+                    .builder
+                    .build_struct_gep(struct_ty, ptr, idx, "field")
+                    .map_err(|_| anyhow!("failed to get field {}", field_name))?;
+                self.builder.build_store(field_ptr, v.into_arg_value())?;
+                self.llvm_int(0)
+            }
+            ast::Expr::While(cond_expr, body_stmts) => {
+                let f = self.module.get_function(&func.name).unwrap();
+                let cond_block = self.context.append_basic_block(f, "while_cond");
+                let body_block = self.context.append_basic_block(f, "while_body");
+                let end_block = self.context.append_basic_block(f, "while_end");
+                self.builder.build_unconditional_branch(cond_block)?;
+
+                self.builder.position_at_end(cond_block);
+                let cond = self.gen_expr(func, lvars, cond_expr)?.expect_int()?;
+                let zero = self.context.i64_type().const_zero();
+                let b = self.builder.build_int_compare(
+                    inkwell::IntPredicate::NE,
+                    cond,
+                    zero,
+                    "cond",
+                )?;
+                self.builder
+                    .build_conditional_branch(b, body_block, end_block)?;
+
+                self.builder.position_at_end(body_block);
+                for stmt in body_stmts {
+                    self.gen_expr(func, lvars, stmt)?;
+                }
+                self.builder.build_unconditional_branch(cond_block)?;
+
+                self.builder.position_at_end(end_block);
+                self.llvm_int(0)
+            }
+            ast::Expr::If(cond_expr, then_stmts, else_stmts) => {
+                let f = self.module.get_function(&func.name).unwrap();
+                let then_block = self.context.append_basic_block(f, "if_then");
+                let else_block = self.context.append_basic_block(f, "if_else");
+                let merge_block = self.context.append_basic_block(f, "if_end");
+                let cond = self.gen_expr(func, lvars, cond_expr)?.expect_int()?;
+                let zero = self.context.i64_type().const_zero();
+                let b = self.builder.build_int_compare(
+                    inkwell::IntPredicate::NE,
+                    cond,
+                    zero,
+                    "cond",
+                )?;
+                self.builder
+                    .build_conditional_branch(b, then_block, else_block)?;
+
+                self.builder.position_at_end(then_block);
+                let then_v = self.gen_branch(func, lvars, then_stmts, merge_block)?;
+                self.builder.position_at_end(else_block);
+                let else_v = self.gen_branch(func, lvars, else_stmts, merge_block)?;
+
+                self.builder.position_at_end(merge_block);
+                match (then_v, else_v) {
+                    (Some((then_v, then_end)), Some((else_v, else_end))) => {
+                        let then_bv = then_v.clone().into_arg_value();
+                        let else_bv = else_v.into_arg_value();
+                        if then_bv.get_type() != else_bv.get_type() {
+                            return Err(anyhow!(
+                                "branches of `if' have different types: {:?}",
+                                expr
+                            ));
+                        }
+                        let phi = self.builder.build_phi(then_bv.get_type(), "if_result")?;
+                        phi.add_incoming(&[(&then_bv, then_end), (&else_bv, else_end)]);
+                        then_v.rewrap(phi.as_basic_value())
+                    }
+                    (Some((v, _)), None) | (None, Some((v, _))) => v,
+                    // Both branches returned
+                    (None, None) => self.dummy_value(func)?,
+                }
+            }
+            ast::Expr::Return(val_expr) => {
+                let v = self.gen_expr(func, lvars, val_expr)?;
+                self.builder.build_return(Some(&v.into_arg_value()))?;
+                // Following instructions (if any) are unreachable
+                let f = self.module.get_function(&func.name).unwrap();
+                let dead_block = self.context.append_basic_block(f, "after_return");
+                self.builder.position_at_end(dead_block);
+                self.dummy_value(func)?
+            }
         };
         Ok(v)
     }
@@ -0,0 +1,1264 @@
+//! Tree-walking interpreter of chiika-1.
+//!
+//! Implements the same semantics as `codegen` and emulates chiika_runtime so
+//! that programs can be run without LLVM. Async externs do not really wait;
+//! their futures are completed by a scheduler with a simulated clock (see
+//! `Interp::run_tasks`).
+use crate::ast;
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+
+/// Run `main` of the program and returns the output and the exit status
+/// (the value `main` returns). `args` (including the program name) are given
+/// to `main` as `argc` and `argv`
+pub fn run_with_args(decls: Vec<ast::Declaration>, args: &[String]) -> Result<(String, i64)> {
+    let mut interp = Interp::new(decls);
+    // `main` may be declared without parameters
+    let main_args = match interp.funcs.get("main") {
+        Some(f) if f.params.len() == 2 => {
+            let items = args
+                .iter()
+                .map(|x| interp.alloc(Object::Str(x.clone())))
+                .collect();
+            vec![args.len() as i64, interp.alloc(Object::Array(items))]
+        }
+        _ => vec![],
+    };
+    let status = interp.call_by_name("main", main_args)?;
+    Ok((interp.output, status))
+}
+
+#[derive(Debug)]
+enum Object {
+    // Name of the struct and the values of the fields
+    Struct(String, Vec<i64>),
+    Array(Vec<i64>),
+    Str(String),
+    Func(String),
+    // The stack of `ChiikaEnv`
+    Env(Vec<i64>),
+    // The tasks to be registered when polled (none if already completed)
+    Future(Vec<Task>),
+    // State of `timeout` (see `Interp::start_timeout`)
+    Timeout {
+        cont: i64,
+        // Length of the env stack before `timeout` is called
+        depth: usize,
+        // Scopes of the caller
+        scopes: Vec<i64>,
+        done: bool,
+    },
+    // State of `select` (see `chiika_select_*` in `call_extern`)
+    Select {
+        // Env of the caller. Set when the future of `chiika_select` is polled
+        env: Option<i64>,
+        // Scopes of the caller
+        scopes: Vec<i64>,
+        // Envs given to the calls of the arms
+        envs: Vec<i64>,
+        // Continuations of the arms
+        conts: Vec<i64>,
+        // Tasks of the calls of the arms, registered when the future of
+        // `chiika_select` is polled
+        tasks: Vec<Task>,
+        // Index of the arm finished first and its value
+        winner: Option<(usize, i64)>,
+    },
+    // Tasks spawned in `scope` of chiika-2
+    Scope {
+        tasks: Vec<i64>,
+        // Task waiting in `chiika_scope_wait`
+        waiter: Option<Waiter>,
+    },
+    Interval {
+        period_ms: u64,
+        next_at: u64,
+        ticks: i64,
+    },
+    // Channel of `chan_new` or `oneshot_new`
+    Channel {
+        buffer: VecDeque<i64>,
+        capacity: usize,
+        // Closed by `chan_close` (or sent by `oneshot_send`)
+        closed: bool,
+        // Tasks waiting in `chan_recv`
+        receivers: VecDeque<Waiter>,
+        // Tasks waiting in `chan_send` with the value
+        senders: VecDeque<(Waiter, i64)>,
+    },
+    // Semaphore of `semaphore_new`, or a mutex (which has 1 permit)
+    Semaphore {
+        permits: i64,
+        // Permits acquired and not released yet
+        acquired: i64,
+        // Tasks waiting in `mutex_lock` or `semaphore_acquire`
+        waiters: VecDeque<Waiter>,
+    },
+    TcpListener(std::net::TcpListener),
+    // With the bytes read but not taken by `tcp_received` yet
+    TcpStream(std::net::TcpStream, Vec<u8>),
+    // Closed by `tcp_close` or freed by `interval_free`
+    Closed,
+}
+
+/// A pending async operation. When finished, `cont` is called with `value`
+#[derive(Debug, Clone)]
+struct Task {
+    wake_at: u64,
+    // Used to keep the order of the tasks which wake at the same time
+    seq: u64,
+    // Each spawned task has its own env
+    env: i64,
+    cont: i64,
+    value: i64,
+    // `timeout`s which drop this task when expired
+    scopes: Vec<i64>,
+}
+
+/// A task waiting for a channel, a mutex, a semaphore or a scope. Resumed
+/// with `Interp::wake`
+#[derive(Debug)]
+struct Waiter {
+    env: i64,
+    cont: i64,
+    scopes: Vec<i64>,
+}
+
+/// Non-local exit from `eval`
+enum Unwind {
+    Return(i64),
+    Error(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Unwind {
+    fn from(e: anyhow::Error) -> Self {
+        Unwind::Error(e)
+    }
+}
+
+type EvalResult = std::result::Result<i64, Unwind>;
+
+struct Interp {
+    funcs: HashMap<String, Rc<ast::Function>>,
+    externs: HashMap<String, ast::Extern>,
+    structs: HashMap<String, ast::Struct>,
+    // Pointer `p` points to `heap[p - 1]` (so that 0 can be used as null)
+    heap: Vec<Object>,
+    func_handles: HashMap<String, i64>,
+    tasks: Vec<Task>,
+    n_tasks: u64,
+    // Simulated clock (milliseconds)
+    clock_ms: u64,
+    output: String,
+    // Set by `chiika_finish` or `chiika_exit`
+    exit_code: Option<i64>,
+    // Set by `chiika_set_args`
+    args: Vec<String>,
+    // `timeout`s the running code is in. Inherited by the tasks it creates
+    scopes: Vec<i64>,
+    // Spawned tasks which are not finished nor cancelled
+    live_tasks: HashSet<i64>,
+}
+
+impl Interp {
+    fn new(decls: Vec<ast::Declaration>) -> Interp {
+        let (structs, externs, funcs) = ast::Declaration::split(decls);
+        Interp {
+            funcs: funcs
+                .into_iter()
+                .map(|x| (x.name.clone(), Rc::new(x)))
+                .collect(),
+            externs: externs.into_iter().map(|x| (x.name.clone(), x)).collect(),
+            structs: structs.into_iter().map(|x| (x.name.clone(), x)).collect(),
+            heap: vec![],
+            func_handles: HashMap::new(),
+            tasks: vec![],
+            n_tasks: 0,
+            clock_ms: 0,
+            output: String::new(),
+            exit_code: None,
+            args: vec![],
+            scopes: vec![],
+            live_tasks: HashSet::new(),
+        }
+    }
+
+    fn alloc(&mut self, obj: Object) -> i64 {
+        self.heap.push(obj);
+        self.heap.len() as i64
+    }
+
+    fn deref(&self, ptr: i64) -> Result<&Object> {
+        self.heap
+            .get((ptr - 1) as usize)
+            .with_context(|| format!("invalid pointer: {}", ptr))
+    }
+
+    fn deref_mut(&mut self, ptr: i64) -> Result<&mut Object> {
+        self.heap
+            .get_mut((ptr - 1) as usize)
+            .with_context(|| format!("invalid pointer: {}", ptr))
+    }
+
+    fn func_handle(&mut self, name: &str) -> i64 {
+        if let Some(h) = self.func_handles.get(name) {
+            return *h;
+        }
+        let h = self.alloc(Object::Func(name.to_string()));
+        self.func_handles.insert(name.to_string(), h);
+        h
+    }
+
+    fn is_func(&self, name: &str) -> bool {
+        self.funcs.contains_key(name) || self.externs.contains_key(name)
+    }
+
+    fn call_by_name(&mut self, name: &str, args: Vec<i64>) -> Result<i64> {
+        if let Some(func) = self.funcs.get(name) {
+            let func = Rc::clone(func);
+            self.call_func(&func, args)
+        } else {
+            self.call_extern(name, args)
+        }
+    }
+
+    fn call_handle(&mut self, f: i64, args: Vec<i64>) -> Result<i64> {
+        let Object::Func(name) = self.deref(f)? else {
+            bail!("not a function: {:?}", self.deref(f)?);
+        };
+        let name = name.clone();
+        self.call_by_name(&name, args)
+    }
+
+    fn call_func(&mut self, func: &ast::Function, args: Vec<i64>) -> Result<i64> {
+        if args.len() != func.params.len() {
+            bail!("wrong number of arguments for {}: {:?}", func.name, args);
+        }
+        let mut lvars = HashMap::new();
+        let mut v = 0;
+        for stmt in &func.body_stmts {
+            match self.eval(func, &args, &mut lvars, stmt) {
+                Ok(x) => v = x,
+                Err(Unwind::Return(x)) => return Ok(x),
+                Err(Unwind::Error(e)) => return Err(e),
+            }
+        }
+        Ok(v)
+    }
+
+    fn eval(
+        &mut self,
+        func: &ast::Function,
+        args: &[i64],
+        lvars: &mut HashMap<String, i64>,
+        expr: &ast::Expr,
+    ) -> EvalResult {
+        let v = match expr {
+            ast::Expr::Number(n) => *n,
+            ast::Expr::Str(s) => self.alloc(Object::Str(s.clone())),
+            ast::Expr::VarRef(s) => {
+                if let Some(idx) = func.params.iter().position(|param| param.name == *s) {
+                    args[idx]
+                } else if let Some(v) = lvars.get(s) {
+                    *v
+                } else if self.is_func(s) {
+                    self.func_handle(s)
+                } else {
+                    return Err(anyhow!("unknown variable or function '{}'", s).into());
+                }
+            }
+            ast::Expr::OpCall(op, lhs, rhs) => {
+                let l = self.eval(func, args, lvars, lhs)?;
+                let r = self.eval(func, args, lvars, rhs)?;
+                match &op[..] {
+                    "+" => l.wrapping_add(r),
+                    "-" => l.wrapping_sub(r),
+                    "==" => (l == r) as i64,
+                    "!=" => (l != r) as i64,
+                    "<" => (l < r) as i64,
+                    "<=" => (l <= r) as i64,
+                    ">" => (l > r) as i64,
+                    ">=" => (l >= r) as i64,
+                    _ => return Err(anyhow!("unknown binop `{}'", op).into()),
+                }
+            }
+            ast::Expr::FunCall(func_expr, arg_exprs) => {
+                // Evaluate the arguments first, like codegen does
+                let mut arg_values = vec![];
+                for e in arg_exprs {
+                    arg_values.push(self.eval(func, args, lvars, e)?);
+                }
+                let f = self.eval(func, args, lvars, func_expr)?;
+                self.call_handle(f, arg_values)?
+            }
+            // Every value is an integer
+            ast::Expr::Cast(expr, _) => self.eval(func, args, lvars, expr)?,
+            ast::Expr::Alloc(name, _) => {
+                lvars.insert(name.clone(), 0);
+                0
+            }
+            ast::Expr::Assign(name, rhs) => {
+                let v = self.eval(func, args, lvars, rhs)?;
+                let Some(var) = lvars.get_mut(name) else {
+                    return Err(anyhow!("unknown variable `{}'", name).into());
+                };
+                *var = v;
+                0
+            }
+            ast::Expr::New(name, arg_exprs) => {
+                let n_fields = self
+                    .structs
+                    .get(name)
+                    .with_context(|| format!("unknown struct `{}'", name))?
+                    .fields
+                    .len();
+                if arg_exprs.len() != n_fields {
+                    return Err(anyhow!("wrong number of fields for {}: {:?}", name, expr).into());
+                }
+                let mut values = vec![];
+                for e in arg_exprs {
+                    values.push(self.eval(func, args, lvars, e)?);
+                }
+                self.alloc(Object::Struct(name.clone(), values))
+            }
+            ast::Expr::FieldRef(obj_expr, field_name) => {
+                let obj = self.eval(func, args, lvars, obj_expr)?;
+                let idx = self.field_index(obj, field_name)?;
+                let Object::Struct(_, values) = self.deref(obj)? else {
+                    unreachable!()
+                };
+                values[idx]
+            }
+            ast::Expr::FieldSet(obj_expr, field_name, rhs) => {
+                let obj = self.eval(func, args, lvars, obj_expr)?;
+                let idx = self.field_index(obj, field_name)?;
+                let v = self.eval(func, args, lvars, rhs)?;
+                let Object::Struct(_, values) = self.deref_mut(obj)? else {
+                    unreachable!()
+                };
+                values[idx] = v;
+                0
+            }
+            ast::Expr::While(cond_expr, body_stmts) => {
+                while self.eval(func, args, lvars, cond_expr)? != 0 {
+                    for stmt in body_stmts {
+                        self.eval(func, args, lvars, stmt)?;
+                    }
+                }
+                0
+            }
+            ast::Expr::If(cond_expr, then_stmts, else_stmts) => {
+                let stmts = if self.eval(func, args, lvars, cond_expr)? != 0 {
+                    then_stmts
+                } else {
+                    else_stmts
+                };
+                let mut v = 0;
+                for stmt in stmts {
+                    v = self.eval(func, args, lvars, stmt)?;
+                }
+                v
+            }
+            ast::Expr::Return(val_expr) => {
+                let v = self.eval(func, args, lvars, val_expr)?;
+                return Err(Unwind::Return(v));
+            }
+        };
+        Ok(v)
+    }
+
+    fn field_index(&self, obj: i64, field_name: &str) -> Result<usize> {
+        let Object::Struct(struct_name, _) = self.deref(obj)? else {
+            bail!("expected struct but got {:?}", self.deref(obj)?);
+        };
+        let (idx, _) = self.structs[struct_name]
+            .field(field_name)
+            .with_context(|| format!("struct {} has no field `{}'", struct_name, field_name))?;
+        Ok(idx)
+    }
+
+    fn env_stack(&mut self, env: i64) -> Result<&mut Vec<i64>> {
+        match self.deref_mut(env)? {
+            Object::Env(stack) => Ok(stack),
+            x => bail!("expected env but got {:?}", x),
+        }
+    }
+
+    fn array(&mut self, arr: i64) -> Result<&mut Vec<i64>> {
+        match self.deref_mut(arr)? {
+            Object::Array(items) => Ok(items),
+            x => bail!("expected array but got {:?}", x),
+        }
+    }
+
+    fn string(&self, s: i64) -> Result<String> {
+        match self.deref(s)? {
+            Object::Str(s) => Ok(s.clone()),
+            x => bail!("expected string but got {:?}", x),
+        }
+    }
+
+    /// Returns a future which calls `cont` with `value` after `delay_ms`
+    fn pending(&mut self, delay_ms: u64, env: i64, cont: i64, value: i64) -> i64 {
+        let task = self.new_task(delay_ms, env, cont, value, self.scopes.clone());
+        self.alloc(Object::Future(vec![task]))
+    }
+
+    fn new_task(
+        &mut self,
+        delay_ms: u64,
+        env: i64,
+        cont: i64,
+        value: i64,
+        scopes: Vec<i64>,
+    ) -> Task {
+        self.n_tasks += 1;
+        Task {
+            wake_at: self.clock_ms + delay_ms,
+            seq: self.n_tasks,
+            env,
+            cont,
+            value,
+            scopes,
+        }
+    }
+
+    /// Call the async function `f` with a continuation which finishes the
+    /// `timeout`. Like the runtime, the state is pushed to the env so that
+    /// the continuation can find it
+    fn start_timeout(&mut self, env: i64, cont: i64, ms: i64, f: i64) -> Result<i64> {
+        let depth = self.env_stack(env)?.len();
+        let state = self.alloc(Object::Timeout {
+            cont,
+            depth,
+            scopes: self.scopes.clone(),
+            done: false,
+        });
+        self.env_stack(env)?.push(state);
+        self.scopes.push(state);
+        let done = self.func_handle("chiika_timeout_done");
+        let future = self.call_handle(f, vec![env, done]);
+        self.scopes.pop();
+        let future = future?;
+        if let Object::Timeout { done: true, .. } = self.deref(state)? {
+            // Finished without waiting; `future` is of the continuation
+            return Ok(future);
+        }
+        // The future of `timeout` owns the one of `f`
+        let mut tasks = self.take_tasks(future)?;
+        let expired = self.func_handle("chiika_timeout_expired");
+        let timer = self.pending(ms.max(0) as u64, env, expired, state);
+        tasks.append(&mut self.take_tasks(timer)?);
+        Ok(self.alloc(Object::Future(tasks)))
+    }
+
+    /// Finish the `timeout` of `state` and returns the future of its
+    /// continuation. Tasks of `f` are dropped if not finished
+    fn finish_timeout(&mut self, env: i64, state: i64, value: i64) -> Result<i64> {
+        let Object::Timeout {
+            cont,
+            depth,
+            scopes,
+            done,
+        } = self.deref_mut(state)?
+        else {
+            bail!("expected timeout but got {:?}", self.deref(state)?);
+        };
+        *done = true;
+        let (cont, depth, scopes) = (*cont, *depth, scopes.clone());
+        self.env_stack(env)?.truncate(depth);
+        let expired = self.func_handle("chiika_timeout_expired");
+        // The timer and the tasks of `f`
+        self.tasks
+            .retain(|t| !(t.cont == expired && t.value == state));
+        self.drop_scope(state);
+        self.scopes = scopes;
+        self.call_handle(cont, vec![env, value])
+    }
+
+    /// Drop the tasks (and the waiters of channels) created in the scope
+    fn drop_scope(&mut self, scope: i64) {
+        self.tasks.retain(|t| !t.scopes.contains(&scope));
+        for obj in &mut self.heap {
+            if let Object::Channel {
+                receivers, senders, ..
+            } = obj
+            {
+                receivers.retain(|w| !w.scopes.contains(&scope));
+                senders.retain(|(w, _)| !w.scopes.contains(&scope));
+            }
+            if let Object::Semaphore { waiters, .. } = obj {
+                waiters.retain(|w| !w.scopes.contains(&scope));
+            }
+            if let Object::Scope { waiter, .. } = obj {
+                if waiter.as_ref().is_some_and(|w| w.scopes.contains(&scope)) {
+                    *waiter = None;
+                }
+            }
+        }
+    }
+
+    /// Remove the spawned task from `live_tasks` and resume the
+    /// `chiika_scope_wait`s which no longer have tasks to wait for
+    fn task_done(&mut self, task: i64) {
+        self.live_tasks.remove(&task);
+        let mut waiters = vec![];
+        for obj in &mut self.heap {
+            if let Object::Scope { tasks, waiter } = obj {
+                if tasks.iter().all(|x| !self.live_tasks.contains(x)) {
+                    waiters.extend(waiter.take());
+                }
+            }
+        }
+        for w in waiters {
+            self.wake(w, 0);
+        }
+    }
+
+    /// Finish the spawned tasks which have nothing pending. Like the runtime,
+    /// a task finishes when its future is ready
+    fn finish_idle_tasks(&mut self) {
+        let mut pending = HashSet::new();
+        for t in &self.tasks {
+            pending.extend(t.scopes.iter().copied());
+        }
+        for obj in &self.heap {
+            match obj {
+                Object::Channel {
+                    receivers, senders, ..
+                } => {
+                    for w in receivers.iter().chain(senders.iter().map(|(w, _)| w)) {
+                        pending.extend(w.scopes.iter().copied());
+                    }
+                }
+                Object::Semaphore { waiters, .. } => {
+                    for w in waiters {
+                        pending.extend(w.scopes.iter().copied());
+                    }
+                }
+                Object::Scope {
+                    waiter: Some(w), ..
+                } => pending.extend(w.scopes.iter().copied()),
+                _ => (),
+            }
+        }
+        let idle = self
+            .live_tasks
+            .iter()
+            .copied()
+            .filter(|x| !pending.contains(x))
+            .collect::<Vec<_>>();
+        for task in idle {
+            self.task_done(task);
+        }
+    }
+
+    /// Same as `cancel` of the runtime
+    fn cancel_task(&mut self, task: i64) -> i64 {
+        if !self.live_tasks.contains(&task) {
+            return 0;
+        }
+        self.drop_scope(task);
+        self.task_done(task);
+        1
+    }
+
+    /// Called when an arm of `select` finishes. Calls the continuation of
+    /// the arm if `chiika_select` is already called (otherwise it is called
+    /// by `chiika_select`)
+    fn finish_select_arm(&mut self, arm_env: i64, value: i64) -> Result<i64> {
+        let state = *self
+            .env_stack(arm_env)?
+            .last()
+            .context("env stack underflow")?;
+        let Object::Select {
+            env, envs, winner, ..
+        } = self.deref_mut(state)?
+        else {
+            bail!("expected select but got {:?}", self.deref(state)?);
+        };
+        if winner.is_none() {
+            let i = envs
+                .iter()
+                .position(|x| *x == arm_env)
+                .context("unknown arm")?;
+            *winner = Some((i, value));
+            if env.is_some() {
+                return self.finish_select(state);
+            }
+        }
+        Ok(self.alloc(Object::Future(vec![])))
+    }
+
+    /// Drop the other arms of `select` and call the continuation of the arm
+    /// finished first
+    fn finish_select(&mut self, state: i64) -> Result<i64> {
+        let Object::Select {
+            env: Some(env),
+            scopes,
+            conts,
+            winner: Some((i, value)),
+            ..
+        } = self.deref(state)?
+        else {
+            bail!("select is not finished: {:?}", self.deref(state)?);
+        };
+        let (env, scopes, cont, value) = (*env, scopes.clone(), conts[*i], *value);
+        self.drop_scope(state);
+        self.scopes = scopes;
+        self.call_handle(cont, vec![env, value])
+    }
+
+    fn waiter(&self, env: i64, cont: i64) -> Waiter {
+        Waiter {
+            env,
+            cont,
+            scopes: self.scopes.clone(),
+        }
+    }
+
+    /// Resume the waiting task with `value`
+    fn wake(&mut self, waiter: Waiter, value: i64) {
+        let task = self.new_task(0, waiter.env, waiter.cont, value, waiter.scopes);
+        self.tasks.push(task);
+    }
+
+    fn new_channel(&mut self, capacity: usize) -> i64 {
+        self.alloc(Object::Channel {
+            buffer: VecDeque::new(),
+            capacity,
+            closed: false,
+            receivers: VecDeque::new(),
+            senders: VecDeque::new(),
+        })
+    }
+
+    fn channel(&mut self, ch: i64) -> Result<&mut Object> {
+        match self.deref_mut(ch)? {
+            x @ Object::Channel { .. } => Ok(x),
+            x => bail!("expected channel but got {:?}", x),
+        }
+    }
+
+    /// Same as `chan_send` of the runtime. Returns `None` if the sender has
+    /// to wait (then `waiter` is resumed later)
+    fn chan_send(&mut self, ch: i64, value: i64, waiter: Waiter) -> Result<Option<i64>> {
+        let Object::Channel {
+            buffer,
+            capacity,
+            closed,
+            receivers,
+            senders,
+        } = self.channel(ch)?
+        else {
+            unreachable!()
+        };
+        if *closed {
+            return Ok(Some(self.closed_result()));
+        }
+        if let Some(r) = receivers.pop_front() {
+            let ok = self.new_result(Ok(value));
+            self.wake(r, ok);
+        } else if buffer.len() < *capacity {
+            buffer.push_back(value);
+        } else {
+            senders.push_back((waiter, value));
+            return Ok(None);
+        }
+        Ok(Some(self.new_result(Ok(0))))
+    }
+
+    /// Same as `chan_recv` of the runtime. Returns `None` if the receiver
+    /// has to wait
+    fn chan_recv(&mut self, ch: i64, waiter: Waiter) -> Result<Option<i64>> {
+        let Object::Channel {
+            buffer,
+            closed,
+            receivers,
+            senders,
+            ..
+        } = self.channel(ch)?
+        else {
+            unreachable!()
+        };
+        let Some(value) = buffer.pop_front() else {
+            if *closed {
+                return Ok(Some(self.closed_result()));
+            }
+            receivers.push_back(waiter);
+            return Ok(None);
+        };
+        // Room for a waiting sender
+        if let Some((s, v)) = senders.pop_front() {
+            buffer.push_back(v);
+            let ok = self.new_result(Ok(0));
+            self.wake(s, ok);
+        }
+        Ok(Some(self.new_result(Ok(value))))
+    }
+
+    /// Close the channel and resume the receivers with `Err("closed")`.
+    /// Waiting senders still send their values like the runtime
+    fn chan_close(&mut self, ch: i64) -> Result<()> {
+        let Object::Channel {
+            closed, receivers, ..
+        } = self.channel(ch)?
+        else {
+            unreachable!()
+        };
+        *closed = true;
+        for r in std::mem::take(receivers) {
+            let err = self.closed_result();
+            self.wake(r, err);
+        }
+        Ok(())
+    }
+
+    fn closed_result(&mut self) -> i64 {
+        self.new_result(Err("closed".to_string()))
+    }
+
+    /// Take the tasks of the future out of it
+    fn take_tasks(&mut self, future: i64) -> Result<Vec<Task>> {
+        match self.deref_mut(future)? {
+            Object::Future(tasks) => Ok(std::mem::take(tasks)),
+            x => bail!("expected future but got {:?}", x),
+        }
+    }
+
+    /// Register the tasks of the future (if not completed yet). Like
+    /// `Trampoline` of the runtime, this is also done for the future returned
+    /// by a continuation, so the async call which follows runs next
+    fn schedule(&mut self, future: i64) -> Result<()> {
+        let mut tasks = self.take_tasks(future)?;
+        self.tasks.append(&mut tasks);
+        Ok(())
+    }
+
+    /// Run the tasks until all of them are finished. Like `block_on` of tokio,
+    /// the rest are dropped when `chiika_main` finishes or `chiika_exit` is
+    /// called
+    fn run_tasks(&mut self) -> Result<()> {
+        while let Some(i) = (0..self.tasks.len()).min_by_key(|i| {
+            let t = &self.tasks[*i];
+            (t.wake_at, t.seq)
+        }) {
+            if self.exit_code.is_some() {
+                self.tasks.clear();
+                break;
+            }
+            let task = self.tasks.remove(i);
+            self.clock_ms = self.clock_ms.max(task.wake_at);
+            self.scopes = task.scopes;
+            let future = self.call_handle(task.cont, vec![task.env, task.value])?;
+            self.schedule(future)?;
+            self.finish_idle_tasks();
+        }
+        Ok(())
+    }
+
+    fn call_extern(&mut self, name: &str, args: Vec<i64>) -> Result<i64> {
+        let v = match (name, &args[..]) {
+            ("chiika_start_tokio", [_]) => {
+                let env = self.alloc(Object::Env(vec![]));
+                let finish = self.func_handle("chiika_finish");
+                let future = self.call_by_name("chiika_start_user", vec![env, finish])?;
+                self.schedule(future)?;
+                self.run_tasks()?;
+                self.exit_code.unwrap_or(0)
+            }
+            // `argv` is an array of strings (see `run_with_args`)
+            ("chiika_set_args", [_argc, argv]) => {
+                let items = self.array(*argv)?.clone();
+                self.args = items
+                    .into_iter()
+                    .map(|x| self.string(x))
+                    .collect::<Result<_>>()?;
+                0
+            }
+            // The interpreter is single-threaded and has no drivers
+            ("chiika_tokio_config", [_, _]) => 0,
+            // Defined in chiika_runtime
+            ("chiika_finish", [_, value]) => {
+                self.exit_code.get_or_insert(*value);
+                self.alloc(Object::Future(vec![]))
+            }
+            // Never calls the continuation
+            ("chiika_exit", [_env, _cont, code]) => {
+                self.exit_code = Some(*code);
+                self.alloc(Object::Future(vec![]))
+            }
+            ("chiika_env_push", [env, item]) => {
+                self.env_stack(*env)?.push(*item);
+                0
+            }
+            ("chiika_env_pop", [env, n]) => {
+                let stack = self.env_stack(*env)?;
+                let mut item = 0;
+                for _ in 0..*n {
+                    item = stack.pop().context("env stack underflow")?;
+                }
+                item
+            }
+            ("chiika_env_ref", [env, n]) => {
+                let stack = self.env_stack(*env)?;
+                let idx = stack.len() as i64 - 1 - n;
+                *stack
+                    .get(idx as usize)
+                    .with_context(|| format!("invalid env index: {}", n))?
+            }
+            ("chiika_env_set", [env, n, item]) => {
+                let stack = self.env_stack(*env)?;
+                let idx = stack.len() as i64 - 1 - n;
+                *stack
+                    .get_mut(idx as usize)
+                    .with_context(|| format!("invalid env index: {}", n))? = *item;
+                0
+            }
+            ("chiika_array_new", []) => self.alloc(Object::Array(vec![])),
+            ("chiika_array_push", [arr, item]) => {
+                self.array(*arr)?.push(*item);
+                *arr
+            }
+            ("chiika_array_get", [arr, idx]) => {
+                let items = self.array(*arr)?;
+                check_index(items, *idx)?;
+                items[*idx as usize]
+            }
+            ("chiika_array_set", [arr, idx, item]) => {
+                let items = self.array(*arr)?;
+                check_index(items, *idx)?;
+                items[*idx as usize] = *item;
+                0
+            }
+            ("chiika_array_len", [arr]) => self.array(*arr)?.len() as i64,
+            ("print", [n]) => {
+                self.output.push_str(&format!("{}\n", n));
+                0
+            }
+            ("print_str", [s]) => {
+                let s = self.string(*s)?;
+                self.output.push_str(&format!("{}\n", s));
+                0
+            }
+            ("arg_count", []) => self.args.len() as i64,
+            ("arg", [n]) => {
+                let Some(arg) = usize::try_from(*n).ok().and_then(|i| self.args.get(i)) else {
+                    bail!(
+                        "argument index out of bounds: the count is {} but the index is {}",
+                        self.args.len(),
+                        n
+                    );
+                };
+                self.alloc(Object::Str(arg.clone()))
+            }
+            ("env_var", [name]) => {
+                let name = self.string(*name)?;
+                self.alloc(Object::Str(std::env::var(name).unwrap_or_default()))
+            }
+            ("sleep_sec", [env, cont, n]) => self.pending(*n as u64 * 1000, *env, *cont, *n),
+            ("sleep_ms", [env, cont, n]) => self.pending((*n).max(0) as u64, *env, *cont, *n),
+            ("now_ms", []) => self.clock_ms as i64,
+            ("timeout", [env, cont, ms, f]) => self.start_timeout(*env, *cont, *ms, *f)?,
+            // Defined in chiika_runtime. The state is above the frame of `f`
+            ("chiika_timeout_done", [env, value]) => {
+                let state = *self
+                    .env_stack(*env)?
+                    .last()
+                    .context("env stack underflow")?;
+                self.finish_timeout(*env, state, *value)?
+            }
+            ("chiika_timeout_expired", [env, state]) => self.finish_timeout(*env, *state, -1)?,
+            // The calls of the arms are scoped by `state` like `timeout` so
+            // that the losers are dropped. The scope is entered when the env
+            // of the arm is created and left when its future is registered
+            ("chiika_select_new", []) => self.alloc(Object::Select {
+                env: None,
+                scopes: self.scopes.clone(),
+                envs: vec![],
+                conts: vec![],
+                tasks: vec![],
+                winner: None,
+            }),
+            ("chiika_select_env", [state]) => {
+                let env = self.alloc(Object::Env(vec![*state]));
+                let Object::Select { envs, .. } = self.deref_mut(*state)? else {
+                    bail!("expected select but got {:?}", self.deref(*state)?);
+                };
+                envs.push(env);
+                self.scopes.push(*state);
+                env
+            }
+            ("chiika_select_arm", [state, future, cont]) => {
+                self.scopes.pop();
+                let mut arm_tasks = self.take_tasks(*future)?;
+                let Object::Select { conts, tasks, .. } = self.deref_mut(*state)? else {
+                    bail!("expected select but got {:?}", self.deref(*state)?);
+                };
+                conts.push(*cont);
+                tasks.append(&mut arm_tasks);
+                0
+            }
+            ("chiika_select_done", [env, value]) => self.finish_select_arm(*env, *value)?,
+            // Like the runtime, the arms are raced only when the future is
+            // polled
+            ("chiika_select", [env, state]) => {
+                let start = self.func_handle("chiika_select_start");
+                self.pending(0, *env, start, *state)
+            }
+            ("chiika_select_start", [env, state]) => {
+                let Object::Select {
+                    env: caller_env,
+                    tasks,
+                    winner,
+                    ..
+                } = self.deref_mut(*state)?
+                else {
+                    bail!("expected select but got {:?}", self.deref(*state)?);
+                };
+                *caller_env = Some(*env);
+                if winner.is_some() {
+                    // An arm finished without waiting
+                    self.finish_select(*state)?
+                } else {
+                    let mut tasks = std::mem::take(tasks);
+                    self.tasks.append(&mut tasks);
+                    self.alloc(Object::Future(vec![]))
+                }
+            }
+            // Ticks at creation and every `ms` after that (like tokio's
+            // `MissedTickBehavior::Burst`)
+            ("interval_new", [ms]) => self.alloc(Object::Interval {
+                period_ms: (*ms).max(1) as u64,
+                next_at: self.clock_ms,
+                ticks: 0,
+            }),
+            ("interval_tick", [env, cont, handle]) => {
+                let clock_ms = self.clock_ms;
+                let Object::Interval {
+                    period_ms,
+                    next_at,
+                    ticks,
+                } = self.deref_mut(*handle)?
+                else {
+                    bail!("expected interval but got {:?}", self.deref(*handle)?);
+                };
+                let delay = next_at.saturating_sub(clock_ms);
+                *next_at += *period_ms;
+                *ticks += 1;
+                let ticks = *ticks;
+                self.pending(delay, *env, *cont, ticks)
+            }
+            ("interval_free", [handle]) => {
+                match self.deref_mut(*handle)? {
+                    x @ Object::Interval { .. } => *x = Object::Closed,
+                    x => bail!("expected interval but got {:?}", x),
+                }
+                0
+            }
+            ("read_int", [env, cont, path]) => {
+                let path = self.string(*path)?;
+                let result = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("{}: {}", path, e))
+                    .and_then(|s| s.trim().parse().map_err(|e| format!("{}: {}", path, e)));
+                let value = self.new_result(result);
+                self.pending(0, *env, *cont, value)
+            }
+            // Like the runtime, `f` is called after the current task waits.
+            // The env of the task serves as the id, and as the scope of the
+            // task so that `cancel` can drop its tasks like `timeout`
+            ("spawn", [f, arg]) => {
+                let env = self.alloc(Object::Env(vec![]));
+                let start = self.func_handle("chiika_task_start");
+                let call = self.alloc(Object::Array(vec![*f, *arg]));
+                let task = self.new_task(0, env, start, call, vec![env]);
+                self.tasks.push(task);
+                self.live_tasks.insert(env);
+                env
+            }
+            ("cancel", [task]) => self.cancel_task(*task),
+            // `call` is `[f, arg]`
+            ("chiika_task_start", [env, call]) => {
+                let [f, arg] = self.array(*call)?[..] else {
+                    bail!("invalid task: {:?}", self.deref(*call)?);
+                };
+                let finish = self.func_handle("chiika_task_finish");
+                // Polled like the future of `chiika_start_user`
+                let future = self.call_handle(f, vec![*env, finish, arg])?;
+                self.schedule(future)?;
+                self.alloc(Object::Future(vec![]))
+            }
+            // Defined in chiika_runtime
+            // The task is finished by `finish_idle_tasks`
+            ("chiika_task_finish", [_, _]) => self.alloc(Object::Future(vec![])),
+            ("chiika_scope_new", []) => self.alloc(Object::Scope {
+                tasks: vec![],
+                waiter: None,
+            }),
+            ("chiika_scope_add", [scope, task]) => {
+                self.scope_tasks(*scope)?.push(*task);
+                *task
+            }
+            // Like `chan_recv`, nothing happens until the future is polled
+            ("chiika_scope_wait", [env, cont, scope]) => {
+                let op = self.alloc(Object::Array(vec![*cont, *scope]));
+                let start = self.func_handle("chiika_scope_start");
+                self.pending(0, *env, start, op)
+            }
+            // `op` is `[cont, scope]`
+            ("chiika_scope_start", [env, op]) => {
+                let [cont, scope] = self.array(*op)?[..] else {
+                    bail!("invalid scope: {:?}", self.deref(*op)?);
+                };
+                let tasks = self.scope_tasks(scope)?.clone();
+                if tasks.iter().all(|x| !self.live_tasks.contains(x)) {
+                    self.call_handle(cont, vec![*env, 0])?
+                } else {
+                    let w = self.waiter(*env, cont);
+                    let Object::Scope { waiter, .. } = self.deref_mut(scope)? else {
+                        unreachable!()
+                    };
+                    *waiter = Some(w);
+                    self.alloc(Object::Future(vec![]))
+                }
+            }
+            ("chiika_scope_cancel", [scope]) => {
+                for task in self.scope_tasks(*scope)?.clone() {
+                    self.cancel_task(task);
+                }
+                0
+            }
+            ("chan_new", [capacity]) => self.new_channel((*capacity).max(1) as usize),
+            ("oneshot_new", []) => self.new_channel(1),
+            // Like the runtime, nothing is sent or received until the future
+            // is polled (see `chiika_chan_start`)
+            ("chan_send", [env, cont, ch, value]) => {
+                let op = self.alloc(Object::Array(vec![*cont, *ch, *value]));
+                let start = self.func_handle("chiika_chan_start");
+                self.pending(0, *env, start, op)
+            }
+            ("chan_recv", [env, cont, ch]) => {
+                let op = self.alloc(Object::Array(vec![*cont, *ch]));
+                let start = self.func_handle("chiika_chan_start");
+                self.pending(0, *env, start, op)
+            }
+            // `op` is `[cont, ch, value]` for `chan_send` and `[cont, ch]`
+            // for `chan_recv`
+            ("chiika_chan_start", [env, op]) => {
+                let (cont, result) = match self.array(*op)?[..] {
+                    [cont, ch, value] => {
+                        let waiter = self.waiter(*env, cont);
+                        (cont, self.chan_send(ch, value, waiter)?)
+                    }
+                    [cont, ch] => {
+                        let waiter = self.waiter(*env, cont);
+                        (cont, self.chan_recv(ch, waiter)?)
+                    }
+                    _ => bail!("invalid channel operation: {:?}", self.deref(*op)?),
+                };
+                match result {
+                    Some(result) => self.call_handle(cont, vec![*env, result])?,
+                    None => self.alloc(Object::Future(vec![])),
+                }
+            }
+            ("chan_close", [ch]) => {
+                self.chan_close(*ch)?;
+                0
+            }
+            // Only closed, as the sends and receives not started yet still
+            // refer to it (like the runtime, where they hold the channel)
+            ("chan_free", [ch]) => {
+                self.chan_close(*ch)?;
+                0
+            }
+            // The buffer is never full because the channel is closed after
+            // the first value
+            ("oneshot_send", [ch, value]) => {
+                let waiter = self.waiter(0, 0);
+                let result = self.chan_send(*ch, *value, waiter)?;
+                self.chan_close(*ch)?;
+                result.context("oneshot channel is full")?
+            }
+            ("mutex_new", []) => self.alloc(Object::Semaphore {
+                permits: 1,
+                acquired: 0,
+                waiters: VecDeque::new(),
+            }),
+            ("semaphore_new", [permits]) => self.alloc(Object::Semaphore {
+                permits: (*permits).max(0),
+                acquired: 0,
+                waiters: VecDeque::new(),
+            }),
+            // Like `chan_recv`, nothing happens until the future is polled
+            ("mutex_lock" | "semaphore_acquire", [env, cont, handle]) => {
+                let op = self.alloc(Object::Array(vec![*cont, *handle]));
+                let start = self.func_handle("chiika_acquire_start");
+                self.pending(0, *env, start, op)
+            }
+            // `op` is `[cont, handle]`
+            ("chiika_acquire_start", [env, op]) => {
+                let [cont, handle] = self.array(*op)?[..] else {
+                    bail!("invalid lock: {:?}", self.deref(*op)?);
+                };
+                let waiter = self.waiter(*env, cont);
+                let (permits, acquired, waiters) = self.semaphore(handle)?;
+                if *permits > 0 {
+                    *permits -= 1;
+                    *acquired += 1;
+                    self.call_handle(cont, vec![*env, 0])?
+                } else {
+                    waiters.push_back(waiter);
+                    self.alloc(Object::Future(vec![]))
+                }
+            }
+            ("mutex_unlock" | "semaphore_release", [handle]) => self.release(*handle)? as i64,
+            // Only the permits are released, as the locks not started yet
+            // still refer to it (like `chan_free`)
+            ("mutex_free" | "semaphore_free", [handle]) => {
+                while self.release(*handle)? {}
+                0
+            }
+            // TCP externs are blocking; programs must not wait for
+            // themselves, e.g. read before writing to the other end
+            ("tcp_listen", [env, cont, host, port]) => {
+                let addr = format!("{}:{}", self.string(*host)?, port);
+                let result = match std::net::TcpListener::bind(&addr) {
+                    Ok(x) => Ok(self.alloc(Object::TcpListener(x))),
+                    Err(e) => Err(format!("{}: {}", addr, e)),
+                };
+                let value = self.new_result(result);
+                self.pending(0, *env, *cont, value)
+            }
+            ("tcp_local_port", [handle]) => {
+                let listener = self.tcp_listener(*handle)?;
+                listener.local_addr().map_or(0, |x| x.port() as i64)
+            }
+            ("tcp_accept", [env, cont, handle]) => {
+                let result = match self.tcp_listener(*handle)?.accept() {
+                    Ok((x, _)) => Ok(self.alloc(Object::TcpStream(x, vec![]))),
+                    Err(e) => Err(e.to_string()),
+                };
+                let value = self.new_result(result);
+                self.pending(0, *env, *cont, value)
+            }
+            ("tcp_connect", [env, cont, host, port]) => {
+                let addr = format!("{}:{}", self.string(*host)?, port);
+                let result = match std::net::TcpStream::connect(&addr) {
+                    Ok(x) => Ok(self.alloc(Object::TcpStream(x, vec![]))),
+                    Err(e) => Err(format!("{}: {}", addr, e)),
+                };
+                let value = self.new_result(result);
+                self.pending(0, *env, *cont, value)
+            }
+            ("tcp_read", [env, cont, handle, max_len]) => {
+                let mut buf = vec![0; (*max_len).max(0) as usize];
+                let (stream, received) = self.tcp_stream(*handle)?;
+                let result = std::io::Read::read(stream, &mut buf).map_err(|e| e.to_string());
+                if let Ok(n) = result {
+                    received.extend_from_slice(&buf[..n]);
+                }
+                let value = self.new_result(result.map(|n| n as i64));
+                self.pending(0, *env, *cont, value)
+            }
+            ("tcp_received", [handle]) => {
+                let (_, received) = self.tcp_stream(*handle)?;
+                let s = String::from_utf8_lossy(&std::mem::take(received)).into_owned();
+                self.alloc(Object::Str(s))
+            }
+            ("tcp_write", [env, cont, handle, data]) => {
+                let data = self.string(*data)?;
+                let result =
+                    std::io::Write::write_all(self.tcp_stream(*handle)?.0, data.as_bytes())
+                        .map(|_| data.len() as i64)
+                        .map_err(|e| e.to_string());
+                let value = self.new_result(result);
+                self.pending(0, *env, *cont, value)
+            }
+            ("tcp_close", [handle]) => {
+                match self.deref_mut(*handle)? {
+                    x @ (Object::TcpListener(_) | Object::TcpStream(..)) => *x = Object::Closed,
+                    x => bail!("expected TCP listener or connection but got {:?}", x),
+                }
+                0
+            }
+            _ => bail!(
+                "extern `{}' is not supported by the interpreter (args: {:?})",
+                name,
+                args
+            ),
+        };
+        Ok(v)
+    }
+
+    fn semaphore(&mut self, handle: i64) -> Result<(&mut i64, &mut i64, &mut VecDeque<Waiter>)> {
+        match self.deref_mut(handle)? {
+            Object::Semaphore {
+                permits,
+                acquired,
+                waiters,
+            } => Ok((permits, acquired, waiters)),
+            x => bail!("expected mutex or semaphore but got {:?}", x),
+        }
+    }
+
+    /// Give a permit to the first waiter, or back to the semaphore. Returns
+    /// false if no permit is acquired
+    fn release(&mut self, handle: i64) -> Result<bool> {
+        let (permits, acquired, waiters) = self.semaphore(handle)?;
+        if *acquired == 0 {
+            return Ok(false);
+        }
+        match waiters.pop_front() {
+            // Stays acquired
+            Some(w) => self.wake(w, 0),
+            None => {
+                *permits += 1;
+                *acquired -= 1;
+            }
+        }
+        Ok(true)
+    }
+
+    fn scope_tasks(&mut self, scope: i64) -> Result<&mut Vec<i64>> {
+        match self.deref_mut(scope)? {
+            Object::Scope { tasks, .. } => Ok(tasks),
+            x => bail!("expected scope but got {:?}", x),
+        }
+    }
+
+    fn tcp_listener(&self, handle: i64) -> Result<&std::net::TcpListener> {
+        match self.deref(handle)? {
+            Object::TcpListener(x) => Ok(x),
+            x => bail!("expected TCP listener but got {:?}", x),
+        }
+    }
+
+    fn tcp_stream(&mut self, handle: i64) -> Result<(&mut std::net::TcpStream, &mut Vec<u8>)> {
+        match self.deref_mut(handle)? {
+            Object::TcpStream(x, received) => Ok((x, received)),
+            x => bail!("expected TCP connection but got {:?}", x),
+        }
+    }
+
+    /// Create a value of `Result` of chiika-2 (see chiika_result.rs of the runtime)
+    fn new_result(&mut self, result: std::result::Result<i64, String>) -> i64 {
+        let (tag, value) = match result {
+            Ok(n) => (0, n),
+            Err(msg) => (1, self.alloc(Object::Str(msg))),
+        };
+        self.alloc(Object::Struct("Result".to_string(), vec![tag, value]))
+    }
+}
+
+/// Same check as chiika_array.rs of the runtime
+fn check_index(items: &[i64], idx: i64) -> Result<()> {
+    if idx < 0 || idx as usize >= items.len() {
+        bail!(
+            "array index out of bounds: the len is {} but the index is {}",
+            items.len(),
+            idx
+        );
+    }
+    Ok(())
+}
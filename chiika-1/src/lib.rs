@@ -0,0 +1,7 @@
+//! chiika-1 as a library. The REPL of chiika-2 uses the codegen to
+//! JIT-compile its inputs
+pub mod ast;
+#[cfg(feature = "llvm")]
+pub mod codegen;
+pub mod interp;
+pub mod parser;
@@ -1,10 +1,7 @@
-mod ast;
-mod codegen;
-mod parser;
 use anyhow::{bail, Context, Result};
 use ariadne::{Label, Report, ReportKind, Source};
+use chiika::{ast, interp, parser::parser};
 use chumsky::Parser;
-use parser::parser;
 
 fn render_parse_error(src: &str, span: std::ops::Range<usize>, msg: String) -> String {
     let mut rendered = vec![];
@@ -19,8 +16,11 @@ fn render_parse_error(src: &str, span: std::ops::Range<usize>, msg: String) -> S
 
 fn main() -> Result<()> {
     let args = std::env::args().collect::<Vec<_>>();
-    let Some(path) = args.get(1) else {
-        bail!("usage: chiika-1 a.chiika1");
+    // With `--interp`, the rest of the arguments are given to the program
+    let (interp_mode, path, prog_args) = match &args[1..] {
+        [flag, path, rest @ ..] if flag == "--interp" => (true, path, rest),
+        [path] => (false, path, &[][..]),
+        _ => bail!("usage: chiika-1 [--interp] a.chiika1 [args...]"),
     };
     let src = std::fs::read_to_string(path).context(format!("failed to read {}", path))?;
     let ast = match parser().parse(src) {
@@ -35,5 +35,21 @@ fn main() -> Result<()> {
         }
     };
     //dbg!(&ast);
-    codegen::run(ast)
+    if interp_mode {
+        let prog_args = [std::slice::from_ref(path), prog_args].concat();
+        let (output, status) = interp::run_with_args(ast, &prog_args)?;
+        print!("{}", output);
+        std::process::exit(status as i32);
+    }
+    compile(ast)
+}
+
+#[cfg(feature = "llvm")]
+fn compile(ast: Vec<ast::Declaration>) -> Result<()> {
+    chiika::codegen::run(ast)
+}
+
+#[cfg(not(feature = "llvm"))]
+fn compile(_ast: Vec<ast::Declaration>) -> Result<()> {
+    bail!("chiika-1 is built without LLVM; use `--interp`")
 }
@@ -1,22 +1,40 @@
 use crate::ast;
 use chumsky::prelude::*;
 
+/// `// ...` or `/* ... */`
+pub fn comment_parser() -> impl Parser<char, (), Error = Simple<char>> + Clone {
+    let line = just("//")
+        .then(filter(|c: &char| *c != '\n').repeated())
+        .ignored();
+    let block = just("/*").then(take_until(just("*/"))).ignored();
+    line.or(block)
+}
+
+/// Whitespaces and comments
+pub fn ws() -> impl Parser<char, (), Error = Simple<char>> + Clone {
+    filter(|c: &char| c.is_whitespace())
+        .ignored()
+        .or(comment_parser())
+        .repeated()
+        .ignored()
+}
+
 pub fn ty_parser() -> impl Parser<char, ast::Ty, Error = Simple<char>> {
     recursive(|ty| {
         let params = ty
             .clone()
-            .padded()
+            .padded_by(ws())
             .separated_by(just(','))
             .delimited_by(just('('), just(')'));
         let sig = params
-            .then_ignore(just("->").padded())
+            .then_ignore(just("->").padded_by(ws()))
             .then(ty.clone())
             .delimited_by(just('('), just(')'));
         let fn_ty = just("$FN")
             .ignore_then(sig)
             .map(|(param_tys, ret_ty)| ast::Ty::fun(param_tys, ret_ty));
 
-        let raw_ty = ident_parser().map(|name| ast::Ty::Raw(name));
+        let raw_ty = ident_parser().map(ast::Ty::Raw);
 
         fn_ty.or(raw_ty)
     })
@@ -49,15 +67,40 @@ pub fn atomic_parser(
         .unwrapped()
         .map(ast::Expr::Number);
 
+    let escape = just('\\').ignore_then(just('\\').or(just('"')).or(just('n').to('\n')));
+    let str_lit = filter(|c: &char| *c != '\\' && *c != '"')
+        .or(escape)
+        .repeated()
+        .delimited_by(just('"'), just('"'))
+        .collect::<String>()
+        .map(ast::Expr::Str);
+
     let parenthesized = expr_parser.clone().delimited_by(just('('), just(')'));
 
+    let args = expr_parser
+        .clone()
+        .padded_by(ws())
+        .separated_by(just(','))
+        .delimited_by(just('('), just(')'));
+
     let funcall = (varref_parser().or(parenthesized.clone()))
-        .then_ignore(just('('))
-        .then(expr_parser.clone().padded().separated_by(just(',')))
-        .then_ignore(just(')'))
+        .then(args.clone())
         .map(create_funcall);
 
-    funcall.or(parenthesized).or(varref_parser()).or(number)
+    let new = text::keyword("new")
+        .ignore_then(ident_parser().padded_by(ws()))
+        .then(args)
+        .map(|(name, args)| ast::Expr::New(name, args));
+
+    let field = just('.').ignore_then(ident_parser());
+
+    new.or(funcall)
+        .or(parenthesized)
+        .or(varref_parser())
+        .or(number)
+        .or(str_lit)
+        .then(field.repeated())
+        .foldl(|obj, name| ast::Expr::FieldRef(Box::new(obj), name))
 }
 
 pub fn expr_parser() -> impl Parser<char, ast::Expr, Error = Simple<char>> {
@@ -70,58 +113,121 @@ pub fn expr_parser() -> impl Parser<char, ast::Expr, Error = Simple<char>> {
             .or(just("+"))
             .or(just("-"));
         let sum = atomic_parser(expr.clone())
-            .then(bin_op.padded().then(atomic_parser(expr.clone())).repeated())
+            .then(
+                bin_op
+                    .padded_by(ws())
+                    .then(atomic_parser(expr.clone()))
+                    .repeated(),
+            )
             .foldl(|lhs, (op, rhs)| {
                 ast::Expr::OpCall(op.to_string(), Box::new(lhs), Box::new(rhs))
             });
 
         let in_cast = atomic_parser(expr.clone())
-            .then_ignore(just("as").padded())
-            .then(ty_parser().padded());
+            .then_ignore(just("as").padded_by(ws()))
+            .then(ty_parser().padded_by(ws()));
         let cast = just("$CAST")
             .ignore_then(in_cast.delimited_by(just('('), just(')')))
             .map(|(expr, ty)| ast::Expr::Cast(Box::new(expr), ty));
 
+        // `alloc x` (int) or `alloc Point x`
         let alloc = just("alloc")
-            .padded()
-            .ignore_then(ident_parser())
-            .map(ast::Expr::Alloc);
+            .padded_by(ws())
+            .ignore_then(ty_parser())
+            .then(ident_parser().padded_by(ws()).or_not())
+            .try_map(|(ty, name), span| match (ty, name) {
+                (ty, Some(name)) => Ok(ast::Expr::Alloc(name, ty)),
+                (ast::Ty::Raw(name), None) => {
+                    Ok(ast::Expr::Alloc(name, ast::Ty::Raw("int".to_string())))
+                }
+                _ => Err(Simple::custom(span, "missing variable name")),
+            });
 
         let assign = ident_parser()
-            .padded()
-            .then_ignore(just('=').padded())
+            .padded_by(ws())
+            .then_ignore(just('=').padded_by(ws()))
             .then(expr.clone())
             .map(|(name, rhs)| ast::Expr::Assign(name, Box::new(rhs)));
 
-        alloc.or(assign).or(cast).or(sum).or(atomic_parser(expr))
+        let field_set = atomic_parser(expr.clone())
+            .then_ignore(just('=').padded_by(ws()))
+            .then(expr.clone())
+            .try_map(|(lhs, rhs), span| match lhs {
+                ast::Expr::FieldRef(obj, name) => Ok(ast::Expr::FieldSet(obj, name, Box::new(rhs))),
+                _ => Err(Simple::custom(span, "invalid left-hand side of assignment")),
+            });
+
+        let block = expr
+            .clone()
+            .padded_by(ws())
+            .separated_by(just(';'))
+            .allow_trailing()
+            .padded_by(ws())
+            .delimited_by(just('{'), just('}'));
+
+        let while_ = text::keyword("while")
+            .ignore_then(expr.clone().padded_by(ws()))
+            .then(block.clone())
+            .map(|(cond, body)| ast::Expr::While(Box::new(cond), body));
+
+        let if_ = text::keyword("if")
+            .ignore_then(expr.clone().padded_by(ws()))
+            .then(block.clone())
+            .then(
+                text::keyword("else")
+                    .padded_by(ws())
+                    .ignore_then(block.clone())
+                    .or_not(),
+            )
+            .map(|((cond, then), els)| {
+                ast::Expr::If(Box::new(cond), then, els.unwrap_or_default())
+            });
+
+        let return_ = text::keyword("return")
+            .ignore_then(expr.clone().padded_by(ws()))
+            .map(|e| ast::Expr::Return(Box::new(e)));
+
+        while_
+            .or(if_)
+            .or(return_)
+            .or(alloc)
+            .or(assign)
+            .or(field_set)
+            .or(cast)
+            .or(sum)
+            .or(atomic_parser(expr))
     })
 }
 
 pub fn stmts_parser() -> impl Parser<char, Vec<ast::Expr>, Error = Simple<char>> {
     expr_parser()
-        .padded()
+        .padded_by(ws())
         .separated_by(just(';'))
         .allow_trailing()
 }
 
 pub fn param_parser() -> impl Parser<char, ast::Param, Error = Simple<char>> {
     ty_parser()
-        .padded()
+        .padded_by(ws())
         .then(ident_parser())
         .map(|(ty, name)| ast::Param { ty, name })
 }
 
 pub fn params_parser() -> impl Parser<char, Vec<ast::Param>, Error = Simple<char>> {
-    param_parser().padded().separated_by(just(','))
+    param_parser().padded_by(ws()).separated_by(just(','))
 }
 
 pub fn func_parser() -> impl Parser<char, ast::Function, Error = Simple<char>> {
     just("func")
-        .ignore_then(ident_parser().padded())
+        .ignore_then(ident_parser().padded_by(ws()))
         .then(params_parser().delimited_by(just('('), just(')')))
-        .then_ignore(just("->").padded())
-        .then(ty_parser().padded())
-        .then(stmts_parser().padded().delimited_by(just('{'), just('}')))
+        .then_ignore(just("->").padded_by(ws()))
+        .then(ty_parser().padded_by(ws()))
+        .then(
+            stmts_parser()
+                .padded_by(ws())
+                .delimited_by(just('{'), just('}')),
+        )
         .map(|(((name, params), ret_ty), body_stmts)| ast::Function {
             name,
             params,
@@ -132,11 +238,11 @@ pub fn func_parser() -> impl Parser<char, ast::Function, Error = Simple<char>> {
 
 pub fn extern_parser() -> impl Parser<char, ast::Extern, Error = Simple<char>> {
     just("extern")
-        .ignore_then(ident_parser().padded())
+        .ignore_then(ident_parser().padded_by(ws()))
         .then(params_parser().delimited_by(just('('), just(')')))
-        .then_ignore(just("->").padded())
-        .then(ty_parser().padded())
-        .then_ignore(just(';').padded())
+        .then_ignore(just("->").padded_by(ws()))
+        .then(ty_parser().padded_by(ws()))
+        .then_ignore(just(';').padded_by(ws()))
         .map(|((name, params), ret_ty)| ast::Extern {
             name,
             params,
@@ -144,12 +250,27 @@ pub fn extern_parser() -> impl Parser<char, ast::Extern, Error = Simple<char>> {
         })
 }
 
+pub fn struct_parser() -> impl Parser<char, ast::Struct, Error = Simple<char>> {
+    just("struct")
+        .ignore_then(ident_parser().padded_by(ws()))
+        .then(
+            param_parser()
+                .padded_by(ws())
+                .then_ignore(just(';'))
+                .repeated()
+                .padded_by(ws())
+                .delimited_by(just('{'), just('}')),
+        )
+        .map(|(name, fields)| ast::Struct { name, fields })
+}
+
 pub fn decl_parser() -> impl Parser<char, ast::Declaration, Error = Simple<char>> {
     func_parser()
         .map(ast::Declaration::Function)
         .or(extern_parser().map(ast::Declaration::Extern))
+        .or(struct_parser().map(ast::Declaration::Struct))
 }
 
 pub fn parser() -> impl Parser<char, Vec<ast::Declaration>, Error = Simple<char>> {
-    decl_parser().padded().repeated().then_ignore(end())
+    decl_parser().padded_by(ws()).repeated().then_ignore(end())
 }
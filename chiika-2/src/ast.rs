@@ -1,7 +1,83 @@
 #[derive(PartialEq, Debug, Clone)]
 pub enum Declaration {
+    Struct(Struct),
+    Enum(Enum),
     Extern(Extern),
     Function(Function),
+    /// Only in the output of the compiler (comments in the source are not
+    /// kept in the AST)
+    Comment(String),
+    /// `import "path";`. Resolved by `module::load` before compilation
+    Import(String),
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Struct {
+    pub name: String,
+    pub fields: Vec<Param>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Enum {
+    pub name: String,
+    pub variants: Vec<Variant>,
+}
+
+impl Enum {
+    /// Returns the tag number and the definition of the variant
+    pub fn variant(&self, name: &str) -> Option<(usize, &Variant)> {
+        self.variants
+            .iter()
+            .position(|x| x.name == name)
+            .map(|idx| (idx, &self.variants[idx]))
+    }
+
+    /// Convert to the struct which represents the values of this enum.
+    /// The first field holds the tag and the rest holds the payloads.
+    pub fn to_struct(&self) -> Struct {
+        let n_fields = self
+            .variants
+            .iter()
+            .map(|x| x.param_tys.len())
+            .max()
+            .unwrap_or(0);
+        let mut fields = vec![Param::new(Ty::raw("int"), "$tag")];
+        for i in 0..n_fields {
+            fields.push(Param::new(Ty::raw("$any"), &enum_field_name(i)));
+        }
+        Struct {
+            name: self.name.clone(),
+            fields,
+        }
+    }
+}
+
+/// The built-in enum to represent the result of fallible functions
+pub fn result_enum() -> Enum {
+    Enum {
+        name: "Result".to_string(),
+        variants: vec![
+            Variant {
+                name: "Ok".to_string(),
+                param_tys: vec![Ty::raw("int")],
+            },
+            Variant {
+                name: "Err".to_string(),
+                param_tys: vec![Ty::raw("string")],
+            },
+        ],
+    }
+}
+
+/// Name of the struct field to hold the i-th payload of an enum value
+pub fn enum_field_name(i: usize) -> String {
+    format!("$val{}", i)
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Variant {
+    pub name: String,
+    pub param_tys: Vec<Ty>,
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -28,6 +104,15 @@ pub struct Function {
     pub params: Vec<Param>,
     pub ret_ty: Ty,
     pub body_stmts: Vec<Expr>,
+    pub attrs: Vec<Attribute>,
+}
+
+/// `#[name(key = value, ...)]` before a function. Values are `Number` or
+/// `Str`
+#[derive(PartialEq, Debug, Clone)]
+pub struct Attribute {
+    pub name: String,
+    pub args: Vec<(String, Expr)>,
 }
 
 impl Function {
@@ -77,12 +162,55 @@ pub struct FunTy {
 #[derive(PartialEq, Debug, Clone)]
 pub enum Expr {
     Number(i64),
+    Str(String),
     VarRef(String),
     OpCall(String, Box<Expr>, Box<Expr>),
     FunCall(Box<Expr>, Vec<Expr>),
     Cast(Box<Expr>, Ty),
-    Alloc(String),
+    Alloc(String, Ty),
     Assign(String, Box<Expr>),
+    New(String, Vec<Expr>),
+    FieldRef(Box<Expr>, String),
+    FieldSet(Box<Expr>, String, Box<Expr>),
+    ArrayLit(Vec<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+    IndexSet(Box<Expr>, Box<Expr>, Box<Expr>),
+    MethodCall(Box<Expr>, String, Vec<Expr>),
+    For(String, Box<Expr>, Vec<Expr>),
+    While(Box<Expr>, Vec<Expr>),
+    If(Box<Expr>, Vec<Expr>, Vec<Expr>),
+    // `Shape::Rect(1, 2)`
+    EnumNew(String, String, Vec<Expr>),
+    Match(Box<Expr>, Vec<MatchArm>),
+    // `select { x = foo() => ..., _ = bar() => ... }`
+    Select(Vec<SelectArm>),
+    // `scope { ... }`
+    Scope(Vec<Expr>),
+    // `foo()?`
+    Try(Box<Expr>),
+    Return(Box<Expr>),
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct MatchArm {
+    pub pattern: Pattern,
+    pub body: Vec<Expr>,
+}
+
+/// `var = call => body`. `var` is `_` if the value is not used
+#[derive(PartialEq, Debug, Clone)]
+pub struct SelectArm {
+    pub var: String,
+    pub call: Expr,
+    pub body: Vec<Expr>,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Pattern {
+    // `Shape::Rect(w, h)`
+    Variant(String, String, Vec<String>),
+    // `_`
+    Wildcard,
 }
 
 pub fn to_source(ast: Vec<Declaration>) -> String {
@@ -96,17 +224,122 @@ impl Expr {
     pub fn var_ref(name: impl Into<String>) -> Expr {
         Expr::VarRef(name.into())
     }
+
+    pub fn fun_call(name: impl Into<String>, args: Vec<Expr>) -> Expr {
+        Expr::FunCall(Box::new(Expr::var_ref(name)), args)
+    }
+
+    /// Returns a new expr whose direct subexpressions are replaced by `f`
+    pub fn map_children(self, f: &mut impl FnMut(Expr) -> Expr) -> Expr {
+        let mut g = |e: Box<Expr>| Box::new(f(*e));
+        match self {
+            Expr::Number(_) | Expr::Str(_) | Expr::VarRef(_) | Expr::Alloc(_, _) => self,
+            Expr::OpCall(op, l, r) => {
+                let l = g(l);
+                Expr::OpCall(op, l, g(r))
+            }
+            Expr::FunCall(fexpr, args) => {
+                let fexpr = g(fexpr);
+                Expr::FunCall(fexpr, args.into_iter().map(&mut *f).collect())
+            }
+            Expr::Cast(e, ty) => Expr::Cast(g(e), ty),
+            Expr::Assign(name, e) => Expr::Assign(name, g(e)),
+            Expr::New(name, args) => Expr::New(name, args.into_iter().map(f).collect()),
+            Expr::FieldRef(obj, name) => Expr::FieldRef(g(obj), name),
+            Expr::FieldSet(obj, name, e) => {
+                let obj = g(obj);
+                Expr::FieldSet(obj, name, g(e))
+            }
+            Expr::ArrayLit(exprs) => Expr::ArrayLit(exprs.into_iter().map(f).collect()),
+            Expr::Index(arr, idx) => {
+                let arr = g(arr);
+                Expr::Index(arr, g(idx))
+            }
+            Expr::IndexSet(arr, idx, e) => {
+                let arr = g(arr);
+                let idx = g(idx);
+                Expr::IndexSet(arr, idx, g(e))
+            }
+            Expr::MethodCall(obj, name, args) => {
+                let obj = g(obj);
+                Expr::MethodCall(obj, name, args.into_iter().map(&mut *f).collect())
+            }
+            Expr::For(var, arr, body) => {
+                let arr = g(arr);
+                Expr::For(var, arr, body.into_iter().map(&mut *f).collect())
+            }
+            Expr::While(cond, body) => {
+                let cond = g(cond);
+                Expr::While(cond, body.into_iter().map(&mut *f).collect())
+            }
+            Expr::If(cond, then, els) => {
+                let cond = g(cond);
+                let then = then.into_iter().map(&mut *f).collect();
+                Expr::If(cond, then, els.into_iter().map(&mut *f).collect())
+            }
+            Expr::EnumNew(name, variant, args) => {
+                Expr::EnumNew(name, variant, args.into_iter().map(f).collect())
+            }
+            Expr::Match(e, arms) => {
+                let e = g(e);
+                let arms = arms
+                    .into_iter()
+                    .map(|arm| MatchArm {
+                        pattern: arm.pattern,
+                        body: arm.body.into_iter().map(&mut *f).collect(),
+                    })
+                    .collect();
+                Expr::Match(e, arms)
+            }
+            Expr::Select(arms) => {
+                let arms = arms
+                    .into_iter()
+                    .map(|arm| SelectArm {
+                        var: arm.var,
+                        call: f(arm.call),
+                        body: arm.body.into_iter().map(&mut *f).collect(),
+                    })
+                    .collect();
+                Expr::Select(arms)
+            }
+            Expr::Scope(body) => Expr::Scope(body.into_iter().map(f).collect()),
+            Expr::Try(e) => Expr::Try(g(e)),
+            Expr::Return(e) => Expr::Return(g(e)),
+        }
+    }
+}
+
+fn join_exprs(exprs: &[Expr], sep: &str) -> String {
+    exprs
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(sep)
 }
 
 impl std::fmt::Display for Declaration {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Declaration::Struct(x) => write!(f, "{}", x),
+            Declaration::Enum(x) => write!(f, "{}", x.to_struct()),
             Declaration::Extern(x) => write!(f, "{}", x),
             Declaration::Function(x) => write!(f, "{}", x),
+            Declaration::Comment(x) => writeln!(f, "{}", x),
+            Declaration::Import(path) => writeln!(f, "import {};", escape_str(path)),
         }
     }
 }
 
+impl std::fmt::Display for Struct {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "struct {} {{", &self.name)?;
+        for field in &self.fields {
+            writeln!(f, "  {};", field)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
 impl std::fmt::Display for Extern {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let params = self
@@ -115,11 +348,7 @@ impl std::fmt::Display for Extern {
             .map(|x| x.to_string())
             .collect::<Vec<_>>()
             .join(", ");
-        write!(
-            f,
-            "extern {}({}) -> {};\n",
-            &self.name, params, &self.ret_ty
-        )
+        writeln!(f, "extern {}({}) -> {};", &self.name, params, &self.ret_ty)
     }
 }
 
@@ -131,15 +360,11 @@ impl std::fmt::Display for Function {
             .map(|x| x.to_string())
             .collect::<Vec<_>>()
             .join(", ");
-        write!(
-            f,
-            "func {}({}) -> {} {{\n",
-            &self.name, params, &self.ret_ty
-        )?;
+        writeln!(f, "func {}({}) -> {} {{", &self.name, params, &self.ret_ty)?;
         for expr in &self.body_stmts {
-            write!(f, "  {};\n", expr)?;
+            writeln!(f, "  {};", expr)?;
         }
-        write!(f, "}}\n")
+        writeln!(f, "}}")
     }
 }
 
@@ -160,7 +385,9 @@ impl std::fmt::Display for Ty {
                     .map(|x| x.to_string())
                     .collect::<Vec<_>>()
                     .join(", ");
-                write!(f, "$FN(({}) -> {})", params, x.ret_ty)
+                // chiika-1 has no async functions (see `compile_extern`)
+                let head = if x.is_async { "$ASYNC_FN" } else { "$FN" };
+                write!(f, "{}(({}) -> {})", head, params, x.ret_ty)
             }
         }
     }
@@ -170,19 +397,100 @@ impl std::fmt::Display for Expr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Expr::Number(n) => write!(f, "{}", n),
+            Expr::Str(s) => write!(f, "{}", escape_str(s)),
             Expr::VarRef(s) => write!(f, "{}", s),
-            Expr::OpCall(op, l, r) => write!(f, "({} {} {})", l, op, r),
+            Expr::OpCall(op, l, r) => write!(f, "({} {} {})", operand(l), op, operand(r)),
             Expr::FunCall(fexpr, arg_exprs) => {
-                let args = arg_exprs
-                    .iter()
-                    .map(|x| x.to_string())
-                    .collect::<Vec<_>>()
-                    .join(", ");
-                write!(f, "{}({})", fexpr, args)
+                write!(f, "{}({})", fexpr, join_exprs(arg_exprs, ", "))
             }
-            Expr::Cast(expr, ty) => write!(f, "($CAST({} as {}))", expr, ty),
-            Expr::Alloc(name) => write!(f, "alloc {}", name),
+            Expr::Cast(expr, ty) => write!(f, "($CAST({} as {}))", operand(expr), ty),
+            Expr::Alloc(name, ty) => write!(f, "alloc {} {}", ty, name),
             Expr::Assign(name, expr) => write!(f, "{} = {}", name, expr),
+            Expr::New(name, arg_exprs) => {
+                write!(f, "new {}({})", name, join_exprs(arg_exprs, ", "))
+            }
+            Expr::FieldRef(obj, name) => write!(f, "{}.{}", obj, name),
+            Expr::FieldSet(obj, name, expr) => write!(f, "{}.{} = {}", obj, name, expr),
+            Expr::ArrayLit(exprs) => write!(f, "[{}]", join_exprs(exprs, ", ")),
+            Expr::Index(arr, idx) => write!(f, "{}[{}]", arr, idx),
+            Expr::IndexSet(arr, idx, expr) => write!(f, "{}[{}] = {}", arr, idx, expr),
+            Expr::MethodCall(obj, name, arg_exprs) => {
+                write!(f, "{}.{}({})", obj, name, join_exprs(arg_exprs, ", "))
+            }
+            Expr::For(var, arr, body) => {
+                write!(f, "for {} in {} {{ {} }}", var, arr, join_exprs(body, "; "))
+            }
+            Expr::While(cond, body) => {
+                write!(f, "while {} {{ {} }}", cond, join_exprs(body, "; "))
+            }
+            Expr::If(cond, then, els) => write!(
+                f,
+                "if {} {{ {} }} else {{ {} }}",
+                cond,
+                join_exprs(then, "; "),
+                join_exprs(els, "; ")
+            ),
+            Expr::EnumNew(name, variant, arg_exprs) => {
+                write!(f, "{}::{}({})", name, variant, join_exprs(arg_exprs, ", "))
+            }
+            Expr::Match(expr, arms) => {
+                write!(f, "match {} {{ ", expr)?;
+                for arm in arms {
+                    write!(
+                        f,
+                        "{} => {{ {} }}, ",
+                        arm.pattern,
+                        join_exprs(&arm.body, "; ")
+                    )?;
+                }
+                write!(f, "}}")
+            }
+            Expr::Select(arms) => {
+                write!(f, "select {{ ")?;
+                for arm in arms {
+                    write!(
+                        f,
+                        "{} = {} => {{ {} }}, ",
+                        arm.var,
+                        arm.call,
+                        join_exprs(&arm.body, "; ")
+                    )?;
+                }
+                write!(f, "}}")
+            }
+            Expr::Scope(body) => write!(f, "scope {{ {} }}", join_exprs(body, "; ")),
+            Expr::Try(expr) => write!(f, "{}?", expr),
+            Expr::Return(expr) => write!(f, "return {}", expr),
+        }
+    }
+}
+
+/// Returns source of an operand of binary operators and casts
+fn operand(e: &Expr) -> String {
+    match e {
+        Expr::If(_, _, _) | Expr::Match(_, _) | Expr::Select(_) | Expr::Scope(_) => {
+            format!("({})", e)
+        }
+        _ => e.to_string(),
+    }
+}
+
+/// Returns string literal which represents `s`
+pub fn escape_str(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+impl std::fmt::Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pattern::Variant(name, variant, vars) => {
+                write!(f, "{}::{}({})", name, variant, vars.join(", "))
+            }
+            Pattern::Wildcard => write!(f, "_"),
         }
     }
 }
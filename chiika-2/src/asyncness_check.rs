@@ -1,94 +1,112 @@
 use crate::ast::{self, FunTy};
 use anyhow::{anyhow, Result};
-use either::Either;
 use std::collections::HashMap;
 
-type FuncName = String;
-
 pub fn gather_sigs(decls: &[ast::Declaration]) -> Result<HashMap<String, ast::FunTy>> {
     let mut sigs = HashMap::new();
-    let mut funcs = HashMap::new();
-    let mut queue = vec![];
+    let mut funcs = vec![];
     // 1st pass
     for decl in decls {
         match decl {
+            ast::Declaration::Struct(_)
+            | ast::Declaration::Enum(_)
+            | ast::Declaration::Comment(_)
+            | ast::Declaration::Import(_) => (),
             ast::Declaration::Extern(x) => {
                 sigs.insert(x.name.clone(), x.fun_ty());
             }
             ast::Declaration::Function(x) => {
-                funcs.insert(x.name.clone(), x);
-                queue.push(x.name.clone());
+                // Assume sync until it turns out to call an async function
+                sigs.insert(x.name.clone(), x.fun_ty(false));
+                funcs.push(x);
             }
         }
     }
 
-    // 2nd pass
-    while let Some(func_name) = queue.pop() {
-        let func = funcs.get(&func_name).unwrap();
-        match gather_sig(func, &sigs)? {
-            Either::Left(called_fname) => {
-                if !queue.contains(&called_fname) {
-                    queue.push(called_fname);
-                }
-            }
-            Either::Right(sig) => {
-                sigs.insert(func.name.clone(), sig);
+    // 2nd pass: repeat until nothing changes so that (mutually) recursive
+    // functions are handled
+    loop {
+        let mut changed = false;
+        for func in &funcs {
+            if !sigs[&func.name].is_async && check_async_all(&func.body_stmts, &sigs)? {
+                sigs.insert(func.name.clone(), func.fun_ty(true));
+                changed = true;
             }
         }
+        if !changed {
+            break;
+        }
     }
 
     Ok(sigs)
 }
 
-fn gather_sig(
-    func: &ast::Function,
+/// Returns true if `exprs` contain a call of an async function.
+/// Must be called after `gather_sigs` is done.
+pub fn contains_async_call(exprs: &[ast::Expr], sigs: &HashMap<String, FunTy>) -> bool {
+    check_async_all(exprs, sigs).unwrap_or(false)
+}
+
+fn check_async_all<'a>(
+    exprs: impl IntoIterator<Item = &'a ast::Expr>,
     sigs: &HashMap<String, FunTy>,
-) -> Result<Either<FuncName, FunTy>> {
-    //    if func.name == "chiika_main" {
-    //        // Entry point function must be compiled as async
-    //        return Ok(Either::Right(func.fun_ty(true)));
-    //    }
+) -> Result<bool> {
     let mut is_async = false;
-    for stmt in &func.body_stmts {
-        match check_async(&func.name, &stmt, sigs)? {
-            Either::Left(x) => return Ok(Either::Left(x)),
-            Either::Right(b) => is_async = is_async || b,
-        }
+    for e in exprs {
+        is_async = check_async(e, sigs)? || is_async;
     }
-    Ok(Either::Right(func.fun_ty(is_async)))
+    Ok(is_async)
 }
 
-fn check_async(
-    func_name: &str,
-    expr: &ast::Expr,
-    sigs: &HashMap<String, FunTy>,
-) -> Result<Either<FuncName, bool>> {
+fn check_async(expr: &ast::Expr, sigs: &HashMap<String, FunTy>) -> Result<bool> {
     match expr {
         ast::Expr::FunCall(fexpr, arg_exprs) => {
             let ast::Expr::VarRef(ref fname) = **fexpr else {
                 return Err(anyhow!("not a function: {:?}", fexpr));
             };
-            if let Some(fun_ty) = sigs.get(fname) {
-                if fun_ty.is_async {
-                    Ok(Either::Right(true))
-                } else {
-                    let mut is_async = false;
-                    for e in arg_exprs {
-                        match check_async(func_name, e, sigs)? {
-                            Either::Left(x) => return Ok(Either::Left(x)),
-                            Either::Right(b) => is_async = is_async || b,
-                        }
-                    }
-                    Ok(Either::Right(is_async))
-                }
-            } else if fname == func_name {
-                // Calling the function itself
-                Ok(Either::Right(false))
+            let Some(fun_ty) = sigs.get(fname) else {
+                return Err(anyhow!("unknown function: {}", fname));
+            };
+            if fun_ty.is_async {
+                Ok(true)
             } else {
-                // Depends on the asyncness of this function
-                Ok(Either::Left(fname.to_string()))
+                check_async_all(arg_exprs, sigs)
             }
         }
-        _ => Ok(Either::Right(false)),
+        ast::Expr::OpCall(_, lhs, rhs) => check_async_all([&**lhs, &**rhs], sigs),
+        ast::Expr::Assign(_, rhs) => check_async(rhs, sigs),
+        ast::Expr::New(_, arg_exprs) => check_async_all(arg_exprs, sigs),
+        ast::Expr::FieldRef(obj, _) => check_async(obj, sigs),
+        ast::Expr::FieldSet(obj, _, rhs) => check_async_all([&**obj, &**rhs], sigs),
+        ast::Expr::ArrayLit(exprs) => check_async_all(exprs, sigs),
+        ast::Expr::Index(arr, idx) => check_async_all([&**arr, &**idx], sigs),
+        ast::Expr::IndexSet(arr, idx, rhs) => check_async_all([&**arr, &**idx, &**rhs], sigs),
+        ast::Expr::MethodCall(obj, _, arg_exprs) => {
+            check_async_all(std::iter::once(&**obj).chain(arg_exprs), sigs)
+        }
+        ast::Expr::For(_, arr, body) => check_async_all(std::iter::once(&**arr).chain(body), sigs),
+        ast::Expr::While(cond, body) => check_async_all(std::iter::once(&**cond).chain(body), sigs),
+        ast::Expr::If(cond, then, els) => {
+            check_async_all(std::iter::once(&**cond).chain(then).chain(els), sigs)
+        }
+        ast::Expr::EnumNew(_, _, arg_exprs) => check_async_all(arg_exprs, sigs),
+        ast::Expr::Match(expr, arms) => check_async_all(
+            std::iter::once(&**expr).chain(arms.iter().flat_map(|arm| &arm.body)),
+            sigs,
+        ),
+        ast::Expr::Select(arms) => check_async_all(
+            arms.iter()
+                .flat_map(|arm| std::iter::once(&arm.call).chain(&arm.body)),
+            sigs,
+        ),
+        // Waits for the tasks spawned in it
+        ast::Expr::Scope(body) => {
+            check_async_all(body, sigs)?;
+            Ok(true)
+        }
+        ast::Expr::Cast(expr, _) | ast::Expr::Try(expr) | ast::Expr::Return(expr) => {
+            check_async(expr, sigs)
+        }
+        _ => Ok(false),
     }
 }
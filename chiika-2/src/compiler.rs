@@ -1,63 +1,263 @@
 use crate::ast::{self, FunTy, Ty};
-use crate::asyncness_check::gather_sigs;
-use anyhow::{anyhow, Result};
+use crate::asyncness_check::{contains_async_call, gather_sigs};
+use anyhow::{anyhow, Context, Result};
 use std::collections::HashMap;
-use std::collections::VecDeque;
 
 #[derive(PartialEq, Debug)]
 struct Compiler {
     sigs: HashMap<String, ast::FunTy>,
-    chapters: VecDeque<Chapter>,
+    structs: HashMap<String, ast::Struct>,
+    enums: HashMap<String, ast::Enum>,
+    chapters: Vec<Chapter>,
+    // Index of the chapter which is being compiled
+    current: usize,
+    // Local variables of the function which is being compiled
+    lvars: Vec<(String, Ty)>,
+    // Used to make unique names for generated variables
+    n_tmps: usize,
+    // Index of the statement of the function which is being compiled
+    stmt_idx: usize,
+    // Splits of the chapters of the function compiled last
+    splits: Vec<Option<Split>>,
+    // Variables of the `scope`s which are being compiled (innermost last)
+    scopes: Vec<String>,
 }
 
 #[derive(PartialEq, Debug)]
 struct Chapter {
     stmts: Vec<ast::Expr>,
-    // The resulting type of the async function called with the last stmt
+    // The type of `$async_result` this chapter receives
     async_result_ty: Ty,
+    // Why this chapter is split from the previous one (None for the first
+    // chapter)
+    split: Option<Split>,
 }
 
 impl Chapter {
-    fn new() -> Chapter {
+    fn new(async_result_ty: Ty, split: Option<Split>) -> Chapter {
         Chapter {
             stmts: vec![],
-            async_result_ty: Ty::raw("[BUG] async_result_ty not set"),
+            async_result_ty,
+            split,
         }
     }
 }
 
+#[derive(PartialEq, Debug, Clone)]
+pub(crate) struct Split {
+    // Index of the statement of the original function which caused the split
+    pub stmt_idx: usize,
+    pub reason: String,
+}
+
+/// How a function is compiled (see `explain.rs`)
+pub(crate) struct Explanation {
+    pub fun_ty: FunTy,
+    pub orig_func: ast::Function,
+    pub chapters: Vec<(ast::Function, Option<Split>)>,
+    // Names of the items of the env frame, from the bottom (the first item
+    // is `$cont`.) Empty if the function is sync
+    pub env_items: Vec<String>,
+}
+
+/// Compile the function `name` and returns the details
+pub(crate) fn explain(ast: &[ast::Declaration], name: &str) -> Result<Explanation> {
+    let mut c = Compiler::new(ast)?;
+    let orig_func = ast
+        .iter()
+        .find_map(|decl| match decl {
+            ast::Declaration::Function(x) if x.name == name => Some(x.clone()),
+            _ => None,
+        })
+        .with_context(|| format!("function `{}' not found", name))?;
+    let funcs = c.compile_func(orig_func.clone())?;
+    let env_items = if funcs.len() == 1 {
+        vec![]
+    } else {
+        let layout = EnvLayout::new(&orig_func, &c.lvars);
+        std::iter::once("$cont".to_string())
+            .chain(layout.items.into_iter().map(|(x, _)| x))
+            .collect()
+    };
+    Ok(Explanation {
+        fun_ty: c.sigs[name].clone(),
+        orig_func,
+        chapters: funcs
+            .into_iter()
+            .zip(std::mem::take(&mut c.splits))
+            .collect(),
+        env_items,
+    })
+}
+
+/// Returns the settings of the tokio runtime given by `#[tokio(...)]` on
+/// `chiika_main`, as pairs of key and value for `chiika_tokio_config`
+pub fn tokio_config(ast: &[ast::Declaration]) -> Result<Vec<(String, String)>> {
+    let mut config = vec![];
+    for decl in ast {
+        let ast::Declaration::Function(f) = decl else {
+            continue;
+        };
+        for attr in &f.attrs {
+            if attr.name != "tokio" {
+                return Err(anyhow!("unknown attribute `{}'", attr.name));
+            }
+            if f.name != "chiika_main" {
+                return Err(anyhow!("`#[tokio]' is only allowed on `chiika_main'"));
+            }
+            for (key, value) in &attr.args {
+                let v = match (&key[..], value) {
+                    ("flavor", ast::Expr::Str(s))
+                        if s == "current_thread" || s == "multi_thread" =>
+                    {
+                        s.clone()
+                    }
+                    ("workers", ast::Expr::Number(n)) if *n > 0 => n.to_string(),
+                    ("drivers", ast::Expr::Str(s))
+                        if s.split(',')
+                            .all(|x| ["all", "none", "io", "time"].contains(&x.trim())) =>
+                    {
+                        s.clone()
+                    }
+                    ("clock", ast::Expr::Str(s)) if s == "paused" || s == "real" => s.clone(),
+                    _ => {
+                        return Err(anyhow!(
+                            "invalid tokio setting: {} = {} (expected flavor = \"current_thread\" \
+                             or \"multi_thread\", workers = <positive integer>, \
+                             drivers = \"all\", \"none\", \"io\", \"time\" or \"io,time\" \
+                             or clock = \"paused\" or \"real\")",
+                            key,
+                            value
+                        ))
+                    }
+                };
+                config.push((key.clone(), v));
+            }
+        }
+    }
+    let has_workers = config.iter().any(|(k, _)| k == "workers");
+    let current_thread = config
+        .iter()
+        .any(|(k, v)| k == "flavor" && v == "current_thread");
+    if has_workers && current_thread {
+        return Err(anyhow!(
+            "`workers' cannot be set for the current_thread runtime"
+        ));
+    }
+    let paused = config.iter().any(|(k, v)| k == "clock" && v == "paused");
+    if paused && !current_thread {
+        return Err(anyhow!(
+            "the paused clock needs flavor = \"current_thread\""
+        ));
+    }
+    Ok(config)
+}
+
 /// Returns new_decls and main_is_async
 pub fn compile(ast: Vec<ast::Declaration>) -> Result<(Vec<ast::Declaration>, bool)> {
-    let mut c = Compiler {
-        sigs: gather_sigs(&ast)?,
-        chapters: Default::default(),
-    };
-    let mut new_decls = vec![];
+    tokio_config(&ast)?;
+    let mut c = Compiler::new(&ast)?;
+    let result_enum = ast::result_enum();
+    let mut new_decls = vec![ast::Declaration::Struct(result_enum.to_struct())];
     for decl in ast {
         match decl {
+            ast::Declaration::Struct(x) => new_decls.push(ast::Declaration::Struct(x)),
+            ast::Declaration::Enum(x) => new_decls.push(ast::Declaration::Struct(x.to_struct())),
             ast::Declaration::Extern(x) => {
                 new_decls.push(ast::Declaration::Extern(c.compile_extern(x)))
             }
             ast::Declaration::Function(x) => {
-                let mut split_funcs = c
-                    .compile_func(x)?
-                    .into_iter()
-                    .map(ast::Declaration::Function)
-                    .collect::<Vec<_>>();
-                new_decls.append(&mut split_funcs);
+                let name = x.name.clone();
+                let split_funcs = c.compile_func(x)?;
+                let n_chapters = split_funcs.len();
+                for (i, f) in split_funcs.into_iter().enumerate() {
+                    let text = if n_chapters == 1 {
+                        format!("// fun {}", name)
+                    } else {
+                        format!("// fun {}: chapter {} (of {})", name, i, n_chapters)
+                    };
+                    new_decls.push(ast::Declaration::Comment(text));
+                    new_decls.push(ast::Declaration::Function(f));
+                }
             }
+            ast::Declaration::Comment(x) => new_decls.push(ast::Declaration::Comment(x)),
+            ast::Declaration::Import(_) => (),
         }
     }
     let main_is_async = c
         .sigs
         .get("chiika_main")
-        .expect("must define `chiika_main'")
+        .context("must define `chiika_main'")?
         .is_async;
     Ok((new_decls, main_is_async))
 }
 
+/// Returns the number of chapters each function is split into
+pub fn count_chapters(ast: &[ast::Declaration]) -> Result<HashMap<String, usize>> {
+    let mut c = Compiler::new(ast)?;
+    let mut counts = HashMap::new();
+    for decl in ast {
+        if let ast::Declaration::Function(x) = decl {
+            counts.insert(x.name.clone(), c.compile_func(x.clone())?.len());
+        }
+    }
+    Ok(counts)
+}
+
+/// Returns the type of the value of `f` (the value of its last statement)
+pub fn infer_value_ty(ast: &[ast::Declaration], f: &ast::Function) -> Result<Ty> {
+    let c = Compiler::new(ast)?;
+    c.infer_stmts_ty(f, &f.body_stmts)
+}
+
 impl Compiler {
+    fn new(ast: &[ast::Declaration]) -> Result<Compiler> {
+        if let Some(ast::Declaration::Import(path)) = ast
+            .iter()
+            .find(|x| matches!(x, ast::Declaration::Import(_)))
+        {
+            return Err(anyhow!(
+                "cannot resolve `import \"{}\"' without the path of the source file",
+                path
+            ));
+        }
+        let mut c = Compiler {
+            sigs: gather_sigs(ast)?,
+            structs: Default::default(),
+            enums: Default::default(),
+            chapters: Default::default(),
+            current: 0,
+            lvars: Default::default(),
+            n_tmps: 0,
+            stmt_idx: 0,
+            splits: vec![],
+            scopes: vec![],
+        };
+        let result_enum = ast::result_enum();
+        c.enums.insert(result_enum.name.clone(), result_enum);
+        for decl in ast {
+            match decl {
+                ast::Declaration::Struct(x) => {
+                    c.structs.insert(x.name.clone(), x.clone());
+                }
+                ast::Declaration::Enum(x) => {
+                    if c.enums.contains_key(&x.name) {
+                        return Err(anyhow!("enum `{}' is already defined", x.name));
+                    }
+                    c.enums.insert(x.name.clone(), x.clone());
+                }
+                _ => (),
+            }
+        }
+        Ok(c)
+    }
+
     fn compile_extern(&self, mut e: ast::Extern) -> ast::Extern {
+        for param in &mut e.params {
+            if let Ty::Fun(fun_ty) = &param.ty {
+                param.ty = Ty::Fun(cps_fun_ty(fun_ty));
+            }
+        }
         if e.is_async {
             e.is_async = false;
             e.params = prepend_async_params(&e.params, e.ret_ty);
@@ -68,11 +268,18 @@ impl Compiler {
 
     fn compile_func(&mut self, mut f: ast::Function) -> Result<Vec<ast::Function>> {
         self.chapters.clear();
-        self.chapters.push_back(Chapter::new());
-        for expr in f.body_stmts.drain(..).collect::<Vec<_>>() {
+        self.chapters.push(Chapter::new(
+            Ty::raw("[BUG] the first chapter has no async result"),
+            None,
+        ));
+        self.current = 0;
+        self.lvars.clear();
+        for (i, expr) in f.body_stmts.drain(..).enumerate().collect::<Vec<_>>() {
+            self.stmt_idx = i;
             let new_expr = self.compile_expr(&f, expr)?;
-            self.chapters.back_mut().unwrap().stmts.push(new_expr);
+            self.push_stmt(new_expr);
         }
+        self.splits = self.chapters.iter().map(|x| x.split.clone()).collect();
 
         if self.chapters.len() == 1 {
             // Has no async call; no modification needed
@@ -80,10 +287,11 @@ impl Compiler {
                 name: f.name,
                 params: f.params,
                 ret_ty: f.ret_ty,
-                body_stmts: self.chapters.pop_front().unwrap().stmts,
+                body_stmts: self.chapters.pop().unwrap().stmts,
+                attrs: vec![],
             }])
         } else {
-            let chaps = self.chapters.drain(..).collect();
+            let chaps = std::mem::take(&mut self.chapters);
             self.generate_split_funcs(f, chaps)
         }
     }
@@ -91,107 +299,1028 @@ impl Compiler {
     fn generate_split_funcs(
         &mut self,
         orig_func: ast::Function,
-        mut chapters: VecDeque<Chapter>,
+        chapters: Vec<Chapter>,
     ) -> Result<Vec<ast::Function>> {
-        let n_chapters = chapters.len();
-        let mut i = 0;
-        let mut last_chap_result_ty = None;
+        let layout = EnvLayout::new(&orig_func, &self.lvars);
+        // The chapter which ends the function
+        let last_chapter = self.current;
         let mut split_funcs = vec![];
-        while let Some(chap) = chapters.pop_front() {
+        for (i, chap) in chapters.into_iter().enumerate() {
+            let mut stmts = chap
+                .stmts
+                .into_iter()
+                .map(|x| layout.lower(x, i == 0))
+                .collect::<Vec<_>>();
+            if i == last_chapter {
+                stmts = append_async_outro(&layout, stmts);
+            }
             let new_func = if i == 0 {
                 ast::Function {
                     name: orig_func.name.clone(),
                     params: prepend_async_params(&orig_func.params, orig_func.ret_ty.clone()),
                     ret_ty: Ty::raw("$FUTURE"),
-                    body_stmts: prepend_async_intro(&orig_func, chap.stmts),
+                    body_stmts: prepend_async_intro(&layout, stmts),
+                    attrs: vec![],
                 }
             } else {
                 ast::Function {
                     name: chapter_func_name(&orig_func.name, i),
                     params: vec![
                         ast::Param::new(Ty::raw("$ENV"), "$env"),
-                        ast::Param::new(last_chap_result_ty.unwrap(), "$async_result"),
+                        ast::Param::new(chap.async_result_ty, "$async_result"),
                     ],
                     ret_ty: Ty::raw("$FUTURE"),
-                    body_stmts: if i == n_chapters - 1 {
-                        append_async_outro(&orig_func, chap.stmts, orig_func.ret_ty.clone())
-                    } else {
-                        chap.stmts
-                    },
+                    body_stmts: stmts,
+                    attrs: vec![],
                 }
             };
-            i += 1;
-            last_chap_result_ty = Some(chap.async_result_ty);
             split_funcs.push(new_func);
         }
         Ok(split_funcs)
     }
 
+    fn split_at_stmt(&self, reason: String) -> Option<Split> {
+        Some(Split {
+            stmt_idx: self.stmt_idx,
+            reason,
+        })
+    }
+
+    fn push_stmt(&mut self, stmt: ast::Expr) {
+        self.chapters[self.current].stmts.push(stmt);
+    }
+
+    /// Register a local variable and returns `alloc` for it
+    fn declare_lvar(&mut self, name: &str, ty: Ty) -> Result<ast::Expr> {
+        match self.lvars.iter().find(|(x, _)| x == name) {
+            Some((_, t)) if *t != ty => {
+                return Err(anyhow!(
+                    "variable `{}' is already declared as {} (got {})",
+                    name,
+                    t,
+                    ty
+                ))
+            }
+            Some(_) => (),
+            None => self.lvars.push((name.to_string(), ty.clone())),
+        }
+        Ok(ast::Expr::Alloc(name.to_string(), ty))
+    }
+
+    /// Functions passed to the params of function types must be declared
+    /// with the same signature (including the asyncness, which decides how
+    /// they are called)
+    fn check_fun_args(&self, callee: &str, fun_ty: &FunTy, args: &[ast::Expr]) -> Result<()> {
+        for (arg, param_ty) in args.iter().zip(&fun_ty.param_tys) {
+            let Ty::Fun(expected) = param_ty else {
+                continue;
+            };
+            let actual = match arg {
+                ast::Expr::VarRef(name) => self.sigs.get(name),
+                _ => None,
+            };
+            match actual {
+                Some(x) if x == expected => (),
+                Some(x) => {
+                    return Err(anyhow!(
+                        "`{}' expects {} but `{}' is {}",
+                        callee,
+                        param_ty,
+                        arg,
+                        Ty::Fun(x.clone())
+                    ))
+                }
+                None => return Err(anyhow!("`{}' expects a function but got `{}'", callee, arg)),
+            }
+        }
+        Ok(())
+    }
+
     fn compile_expr(&mut self, orig_func: &ast::Function, e: ast::Expr) -> Result<ast::Expr> {
         let new_e = match e {
-            ast::Expr::Alloc(_) => e,
-            ast::Expr::Number(_) => e,
+            ast::Expr::Alloc(name, ty) => {
+                check_not_param(orig_func, &name)?;
+                self.declare_lvar(&name, ty)?
+            }
+            ast::Expr::Number(_) | ast::Expr::Str(_) => e,
             ast::Expr::OpCall(op, lhs, rhs) => {
-                let l = self.compile_expr(orig_func, *lhs)?;
-                let r = self.compile_expr(orig_func, *rhs)?;
+                let mut v = self.compile_operands(orig_func, vec![*lhs, *rhs])?;
+                let r = v.pop().unwrap();
+                let l = v.pop().unwrap();
                 ast::Expr::OpCall(op, Box::new(l), Box::new(r))
             }
-            ast::Expr::VarRef(ref name) => {
-                if self.sigs.contains_key(name) {
-                    e
-                } else if self.chapters.len() == 1 {
-                    // The variable is just there in the first chapter
-                    e
-                } else {
-                    let idx = orig_func
-                        .params
-                        .iter()
-                        .position(|x| x.name == *name)
-                        .expect(&format!("unknown variable `{}'", name));
-                    ast::Expr::FunCall(
-                        Box::new(ast::Expr::var_ref("chiika_env_ref")),
-                        vec![ast::Expr::var_ref("$env"), ast::Expr::Number(idx as i64)],
-                    )
-                }
-            }
+            // Params and local variables are converted into env access
+            // later if needed (see `EnvLayout`)
+            ast::Expr::VarRef(_) => e,
             ast::Expr::FunCall(fexpr, arg_exprs) => {
-                let mut new_args = arg_exprs
-                    .into_iter()
-                    .map(|x| self.compile_expr(orig_func, x))
-                    .collect::<Result<Vec<_>>>()?;
                 let ast::Expr::VarRef(callee_name) = *fexpr else {
                     return Err(anyhow!("not a function: {:?}", fexpr));
                 };
                 let Some(fun_ty) = self.sigs.get(&callee_name) else {
                     return Err(anyhow!("unknown function: {:?}", callee_name));
                 };
+                self.check_fun_args(&callee_name, fun_ty, &arg_exprs)?;
+                let fun_ty = fun_ty.clone();
+                let new_args = self.compile_operands(orig_func, arg_exprs)?;
                 if fun_ty.is_async {
-                    new_args.insert(0, ast::Expr::var_ref("$env"));
-                    new_args.insert(
-                        1,
-                        ast::Expr::var_ref(chapter_func_name(&orig_func.name, self.chapters.len())),
-                    );
-                    let cps_call =
-                        ast::Expr::FunCall(Box::new(ast::Expr::VarRef(callee_name)), new_args);
-
-                    // Change chapter here
-                    let last_chapter = self.chapters.back_mut().unwrap();
-                    last_chapter.stmts.push(cps_call);
-                    last_chapter.async_result_ty = (*fun_ty.ret_ty).clone();
-                    self.chapters.push_back(Chapter::new());
-
-                    ast::Expr::VarRef("$async_result".to_string())
+                    let result_ty = (*fun_ty.ret_ty).clone();
+                    self.async_call(orig_func, &callee_name, new_args, result_ty)
+                } else if callee_name == "spawn" && !self.scopes.is_empty() {
+                    // Register the task to the innermost `scope`
+                    let scope = ast::Expr::var_ref(self.scopes.last().unwrap());
+                    let spawn = ast::Expr::fun_call(callee_name, new_args);
+                    ast::Expr::fun_call("chiika_scope_add", vec![scope, spawn])
                 } else {
                     ast::Expr::FunCall(Box::new(ast::Expr::VarRef(callee_name)), new_args)
                 }
             }
-            ast::Expr::Cast(_, _) => panic!("chiika-2 does not have cast operation"),
+            // chiika-2 does not have cast operation but it is generated
+            // by the compiler (see `compile_match`)
+            ast::Expr::Cast(expr, ty) => {
+                ast::Expr::Cast(Box::new(self.compile_expr(orig_func, *expr)?), ty)
+            }
             ast::Expr::Assign(name, rhs) => {
                 ast::Expr::Assign(name, Box::new(self.compile_expr(orig_func, *rhs)?))
             }
+            ast::Expr::New(name, arg_exprs) => {
+                let new_args = self.compile_operands(orig_func, arg_exprs)?;
+                ast::Expr::New(name, new_args)
+            }
+            ast::Expr::FieldRef(obj, name) => {
+                ast::Expr::FieldRef(Box::new(self.compile_expr(orig_func, *obj)?), name)
+            }
+            ast::Expr::FieldSet(obj, name, rhs) => {
+                let mut v = self.compile_operands(orig_func, vec![*obj, *rhs])?;
+                let new_rhs = v.pop().unwrap();
+                let new_obj = v.pop().unwrap();
+                ast::Expr::FieldSet(Box::new(new_obj), name, Box::new(new_rhs))
+            }
+            ast::Expr::ArrayLit(exprs) => {
+                let mut arr = ast::Expr::fun_call("chiika_array_new", vec![]);
+                for item in self.compile_operands(orig_func, exprs)? {
+                    arr = ast::Expr::fun_call("chiika_array_push", vec![arr, item]);
+                }
+                arr
+            }
+            ast::Expr::Index(arr, idx) => {
+                let new_args = self.compile_operands(orig_func, vec![*arr, *idx])?;
+                ast::Expr::fun_call("chiika_array_get", new_args)
+            }
+            ast::Expr::IndexSet(arr, idx, rhs) => {
+                let new_args = self.compile_operands(orig_func, vec![*arr, *idx, *rhs])?;
+                ast::Expr::fun_call("chiika_array_set", new_args)
+            }
+            ast::Expr::MethodCall(obj, name, arg_exprs) => {
+                let mut operands = vec![*obj];
+                operands.extend(arg_exprs);
+                let new_args = self.compile_operands(orig_func, operands)?;
+                match (&name[..], new_args.len()) {
+                    ("push", 2) => ast::Expr::fun_call("chiika_array_push", new_args),
+                    ("len", 1) => ast::Expr::fun_call("chiika_array_len", new_args),
+                    _ => return Err(anyhow!("unknown method `{}'", name)),
+                }
+            }
+            ast::Expr::For(var, arr, body) => self.compile_for(orig_func, var, *arr, body)?,
+            ast::Expr::While(_, _) => panic!("chiika-2 does not have while loop"),
+            ast::Expr::If(cond, then, els) => self.compile_if(orig_func, *cond, then, els)?,
+            ast::Expr::EnumNew(name, variant, arg_exprs) => {
+                let enum_def = self
+                    .enums
+                    .get(&name)
+                    .with_context(|| format!("unknown enum `{}'", name))?
+                    .clone();
+                let (tag, v) = enum_def
+                    .variant(&variant)
+                    .with_context(|| format!("enum {} has no variant `{}'", name, variant))?;
+                if arg_exprs.len() != v.param_tys.len() {
+                    return Err(anyhow!(
+                        "wrong number of arguments for {}::{}",
+                        name,
+                        variant
+                    ));
+                }
+                let n_fields = enum_def.to_struct().fields.len();
+                let mut new_args = vec![ast::Expr::Number(tag as i64)];
+                for arg in self.compile_operands(orig_func, arg_exprs)? {
+                    new_args.push(ast::Expr::Cast(Box::new(arg), Ty::raw("$any")));
+                }
+                while new_args.len() < n_fields {
+                    new_args.push(ast::Expr::Number(0));
+                }
+                ast::Expr::New(name, new_args)
+            }
+            ast::Expr::Match(expr, arms) => self.compile_match(orig_func, *expr, arms)?,
+            ast::Expr::Select(arms) => self.compile_select(orig_func, arms)?,
+            ast::Expr::Scope(body) => self.compile_scope(orig_func, body)?,
+            ast::Expr::Try(expr) => self.compile_try(orig_func, *expr)?,
+            // Converted into a call of `$cont` later if in an async function
+            // (see `EnvLayout`)
+            ast::Expr::Return(expr) => {
+                let value = self.compile_expr(orig_func, *expr)?;
+                for stmt in self.cancel_scopes() {
+                    self.push_stmt(stmt);
+                }
+                ast::Expr::Return(Box::new(value))
+            }
         };
         Ok(new_e)
     }
+
+    /// Call the async function with the next chapter as the continuation
+    /// and make it current. `new_args` are already compiled. Returns the
+    /// value of the call in the next chapter
+    fn async_call(
+        &mut self,
+        orig_func: &ast::Function,
+        callee_name: &str,
+        mut new_args: Vec<ast::Expr>,
+        result_ty: Ty,
+    ) -> ast::Expr {
+        let next_chapter = self.chapters.len();
+        new_args.insert(0, ast::Expr::var_ref("$env"));
+        new_args.insert(
+            1,
+            ast::Expr::var_ref(chapter_func_name(&orig_func.name, next_chapter)),
+        );
+        let cps_call = ast::Expr::fun_call(callee_name, new_args);
+
+        // Change chapter here
+        self.push_stmt(cps_call);
+        let split = self.split_at_stmt(format!("async call of `{}'", callee_name));
+        self.chapters.push(Chapter::new(result_ty, split));
+        self.current = next_chapter;
+
+        ast::Expr::VarRef("$async_result".to_string())
+    }
+
+    /// Compile `scope { ... }`. Tasks spawned in it are registered to the
+    /// scope and waited for at the end, or cancelled if the function returns
+    /// from inside (see `cancel_scopes`)
+    fn compile_scope(
+        &mut self,
+        orig_func: &ast::Function,
+        body: Vec<ast::Expr>,
+    ) -> Result<ast::Expr> {
+        let ty = self.infer_stmts_ty(orig_func, &body)?;
+        self.n_tmps += 1;
+        let scope = format!("$scope_{}", self.n_tmps);
+        let alloc = self.declare_lvar(&scope, Ty::raw("$any"))?;
+        self.push_stmt(alloc);
+        self.push_stmt(ast::Expr::Assign(
+            scope.clone(),
+            Box::new(ast::Expr::fun_call("chiika_scope_new", vec![])),
+        ));
+
+        self.scopes.push(scope.clone());
+        let mut stmts = body;
+        let last = stmts.pop().unwrap_or(ast::Expr::Number(0));
+        for stmt in stmts {
+            let new_stmt = self.compile_expr(orig_func, stmt)?;
+            self.push_stmt(new_stmt);
+        }
+        let value = self.compile_expr(orig_func, last)?;
+        self.scopes.pop();
+        if let ast::Expr::Return(_) = value {
+            // The tasks are already cancelled
+            return Ok(value);
+        }
+
+        // Keep the value while waiting
+        let value = self.spill(value, ty)?;
+        self.async_call(
+            orig_func,
+            "chiika_scope_wait",
+            vec![ast::Expr::var_ref(&scope)],
+            Ty::raw("int"),
+        );
+        Ok(value)
+    }
+
+    /// Stmts to cancel the tasks of the `scope`s which the function returns
+    /// from
+    fn cancel_scopes(&self) -> Vec<ast::Expr> {
+        self.scopes
+            .iter()
+            .rev()
+            .map(|x| ast::Expr::fun_call("chiika_scope_cancel", vec![ast::Expr::var_ref(x)]))
+            .collect()
+    }
+
+    /// Compile `exprs` which are evaluated from left to right. If an async
+    /// call follows, the value is saved to a temporary variable beforehand
+    /// so that it is not lost (or evaluated too late) in the next chapter
+    fn compile_operands(
+        &mut self,
+        orig_func: &ast::Function,
+        exprs: Vec<ast::Expr>,
+    ) -> Result<Vec<ast::Expr>> {
+        let mut new_exprs = vec![];
+        for i in 0..exprs.len() {
+            let e = exprs[i].clone();
+            let new_e = if contains_async_call(&exprs[i + 1..], &self.sigs) {
+                let ty = self.infer_stmts_ty(orig_func, std::slice::from_ref(&e))?;
+                let compiled = self.compile_expr(orig_func, e)?;
+                self.spill(compiled, ty)?
+            } else {
+                self.compile_expr(orig_func, e)?
+            };
+            new_exprs.push(new_e);
+        }
+        Ok(new_exprs)
+    }
+
+    /// Store the value of `e` to a new temporary variable (unless it is a
+    /// constant or a user variable)
+    fn spill(&mut self, e: ast::Expr, ty: Ty) -> Result<ast::Expr> {
+        match &e {
+            ast::Expr::Number(_) | ast::Expr::Str(_) => return Ok(e),
+            ast::Expr::VarRef(name) if name != "$async_result" => return Ok(e),
+            _ => (),
+        }
+        self.n_tmps += 1;
+        let tmp = format!("$tmp_{}", self.n_tmps);
+        let alloc = self.declare_lvar(&tmp, ty)?;
+        self.push_stmt(alloc);
+        self.push_stmt(ast::Expr::Assign(tmp.clone(), Box::new(e)));
+        Ok(ast::Expr::var_ref(&tmp))
+    }
+
+    /// Convert `for x in arr { ... }` into a while loop
+    fn compile_for(
+        &mut self,
+        orig_func: &ast::Function,
+        var: String,
+        arr: ast::Expr,
+        body: Vec<ast::Expr>,
+    ) -> Result<ast::Expr> {
+        if contains_async_call(&body, &self.sigs) {
+            return Err(anyhow!(
+                "calling async function in `for' is not supported yet"
+            ));
+        }
+        check_not_param(orig_func, &var)?;
+        let new_arr = self.compile_expr(orig_func, arr)?;
+        self.n_tmps += 1;
+        let arr_var = format!("$for_arr_{}", self.n_tmps);
+        let idx_var = format!("$for_idx_{}", self.n_tmps);
+        let setup = vec![
+            self.declare_lvar(&arr_var, Ty::raw("array"))?,
+            ast::Expr::Assign(arr_var.clone(), Box::new(new_arr)),
+            self.declare_lvar(&idx_var, Ty::raw("int"))?,
+            ast::Expr::Assign(idx_var.clone(), Box::new(ast::Expr::Number(0))),
+            self.declare_lvar(&var, Ty::raw("int"))?,
+        ];
+        for stmt in setup {
+            self.push_stmt(stmt);
+        }
+
+        let get_item = ast::Expr::fun_call(
+            "chiika_array_get",
+            vec![ast::Expr::var_ref(&arr_var), ast::Expr::var_ref(&idx_var)],
+        );
+        let mut new_body = vec![ast::Expr::Assign(var, Box::new(get_item))];
+        new_body.append(&mut self.compile_sync_stmts(orig_func, body)?);
+        let incr = ast::Expr::OpCall(
+            "+".to_string(),
+            Box::new(ast::Expr::var_ref(&idx_var)),
+            Box::new(ast::Expr::Number(1)),
+        );
+        new_body.push(ast::Expr::Assign(idx_var.clone(), Box::new(incr)));
+
+        let cond = ast::Expr::OpCall(
+            "<".to_string(),
+            Box::new(ast::Expr::var_ref(&idx_var)),
+            Box::new(ast::Expr::fun_call(
+                "chiika_array_len",
+                vec![ast::Expr::var_ref(&arr_var)],
+            )),
+        );
+        Ok(ast::Expr::While(Box::new(cond), new_body))
+    }
+
+    /// Compile stmts which does not contain async calls (i.e. does not
+    /// change the chapter.)
+    fn compile_sync_stmts(
+        &mut self,
+        orig_func: &ast::Function,
+        stmts: Vec<ast::Expr>,
+    ) -> Result<Vec<ast::Expr>> {
+        let saved = std::mem::take(&mut self.chapters[self.current].stmts);
+        for stmt in stmts {
+            let new_stmt = self.compile_expr(orig_func, stmt)?;
+            self.push_stmt(new_stmt);
+        }
+        let new_stmts = std::mem::replace(&mut self.chapters[self.current].stmts, saved);
+        Ok(new_stmts)
+    }
+
+    /// Compile `if`. If any of the branches contains async call, the rest
+    /// of the function is split into a new chapter and each branch ends by
+    /// calling it.
+    fn compile_if(
+        &mut self,
+        orig_func: &ast::Function,
+        cond: ast::Expr,
+        then: Vec<ast::Expr>,
+        els: Vec<ast::Expr>,
+    ) -> Result<ast::Expr> {
+        let new_cond = self.compile_expr(orig_func, cond)?;
+        if !contains_async_call(&then, &self.sigs) && !contains_async_call(&els, &self.sigs) {
+            let new_then = self.compile_sync_stmts(orig_func, then)?;
+            let new_els = self.compile_sync_stmts(orig_func, els)?;
+            return Ok(ast::Expr::If(Box::new(new_cond), new_then, new_els));
+        }
+
+        let result_ty = self.infer_stmts_ty(orig_func, &then)?;
+        let branch_chapter = self.current;
+        let join_chapter = self.chapters.len();
+        let split = self.split_at_stmt("join of `if' (or `match') with async calls".to_string());
+        self.chapters.push(Chapter::new(result_ty, split));
+        let new_then = self.compile_branch(orig_func, branch_chapter, then, join_chapter)?;
+        let new_els = self.compile_branch(orig_func, branch_chapter, els, join_chapter)?;
+        self.current = branch_chapter;
+        self.push_stmt(ast::Expr::If(Box::new(new_cond), new_then, new_els));
+        self.current = join_chapter;
+        Ok(ast::Expr::VarRef("$async_result".to_string()))
+    }
+
+    /// Compile a branch of `if` which starts in `start_chapter` and
+    /// ends by calling `join_chapter` with its value.
+    /// Returns the stmts which belong to `start_chapter`.
+    fn compile_branch(
+        &mut self,
+        orig_func: &ast::Function,
+        start_chapter: usize,
+        mut stmts: Vec<ast::Expr>,
+        join_chapter: usize,
+    ) -> Result<Vec<ast::Expr>> {
+        self.current = start_chapter;
+        let saved = std::mem::take(&mut self.chapters[start_chapter].stmts);
+        let last = stmts.pop().unwrap_or(ast::Expr::Number(0));
+        for stmt in stmts {
+            let new_stmt = self.compile_expr(orig_func, stmt)?;
+            self.push_stmt(new_stmt);
+        }
+        let value = self.compile_expr(orig_func, last)?;
+        if let ast::Expr::Return(_) = value {
+            // No need to go to the join chapter
+            self.push_stmt(value);
+        } else {
+            let goto_join = ast::Expr::fun_call(
+                chapter_func_name(&orig_func.name, join_chapter),
+                vec![ast::Expr::var_ref("$env"), value],
+            );
+            self.push_stmt(goto_join);
+        }
+        Ok(std::mem::replace(
+            &mut self.chapters[start_chapter].stmts,
+            saved,
+        ))
+    }
+
+    /// Convert `match` into a chain of `if`s which compares the tag of
+    /// the enum value
+    fn compile_match(
+        &mut self,
+        orig_func: &ast::Function,
+        expr: ast::Expr,
+        arms: Vec<ast::MatchArm>,
+    ) -> Result<ast::Expr> {
+        let new_expr = self.compile_expr(orig_func, expr)?;
+        let enum_name = arms.iter().find_map(|arm| match &arm.pattern {
+            ast::Pattern::Variant(name, _, _) => Some(name.clone()),
+            ast::Pattern::Wildcard => None,
+        });
+        let mut els = None;
+        let mut branches = vec![];
+        if let Some(enum_name) = enum_name {
+            let enum_def = self
+                .enums
+                .get(&enum_name)
+                .with_context(|| format!("unknown enum `{}'", enum_name))?
+                .clone();
+            self.n_tmps += 1;
+            let tmp = format!("$match_{}", self.n_tmps);
+            let alloc = self.declare_lvar(&tmp, Ty::Raw(enum_name.clone()))?;
+            self.push_stmt(alloc);
+            self.push_stmt(ast::Expr::Assign(tmp.clone(), Box::new(new_expr)));
+
+            let mut covered = vec![false; enum_def.variants.len()];
+            for arm in arms {
+                let ast::Pattern::Variant(name, variant, vars) = arm.pattern else {
+                    els = Some(arm.body);
+                    break;
+                };
+                if name != enum_name {
+                    return Err(anyhow!(
+                        "expected {} but got {}::{}",
+                        enum_name,
+                        name,
+                        variant
+                    ));
+                }
+                let (tag, v) = enum_def
+                    .variant(&variant)
+                    .with_context(|| format!("enum {} has no variant `{}'", name, variant))?;
+                if vars.len() != v.param_tys.len() {
+                    return Err(anyhow!(
+                        "wrong number of variables for {}::{}",
+                        name,
+                        variant
+                    ));
+                }
+                let mut body = vec![];
+                let mut arm_body = arm.body;
+                for (i, (var, ty)) in vars.iter().zip(&v.param_tys).enumerate() {
+                    // Renamed so that each arm has its own variables (arms
+                    // may bind the same name to different types)
+                    self.n_tmps += 1;
+                    let slot = format!("${}_{}", var, self.n_tmps);
+                    arm_body = arm_body
+                        .into_iter()
+                        .map(|x| rename_var(x, var, &slot))
+                        .collect();
+                    let payload = ast::Expr::FieldRef(
+                        Box::new(ast::Expr::var_ref(&tmp)),
+                        ast::enum_field_name(i),
+                    );
+                    body.push(ast::Expr::Alloc(slot.clone(), ty.clone()));
+                    body.push(ast::Expr::Assign(
+                        slot,
+                        Box::new(ast::Expr::Cast(Box::new(payload), ty.clone())),
+                    ));
+                }
+                body.extend(arm_body);
+                let cond = ast::Expr::OpCall(
+                    "==".to_string(),
+                    Box::new(ast::Expr::FieldRef(
+                        Box::new(ast::Expr::var_ref(&tmp)),
+                        "$tag".to_string(),
+                    )),
+                    Box::new(ast::Expr::Number(tag as i64)),
+                );
+                covered[tag] = true;
+                branches.push((cond, body));
+            }
+            if els.is_none() {
+                if covered.contains(&false) {
+                    return Err(anyhow!("non-exhaustive match on {}", enum_name));
+                }
+                // No need to check the tag of the last variant
+                els = branches.pop().map(|(_, body)| body);
+            }
+        } else {
+            // Only has `_`
+            self.push_stmt(new_expr);
+            els = arms.into_iter().next().map(|arm| arm.body);
+        }
+
+        let mut stmts = els.context("match has no arms")?;
+        for (cond, body) in branches.into_iter().rev() {
+            stmts = vec![ast::Expr::If(Box::new(cond), body, stmts)];
+        }
+        let last = stmts.pop().unwrap_or(ast::Expr::Number(0));
+        for stmt in stmts {
+            let new_stmt = self.compile_expr(orig_func, stmt)?;
+            self.push_stmt(new_stmt);
+        }
+        self.compile_expr(orig_func, last)
+    }
+
+    /// Compile `select`. The calls of the arms are started with their own
+    /// envs and continuations (`chiika_select_env` and `chiika_select_done`)
+    /// and raced by `chiika_select`, which drops the others and calls the
+    /// chapter of the arm finished first. Each arm ends by calling the join
+    /// chapter like `if`
+    fn compile_select(
+        &mut self,
+        orig_func: &ast::Function,
+        arms: Vec<ast::SelectArm>,
+    ) -> Result<ast::Expr> {
+        if arms.is_empty() {
+            return Err(anyhow!("select has no arms"));
+        }
+        let mut calls = vec![];
+        let mut all_args = vec![];
+        for arm in &arms {
+            let callee = match &arm.call {
+                ast::Expr::FunCall(fexpr, args) => match &**fexpr {
+                    ast::Expr::VarRef(name) if self.sigs.get(name).is_some_and(|x| x.is_async) => {
+                        self.check_fun_args(name, &self.sigs[name], args)?;
+                        all_args.extend(args.iter().cloned());
+                        Some((name.clone(), args.len()))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            calls.push(callee.with_context(|| {
+                format!(
+                    "an arm of `select' must be an async call but got `{}'",
+                    arm.call
+                )
+            })?);
+        }
+        let mut new_args = self.compile_operands(orig_func, all_args)?.into_iter();
+
+        let result_ty = self.infer(
+            orig_func,
+            &mut self.lvars.iter().cloned().collect(),
+            &ast::Expr::Select(arms.clone()),
+        )?;
+        self.n_tmps += 1;
+        let state = format!("$select_{}", self.n_tmps);
+        let alloc = self.declare_lvar(&state, Ty::raw("$any"))?;
+        self.push_stmt(alloc);
+        self.push_stmt(ast::Expr::Assign(
+            state.clone(),
+            Box::new(ast::Expr::fun_call("chiika_select_new", vec![])),
+        ));
+
+        let first_arm_chapter = self.chapters.len();
+        for (i, (callee, _)) in calls.iter().enumerate() {
+            let ret_ty = (*self.sigs[callee].ret_ty).clone();
+            let split = self.split_at_stmt(format!("arm {} of `select' (`{}')", i, callee));
+            self.chapters.push(Chapter::new(ret_ty, split));
+        }
+        let join_chapter = self.chapters.len();
+        let split = self.split_at_stmt("join of `select'".to_string());
+        self.chapters.push(Chapter::new(result_ty, split));
+
+        for (i, (callee, n_args)) in calls.iter().enumerate() {
+            let mut args = vec![
+                ast::Expr::fun_call("chiika_select_env", vec![ast::Expr::var_ref(&state)]),
+                ast::Expr::var_ref("chiika_select_done"),
+            ];
+            args.extend(new_args.by_ref().take(*n_args));
+            let arm_chapter = chapter_func_name(&orig_func.name, first_arm_chapter + i);
+            let start_arm = ast::Expr::fun_call(
+                "chiika_select_arm",
+                vec![
+                    ast::Expr::var_ref(&state),
+                    ast::Expr::fun_call(callee.clone(), args),
+                    ast::Expr::var_ref(arm_chapter),
+                ],
+            );
+            self.push_stmt(start_arm);
+        }
+        self.push_stmt(ast::Expr::fun_call(
+            "chiika_select",
+            vec![ast::Expr::var_ref("$env"), ast::Expr::var_ref(&state)],
+        ));
+
+        for (i, arm) in arms.into_iter().enumerate() {
+            let arm_chapter = first_arm_chapter + i;
+            let mut body = vec![];
+            if arm.var != "_" {
+                let ty = self.chapters[arm_chapter].async_result_ty.clone();
+                body.push(ast::Expr::Alloc(arm.var.clone(), ty));
+                body.push(ast::Expr::Assign(
+                    arm.var,
+                    Box::new(ast::Expr::var_ref("$async_result")),
+                ));
+            }
+            body.extend(arm.body);
+            let stmts = self.compile_branch(orig_func, arm_chapter, body, join_chapter)?;
+            self.chapters[arm_chapter].stmts = stmts;
+        }
+        self.current = join_chapter;
+        Ok(ast::Expr::VarRef("$async_result".to_string()))
+    }
+
+    /// Convert `expr?` into a `match`-like check which returns the `Result`
+    /// if it is an error
+    fn compile_try(&mut self, orig_func: &ast::Function, expr: ast::Expr) -> Result<ast::Expr> {
+        if orig_func.ret_ty != Ty::raw("Result") {
+            return Err(anyhow!(
+                "`?' can only be used in a function returning Result (in {})",
+                orig_func.name
+            ));
+        }
+        let new_expr = self.compile_expr(orig_func, expr)?;
+        self.n_tmps += 1;
+        let tmp = format!("$try_{}", self.n_tmps);
+        let alloc = self.declare_lvar(&tmp, Ty::raw("Result"))?;
+        self.push_stmt(alloc);
+        self.push_stmt(ast::Expr::Assign(tmp.clone(), Box::new(new_expr)));
+
+        let (err_tag, _) = self.enums["Result"].variant("Err").unwrap();
+        let is_err = ast::Expr::OpCall(
+            "==".to_string(),
+            Box::new(ast::Expr::FieldRef(
+                Box::new(ast::Expr::var_ref(&tmp)),
+                "$tag".to_string(),
+            )),
+            Box::new(ast::Expr::Number(err_tag as i64)),
+        );
+        let mut then = self.cancel_scopes();
+        then.push(ast::Expr::Return(Box::new(ast::Expr::var_ref(&tmp))));
+        let check = ast::Expr::If(Box::new(is_err), then, vec![]);
+        self.push_stmt(check);
+
+        let payload =
+            ast::Expr::FieldRef(Box::new(ast::Expr::var_ref(&tmp)), ast::enum_field_name(0));
+        Ok(ast::Expr::Cast(Box::new(payload), Ty::raw("int")))
+    }
+
+    /// Returns the type of the value of `stmts`
+    fn infer_stmts_ty(&self, orig_func: &ast::Function, stmts: &[ast::Expr]) -> Result<Ty> {
+        let mut lvars = self.lvars.iter().cloned().collect::<HashMap<_, _>>();
+        self.infer_stmts(orig_func, &mut lvars, stmts)
+    }
+
+    fn infer_stmts(
+        &self,
+        orig_func: &ast::Function,
+        lvars: &mut HashMap<String, Ty>,
+        stmts: &[ast::Expr],
+    ) -> Result<Ty> {
+        let mut ty = Ty::raw("int");
+        for stmt in stmts {
+            ty = self.infer(orig_func, lvars, stmt)?;
+        }
+        Ok(ty)
+    }
+
+    fn infer(
+        &self,
+        orig_func: &ast::Function,
+        lvars: &mut HashMap<String, Ty>,
+        e: &ast::Expr,
+    ) -> Result<Ty> {
+        let ty = match e {
+            ast::Expr::Number(_) | ast::Expr::OpCall(_, _, _) | ast::Expr::Try(_) => Ty::raw("int"),
+            ast::Expr::Str(_) => Ty::raw("string"),
+            ast::Expr::Return(expr) => self.infer(orig_func, lvars, expr)?,
+            ast::Expr::VarRef(name) => {
+                if let Some(ty) = lvars.get(name) {
+                    ty.clone()
+                } else if let Some(param) = orig_func.params.iter().find(|x| x.name == *name) {
+                    param.ty.clone()
+                } else if let Some(fun_ty) = self.sigs.get(name) {
+                    Ty::Fun(fun_ty.clone())
+                } else {
+                    return Err(anyhow!("unknown variable `{}'", name));
+                }
+            }
+            ast::Expr::FunCall(fexpr, _) => match &**fexpr {
+                ast::Expr::VarRef(name) if self.sigs.contains_key(name) => {
+                    (*self.sigs[name].ret_ty).clone()
+                }
+                _ => return Err(anyhow!("not a function: {:?}", fexpr)),
+            },
+            ast::Expr::Cast(_, ty) => ty.clone(),
+            ast::Expr::Alloc(name, ty) => {
+                lvars.insert(name.clone(), ty.clone());
+                Ty::raw("int")
+            }
+            ast::Expr::Assign(_, _)
+            | ast::Expr::FieldSet(_, _, _)
+            | ast::Expr::IndexSet(_, _, _)
+            | ast::Expr::For(_, _, _)
+            | ast::Expr::While(_, _)
+            | ast::Expr::Index(_, _) => Ty::raw("int"),
+            ast::Expr::New(name, _) | ast::Expr::EnumNew(name, _, _) => Ty::Raw(name.clone()),
+            ast::Expr::FieldRef(obj, name) => {
+                let obj_ty = self.infer(orig_func, lvars, obj)?;
+                let field = match &obj_ty {
+                    Ty::Raw(s) => self
+                        .structs
+                        .get(s)
+                        .and_then(|x| x.fields.iter().find(|x| x.name == *name)),
+                    _ => None,
+                };
+                field
+                    .with_context(|| format!("{} has no field `{}'", obj_ty, name))?
+                    .ty
+                    .clone()
+            }
+            ast::Expr::ArrayLit(_) => Ty::raw("array"),
+            ast::Expr::MethodCall(_, name, _) => match &name[..] {
+                "push" => Ty::raw("array"),
+                _ => Ty::raw("int"),
+            },
+            ast::Expr::If(_, then, els) => {
+                if let Some(ast::Expr::Return(_)) = then.last() {
+                    self.infer_stmts(orig_func, lvars, els)?
+                } else {
+                    self.infer_stmts(orig_func, lvars, then)?
+                }
+            }
+            ast::Expr::Match(_, arms) => {
+                // Use an arm which does not `return`
+                let arm = arms
+                    .iter()
+                    .find(|arm| !matches!(arm.body.last(), Some(ast::Expr::Return(_))))
+                    .or(arms.first())
+                    .context("match has no arms")?;
+                if let ast::Pattern::Variant(name, variant, vars) = &arm.pattern {
+                    if let Some((_, v)) = self.enums.get(name).and_then(|x| x.variant(variant)) {
+                        for (var, ty) in vars.iter().zip(&v.param_tys) {
+                            lvars.insert(var.clone(), ty.clone());
+                        }
+                    }
+                }
+                self.infer_stmts(orig_func, lvars, &arm.body)?
+            }
+            ast::Expr::Scope(body) => self.infer_stmts(orig_func, lvars, body)?,
+            ast::Expr::Select(arms) => {
+                let arm = arms
+                    .iter()
+                    .find(|arm| !matches!(arm.body.last(), Some(ast::Expr::Return(_))))
+                    .or(arms.first())
+                    .context("select has no arms")?;
+                let var_ty = self.infer(orig_func, lvars, &arm.call)?;
+                lvars.insert(arm.var.clone(), var_ty);
+                self.infer_stmts(orig_func, lvars, &arm.body)?
+            }
+        };
+        Ok(ty)
+    }
+}
+
+/// Layout of the env frame of a split function.
+/// `$cont`, params and local variables are pushed in this order.
+struct EnvLayout {
+    // Name and type of the params and the local variables (`$cont` is not
+    // included)
+    items: Vec<(String, Ty)>,
+    n_params: usize,
+    // Return type of the original function
+    ret_ty: Ty,
+}
+
+impl EnvLayout {
+    fn new(orig_func: &ast::Function, lvars: &[(String, Ty)]) -> EnvLayout {
+        let mut items = orig_func
+            .params
+            .iter()
+            .map(|x| (x.name.clone(), x.ty.clone()))
+            .collect::<Vec<_>>();
+        items.extend(lvars.iter().cloned());
+        EnvLayout {
+            items,
+            n_params: orig_func.params.len(),
+            ret_ty: orig_func.ret_ty.clone(),
+        }
+    }
+
+    fn frame_size(&self) -> usize {
+        self.items.len() + 1
+    }
+
+    /// Returns the index from the stack top and the type of the item
+    fn find(&self, name: &str, include_params: bool) -> Option<(usize, &Ty)> {
+        // Names are unique as local variables cannot shadow params
+        let pos = self.items.iter().position(|(x, _)| x == name)?;
+        if pos < self.n_params && !include_params {
+            return None;
+        }
+        // +1 for $cont
+        Some((self.frame_size() - 1 - (pos + 1), &self.items[pos].1))
+    }
+
+    /// Returns the expr which pops the frame and passes `value` to `$cont`
+    fn call_cont(&self, value: ast::Expr) -> ast::Expr {
+        let env_pop = ast::Expr::fun_call(
+            "chiika_env_pop",
+            vec![
+                ast::Expr::var_ref("$env"),
+                ast::Expr::Number(self.frame_size() as i64),
+            ],
+        );
+        let fun_ty = FunTy {
+            is_async: false, // chiika-1 does not have notion of asyncness
+            param_tys: vec![Ty::raw("$ENV"), self.ret_ty.clone()],
+            ret_ty: Box::new(Ty::raw("$FUTURE")),
+        };
+        let cast = ast::Expr::Cast(Box::new(env_pop), Ty::Fun(fun_ty));
+        ast::Expr::FunCall(Box::new(cast), vec![ast::Expr::var_ref("$env"), value])
+    }
+
+    fn is_lvar(&self, name: &str) -> bool {
+        self.items[self.n_params..].iter().any(|(x, _)| x == name)
+    }
+
+    /// Convert access to local variables (and params, except in the first
+    /// chapter) into access to the env. Also `return` is converted to call
+    /// `$cont`
+    fn lower(&self, e: ast::Expr, in_first_chapter: bool) -> ast::Expr {
+        match e {
+            ast::Expr::Return(value) => {
+                let v = self.lower(*value, in_first_chapter);
+                ast::Expr::Return(Box::new(self.call_cont(v)))
+            }
+            // The slot is already pushed in the intro
+            ast::Expr::Alloc(ref name, _) if self.is_lvar(name) => ast::Expr::Number(0),
+            ast::Expr::Assign(name, rhs) if self.is_lvar(&name) => {
+                let (n, _) = self.find(&name, false).unwrap();
+                let v = ast::Expr::Cast(
+                    Box::new(self.lower(*rhs, in_first_chapter)),
+                    Ty::raw("$any"),
+                );
+                ast::Expr::fun_call(
+                    "chiika_env_set",
+                    vec![ast::Expr::var_ref("$env"), ast::Expr::Number(n as i64), v],
+                )
+            }
+            ast::Expr::VarRef(ref name) => match self.find(name, !in_first_chapter) {
+                Some((n, ty)) => {
+                    let env_ref = ast::Expr::fun_call(
+                        "chiika_env_ref",
+                        vec![ast::Expr::var_ref("$env"), ast::Expr::Number(n as i64)],
+                    );
+                    ast::Expr::Cast(Box::new(env_ref), ty.clone())
+                }
+                None => e,
+            },
+            _ => e.map_children(&mut |x| self.lower(x, in_first_chapter)),
+        }
+    }
+}
+
+/// Type of an async function after the CPS transformation
+/// Rename the variable `from` in `e` to `to`
+fn rename_var(e: ast::Expr, from: &str, to: &str) -> ast::Expr {
+    let rename = |name: String| if name == from { to.to_string() } else { name };
+    let rename_all = |stmts: Vec<ast::Expr>| {
+        stmts
+            .into_iter()
+            .map(|x| rename_var(x, from, to))
+            .collect::<Vec<_>>()
+    };
+    match e {
+        ast::Expr::VarRef(name) => ast::Expr::VarRef(rename(name)),
+        ast::Expr::Alloc(name, ty) => ast::Expr::Alloc(rename(name), ty),
+        ast::Expr::Assign(name, rhs) => {
+            ast::Expr::Assign(rename(name), Box::new(rename_var(*rhs, from, to)))
+        }
+        ast::Expr::For(var, arr, body) => ast::Expr::For(
+            rename(var),
+            Box::new(rename_var(*arr, from, to)),
+            rename_all(body),
+        ),
+        ast::Expr::Match(e, arms) => {
+            let arms = arms
+                .into_iter()
+                .map(|arm| ast::MatchArm {
+                    pattern: match arm.pattern {
+                        ast::Pattern::Variant(name, variant, vars) => ast::Pattern::Variant(
+                            name,
+                            variant,
+                            vars.into_iter().map(rename).collect(),
+                        ),
+                        ast::Pattern::Wildcard => ast::Pattern::Wildcard,
+                    },
+                    body: rename_all(arm.body),
+                })
+                .collect();
+            ast::Expr::Match(Box::new(rename_var(*e, from, to)), arms)
+        }
+        ast::Expr::Select(arms) => ast::Expr::Select(
+            arms.into_iter()
+                .map(|arm| ast::SelectArm {
+                    var: rename(arm.var),
+                    call: rename_var(arm.call, from, to),
+                    body: rename_all(arm.body),
+                })
+                .collect(),
+        ),
+        _ => e.map_children(&mut |x| rename_var(x, from, to)),
+    }
+}
+
+/// Local variables cannot have the name of a param. Shadowing would behave
+/// differently in sync functions, where chiika-1 looks up params first, and
+/// in async ones, where both are items of the env
+fn check_not_param(orig_func: &ast::Function, name: &str) -> Result<()> {
+    if orig_func.params.iter().any(|x| x.name == name) {
+        return Err(anyhow!(
+            "variable `{}' shadows a param of {}",
+            name,
+            orig_func.name
+        ));
+    }
+    Ok(())
+}
+
+fn cps_fun_ty(fun_ty: &FunTy) -> FunTy {
+    if !fun_ty.is_async {
+        return fun_ty.clone();
+    }
+    let params = fun_ty
+        .param_tys
+        .iter()
+        .map(|ty| ast::Param::new(ty.clone(), ""))
+        .collect::<Vec<_>>();
+    FunTy {
+        is_async: false,
+        param_tys: prepend_async_params(&params, (*fun_ty.ret_ty).clone())
+            .into_iter()
+            .map(|x| x.ty)
+            .collect(),
+        ret_ty: Box::new(Ty::raw("$FUTURE")),
+    }
 }
 
 /// Prepend params for async
@@ -209,13 +1338,18 @@ fn prepend_async_params(params: &[ast::Param], result_ty: Ty) -> Vec<ast::Param>
     new_params
 }
 
-fn prepend_async_intro(orig_func: &ast::Function, mut stmts: Vec<ast::Expr>) -> Vec<ast::Expr> {
-    let push_items = vec![ast::Expr::var_ref("$cont")].into_iter().chain(
-        orig_func
-            .params
-            .iter()
-            .map(|param| ast::Expr::var_ref(&param.name)),
-    );
+fn prepend_async_intro(layout: &EnvLayout, mut stmts: Vec<ast::Expr>) -> Vec<ast::Expr> {
+    let push_items =
+        vec![ast::Expr::var_ref("$cont")]
+            .into_iter()
+            .chain(layout.items.iter().enumerate().map(|(i, (name, _))| {
+                if i < layout.n_params {
+                    ast::Expr::var_ref(name)
+                } else {
+                    // Initial value of local variables
+                    ast::Expr::Number(0)
+                }
+            }));
 
     let mut push_calls = push_items
         .map(|arg| {
@@ -230,28 +1364,13 @@ fn prepend_async_intro(orig_func: &ast::Function, mut stmts: Vec<ast::Expr>) ->
     push_calls
 }
 
-fn append_async_outro(
-    orig_func: &ast::Function,
-    mut stmts: Vec<ast::Expr>,
-    result_ty: Ty,
-) -> Vec<ast::Expr> {
+fn append_async_outro(layout: &EnvLayout, mut stmts: Vec<ast::Expr>) -> Vec<ast::Expr> {
+    if let Some(ast::Expr::Return(_)) = stmts.last() {
+        // Already calls $cont
+        return stmts;
+    }
     let result_value = stmts.pop().unwrap();
-    let n_pop = orig_func.params.len() + 1; // +1 for $cont
-    let env_pop = ast::Expr::FunCall(
-        Box::new(ast::Expr::var_ref("chiika_env_pop")),
-        vec![ast::Expr::var_ref("$env"), ast::Expr::Number(n_pop as i64)],
-    );
-    let fun_ty = FunTy {
-        is_async: false, // chiika-1 does not have notion of asyncness
-        param_tys: vec![Ty::raw("$ENV"), result_ty],
-        ret_ty: Box::new(Ty::raw("$FUTURE")),
-    };
-    let cast = ast::Expr::Cast(Box::new(env_pop), Ty::Fun(fun_ty));
-    let call_cont = ast::Expr::FunCall(
-        Box::new(cast),
-        vec![ast::Expr::var_ref("$env"), result_value],
-    );
-    stmts.push(call_cont);
+    stmts.push(layout.call_cont(result_value));
     stmts
 }
 
@@ -259,3 +1378,69 @@ fn append_async_outro(
 fn chapter_func_name(orig_name: &str, chapter_idx: usize) -> String {
     format!("{}_{}", orig_name, chapter_idx)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chumsky::Parser;
+
+    fn compile_src(src: &str) -> String {
+        let decls = crate::parser::parser().parse(src).unwrap();
+        let (compiled, _) = compile(decls).unwrap();
+        ast::to_source(compiled)
+    }
+
+    #[test]
+    fn test_struct() {
+        let src = "
+          struct Point { int x; int y; }
+          fun chiika_main() -> int {
+            alloc Point p;
+            p = new Point(1, 2);
+            p.x = p.y + 1;
+            p.x
+          }
+        ";
+        let out = compile_src(src);
+        assert!(out.contains("struct Point {\n  int x;\n  int y;\n}\n"));
+        assert!(out.contains("alloc Point p;"));
+        assert!(out.contains("p = new Point(1, 2);"));
+        assert!(out.contains("p.x = (p.y + 1);"));
+    }
+
+    #[test]
+    fn test_struct_param_across_async_call() {
+        let src = "
+          extern_async sleep_sec(int n) -> int;
+          struct Point { int x; int y; }
+          fun get_x(Point p) -> int {
+            sleep_sec(1);
+            p.x
+          }
+          fun chiika_main() -> int { get_x(new Point(1, 2)) }
+        ";
+        let out = compile_src(src);
+        // Saved to the env as `$any` and cast back to `Point`
+        assert!(out.contains("chiika_env_push($env, ($CAST(p as $any)));"));
+        assert!(out.contains("($CAST(chiika_env_ref($env, 0) as Point)).x"));
+    }
+
+    #[test]
+    fn test_local_shadowing_param() {
+        let compile_err = |body: &str| {
+            let src = format!(
+                "extern_async sleep_sec(int n) -> int;
+                 fun f(int n) -> int {{ {} }}
+                 fun chiika_main() -> int {{ f(1) }}",
+                body
+            );
+            let decls = crate::parser::parser().parse(src).unwrap();
+            compile(decls).unwrap_err().to_string()
+        };
+        let msg = "variable `n' shadows a param of f";
+        // Rejected in both sync and async functions
+        assert_eq!(compile_err("alloc n; n = 2; n"), msg);
+        assert_eq!(compile_err("sleep_sec(n); alloc n; n = 2; n"), msg);
+        assert_eq!(compile_err("for n in [1] { n }; 0"), msg);
+    }
+}
@@ -0,0 +1,192 @@
+//! `chiika-2 --explain <fn>`: shows how a function is split into chapters
+//!
+//! The chiika-2 source of the function is shown on the left and the
+//! generated chiika-1 functions on the right. Statements which cause a split
+//! are marked with the chapter they start, and each chapter is annotated with
+//! the reason of the split and the operations on `ChiikaEnv`.
+use crate::ast;
+use crate::compiler::{self, Explanation};
+use crate::formatter::format_stmt;
+use anyhow::Result;
+
+const SEPARATOR: &str = " | ";
+
+/// Returns the explanation of the function `name` in the source
+pub fn explain(src: &str, name: &str) -> Result<String> {
+    explain_decls(&crate::parse(src)?, name)
+}
+
+/// Same as `explain` but resolves imports of the file. Functions of the
+/// imported modules are specified like `math__add`
+pub fn explain_file(path: &std::path::Path, name: &str) -> Result<String> {
+    explain_decls(&crate::module::load(path)?, name)
+}
+
+fn explain_decls(decls: &[ast::Declaration], name: &str) -> Result<String> {
+    let ex = compiler::explain(decls, name)?;
+    let n = ex.chapters.len();
+    let mut out = format!("{}: {}", name, fun_ty(&ex.fun_ty));
+    if n > 1 {
+        out.push_str(&format!(" (split into {} chapters)\n", n));
+        out.push_str(&format!("env frame: {}\n", ex.env_items.join(", ")));
+    } else {
+        out.push_str(" (not split)\n");
+    }
+    out.push('\n');
+
+    let left = source_lines(&ex);
+    let right = chapter_lines(&ex);
+    let width = left
+        .iter()
+        .chain([&"chiika-2".to_string()])
+        .map(|x| x.chars().count())
+        .max()
+        .unwrap();
+    let header = [("chiika-2".to_string(), "chiika-1".to_string())];
+    let rows = left.len().max(right.len());
+    let body = (0..rows).map(|i| {
+        (
+            left.get(i).cloned().unwrap_or_default(),
+            right.get(i).cloned().unwrap_or_default(),
+        )
+    });
+    for (l, r) in header.into_iter().chain(body) {
+        let line = format!("{:width$}{}{}", l, SEPARATOR, r, width = width);
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+fn fun_ty(ty: &ast::FunTy) -> String {
+    let params = ty
+        .param_tys
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>();
+    format!(
+        "{} ({}) -> {}",
+        if ty.is_async { "async" } else { "sync" },
+        params.join(", "),
+        ty.ret_ty
+    )
+}
+
+/// The chiika-2 function with the marks of splits
+fn source_lines(ex: &Explanation) -> Vec<String> {
+    let f = &ex.orig_func;
+    let params = f
+        .params
+        .iter()
+        .map(|x| format!("{} {}", x.ty, x.name))
+        .collect::<Vec<_>>();
+    let mut lines = vec![format!(
+        "fun {}({}) -> {} {{",
+        f.name,
+        params.join(", "),
+        f.ret_ty
+    )];
+    for (i, stmt) in f.body_stmts.iter().enumerate() {
+        let sep = if i + 1 == f.body_stmts.len() { "" } else { ";" };
+        let text = format!("  {}{}", format_stmt(stmt, 1), sep);
+        let mut stmt_lines = text.lines().map(|x| x.to_string()).collect::<Vec<_>>();
+        let starts = ex
+            .chapters
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, split))| split.as_ref().is_some_and(|x| x.stmt_idx == i))
+            .map(|(j, _)| j.to_string())
+            .collect::<Vec<_>>();
+        if !starts.is_empty() {
+            let mark = if starts.len() == 1 {
+                format!("  <- chapter {}", starts[0])
+            } else {
+                format!("  <- chapters {}", starts.join(", "))
+            };
+            stmt_lines[0].push_str(&mark);
+        }
+        lines.append(&mut stmt_lines);
+    }
+    lines.push("}".to_string());
+    lines
+}
+
+/// The chapter functions with the annotations
+fn chapter_lines(ex: &Explanation) -> Vec<String> {
+    let n = ex.chapters.len();
+    let mut lines = vec![];
+    for (i, (func, split)) in ex.chapters.iter().enumerate() {
+        if i > 0 {
+            lines.push(String::new());
+        }
+        if n > 1 {
+            let reason = match split {
+                Some(x) => format!(": after {} in statement {}", x.reason, x.stmt_idx + 1),
+                None => String::new(),
+            };
+            lines.push(format!("// chapter {} (of {}){}", i, n, reason));
+            let ops = env_ops(ex, func);
+            if !ops.is_empty() {
+                lines.push(format!("// env: {}", ops.join("; ")));
+            }
+        }
+        lines.extend(func.to_string().lines().map(|x| x.to_string()));
+    }
+    lines
+}
+
+/// Summary of the calls of `chiika_env_xx` in the function
+fn env_ops(ex: &Explanation, func: &ast::Function) -> Vec<String> {
+    let mut calls = vec![];
+    for stmt in &func.body_stmts {
+        collect_env_calls(stmt, &mut calls);
+    }
+    let slot = |n: i64| {
+        let idx = ex.env_items.len() as i64 - 1 - n;
+        ex.env_items
+            .get(idx as usize)
+            .cloned()
+            .unwrap_or_else(|| format!("?{}", n))
+    };
+    let mut ops: Vec<(&str, Vec<String>)> = vec![];
+    let mut n_pushes = 0;
+    for (name, n) in calls {
+        let (op, item) = match &name[..] {
+            "chiika_env_push" => {
+                n_pushes += 1;
+                ("push", ex.env_items[n_pushes - 1].clone())
+            }
+            "chiika_env_pop" => ("pop", format!("{} items", n)),
+            "chiika_env_ref" => ("ref", slot(n)),
+            "chiika_env_set" => ("set", slot(n)),
+            _ => continue,
+        };
+        match ops.iter_mut().find(|(x, _)| *x == op) {
+            Some((_, items)) if items.contains(&item) => (),
+            Some((_, items)) => items.push(item),
+            None => ops.push((op, vec![item])),
+        }
+    }
+    ops.into_iter()
+        .map(|(op, items)| format!("{} {}", op, items.join(", ")))
+        .collect()
+}
+
+/// Collect the name and the second argument of the calls of `chiika_env_xx`
+fn collect_env_calls(e: &ast::Expr, calls: &mut Vec<(String, i64)>) {
+    if let ast::Expr::FunCall(fexpr, args) = e {
+        if let ast::Expr::VarRef(name) = &**fexpr {
+            if name.starts_with("chiika_env_") {
+                let n = match args.get(1) {
+                    Some(ast::Expr::Number(n)) => *n,
+                    _ => 0,
+                };
+                calls.push((name.clone(), n));
+            }
+        }
+    }
+    e.clone().map_children(&mut |x| {
+        collect_env_calls(&x, calls);
+        x
+    });
+}
@@ -0,0 +1,462 @@
+//! Formatter of chiika-2 source (`chiika-2 fmt`)
+use crate::ast;
+use crate::parser::Trivia;
+use anyhow::{anyhow, Result};
+use chumsky::Parser;
+use std::cell::RefCell;
+use std::ops::Range;
+use std::rc::Rc;
+
+const INDENT: &str = "  ";
+
+/// Returns `src` formatted in the canonical style. Comments are kept if they
+/// are between declarations or statements.
+pub fn format(src: &str) -> Result<String> {
+    // Report syntax errors in the same way as the compiler
+    crate::parse(src)?;
+    let trivia = Rc::new(RefCell::new(Trivia::default()));
+    let decls = crate::parser::parser_with_comments(trivia.clone())
+        .parse(src)
+        .map_err(|errs| {
+            let e = &errs[0];
+            anyhow!(
+                "{}comments are only supported between declarations or statements",
+                crate::render_parse_error(src, e.span(), e.to_string())
+            )
+        })?;
+    let f = Formatter {
+        chars: src.chars().collect(),
+        trivia: RefCell::new(trivia.take()),
+    };
+    Ok(f.decls(&decls))
+}
+
+/// Format a statement (without comments) at the indentation level `level`
+pub(crate) fn format_stmt(e: &ast::Expr, level: usize) -> String {
+    let f = Formatter {
+        chars: vec![],
+        trivia: RefCell::new(Trivia::default()),
+    };
+    f.expr(e, level, true)
+}
+
+/// A formatted declaration or statement, or a comment between them
+enum Item<T> {
+    Code(T),
+    Comment(Comment),
+}
+
+struct Comment {
+    text: String,
+    /// Offset (in chars) in the source
+    pos: usize,
+}
+
+struct Formatter {
+    chars: Vec<char>,
+    /// Consumed from the front as the formatter goes through the source. It is
+    /// empty when there are no comments (then all the positions are 0)
+    trivia: RefCell<Trivia>,
+}
+
+impl Formatter {
+    /// Position of the next declaration or statement
+    fn next_item(&self) -> usize {
+        let item = self.trivia.borrow_mut().items.pop_first();
+        item.unwrap_or_default()
+    }
+
+    /// Span of the next block
+    fn next_block(&self) -> Range<usize> {
+        let block = self.trivia.borrow_mut().blocks.pop_first();
+        let (start, end) = block.unwrap_or_default();
+        start..end
+    }
+
+    fn peek_block(&self) -> Range<usize> {
+        let trivia = self.trivia.borrow();
+        let (start, end) = trivia.blocks.first_key_value().unwrap_or((&0, &0));
+        *start..*end
+    }
+
+    fn has_comments(&self, span: Range<usize>) -> bool {
+        self.trivia.borrow().comments.range(span).next().is_some()
+    }
+
+    /// Take the comments before `pos`
+    fn comments_before(&self, pos: usize) -> Vec<Comment> {
+        let mut trivia = self.trivia.borrow_mut();
+        let mut comments = vec![];
+        while let Some(entry) = trivia.comments.first_entry() {
+            if *entry.key() >= pos {
+                break;
+            }
+            let (pos, text) = entry.remove_entry();
+            comments.push(Comment { text, pos });
+        }
+        comments
+    }
+
+    /// Returns true if the comment is on the same line as the preceding code
+    fn is_trailing(&self, comment: &Comment) -> bool {
+        let before = &self.chars[..comment.pos];
+        match before.iter().rev().find(|c| **c != ' ' && **c != '\t') {
+            Some(c) => *c != '\n',
+            None => false,
+        }
+    }
+
+    /// Returns true if the line after the comment is empty
+    fn blank_line_after(&self, comment: &Comment) -> bool {
+        let end = comment.pos + comment.text.chars().count();
+        let mut lines = self.chars[end..].split(|c| *c == '\n').skip(1);
+        lines
+            .next()
+            .is_some_and(|line| line.iter().all(|c| c.is_whitespace()))
+    }
+
+    fn decls(&self, decls: &[ast::Declaration]) -> String {
+        let mut items = vec![];
+        for decl in decls {
+            let pos = self.next_item();
+            items.extend(self.comments_before(pos).into_iter().map(Item::Comment));
+            items.push(Item::Code((decl, self.decl(decl))));
+        }
+        items.extend(
+            self.comments_before(usize::MAX)
+                .into_iter()
+                .map(Item::Comment),
+        );
+
+        let mut out = String::new();
+        let mut prev: Option<&Item<(&ast::Declaration, String)>> = None;
+        for (i, item) in items.iter().enumerate() {
+            if let Item::Comment(c) = item {
+                if self.is_trailing(c) && !out.is_empty() {
+                    out.pop();
+                    out.push_str(&format!(" {}\n", c.text.trim_end()));
+                    continue;
+                }
+            }
+            let blank = match prev {
+                None => false,
+                Some(Item::Comment(c)) => self.blank_line_after(c),
+                Some(Item::Code((p, _))) => {
+                    // Comments go with the next declaration
+                    let next = items[i..].iter().find_map(|x| match x {
+                        Item::Code((decl, _)) => Some(decl),
+                        Item::Comment(_) => None,
+                    });
+                    !(matches!(p, ast::Declaration::Extern(_))
+                        && matches!(next, Some(ast::Declaration::Extern(_)))
+                        || matches!(p, ast::Declaration::Import(_))
+                            && matches!(next, Some(ast::Declaration::Import(_))))
+                }
+            };
+            if blank {
+                out.push('\n');
+            }
+            match item {
+                Item::Code((_, s)) => out.push_str(s),
+                Item::Comment(c) => out.push_str(&format!("{}\n", c.text.trim_end())),
+            }
+            prev = Some(item);
+        }
+        out
+    }
+
+    fn decl(&self, decl: &ast::Declaration) -> String {
+        match decl {
+            ast::Declaration::Struct(x) => {
+                let mut s = format!("struct {} {{\n", x.name);
+                for field in &x.fields {
+                    s.push_str(&format!("{}{} {};\n", INDENT, field.ty, field.name));
+                }
+                s + "}\n"
+            }
+            ast::Declaration::Enum(x) => {
+                let mut s = format!("enum {} {{\n", x.name);
+                for v in &x.variants {
+                    if v.param_tys.is_empty() {
+                        s.push_str(&format!("{}{},\n", INDENT, v.name));
+                    } else {
+                        let tys = v
+                            .param_tys
+                            .iter()
+                            .map(|x| x.to_string())
+                            .collect::<Vec<_>>();
+                        s.push_str(&format!("{}{}({}),\n", INDENT, v.name, tys.join(", ")));
+                    }
+                }
+                s + "}\n"
+            }
+            ast::Declaration::Extern(x) => format!(
+                "{} {}({}) -> {};\n",
+                if x.is_async { "extern_async" } else { "extern" },
+                x.name,
+                params(&x.params),
+                x.ret_ty
+            ),
+            ast::Declaration::Function(x) => format!(
+                "{}fun {}({}) -> {} {}\n",
+                x.attrs.iter().map(|a| attr(a) + "\n").collect::<String>(),
+                x.name,
+                params(&x.params),
+                x.ret_ty,
+                self.block(&x.body_stmts, 0, true)
+            ),
+            ast::Declaration::Comment(_) => panic!("not a chiika-2 declaration: {:?}", decl),
+            ast::Declaration::Import(path) => format!("import {};\n", ast::escape_str(path)),
+        }
+    }
+
+    /// Format `{ ... }`. The statements are written in separate lines if
+    /// `multiline` is true or there are comments
+    fn block(&self, stmts: &[ast::Expr], level: usize, multiline: bool) -> String {
+        let span = self.next_block();
+        let multiline = multiline || self.has_comments(span.clone());
+        let (stmt_level, stmt) = if multiline {
+            (level + 1, true)
+        } else {
+            (level, false)
+        };
+        let mut items = vec![];
+        for x in stmts {
+            let pos = self.next_item();
+            items.extend(self.comments_before(pos).into_iter().map(Item::Comment));
+            items.push(Item::Code(self.expr(x, stmt_level, stmt)));
+        }
+        items.extend(
+            self.comments_before(span.end)
+                .into_iter()
+                .map(Item::Comment),
+        );
+        if items.is_empty() {
+            return "{}".to_string();
+        }
+        if !multiline {
+            let stmts = items
+                .into_iter()
+                .filter_map(|x| match x {
+                    Item::Code(s) => Some(s),
+                    Item::Comment(_) => None,
+                })
+                .collect::<Vec<_>>();
+            return format!("{{ {} }}", stmts.join("; "));
+        }
+        let indent = INDENT.repeat(level + 1);
+        let mut lines = vec!["{".to_string()];
+        for (i, item) in items.iter().enumerate() {
+            match item {
+                Item::Comment(c) => {
+                    let comment = c.text.trim_end();
+                    if self.is_trailing(c) {
+                        lines.last_mut().unwrap().push_str(&format!(" {}", comment));
+                    } else {
+                        lines.push(format!("{}{}", indent, comment));
+                    }
+                }
+                Item::Code(s) => {
+                    let is_last = items[i + 1..].iter().all(|x| matches!(x, Item::Comment(_)));
+                    let sep = if is_last { "" } else { ";" };
+                    lines.push(format!("{}{}{}", indent, s, sep));
+                }
+            }
+        }
+        lines.push(format!("{}}}", INDENT.repeat(level)));
+        lines.join("\n")
+    }
+
+    /// Format an expression which starts at the line of indentation level
+    /// `level`. `stmt` is true if it is a statement (blocks are written in
+    /// multiple lines)
+    fn expr(&self, e: &ast::Expr, level: usize, stmt: bool) -> String {
+        let sub = |x: &ast::Expr| self.expr(x, level, false);
+        let operand = |x: &ast::Expr| {
+            if is_atomic(x) {
+                sub(x)
+            } else {
+                format!("({})", sub(x))
+            }
+        };
+        let args = |xs: &[ast::Expr]| xs.iter().map(sub).collect::<Vec<_>>().join(", ");
+        match e {
+            ast::Expr::Number(n) => n.to_string(),
+            ast::Expr::Str(s) => ast::escape_str(s),
+            ast::Expr::VarRef(name) => name.clone(),
+            ast::Expr::OpCall(op, l, r) => {
+                // Binary operators are left-associative
+                let lhs = match **l {
+                    ast::Expr::OpCall(_, _, _) => sub(l),
+                    _ => operand(l),
+                };
+                format!("{} {} {}", lhs, op, operand(r))
+            }
+            ast::Expr::FunCall(fexpr, arg_exprs) => match &**fexpr {
+                ast::Expr::VarRef(name) => format!("{}({})", name, args(arg_exprs)),
+                _ => format!("({})({})", sub(fexpr), args(arg_exprs)),
+            },
+            ast::Expr::Alloc(name, ty) if *ty == ast::Ty::raw("int") => format!("alloc {}", name),
+            ast::Expr::Alloc(name, ty) => format!("alloc {} {}", ty, name),
+            ast::Expr::Assign(name, rhs) => format!("{} = {}", name, sub(rhs)),
+            ast::Expr::New(name, arg_exprs) => format!("new {}({})", name, args(arg_exprs)),
+            ast::Expr::FieldRef(obj, name) => format!("{}.{}", operand(obj), name),
+            ast::Expr::FieldSet(obj, name, rhs) => {
+                format!("{}.{} = {}", operand(obj), name, sub(rhs))
+            }
+            ast::Expr::ArrayLit(exprs) => format!("[{}]", args(exprs)),
+            ast::Expr::Index(arr, idx) => format!("{}[{}]", operand(arr), sub(idx)),
+            ast::Expr::IndexSet(arr, idx, rhs) => {
+                format!("{}[{}] = {}", operand(arr), sub(idx), sub(rhs))
+            }
+            ast::Expr::MethodCall(obj, name, arg_exprs) => {
+                format!("{}.{}({})", operand(obj), name, args(arg_exprs))
+            }
+            ast::Expr::For(var, arr, body) => format!(
+                "for {} in {} {}",
+                var,
+                sub(arr),
+                self.block(body, level, stmt)
+            ),
+            ast::Expr::If(cond, then, els) => {
+                let mut s = format!("if {} {}", sub(cond), self.block(then, level, stmt));
+                // A missing `else` is an empty block too (see `Trivia`)
+                let els = self.block(els, level, stmt);
+                if els != "{}" {
+                    s.push_str(&format!(" else {}", els));
+                }
+                s
+            }
+            ast::Expr::EnumNew(name, variant, arg_exprs) if arg_exprs.is_empty() => {
+                format!("{}::{}", name, variant)
+            }
+            ast::Expr::EnumNew(name, variant, arg_exprs) => {
+                format!("{}::{}({})", name, variant, args(arg_exprs))
+            }
+            ast::Expr::Match(expr, arms) => {
+                let head = format!("match {} ", self.expr(expr, level, false));
+                let bodies = arms.iter().map(|arm| &arm.body[..]).collect::<Vec<_>>();
+                self.arms(head, |i| pattern(&arms[i].pattern), &bodies, level, stmt)
+            }
+            ast::Expr::Select(arms) => {
+                let arm_head = |i: usize| {
+                    let arm: &ast::SelectArm = &arms[i];
+                    format!("{} = {}", arm.var, self.expr(&arm.call, level, false))
+                };
+                let bodies = arms.iter().map(|arm| &arm.body[..]).collect::<Vec<_>>();
+                self.arms("select ".to_string(), arm_head, &bodies, level, stmt)
+            }
+            ast::Expr::Scope(body) => format!("scope {}", self.block(body, level, stmt)),
+            ast::Expr::Try(expr) => format!("{}?", operand(expr)),
+            ast::Expr::Return(expr) => format!("return {}", sub(expr)),
+            ast::Expr::Cast(_, _) | ast::Expr::While(_, _) => {
+                panic!("not a chiika-2 expression: {:?}", e)
+            }
+        }
+    }
+
+    /// Format the arms of `match` or `select`. `arm_head(i)` is the part
+    /// before `=>` of the i-th arm. It is called in the source order so that
+    /// the blocks in it are taken in order
+    fn arms(
+        &self,
+        head: String,
+        arm_head: impl Fn(usize) -> String,
+        bodies: &[&[ast::Expr]],
+        level: usize,
+        stmt: bool,
+    ) -> String {
+        let arms_span = self.next_block();
+        let multiline = stmt || self.has_comments(arms_span);
+        let arm_level = if multiline { level + 1 } else { level };
+        let arms = bodies
+            .iter()
+            .enumerate()
+            .map(|(i, body)| {
+                let arm_head = arm_head(i);
+                let body = match body {
+                    [x] if !is_block_like(x) && !self.has_comments(self.peek_block()) => {
+                        self.next_block();
+                        self.next_item();
+                        self.expr(x, arm_level, false)
+                    }
+                    stmts => self.block(stmts, arm_level, multiline),
+                };
+                format!("{} => {},", arm_head, body)
+            })
+            .collect::<Vec<_>>();
+        if multiline {
+            let indent = INDENT.repeat(level + 1);
+            let lines = arms
+                .iter()
+                .map(|x| format!("{}{}\n", indent, x))
+                .collect::<String>();
+            format!("{}{{\n{}{}}}", head, lines, INDENT.repeat(level))
+        } else {
+            format!("{}{{ {} }}", head, arms.join(" "))
+        }
+    }
+}
+
+fn params(params: &[ast::Param]) -> String {
+    params
+        .iter()
+        .map(|x| format!("{} {}", x.ty, x.name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn attr(a: &ast::Attribute) -> String {
+    if a.args.is_empty() {
+        return format!("#[{}]", a.name);
+    }
+    let args = a
+        .args
+        .iter()
+        .map(|(key, value)| format!("{} = {}", key, value))
+        .collect::<Vec<_>>();
+    format!("#[{}({})]", a.name, args.join(", "))
+}
+
+fn pattern(pat: &ast::Pattern) -> String {
+    match pat {
+        ast::Pattern::Variant(name, variant, vars) if vars.is_empty() => {
+            format!("{}::{}", name, variant)
+        }
+        ast::Pattern::Variant(name, variant, vars) => {
+            format!("{}::{}({})", name, variant, vars.join(", "))
+        }
+        ast::Pattern::Wildcard => "_".to_string(),
+    }
+}
+
+/// Returns true if `e` can be an operand of binary operators or postfixes
+/// without parentheses
+fn is_atomic(e: &ast::Expr) -> bool {
+    matches!(
+        e,
+        ast::Expr::Number(_)
+            | ast::Expr::Str(_)
+            | ast::Expr::VarRef(_)
+            | ast::Expr::FunCall(_, _)
+            | ast::Expr::New(_, _)
+            | ast::Expr::EnumNew(_, _, _)
+            | ast::Expr::ArrayLit(_)
+            | ast::Expr::FieldRef(_, _)
+            | ast::Expr::Index(_, _)
+            | ast::Expr::MethodCall(_, _, _)
+            | ast::Expr::Try(_)
+    )
+}
+
+/// Returns true if `e` has blocks
+fn is_block_like(e: &ast::Expr) -> bool {
+    matches!(
+        e,
+        ast::Expr::If(_, _, _)
+            | ast::Expr::Match(_, _)
+            | ast::Expr::Select(_)
+            | ast::Expr::Scope(_)
+            | ast::Expr::For(_, _, _)
+    )
+}
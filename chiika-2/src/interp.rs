@@ -0,0 +1,1011 @@
+//! Reference interpreter of chiika-2.
+//!
+//! Executes chiika-2 programs directly, without the CPS transformation.
+//! Evaluation is a Rust future run on a tokio runtime, so calls of async
+//! externs simply await and the arms of `select` are raced by polling them
+//! together. The runtime is given by the caller; the tests start its clock
+//! paused (time advances automatically when idle, with the `test-util`
+//! feature of tokio) so that `sleep_sec` does not really wait.
+//! The output must be the same as the compiled program; this is used as
+//! the oracle for the tests of `compiler`.
+//!
+//! `Session` keeps the interpreter alive between evaluations (with the real
+//! clock) and is used by the REPL.
+use crate::ast;
+use anyhow::{anyhow, bail, Context, Result};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::future::{poll_fn, Future};
+use std::pin::{pin, Pin};
+use std::rc::Rc;
+use std::task::Poll;
+use std::time::Duration;
+
+/// Run `chiika_main` of the program on `rt` (a current_thread runtime with
+/// all the drivers) and returns the output
+pub fn run(rt: &tokio::runtime::Runtime, decls: Vec<ast::Declaration>) -> Result<String> {
+    Ok(run_with_status(rt, decls)?.0)
+}
+
+/// Run `chiika_main` of the program and returns the output and the exit
+/// status (the value of `chiika_main` or the argument of `chiika_exit`)
+pub fn run_with_status(
+    rt: &tokio::runtime::Runtime,
+    decls: Vec<ast::Declaration>,
+) -> Result<(String, i64)> {
+    run_with_args(rt, decls, &[])
+}
+
+/// Same as `run_with_status` but the program gets `args` (including the
+/// program name) as the command-line arguments
+pub fn run_with_args(
+    rt: &tokio::runtime::Runtime,
+    decls: Vec<ast::Declaration>,
+    args: &[String],
+) -> Result<(String, i64)> {
+    let mut interp = Interp::new(rt);
+    interp.args = args.to_vec();
+    interp.declare(decls);
+    let interp = Rc::new(interp);
+    let status = match block_on(rt, &interp, interp.call("chiika_main", vec![])).and_then(|x| x) {
+        Ok(v) => v.expect_int()?,
+        Err(e) => match e.downcast_ref::<Exit>() {
+            Some(Exit(code)) => *code,
+            None => return Err(e),
+        },
+    };
+    Ok((interp.output.take(), status))
+}
+
+/// Run `future` (`chiika_main` or the statements given to the REPL) with the
+/// tasks it spawns, like `chiika_start_tokio` of the runtime: the future is
+/// polled first and then the tasks in the order they were spawned, and the
+/// tasks still running are dropped when it finishes. Fails if a task fails
+/// (e.g. calls `chiika_exit`)
+fn block_on<F: Future>(
+    rt: &tokio::runtime::Runtime,
+    interp: &Interp,
+    future: F,
+) -> Result<F::Output> {
+    let mut future = pin!(future);
+    let mut tasks: Vec<(i64, Eval<'static, Result<Value>>)> = vec![];
+    let result = rt.block_on(poll_fn(|ctx| {
+        if let Poll::Ready(v) = future.as_mut().poll(ctx) {
+            return Poll::Ready(Ok(v));
+        }
+        let n_ended = interp.n_ended.get();
+        let mut i = 0;
+        loop {
+            tasks.append(&mut interp.spawned.take());
+            let Some((id, task)) = tasks.get_mut(i) else {
+                break;
+            };
+            if !interp.live.borrow().contains(id) {
+                // Cancelled
+                drop(tasks.remove(i));
+                continue;
+            }
+            let Poll::Ready(result) = task.as_mut().poll(ctx) else {
+                i += 1;
+                continue;
+            };
+            let (id, _) = tasks.remove(i);
+            interp.end_task(id);
+            if let Err(e) = result {
+                return Poll::Ready(Err(e));
+            }
+        }
+        if interp.n_ended.get() != n_ended {
+            // `scope`s polled before may be waiting for the tasks
+            ctx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }));
+    // They refer to `interp`
+    interp.spawned.take();
+    result
+}
+
+/// Interpreter which keeps the declarations and the tokio runtime between
+/// evaluations. Unlike `run`, the output is written to stdout immediately
+/// and `sleep_sec` really sleeps.
+pub struct Session {
+    rt: tokio::runtime::Runtime,
+    interp: Rc<Interp>,
+}
+
+impl Session {
+    pub fn new() -> Result<Session> {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let mut interp = Interp::new(&rt);
+        interp.echo = true;
+        Ok(Session {
+            rt,
+            interp: Rc::new(interp),
+        })
+    }
+
+    /// Add declarations. Existing ones with the same name are replaced
+    pub fn declare(&mut self, decls: Vec<ast::Declaration>) {
+        self.interp.declare(decls);
+    }
+
+    /// Evaluate the statements and returns the value of the last one
+    pub fn eval(&mut self, stmts: &[ast::Expr]) -> Result<String> {
+        let mut lvars = HashMap::new();
+        match block_on(
+            &self.rt,
+            &self.interp,
+            self.interp.eval_stmts(&mut lvars, stmts),
+        )? {
+            Ok(v) | Err(Unwind::Return(v)) => Ok(self.interp.show(&v)),
+            Err(Unwind::Error(e)) => Err(e),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Int(i64),
+    Str(Rc<str>),
+    // Name of the struct and the values of the fields
+    Struct(String, Rc<RefCell<Vec<Value>>>),
+    // Name of the enum, index of the variant and the payloads
+    Enum(String, usize, Rc<Vec<Value>>),
+    // Like chiika_array, items are integers
+    Array(Rc<RefCell<Vec<i64>>>),
+    // Name of a function passed to an extern (e.g. `timeout`)
+    Func(String),
+}
+
+impl Value {
+    fn expect_int(&self) -> Result<i64> {
+        match self {
+            Value::Int(n) => Ok(*n),
+            _ => Err(anyhow!("expected int but got {:?}", self)),
+        }
+    }
+
+    fn expect_array(&self) -> Result<Rc<RefCell<Vec<i64>>>> {
+        match self {
+            Value::Array(items) => Ok(items.clone()),
+            _ => Err(anyhow!("expected array but got {:?}", self)),
+        }
+    }
+}
+
+/// Non-local exit from `eval`
+enum Unwind {
+    Return(Value),
+    Error(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Unwind {
+    fn from(e: anyhow::Error) -> Self {
+        Unwind::Error(e)
+    }
+}
+
+/// Value of `timeout` when the function did not finish in time (same as
+/// the runtime)
+const TIMEOUT_SENTINEL: i64 = -1;
+
+/// Local variable holding the ids of the tasks spawned in the innermost
+/// `scope` (as `Value::Array`). Not a valid identifier of chiika-2
+const SCOPE_VAR: &str = "$scope";
+
+/// Raised by `chiika_exit` to stop the program
+#[derive(Debug)]
+struct Exit(i64);
+
+impl std::fmt::Display for Exit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "program exited with status {}", self.0)
+    }
+}
+
+impl std::error::Error for Exit {}
+
+type EvalResult = std::result::Result<Value, Unwind>;
+
+/// Future of an evaluation (boxed because the evaluation is recursive)
+type Eval<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+/// Shared by the futures of the evaluation, so the state which changes is in
+/// `RefCell`s. They are not borrowed across `await`
+struct Interp {
+    funcs: RefCell<HashMap<String, Rc<ast::Function>>>,
+    externs: RefCell<HashMap<String, ast::Extern>>,
+    structs: RefCell<HashMap<String, ast::Struct>>,
+    enums: RefCell<HashMap<String, ast::Enum>>,
+    output: RefCell<String>,
+    /// Print the output immediately instead of collecting it
+    echo: bool,
+    /// Command-line arguments including the program name
+    args: Vec<String>,
+    /// TCP listeners and connections. Handles are the keys
+    sockets: RefCell<HashMap<i64, Rc<Socket>>>,
+    /// Intervals. Handles are the keys
+    intervals: RefCell<HashMap<i64, Rc<RefCell<Interval>>>>,
+    /// Mutexes and semaphores. Handles are the keys
+    locks: RefCell<HashMap<i64, Rc<Lock>>>,
+    /// Origin of `now_ms`
+    start: tokio::time::Instant,
+    /// Tasks spawned but not given to `block_on` yet, with the ids
+    spawned: RefCell<Vec<(i64, Eval<'static, Result<Value>>)>>,
+    last_task_id: Cell<i64>,
+    /// Ids of the tasks which are not finished nor cancelled
+    live: RefCell<HashSet<i64>>,
+    /// Number of the tasks finished or cancelled so far
+    n_ended: Cell<u64>,
+}
+
+struct Interval {
+    interval: tokio::time::Interval,
+    ticks: i64,
+}
+
+/// Semaphore of `semaphore_new`, or a mutex (which has 1 permit)
+struct Lock {
+    semaphore: tokio::sync::Semaphore,
+    // Permits acquired and not released yet
+    acquired: Cell<i64>,
+}
+
+impl Lock {
+    /// Give back a permit. Returns false if no permit is acquired
+    fn release(&self) -> bool {
+        if self.acquired.get() == 0 {
+            return false;
+        }
+        self.acquired.set(self.acquired.get() - 1);
+        self.semaphore.add_permits(1);
+        true
+    }
+}
+
+enum Socket {
+    Listener(tokio::net::TcpListener),
+    // With the bytes read but not taken by `tcp_received` yet
+    Stream(tokio::net::TcpStream, RefCell<Vec<u8>>),
+}
+
+impl Interp {
+    fn new(rt: &tokio::runtime::Runtime) -> Interp {
+        let interp = Interp {
+            funcs: RefCell::new(HashMap::new()),
+            externs: RefCell::new(HashMap::new()),
+            structs: RefCell::new(HashMap::new()),
+            enums: RefCell::new(HashMap::new()),
+            output: RefCell::new(String::new()),
+            echo: false,
+            args: vec![],
+            sockets: RefCell::new(HashMap::new()),
+            intervals: RefCell::new(HashMap::new()),
+            locks: RefCell::new(HashMap::new()),
+            start: rt.block_on(async { tokio::time::Instant::now() }),
+            spawned: RefCell::new(vec![]),
+            last_task_id: Cell::new(0),
+            live: RefCell::new(HashSet::new()),
+            n_ended: Cell::new(0),
+        };
+        let result_enum = ast::result_enum();
+        interp
+            .enums
+            .borrow_mut()
+            .insert(result_enum.name.clone(), result_enum);
+        interp
+    }
+
+    fn declare(&self, decls: Vec<ast::Declaration>) {
+        for decl in decls {
+            match decl {
+                ast::Declaration::Struct(x) => {
+                    self.structs.borrow_mut().insert(x.name.clone(), x);
+                }
+                ast::Declaration::Enum(x) => {
+                    self.enums.borrow_mut().insert(x.name.clone(), x);
+                }
+                ast::Declaration::Extern(x) => {
+                    self.externs.borrow_mut().insert(x.name.clone(), x);
+                }
+                ast::Declaration::Function(x) => {
+                    self.funcs.borrow_mut().insert(x.name.clone(), Rc::new(x));
+                }
+                // Imports are resolved by `module::load` beforehand
+                ast::Declaration::Comment(_) | ast::Declaration::Import(_) => (),
+            }
+        }
+    }
+
+    /// Format the value in the same syntax as chiika-2 expressions
+    fn show(&self, v: &Value) -> String {
+        let join = |values: &[Value]| {
+            values
+                .iter()
+                .map(|x| self.show(x))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match v {
+            Value::Int(n) => n.to_string(),
+            Value::Str(s) => ast::escape_str(s),
+            Value::Struct(name, values) => format!("new {}({})", name, join(&values.borrow())),
+            Value::Enum(name, idx, values) => {
+                let variant = &self.enums.borrow()[name].variants[*idx].name;
+                if values.is_empty() {
+                    format!("{}::{}", name, variant)
+                } else {
+                    format!("{}::{}({})", name, variant, join(values))
+                }
+            }
+            Value::Array(items) => {
+                let items = items
+                    .borrow()
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>();
+                format!("[{}]", items.join(", "))
+            }
+            Value::Func(name) => name.clone(),
+        }
+    }
+
+    fn call<'a>(self: &'a Rc<Self>, name: &'a str, args: Vec<Value>) -> Eval<'a, Result<Value>> {
+        Box::pin(async move {
+            let func = self.funcs.borrow().get(name).cloned();
+            if let Some(func) = func {
+                if args.len() != func.params.len() {
+                    bail!("wrong number of arguments for {}: {:?}", name, args);
+                }
+                let mut lvars = func
+                    .params
+                    .iter()
+                    .map(|x| x.name.clone())
+                    .zip(args)
+                    .collect::<HashMap<_, _>>();
+                match self.eval_stmts(&mut lvars, &func.body_stmts).await {
+                    Ok(v) | Err(Unwind::Return(v)) => Ok(v),
+                    Err(Unwind::Error(e)) => Err(e),
+                }
+            } else if self.externs.borrow().contains_key(name) {
+                self.call_extern(name, args).await
+            } else {
+                bail!("unknown function: {}", name)
+            }
+        })
+    }
+
+    fn eval_stmts<'a>(
+        self: &'a Rc<Self>,
+        lvars: &'a mut HashMap<String, Value>,
+        stmts: &'a [ast::Expr],
+    ) -> Eval<'a, EvalResult> {
+        Box::pin(async move {
+            let mut v = Value::Int(0);
+            for stmt in stmts {
+                v = self.eval(lvars, stmt).await?;
+            }
+            Ok(v)
+        })
+    }
+
+    fn eval<'a>(
+        self: &'a Rc<Self>,
+        lvars: &'a mut HashMap<String, Value>,
+        expr: &'a ast::Expr,
+    ) -> Eval<'a, EvalResult> {
+        Box::pin(async move {
+            let v = match expr {
+                ast::Expr::Number(n) => Value::Int(*n),
+                ast::Expr::Str(s) => Value::Str(s.as_str().into()),
+                ast::Expr::VarRef(name) => match lvars.get(name) {
+                    Some(v) => v.clone(),
+                    None if self.funcs.borrow().contains_key(name) => Value::Func(name.clone()),
+                    None => return Err(anyhow!("unknown variable `{}'", name).into()),
+                },
+                ast::Expr::OpCall(op, lhs, rhs) => {
+                    let l = self.eval(lvars, lhs).await?.expect_int()?;
+                    let r = self.eval(lvars, rhs).await?.expect_int()?;
+                    Value::Int(match &op[..] {
+                        "+" => l.wrapping_add(r),
+                        "-" => l.wrapping_sub(r),
+                        "==" => (l == r) as i64,
+                        "!=" => (l != r) as i64,
+                        "<" => (l < r) as i64,
+                        "<=" => (l <= r) as i64,
+                        ">" => (l > r) as i64,
+                        ">=" => (l >= r) as i64,
+                        _ => return Err(anyhow!("unknown binop `{}'", op).into()),
+                    })
+                }
+                ast::Expr::FunCall(fexpr, arg_exprs) => {
+                    let ast::Expr::VarRef(name) = &**fexpr else {
+                        return Err(anyhow!("not a function: {:?}", fexpr).into());
+                    };
+                    let args = self.eval_args(lvars, arg_exprs).await?;
+                    let v = self.call(name, args).await?;
+                    if let (true, Some(Value::Array(ids))) = (name == "spawn", lvars.get(SCOPE_VAR))
+                    {
+                        // Register the task to the innermost `scope`
+                        ids.borrow_mut().push(v.expect_int()?);
+                    }
+                    v
+                }
+                ast::Expr::Cast(expr, _) => self.eval(lvars, expr).await?,
+                ast::Expr::Alloc(name, _) => {
+                    lvars.insert(name.clone(), Value::Int(0));
+                    Value::Int(0)
+                }
+                ast::Expr::Assign(name, rhs) => {
+                    let v = self.eval(lvars, rhs).await?;
+                    let Some(var) = lvars.get_mut(name) else {
+                        return Err(anyhow!("unknown variable `{}'", name).into());
+                    };
+                    *var = v;
+                    Value::Int(0)
+                }
+                ast::Expr::New(name, arg_exprs) => {
+                    let n_fields = self
+                        .structs
+                        .borrow()
+                        .get(name)
+                        .with_context(|| format!("unknown struct `{}'", name))?
+                        .fields
+                        .len();
+                    if arg_exprs.len() != n_fields {
+                        return Err(anyhow!("wrong number of fields for {}", name).into());
+                    }
+                    let values = self.eval_args(lvars, arg_exprs).await?;
+                    Value::Struct(name.clone(), Rc::new(RefCell::new(values)))
+                }
+                ast::Expr::FieldRef(obj_expr, field_name) => {
+                    let obj = self.eval(lvars, obj_expr).await?;
+                    let (idx, values) = self.field(&obj, field_name)?;
+                    let v = values.borrow()[idx].clone();
+                    v
+                }
+                ast::Expr::FieldSet(obj_expr, field_name, rhs) => {
+                    let obj = self.eval(lvars, obj_expr).await?;
+                    let (idx, values) = self.field(&obj, field_name)?;
+                    let v = self.eval(lvars, rhs).await?;
+                    values.borrow_mut()[idx] = v;
+                    Value::Int(0)
+                }
+                ast::Expr::ArrayLit(exprs) => {
+                    let mut items = vec![];
+                    for e in exprs {
+                        items.push(self.eval(lvars, e).await?.expect_int()?);
+                    }
+                    Value::Array(Rc::new(RefCell::new(items)))
+                }
+                ast::Expr::Index(arr_expr, idx_expr) => {
+                    let arr = self.eval(lvars, arr_expr).await?.expect_array()?;
+                    let idx = self.eval(lvars, idx_expr).await?.expect_int()?;
+                    let items = arr.borrow();
+                    check_index(&items, idx)?;
+                    Value::Int(items[idx as usize])
+                }
+                ast::Expr::IndexSet(arr_expr, idx_expr, rhs) => {
+                    let arr = self.eval(lvars, arr_expr).await?.expect_array()?;
+                    let idx = self.eval(lvars, idx_expr).await?.expect_int()?;
+                    let v = self.eval(lvars, rhs).await?.expect_int()?;
+                    let mut items = arr.borrow_mut();
+                    check_index(&items, idx)?;
+                    items[idx as usize] = v;
+                    Value::Int(0)
+                }
+                ast::Expr::MethodCall(obj_expr, name, arg_exprs) => {
+                    let arr = self.eval(lvars, obj_expr).await?;
+                    let args = self.eval_args(lvars, arg_exprs).await?;
+                    let items = arr.expect_array()?;
+                    match (&name[..], &args[..]) {
+                        ("push", [item]) => {
+                            items.borrow_mut().push(item.expect_int()?);
+                            arr
+                        }
+                        ("len", []) => Value::Int(items.borrow().len() as i64),
+                        _ => return Err(anyhow!("unknown method `{}'", name).into()),
+                    }
+                }
+                ast::Expr::For(var, arr_expr, body) => {
+                    let arr = self.eval(lvars, arr_expr).await?.expect_array()?;
+                    lvars.insert(var.clone(), Value::Int(0));
+                    let mut i = 0;
+                    // The length is checked every time like the compiled loop
+                    while i < arr.borrow().len() {
+                        let item = arr.borrow()[i];
+                        lvars.insert(var.clone(), Value::Int(item));
+                        self.eval_stmts(lvars, body).await?;
+                        i += 1;
+                    }
+                    Value::Int(0)
+                }
+                ast::Expr::While(cond, body) => {
+                    while self.eval(lvars, cond).await?.expect_int()? != 0 {
+                        self.eval_stmts(lvars, body).await?;
+                    }
+                    Value::Int(0)
+                }
+                ast::Expr::If(cond, then, els) => {
+                    if self.eval(lvars, cond).await?.expect_int()? != 0 {
+                        self.eval_stmts(lvars, then).await?
+                    } else {
+                        self.eval_stmts(lvars, els).await?
+                    }
+                }
+                ast::Expr::EnumNew(name, variant, arg_exprs) => {
+                    let (tag, n_params) = {
+                        let enums = self.enums.borrow();
+                        let enum_def = enums
+                            .get(name)
+                            .with_context(|| format!("unknown enum `{}'", name))?;
+                        let (tag, v) = enum_def.variant(variant).with_context(|| {
+                            format!("enum {} has no variant `{}'", name, variant)
+                        })?;
+                        (tag, v.param_tys.len())
+                    };
+                    if arg_exprs.len() != n_params {
+                        return Err(
+                            anyhow!("wrong number of arguments for {}::{}", name, variant).into(),
+                        );
+                    }
+                    let args = self.eval_args(lvars, arg_exprs).await?;
+                    Value::Enum(name.clone(), tag, Rc::new(args))
+                }
+                ast::Expr::Match(expr, arms) => {
+                    let v = self.eval(lvars, expr).await?;
+                    let Value::Enum(enum_name, tag, payloads) = &v else {
+                        return Err(anyhow!("expected enum but got {:?}", v).into());
+                    };
+                    for arm in arms {
+                        match &arm.pattern {
+                            ast::Pattern::Wildcard => {
+                                return self.eval_stmts(lvars, &arm.body).await
+                            }
+                            ast::Pattern::Variant(name, variant, vars) => {
+                                let (t, _) = self.enums.borrow()[enum_name]
+                                    .variant(variant)
+                                    .with_context(|| {
+                                        format!("unknown variant {}::{}", name, variant)
+                                    })?;
+                                if t != *tag {
+                                    continue;
+                                }
+                                for (var, payload) in vars.iter().zip(payloads.iter()) {
+                                    lvars.insert(var.clone(), payload.clone());
+                                }
+                                return self.eval_stmts(lvars, &arm.body).await;
+                            }
+                        }
+                    }
+                    return Err(anyhow!("no arm matched for {:?}", v).into());
+                }
+                ast::Expr::Scope(body) => {
+                    let ids = Rc::new(RefCell::new(vec![]));
+                    let outer = lvars.insert(SCOPE_VAR.to_string(), Value::Array(ids.clone()));
+                    let result = self.eval_stmts(lvars, body).await;
+                    match outer {
+                        Some(x) => lvars.insert(SCOPE_VAR.to_string(), x),
+                        None => lvars.remove(SCOPE_VAR),
+                    };
+                    let ids = ids.take();
+                    match result {
+                        Ok(v) => {
+                            // Woken by `block_on` when a task ends
+                            poll_fn(|_| {
+                                if ids.iter().any(|id| self.live.borrow().contains(id)) {
+                                    Poll::Pending
+                                } else {
+                                    Poll::Ready(())
+                                }
+                            })
+                            .await;
+                            v
+                        }
+                        Err(Unwind::Return(v)) => {
+                            for id in ids {
+                                self.end_task(id);
+                            }
+                            return Err(Unwind::Return(v));
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                ast::Expr::Select(arms) => {
+                    // The args of all the arms are evaluated first, in order
+                    let mut calls = vec![];
+                    for arm in arms {
+                        let ast::Expr::FunCall(fexpr, arg_exprs) = &arm.call else {
+                            return Err(anyhow!("not a call: {:?}", arm.call).into());
+                        };
+                        let ast::Expr::VarRef(name) = &**fexpr else {
+                            return Err(anyhow!("not a function: {:?}", fexpr).into());
+                        };
+                        calls.push((name, self.eval_args(lvars, arg_exprs).await?));
+                    }
+                    let calls = calls
+                        .into_iter()
+                        .map(|(name, args)| Some(self.call(name, args)))
+                        .collect();
+                    let (i, v) = race(calls).await?;
+                    let arm = &arms[i];
+                    if arm.var != "_" {
+                        lvars.insert(arm.var.clone(), v);
+                    }
+                    return self.eval_stmts(lvars, &arm.body).await;
+                }
+                ast::Expr::Try(expr) => {
+                    let v = self.eval(lvars, expr).await?;
+                    match &v {
+                        Value::Enum(name, 0, payloads) if name == "Result" => payloads[0].clone(),
+                        Value::Enum(name, _, _) if name == "Result" => {
+                            return Err(Unwind::Return(v));
+                        }
+                        _ => return Err(anyhow!("expected Result but got {:?}", v).into()),
+                    }
+                }
+                ast::Expr::Return(expr) => {
+                    let v = self.eval(lvars, expr).await?;
+                    return Err(Unwind::Return(v));
+                }
+            };
+            Ok(v)
+        })
+    }
+
+    async fn eval_args(
+        self: &Rc<Self>,
+        lvars: &mut HashMap<String, Value>,
+        exprs: &[ast::Expr],
+    ) -> std::result::Result<Vec<Value>, Unwind> {
+        let mut values = vec![];
+        for e in exprs {
+            values.push(self.eval(lvars, e).await?);
+        }
+        Ok(values)
+    }
+
+    /// Returns the index of the field and the fields of the struct
+    fn field(&self, obj: &Value, field_name: &str) -> Result<(usize, Rc<RefCell<Vec<Value>>>)> {
+        let Value::Struct(struct_name, values) = obj else {
+            bail!("expected struct but got {:?}", obj);
+        };
+        let idx = self.structs.borrow()[struct_name]
+            .fields
+            .iter()
+            .position(|x| x.name == field_name)
+            .with_context(|| format!("struct {} has no field `{}'", struct_name, field_name))?;
+        Ok((idx, values.clone()))
+    }
+
+    fn write(&self, s: &str) {
+        if self.echo {
+            print!("{}", s);
+        } else {
+            self.output.borrow_mut().push_str(s);
+        }
+    }
+
+    /// Start running `f(arg)` concurrently and returns the id of the task.
+    /// Like the runtime, `f` is called after the current task waits
+    fn spawn(self: &Rc<Self>, f: &str, arg: Value) -> i64 {
+        let id = self.last_task_id.get() + 1;
+        self.last_task_id.set(id);
+        let interp = Rc::clone(self);
+        let f = f.to_string();
+        let task = Box::pin(async move { interp.call(&f, vec![arg]).await });
+        self.spawned.borrow_mut().push((id, task));
+        self.live.borrow_mut().insert(id);
+        id
+    }
+
+    /// Called when the task is finished or cancelled (the future of a
+    /// cancelled one is dropped by `block_on`). Returns false if it was
+    /// already
+    fn end_task(&self, id: i64) -> bool {
+        if !self.live.borrow_mut().remove(&id) {
+            return false;
+        }
+        self.n_ended.set(self.n_ended.get() + 1);
+        true
+    }
+
+    fn new_socket(&self, socket: Socket) -> i64 {
+        let mut sockets = self.sockets.borrow_mut();
+        // Start from 1 like the pointers of the compiled program
+        let handle = sockets.keys().max().map_or(1, |x| x + 1);
+        sockets.insert(handle, Rc::new(socket));
+        handle
+    }
+
+    fn socket(&self, handle: i64) -> Option<Rc<Socket>> {
+        self.sockets.borrow().get(&handle).cloned()
+    }
+
+    fn new_lock(&self, permits: i64) -> i64 {
+        let lock = Lock {
+            semaphore: tokio::sync::Semaphore::new(permits.max(0) as usize),
+            acquired: Cell::new(0),
+        };
+        let mut locks = self.locks.borrow_mut();
+        let handle = locks.keys().max().map_or(1, |x| x + 1);
+        locks.insert(handle, Rc::new(lock));
+        handle
+    }
+
+    fn lock(&self, handle: i64) -> Result<Rc<Lock>> {
+        self.locks
+            .borrow()
+            .get(&handle)
+            .cloned()
+            .with_context(|| format!("expected mutex or semaphore but got {}", handle))
+    }
+
+    fn call_extern<'a>(
+        self: &'a Rc<Self>,
+        name: &'a str,
+        args: Vec<Value>,
+    ) -> Eval<'a, Result<Value>> {
+        Box::pin(async move {
+            let v = match (name, &args[..]) {
+                ("print", [Value::Int(n)]) => {
+                    self.write(&format!("{}\n", n));
+                    Value::Int(0)
+                }
+                ("print_str", [Value::Str(s)]) => {
+                    self.write(&format!("{}\n", s));
+                    Value::Int(0)
+                }
+                ("sleep_sec", [Value::Int(n)]) => {
+                    tokio::time::sleep(Duration::from_secs(*n as u64)).await;
+                    Value::Int(*n)
+                }
+                ("sleep_ms", [Value::Int(n)]) => {
+                    tokio::time::sleep(Duration::from_millis((*n).max(0) as u64)).await;
+                    Value::Int(*n)
+                }
+                ("now_ms", []) => {
+                    Value::Int((tokio::time::Instant::now() - self.start).as_millis() as i64)
+                }
+                // `f` is dropped (i.e. cancelled) if it does not finish in time
+                ("timeout", [Value::Int(ms), Value::Func(f)]) => {
+                    let d = Duration::from_millis((*ms).max(0) as u64);
+                    match tokio::time::timeout(d, self.call(f, vec![])).await {
+                        Ok(v) => v?,
+                        Err(_) => Value::Int(TIMEOUT_SENTINEL),
+                    }
+                }
+                ("interval_new", [Value::Int(ms)]) => {
+                    let period = Duration::from_millis((*ms).max(1) as u64);
+                    let interval = tokio::time::interval(period);
+                    let mut intervals = self.intervals.borrow_mut();
+                    let handle = intervals.keys().max().map_or(1, |x| x + 1);
+                    intervals.insert(
+                        handle,
+                        Rc::new(RefCell::new(Interval { interval, ticks: 0 })),
+                    );
+                    Value::Int(handle)
+                }
+                ("interval_tick", [Value::Int(handle)]) => {
+                    let Some(interval) = self.intervals.borrow().get(handle).cloned() else {
+                        bail!("expected interval but got {}", handle);
+                    };
+                    poll_fn(|ctx| interval.borrow_mut().interval.poll_tick(ctx)).await;
+                    let mut interval = interval.borrow_mut();
+                    interval.ticks += 1;
+                    Value::Int(interval.ticks)
+                }
+                ("interval_free", [Value::Int(handle)]) => {
+                    if self.intervals.borrow_mut().remove(handle).is_none() {
+                        bail!("expected interval but got {}", handle);
+                    }
+                    Value::Int(0)
+                }
+                ("arg_count", []) => Value::Int(self.args.len() as i64),
+                ("arg", [Value::Int(n)]) => {
+                    let Some(arg) = usize::try_from(*n).ok().and_then(|i| self.args.get(i)) else {
+                        bail!(
+                            "argument index out of bounds: the count is {} but the index is {}",
+                            self.args.len(),
+                            n
+                        );
+                    };
+                    Value::Str(arg.as_str().into())
+                }
+                ("env_var", [Value::Str(name)]) => {
+                    Value::Str(std::env::var(name.as_ref()).unwrap_or_default().into())
+                }
+                ("tcp_listen", [Value::Str(host), Value::Int(port)]) => {
+                    let addr = format!("{}:{}", host, port);
+                    let result = tokio::net::TcpListener::bind(&addr)
+                        .await
+                        .map(|x| self.new_socket(Socket::Listener(x)))
+                        .map_err(|e| format!("{}: {}", addr, e));
+                    result_value(result)
+                }
+                ("tcp_local_port", [Value::Int(handle)]) => {
+                    let socket = self.socket(*handle);
+                    let Some(Socket::Listener(listener)) = socket.as_deref() else {
+                        bail!("expected TCP listener but got {}", handle);
+                    };
+                    Value::Int(listener.local_addr().map_or(0, |x| x.port() as i64))
+                }
+                ("tcp_accept", [Value::Int(handle)]) => {
+                    let socket = self.socket(*handle);
+                    let Some(Socket::Listener(listener)) = socket.as_deref() else {
+                        bail!("expected TCP listener but got {}", handle);
+                    };
+                    let result = listener
+                        .accept()
+                        .await
+                        .map(|(x, _)| self.new_socket(Socket::Stream(x, RefCell::new(vec![]))))
+                        .map_err(|e| e.to_string());
+                    result_value(result)
+                }
+                ("tcp_connect", [Value::Str(host), Value::Int(port)]) => {
+                    let addr = format!("{}:{}", host, port);
+                    let result = tokio::net::TcpStream::connect(&addr)
+                        .await
+                        .map(|x| self.new_socket(Socket::Stream(x, RefCell::new(vec![]))))
+                        .map_err(|e| format!("{}: {}", addr, e));
+                    result_value(result)
+                }
+                ("tcp_read", [Value::Int(handle), Value::Int(max_len)]) => {
+                    let socket = self.socket(*handle);
+                    let Some(Socket::Stream(stream, received)) = socket.as_deref() else {
+                        bail!("expected TCP connection but got {}", handle);
+                    };
+                    let mut buf = vec![0; (*max_len).max(0) as usize];
+                    let result = read_some(stream, &mut buf).await.map_err(|e| e.to_string());
+                    if let Ok(n) = result {
+                        received.borrow_mut().extend_from_slice(&buf[..n]);
+                    }
+                    result_value(result.map(|n| n as i64))
+                }
+                ("tcp_received", [Value::Int(handle)]) => {
+                    let socket = self.socket(*handle);
+                    let Some(Socket::Stream(_, received)) = socket.as_deref() else {
+                        bail!("expected TCP connection but got {}", handle);
+                    };
+                    let bytes = received.take();
+                    Value::Str(String::from_utf8_lossy(&bytes).as_ref().into())
+                }
+                ("tcp_write", [Value::Int(handle), Value::Str(data)]) => {
+                    let socket = self.socket(*handle);
+                    let Some(Socket::Stream(stream, _)) = socket.as_deref() else {
+                        bail!("expected TCP connection but got {}", handle);
+                    };
+                    let result = write_all(stream, data.as_bytes())
+                        .await
+                        .map(|_| data.len() as i64)
+                        .map_err(|e| e.to_string());
+                    result_value(result)
+                }
+                ("tcp_close", [Value::Int(handle)]) => {
+                    if self.sockets.borrow_mut().remove(handle).is_none() {
+                        bail!("expected TCP listener or connection but got {}", handle);
+                    }
+                    Value::Int(0)
+                }
+                ("mutex_new", []) => Value::Int(self.new_lock(1)),
+                ("semaphore_new", [Value::Int(permits)]) => Value::Int(self.new_lock(*permits)),
+                ("mutex_lock" | "semaphore_acquire", [Value::Int(handle)]) => {
+                    let lock = self.lock(*handle)?;
+                    // The semaphore is never closed. The permit is given back
+                    // by `mutex_unlock` or `semaphore_release`
+                    lock.semaphore.acquire().await?.forget();
+                    lock.acquired.set(lock.acquired.get() + 1);
+                    Value::Int(0)
+                }
+                ("mutex_unlock" | "semaphore_release", [Value::Int(handle)]) => {
+                    Value::Int(self.lock(*handle)?.release() as i64)
+                }
+                // The locks already waiting hold the semaphore
+                ("mutex_free" | "semaphore_free", [Value::Int(handle)]) => {
+                    let lock = self.lock(*handle)?;
+                    while lock.release() {}
+                    self.locks.borrow_mut().remove(handle);
+                    Value::Int(0)
+                }
+                ("spawn", [Value::Func(f), arg]) => Value::Int(self.spawn(f, arg.clone())),
+                ("cancel", [Value::Int(id)]) => Value::Int(self.end_task(*id) as i64),
+                ("chiika_exit", [Value::Int(code)]) => return Err(Exit(*code).into()),
+                ("read_int", [Value::Str(path)]) => {
+                    let result = tokio::fs::read_to_string(path.as_ref())
+                        .await
+                        .map_err(|e| format!("{}: {}", path, e))
+                        .and_then(|s| s.trim().parse().map_err(|e| format!("{}: {}", path, e)));
+                    result_value(result)
+                }
+                _ => bail!(
+                    "extern `{}' is not supported by the interpreter (args: {:?})",
+                    name,
+                    args
+                ),
+            };
+            Ok(v)
+        })
+    }
+}
+
+/// Race the calls of the arms of `select` and returns the index and the
+/// value of the one which finishes first. The others are dropped. Like the
+/// runtime, where all the arms are called before any of them is awaited,
+/// each call is polled once even if an earlier one finishes at once
+async fn race(mut calls: Vec<Option<Eval<'_, Result<Value>>>>) -> Result<(usize, Value)> {
+    let mut started = false;
+    poll_fn(|ctx| {
+        let mut winner = None;
+        for (i, slot) in calls.iter_mut().enumerate() {
+            if started && winner.is_some() {
+                break;
+            }
+            let Some(call) = slot else {
+                continue;
+            };
+            if let Poll::Ready(result) = call.as_mut().poll(ctx) {
+                *slot = None;
+                match result {
+                    Ok(v) if winner.is_none() => winner = Some((i, v)),
+                    Ok(_) => (),
+                    Err(e) => return Poll::Ready(Err(e)),
+                }
+            }
+        }
+        started = true;
+        winner.map_or(Poll::Pending, |x| Poll::Ready(Ok(x)))
+    })
+    .await
+}
+
+/// Read at most `buf.len()` bytes. Only `&TcpStream` is needed, so the
+/// connection can be used by other calls meanwhile
+async fn read_some(stream: &tokio::net::TcpStream, buf: &mut [u8]) -> std::io::Result<usize> {
+    loop {
+        stream.readable().await?;
+        match stream.try_read(buf) {
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Write all of `data` (like `read_some`, with `&TcpStream`)
+async fn write_all(stream: &tokio::net::TcpStream, mut data: &[u8]) -> std::io::Result<()> {
+    while !data.is_empty() {
+        stream.writable().await?;
+        match stream.try_write(data) {
+            Ok(n) => data = &data[n..],
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Value of the built-in `Result`
+fn result_value(result: std::result::Result<i64, String>) -> Value {
+    match result {
+        Ok(n) => Value::Enum("Result".to_string(), 0, Rc::new(vec![Value::Int(n)])),
+        Err(msg) => Value::Enum(
+            "Result".to_string(),
+            1,
+            Rc::new(vec![Value::Str(msg.into())]),
+        ),
+    }
+}
+
+/// Same check as chiika_array.rs of the runtime
+fn check_index(items: &[i64], idx: i64) -> Result<()> {
+    if idx < 0 || idx as usize >= items.len() {
+        bail!(
+            "array index out of bounds: the len is {} but the index is {}",
+            items.len(),
+            idx
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+//! JIT for the REPL (with the `llvm` feature).
+//!
+//! Each input is compiled together with the declarations so far into
+//! chiika-1, then into an LLVM module by the codegen of chiika-1, which is
+//! run by an execution engine. chiika_runtime is linked into this binary; its
+//! functions are given to the engine by address, and the inputs run on the
+//! tokio runtime of `chiika_runtime::repl::Session`, kept for the whole
+//! session.
+use crate::ast;
+use anyhow::{anyhow, Result};
+use chumsky::Parser;
+use std::collections::HashMap;
+
+pub struct Jit {
+    context: inkwell::context::Context,
+    session: chiika_runtime::repl::Session,
+    symbols: HashMap<&'static str, usize>,
+}
+
+impl Jit {
+    pub fn new() -> Result<Jit> {
+        inkwell::targets::Target::initialize_native(&Default::default()).map_err(|e| anyhow!(e))?;
+        Ok(Jit {
+            context: inkwell::context::Context::create(),
+            session: chiika_runtime::repl::Session::new().map_err(|e| anyhow!(e))?,
+            symbols: chiika_runtime::repl::symbols().into_iter().collect(),
+        })
+    }
+
+    /// Compile the program and run `entry` (a function without params which
+    /// returns an int). Returns its value
+    pub fn run(&self, decls: Vec<ast::Declaration>, entry: &str) -> Result<i64> {
+        // The engine aborts the process on unresolved symbols
+        for decl in &decls {
+            if let ast::Declaration::Extern(x) = decl {
+                if !self.symbols.contains_key(x.name.as_str()) {
+                    return Err(anyhow!("extern `{}' is not in chiika_runtime", x.name));
+                }
+            }
+        }
+        let src = crate::repl_to_chiika1(decls, entry)?;
+        let chiika1 = chiika::parser::parser()
+            .parse(src)
+            .map_err(|errs| anyhow!("failed to parse the chiika-1 output: {:?}", errs))?;
+        let module = chiika::codegen::gen_module(&self.context, chiika1, false)?;
+        let engine = module
+            .create_jit_execution_engine(inkwell::OptimizationLevel::None)
+            .map_err(|e| anyhow!("{}", e.to_string()))?;
+        for (name, addr) in &self.symbols {
+            if let Some(f) = module.get_function(name) {
+                engine.add_global_mapping(&f, *addr);
+            }
+        }
+        let start =
+            unsafe { engine.get_function::<chiika_runtime::StartUser>("chiika_start_user") }
+                .map_err(|e| anyhow!("{}", e))?;
+        // The compiled code is freed with `engine` after the input finishes
+        Ok(self.session.run(unsafe { start.as_raw() }))
+    }
+}
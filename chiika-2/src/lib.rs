@@ -0,0 +1,128 @@
+pub mod ast;
+pub mod asyncness_check;
+pub mod compiler;
+pub mod explain;
+pub mod formatter;
+pub mod interp;
+#[cfg(feature = "llvm")]
+mod jit;
+pub mod lsp;
+pub mod module;
+pub mod parser;
+pub mod repl;
+use anyhow::{bail, Context, Result};
+use ariadne::{Label, Report, ReportKind, Source};
+use chumsky::Parser;
+
+pub(crate) fn render_parse_error(src: &str, span: std::ops::Range<usize>, msg: String) -> String {
+    let mut rendered = vec![];
+    Report::build(ReportKind::Error, "", span.start)
+        .with_message(msg.clone())
+        .with_label(Label::new(("", span)).with_message(msg))
+        .finish()
+        .write(("", Source::from(src)), &mut rendered)
+        .unwrap();
+    String::from_utf8_lossy(&rendered).to_string()
+}
+
+/// Parse chiika-2 source
+pub fn parse(src: &str) -> Result<Vec<ast::Declaration>> {
+    match parser::parser().parse(src) {
+        Ok(x) => Ok(x),
+        Err(errs) => {
+            let mut s = String::new();
+            errs.into_iter().for_each(|e| {
+                s += &render_parse_error(src, e.span(), e.to_string());
+            });
+            bail!(s);
+        }
+    }
+}
+
+/// Compile chiika-2 source into chiika-1 source
+pub fn compile_to_chiika1(src: &str) -> Result<String> {
+    decls_to_chiika1(parse(src)?)
+}
+
+/// Compile a chiika-2 file (and the modules it imports) into chiika-1 source
+pub fn compile_file_to_chiika1(path: &std::path::Path) -> Result<String> {
+    decls_to_chiika1(module::load(path)?)
+}
+
+fn decls_to_chiika1(ast: Vec<ast::Declaration>) -> Result<String> {
+    let configure_tokio = compiler::tokio_config(&ast)?
+        .into_iter()
+        .map(|(key, value)| {
+            format!(
+                "chiika_tokio_config({}, {});\n  ",
+                ast::escape_str(&key),
+                ast::escape_str(&value)
+            )
+        })
+        .collect::<String>();
+    let main = format!(
+        "func main(int argc, $any argv) -> int {{
+  chiika_set_args(argc, argv);
+  {}chiika_start_tokio(0)
+}}
+",
+        configure_tokio
+    );
+    to_chiika1(ast, "chiika_main", &main)
+}
+
+/// Compile the declarations of the REPL. `chiika_start_user` calls `entry`
+/// and `main` is not generated, as the JIT calls `chiika_start_user`
+/// directly
+#[cfg(feature = "llvm")]
+pub(crate) fn repl_to_chiika1(ast: Vec<ast::Declaration>, entry: &str) -> Result<String> {
+    to_chiika1(ast, entry, "")
+}
+
+/// Compile the declarations into chiika-1 with the prelude, followed by
+/// `chiika_start_user` which calls `entry` (a function without params) and
+/// `main` (chiika-1 source)
+fn to_chiika1(ast: Vec<ast::Declaration>, entry: &str, main: &str) -> Result<String> {
+    // Errors of the compiler come first
+    let sigs = asyncness_check::gather_sigs(&ast);
+    let (compiled, _) = compiler::compile(ast)?;
+    let entry_is_async = sigs?
+        .get(entry)
+        .with_context(|| format!("must define `{}'", entry))?
+        .is_async;
+    let call_entry = if entry_is_async {
+        format!("{}($env, $cont)", entry)
+    } else {
+        format!("$cont($env, {}())", entry)
+    };
+    let prelude = format!(
+        "
+extern chiika_env_push($ENV $env, $any obj) -> int;
+extern chiika_env_pop($ENV $env, int n) -> $any;
+extern chiika_env_ref($ENV $env, int n) -> $any;
+extern chiika_env_set($ENV $env, int n, $any obj) -> int;
+extern chiika_set_args(int argc, $any argv) -> int;
+extern chiika_tokio_config(string key, string value) -> int;
+extern chiika_start_tokio(int n) -> int;
+extern chiika_array_new() -> array;
+extern chiika_array_push(array arr, int item) -> array;
+extern chiika_array_get(array arr, int idx) -> int;
+extern chiika_array_set(array arr, int idx, int item) -> int;
+extern chiika_array_len(array arr) -> int;
+extern chiika_select_new() -> $any;
+extern chiika_select_env($any state) -> $ENV;
+extern chiika_select_done($ENV $env, $any value) -> $FUTURE;
+extern chiika_select_arm($any state, $FUTURE future, $FN(($ENV, $any) -> $FUTURE) cont) -> int;
+extern chiika_select($ENV $env, $any state) -> $FUTURE;
+extern chiika_scope_new() -> $any;
+extern chiika_scope_add($any scope, int task) -> int;
+extern chiika_scope_wait($ENV $env, $FN(($ENV, int) -> $FUTURE) $cont, $any scope) -> $FUTURE;
+extern chiika_scope_cancel($any scope) -> int;
+func chiika_start_user($ENV $env, $FN(($ENV, $any) -> $FUTURE) $cont) -> $FUTURE {{
+    {}
+}}
+{}",
+        call_entry, main
+    );
+    Ok(format!("{}\n{}\n", prelude, ast::to_source(compiled)))
+}
@@ -0,0 +1,359 @@
+//! Language server of chiika-2 (`chiika-2 lsp`)
+//!
+//! Supports diagnostics, hover (signature and asyncness of functions),
+//! go to definition of functions and externs and code lenses showing the
+//! number of chapters each function is split into.
+use crate::{ast, asyncness_check, compiler, module, parser};
+use anyhow::Result;
+use chumsky::Parser;
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _,
+    PublishDiagnostics,
+};
+use lsp_types::request::{CodeLensRequest, GotoDefinition, HoverRequest, Request as _};
+use lsp_types::{
+    CodeLens, CodeLensOptions, CodeLensParams, Command, Diagnostic, DiagnosticSeverity,
+    GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams,
+    HoverProviderCapability, Location, MarkupContent, MarkupKind, OneOf, Position,
+    PublishDiagnosticsParams, Range, ServerCapabilities, TextDocumentSyncCapability,
+    TextDocumentSyncKind, Url,
+};
+use std::collections::HashMap;
+
+/// Result of analyzing a document
+pub struct Analysis {
+    chars: Vec<char>,
+    /// Declarations and their spans. Empty if the document has syntax errors
+    decls: Vec<(ast::Declaration, std::ops::Range<usize>)>,
+    sigs: HashMap<String, ast::FunTy>,
+    chapters: HashMap<String, usize>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Analysis {
+    pub fn new(src: &str) -> Analysis {
+        Analysis::for_file(src, None)
+    }
+
+    /// `path` is needed to resolve imports
+    pub fn for_file(src: &str, path: Option<&std::path::Path>) -> Analysis {
+        let mut a = Analysis {
+            chars: src.chars().collect(),
+            decls: vec![],
+            sigs: HashMap::new(),
+            chapters: HashMap::new(),
+            diagnostics: vec![],
+        };
+        match parser::parser_with_spans().parse(src) {
+            Ok(decls) => a.decls = decls,
+            Err(errs) => {
+                for e in errs {
+                    let range = a.range(e.span());
+                    a.diagnostics.push(a.error(range, e.to_string()));
+                }
+                return a;
+            }
+        }
+        let mut ast = a.decls.iter().map(|(x, _)| x.clone()).collect::<Vec<_>>();
+        // Errors of the compiler have no location
+        let top = Range::default();
+        if let Some(path) = path {
+            match module::resolve(path, ast) {
+                Ok(x) => ast = x,
+                Err(e) => {
+                    a.diagnostics.push(a.error(top, format!("{:#}", e)));
+                    return a;
+                }
+            }
+        }
+        match asyncness_check::gather_sigs(&ast) {
+            Ok(sigs) => a.sigs = sigs,
+            Err(e) => {
+                a.diagnostics.push(a.error(top, e.to_string()));
+                return a;
+            }
+        }
+        match compiler::count_chapters(&ast) {
+            Ok(chapters) => a.chapters = chapters,
+            Err(e) => a.diagnostics.push(a.error(top, e.to_string())),
+        }
+        if a.diagnostics.is_empty() {
+            if let Err(e) = compiler::compile(ast) {
+                a.diagnostics.push(a.error(top, e.to_string()));
+            }
+        }
+        a
+    }
+
+    fn error(&self, range: Range, message: String) -> Diagnostic {
+        Diagnostic {
+            range,
+            severity: Some(DiagnosticSeverity::ERROR),
+            source: Some("chiika-2".to_string()),
+            message,
+            ..Default::default()
+        }
+    }
+
+    /// Markdown shown when hovering at `pos`
+    pub fn hover(&self, pos: Position) -> Option<String> {
+        let name = self.word_at(pos)?;
+        let (decl, _) = self.find_decl(&name)?;
+        let sig = self.sigs.get(&name)?;
+        let (head, params, ret_ty) = match decl {
+            ast::Declaration::Extern(x) if x.is_async => ("extern_async", &x.params, &x.ret_ty),
+            ast::Declaration::Extern(x) => ("extern", &x.params, &x.ret_ty),
+            ast::Declaration::Function(x) => ("fun", &x.params, &x.ret_ty),
+            _ => return None,
+        };
+        let params = params
+            .iter()
+            .map(|x| format!("{} {}", x.ty, x.name))
+            .collect::<Vec<_>>();
+        let asyncness = match self.chapters.get(&name) {
+            Some(n) if sig.is_async => format!("async ({} chapters)", n),
+            _ if sig.is_async => "async".to_string(),
+            _ => "sync".to_string(),
+        };
+        Some(format!(
+            "```\n{} {}({}) -> {}\n```\n{}",
+            head,
+            name,
+            params.join(", "),
+            ret_ty,
+            asyncness
+        ))
+    }
+
+    /// Location of the function or extern referred at `pos`
+    pub fn definition(&self, pos: Position) -> Option<Range> {
+        let name = self.word_at(pos)?;
+        let (_, span) = self.find_decl(&name)?;
+        Some(self.range(span.clone()))
+    }
+
+    /// Number of chapters shown above each function
+    pub fn code_lenses(&self) -> Vec<CodeLens> {
+        self.decls
+            .iter()
+            .filter_map(|(decl, span)| {
+                let ast::Declaration::Function(x) = decl else {
+                    return None;
+                };
+                let n = self.chapters.get(&x.name)?;
+                let title = if *n == 1 {
+                    "1 chapter".to_string()
+                } else {
+                    format!("{} chapters", n)
+                };
+                let start = self.position(span.start);
+                Some(CodeLens {
+                    range: Range::new(start, start),
+                    command: Some(Command::new(title, String::new(), None)),
+                    data: None,
+                })
+            })
+            .collect()
+    }
+
+    fn find_decl(&self, name: &str) -> Option<&(ast::Declaration, std::ops::Range<usize>)> {
+        self.decls.iter().find(|(decl, _)| match decl {
+            ast::Declaration::Extern(x) => x.name == name,
+            ast::Declaration::Function(x) => x.name == name,
+            _ => false,
+        })
+    }
+
+    /// Returns the identifier at (or just before) `pos`
+    fn word_at(&self, pos: Position) -> Option<String> {
+        let is_ident = |c: &char| c.is_alphanumeric() || *c == '_';
+        let mut offset = self.offset(pos);
+        if !self.chars.get(offset).is_some_and(is_ident) {
+            offset = offset.checked_sub(1)?;
+            if !self.chars.get(offset).is_some_and(is_ident) {
+                return None;
+            }
+        }
+        let start = self.chars[..offset]
+            .iter()
+            .rposition(|c| !is_ident(c))
+            .map_or(0, |i| i + 1);
+        let end = self.chars[offset..]
+            .iter()
+            .position(|c| !is_ident(c))
+            .map_or(self.chars.len(), |i| offset + i);
+        Some(self.chars[start..end].iter().collect())
+    }
+
+    fn range(&self, span: std::ops::Range<usize>) -> Range {
+        Range::new(self.position(span.start), self.position(span.end))
+    }
+
+    /// Convert char offset to LSP position (of which `character` is counted
+    /// in UTF-16)
+    fn position(&self, offset: usize) -> Position {
+        let mut line = 0;
+        let mut character = 0;
+        for c in self.chars.iter().take(offset) {
+            if *c == '\n' {
+                line += 1;
+                character = 0;
+            } else {
+                character += c.len_utf16() as u32;
+            }
+        }
+        Position::new(line, character)
+    }
+
+    fn offset(&self, pos: Position) -> usize {
+        let mut line = 0;
+        let mut character = 0;
+        for (i, c) in self.chars.iter().enumerate() {
+            if line == pos.line && character >= pos.character {
+                return i;
+            }
+            if *c == '\n' {
+                if line == pos.line {
+                    return i;
+                }
+                line += 1;
+                character = 0;
+            } else {
+                character += c.len_utf16() as u32;
+            }
+        }
+        self.chars.len()
+    }
+}
+
+/// Run the language server on stdio
+pub fn run() -> Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        code_lens_provider: Some(CodeLensOptions {
+            resolve_provider: Some(false),
+        }),
+        ..Default::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+
+    let mut docs = HashMap::new();
+    for msg in &connection.receiver {
+        match msg {
+            Message::Request(req) => {
+                if connection.handle_shutdown(&req)? {
+                    break;
+                }
+                let resp = handle_request(&docs, req);
+                connection.sender.send(Message::Response(resp))?;
+            }
+            Message::Notification(not) => {
+                if let Some(diags) = handle_notification(&mut docs, not)? {
+                    connection.sender.send(Message::Notification(diags))?;
+                }
+            }
+            Message::Response(_) => (),
+        }
+    }
+    io_threads.join()?;
+    Ok(())
+}
+
+/// Update `docs` and returns the diagnostics to publish, if any
+fn handle_notification(
+    docs: &mut HashMap<Url, Analysis>,
+    not: Notification,
+) -> Result<Option<Notification>> {
+    let (uri, analysis) = match &not.method[..] {
+        DidOpenTextDocument::METHOD => {
+            let params: lsp_types::DidOpenTextDocumentParams = serde_json::from_value(not.params)?;
+            let doc = params.text_document;
+            let analysis = Analysis::for_file(&doc.text, doc.uri.to_file_path().ok().as_deref());
+            (doc.uri, Some(analysis))
+        }
+        DidChangeTextDocument::METHOD => {
+            let params: lsp_types::DidChangeTextDocumentParams =
+                serde_json::from_value(not.params)?;
+            // Full sync: the last change has the whole text
+            let Some(change) = params.content_changes.last() else {
+                return Ok(None);
+            };
+            let uri = params.text_document.uri;
+            let analysis = Analysis::for_file(&change.text, uri.to_file_path().ok().as_deref());
+            (uri, Some(analysis))
+        }
+        DidCloseTextDocument::METHOD => {
+            let params: lsp_types::DidCloseTextDocumentParams = serde_json::from_value(not.params)?;
+            (params.text_document.uri, None)
+        }
+        _ => return Ok(None),
+    };
+    let diagnostics = analysis.as_ref().map_or(vec![], |x| x.diagnostics.clone());
+    match analysis {
+        Some(x) => docs.insert(uri.clone(), x),
+        None => docs.remove(&uri),
+    };
+    let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+    Ok(Some(Notification::new(
+        PublishDiagnostics::METHOD.to_string(),
+        params,
+    )))
+}
+
+fn handle_request(docs: &HashMap<Url, Analysis>, req: Request) -> Response {
+    let id = req.id.clone();
+    let result = match &req.method[..] {
+        HoverRequest::METHOD => serde_json::from_value::<HoverParams>(req.params).map(|params| {
+            let doc = params.text_document_position_params;
+            let hover = docs.get(&doc.text_document.uri).and_then(|a| {
+                a.hover(doc.position).map(|value| Hover {
+                    contents: HoverContents::Markup(MarkupContent {
+                        kind: MarkupKind::Markdown,
+                        value,
+                    }),
+                    range: None,
+                })
+            });
+            serde_json::to_value(hover)
+        }),
+        GotoDefinition::METHOD => {
+            serde_json::from_value::<GotoDefinitionParams>(req.params).map(|params| {
+                let doc = params.text_document_position_params;
+                let uri = doc.text_document.uri;
+                let location = docs.get(&uri).and_then(|a| {
+                    a.definition(doc.position).map(|range| {
+                        GotoDefinitionResponse::Scalar(Location::new(uri.clone(), range))
+                    })
+                });
+                serde_json::to_value(location)
+            })
+        }
+        CodeLensRequest::METHOD => {
+            serde_json::from_value::<CodeLensParams>(req.params).map(|params| {
+                let lenses = docs.get(&params.text_document.uri).map(|a| a.code_lenses());
+                serde_json::to_value(lenses)
+            })
+        }
+        _ => {
+            return Response::new_err(
+                id,
+                ErrorCode::MethodNotFound as i32,
+                format!("unsupported method: {}", req.method),
+            )
+        }
+    };
+    match result {
+        Ok(Ok(value)) => Response {
+            id,
+            result: Some(value),
+            error: None,
+        },
+        Ok(Err(e)) | Err(e) => {
+            Response::new_err(id, ErrorCode::InvalidParams as i32, e.to_string())
+        }
+    }
+}
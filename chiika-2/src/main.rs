@@ -1,62 +1,90 @@
-mod ast;
-mod asyncness_check;
-mod compiler;
-mod parser;
 use anyhow::{bail, Context, Result};
-use ariadne::{Label, Report, ReportKind, Source};
-use chumsky::Parser;
-use parser::parser;
-
-fn render_parse_error(src: &str, span: std::ops::Range<usize>, msg: String) -> String {
-    let mut rendered = vec![];
-    Report::build(ReportKind::Error, "", span.start)
-        .with_message(msg.clone())
-        .with_label(Label::new(("", span)).with_message(msg))
-        .finish()
-        .write(("", Source::from(src)), &mut rendered)
-        .unwrap();
-    String::from_utf8_lossy(&rendered).to_string()
-}
+use std::io::{BufRead, Write};
 
 fn main() -> Result<()> {
     let args = std::env::args().collect::<Vec<_>>();
+    if args.get(1).is_some_and(|x| x == "fmt") {
+        return fmt(&args[2..]);
+    }
+    if args.get(1).is_some_and(|x| x == "lsp") {
+        return chiika_2::lsp::run();
+    }
+    if args.get(1).is_some_and(|x| x == "repl") {
+        return repl();
+    }
+    if args.get(1).is_some_and(|x| x == "--explain") {
+        let [_, _, name, path] = &args[..] else {
+            bail!("usage: chiika-2 --explain <fn> a.chiika2");
+        };
+        let path = std::path::Path::new(path);
+        print!("{}", chiika_2::explain::explain_file(path, name)?);
+        return Ok(());
+    }
     let Some(path) = args.get(1) else {
-        bail!("usage: chiika-2 a.chiika2 > a.chiika1");
-    };
-    let src = std::fs::read_to_string(path).context(format!("failed to read {}", path))?;
-    let ast = match parser().parse(src) {
-        Ok(x) => x,
-        Err(errs) => {
-            let src = std::fs::read_to_string(path)?;
-            let mut s = String::new();
-            errs.into_iter().for_each(|e| {
-                s += &render_parse_error(&src, e.span(), e.to_string());
-            });
-            bail!(s);
-        }
+        bail!("usage: chiika-2 a.chiika2 > a.chiika1\n       chiika-2 fmt [--check] a.chiika2...\n       chiika-2 lsp\n       chiika-2 repl\n       chiika-2 --explain <fn> a.chiika2");
     };
-    let (compiled, main_is_async) = compiler::compile(ast)?;
-    let call_chiika_main = if main_is_async {
-        "chiika_main($env, $cont)"
-    } else {
-        "$cont($env, chiika_main())"
-    };
-    println!(
-        "
-extern chiika_env_push($ENV $env, $any obj) -> int;
-extern chiika_env_pop($ENV $env, int n) -> $any;
-extern chiika_env_ref($ENV $env, int n) -> int;
-extern chiika_start_tokio(int n) -> int;
-func chiika_start_user($ENV $env, $FN(($ENV, $any) -> $FUTURE) $cont) -> $FUTURE {{
-    {}
-}}
-func main() -> int {{
-  chiika_start_tokio(0);
-  0
-}}
-",
-        call_chiika_main
+    print!(
+        "{}",
+        chiika_2::compile_file_to_chiika1(std::path::Path::new(path))?
     );
-    println!("{}", ast::to_source(compiled));
     Ok(())
 }
+
+/// Format the files in place. With `--check`, only reports the files which
+/// are not formatted and exits with 1 if any
+fn fmt(args: &[String]) -> Result<()> {
+    let check = args.iter().any(|x| x == "--check");
+    let paths = args.iter().filter(|x| *x != "--check").collect::<Vec<_>>();
+    if paths.is_empty() {
+        bail!("usage: chiika-2 fmt [--check] a.chiika2...");
+    }
+    let mut unformatted = false;
+    for path in paths {
+        let src = std::fs::read_to_string(path).context(format!("failed to read {}", path))?;
+        let formatted =
+            chiika_2::formatter::format(&src).context(format!("failed to format {}", path))?;
+        if formatted == src {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", path);
+            unformatted = true;
+        } else {
+            std::fs::write(path, formatted).context(format!("failed to write {}", path))?;
+        }
+    }
+    if unformatted {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Read inputs from stdin until EOF or `:quit`. An input continues to the
+/// next line while brackets are not closed
+fn repl() -> Result<()> {
+    let mut repl = chiika_2::repl::Repl::new()?;
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        let mut input = String::new();
+        loop {
+            print!("{}", if input.is_empty() { "> " } else { ". " });
+            std::io::stdout().flush()?;
+            let Some(line) = lines.next() else {
+                return Ok(());
+            };
+            input.push_str(&line?);
+            input.push('\n');
+            if !chiika_2::repl::is_incomplete(&input) {
+                break;
+            }
+        }
+        if input.trim() == ":quit" {
+            return Ok(());
+        }
+        match repl.eval(&input) {
+            Ok(msg) if msg.is_empty() => (),
+            Ok(msg) => println!("{}", msg),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
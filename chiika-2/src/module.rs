@@ -0,0 +1,246 @@
+//! Resolution of `import "path";`
+//!
+//! A program is loaded into one list of declarations so that the rest of the
+//! compiler (including the asyncness inference) works across modules.
+//! The path is relative to the importing file. Functions of an imported
+//! module are called as `math::add(...)`, where `math` is the file name
+//! without the extension, and renamed to `math__add`. Externs, structs and
+//! enums are not namespaced; they are shared by all modules.
+use crate::ast;
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Load the file and the modules it imports
+pub fn load(path: &Path) -> Result<Vec<ast::Declaration>> {
+    let src = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let decls = crate::parse(&src).with_context(|| format!("in {}", path.display()))?;
+    resolve(path, decls)
+}
+
+/// Resolve the imports of `decls`, the parsed source of `path`
+pub fn resolve(path: &Path, decls: Vec<ast::Declaration>) -> Result<Vec<ast::Declaration>> {
+    let mut loader = Loader::default();
+    loader.add_module(path, decls, true)?;
+    Ok(loader.decls)
+}
+
+/// Name of a function `name` of the module `module` after loading
+pub fn mangle(module: &str, name: &str) -> String {
+    format!("{}__{}", module, name)
+}
+
+#[derive(Default)]
+struct Loader {
+    // Modules being loaded (to detect cycles)
+    stack: Vec<PathBuf>,
+    // Loaded modules (by the canonical path)
+    modules: HashMap<PathBuf, Module>,
+    decls: Vec<ast::Declaration>,
+}
+
+struct Module {
+    name: String,
+    funcs: HashSet<String>,
+}
+
+impl Loader {
+    /// Load the module at `path` (relative to the current directory) and
+    /// returns the canonical path
+    fn load(&mut self, path: &Path) -> Result<PathBuf> {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        if let Some(i) = self.stack.iter().position(|x| *x == canonical) {
+            let cycle = self.stack[i..]
+                .iter()
+                .chain([&canonical])
+                .map(|x| x.display().to_string())
+                .collect::<Vec<_>>();
+            bail!("import cycle: {}", cycle.join(" -> "));
+        }
+        if !self.modules.contains_key(&canonical) {
+            let src = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            let decls = crate::parse(&src).with_context(|| format!("in {}", path.display()))?;
+            self.add_module(path, decls, false)?;
+        }
+        Ok(canonical)
+    }
+
+    fn add_module(
+        &mut self,
+        path: &Path,
+        decls: Vec<ast::Declaration>,
+        is_main: bool,
+    ) -> Result<()> {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+
+        // Load the imported modules first
+        self.stack.push(canonical.clone());
+        let mut imports = HashMap::new();
+        for decl in &decls {
+            if let ast::Declaration::Import(import_path) = decl {
+                let m = self.load(&dir.join(import_path))?;
+                let name = self.modules[&m].name.clone();
+                if imports.insert(name.clone(), m).is_some() {
+                    bail!("{}: module `{}' is imported twice", path.display(), name);
+                }
+            }
+        }
+        self.stack.pop();
+
+        let name = if is_main {
+            String::new()
+        } else {
+            module_name(path)?
+        };
+        if let Some((p, _)) = self.modules.iter().find(|(_, m)| m.name == name) {
+            bail!(
+                "module name `{}' conflicts: {} and {}",
+                name,
+                p.display(),
+                path.display()
+            );
+        }
+        let module = Module {
+            name,
+            funcs: decls
+                .iter()
+                .filter_map(|x| match x {
+                    ast::Declaration::Function(f) => Some(f.name.clone()),
+                    _ => None,
+                })
+                .collect(),
+        };
+        let imported = imports
+            .iter()
+            .map(|(name, p)| (name.clone(), &self.modules[p]))
+            .collect::<HashMap<_, _>>();
+        let scope = Scope {
+            module: &module,
+            imports: imported,
+        };
+        let mut new_decls = vec![];
+        for decl in decls {
+            match decl {
+                ast::Declaration::Function(mut f) => {
+                    f.name = scope.own_func(&f.name);
+                    f.body_stmts = f
+                        .body_stmts
+                        .into_iter()
+                        .map(|x| scope.rewrite(x))
+                        .collect::<Result<_>>()
+                        .with_context(|| format!("in {}", path.display()))?;
+                    new_decls.push(ast::Declaration::Function(f));
+                }
+                ast::Declaration::Extern(_)
+                | ast::Declaration::Struct(_)
+                | ast::Declaration::Enum(_) => new_decls.push(decl),
+                ast::Declaration::Comment(_) | ast::Declaration::Import(_) => (),
+            }
+        }
+        for decl in new_decls {
+            self.add_decl(decl, path)?;
+        }
+        self.modules.insert(canonical, module);
+        Ok(())
+    }
+
+    /// Add a declaration. Shared ones (externs, structs and enums) may be
+    /// declared in more than one module if they are the same
+    fn add_decl(&mut self, decl: ast::Declaration, path: &Path) -> Result<()> {
+        let name = match &decl {
+            ast::Declaration::Extern(x) => &x.name,
+            ast::Declaration::Struct(x) => &x.name,
+            ast::Declaration::Enum(x) => &x.name,
+            _ => {
+                self.decls.push(decl);
+                return Ok(());
+            }
+        };
+        let existing = self.decls.iter().find(|x| match x {
+            ast::Declaration::Extern(x) => x.name == *name,
+            ast::Declaration::Struct(x) => x.name == *name,
+            ast::Declaration::Enum(x) => x.name == *name,
+            _ => false,
+        });
+        match existing {
+            Some(x) if *x == decl => Ok(()),
+            Some(_) => Err(anyhow!(
+                "{}: `{}' is already declared differently in another module",
+                path.display(),
+                name
+            )),
+            None => {
+                self.decls.push(decl);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Names visible in a module
+struct Scope<'a> {
+    module: &'a Module,
+    imports: HashMap<String, &'a Module>,
+}
+
+impl Scope<'_> {
+    fn own_func(&self, name: &str) -> String {
+        if self.module.name.is_empty() {
+            name.to_string()
+        } else {
+            mangle(&self.module.name, name)
+        }
+    }
+
+    /// Rename calls of the functions of this module and the imported ones
+    fn rewrite(&self, e: ast::Expr) -> Result<ast::Expr> {
+        let mut err = None;
+        let new_e = self.rewrite_(e, &mut err);
+        match err {
+            Some(e) => Err(e),
+            None => Ok(new_e),
+        }
+    }
+
+    fn rewrite_(&self, e: ast::Expr, err: &mut Option<anyhow::Error>) -> ast::Expr {
+        match e {
+            ast::Expr::FunCall(fexpr, args) => {
+                let fexpr = match *fexpr {
+                    ast::Expr::VarRef(name) if self.module.funcs.contains(&name) => {
+                        ast::Expr::VarRef(self.own_func(&name))
+                    }
+                    x => self.rewrite_(x, err),
+                };
+                let args = args.into_iter().map(|x| self.rewrite_(x, err)).collect();
+                ast::Expr::FunCall(Box::new(fexpr), args)
+            }
+            // `math::add(...)` is parsed as an enum value
+            ast::Expr::EnumNew(module, name, args) if self.imports.contains_key(&module) => {
+                if !self.imports[&module].funcs.contains(&name) {
+                    err.get_or_insert(anyhow!("module `{}' has no function `{}'", module, name));
+                }
+                let args = args.into_iter().map(|x| self.rewrite_(x, err)).collect();
+                ast::Expr::FunCall(Box::new(ast::Expr::VarRef(mangle(&module, &name))), args)
+            }
+            _ => e.map_children(&mut |x| self.rewrite_(x, err)),
+        }
+    }
+}
+
+fn module_name(path: &Path) -> Result<String> {
+    let name = path
+        .file_stem()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        bail!("{}: cannot be used as a module name", path.display());
+    }
+    Ok(name)
+}
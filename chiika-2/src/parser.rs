@@ -1,24 +1,118 @@
 use crate::ast;
 use chumsky::prelude::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+use std::rc::Rc;
 
+/// What `parser_with_comments` records besides the AST, which has no place
+/// for comments. The formatter visits the declarations, statements and
+/// blocks in the source order, so it can take their positions from here one
+/// by one to put the comments between them.
+#[derive(Debug, Default)]
+pub struct Trivia {
+    /// Text including `//` or `/* */`, by the offset (in chars) in the source
+    pub comments: BTreeMap<usize, String>,
+    /// Start of each declaration and statement
+    pub items: BTreeSet<usize>,
+    /// Start and end of each block. The arms of a `match` or `select`, an arm without
+    /// braces and a missing `else` (which is empty) are counted as blocks too
+    pub blocks: BTreeMap<usize, usize>,
+}
+
+/// Where to record `Trivia`. `None` unless parsing for the formatter
+type Recorder = Option<Rc<RefCell<Trivia>>>;
+
+fn record_item<T>(rec: &Recorder) -> impl Fn(T, Range<usize>) -> T + Clone {
+    let rec = rec.clone();
+    move |x, span| {
+        if let Some(t) = &rec {
+            t.borrow_mut().items.insert(span.start);
+        }
+        x
+    }
+}
+
+fn record_block<T>(rec: &Recorder) -> impl Fn(T, Range<usize>) -> T + Clone {
+    let rec = rec.clone();
+    move |x, span| {
+        if let Some(t) = &rec {
+            t.borrow_mut().blocks.insert(span.start, span.end);
+        }
+        x
+    }
+}
+
+/// Record a missing `else` as an empty block
+fn record_missing_else<T>(rec: &Recorder) -> impl Fn(T, Range<usize>) -> T + Clone {
+    let record = record_block(rec);
+    move |x, span| record(x, span.start..span.start)
+}
+
+/// `// ...` or `/* ... */`
+fn comment_parser() -> impl Parser<char, String, Error = Simple<char>> + Clone {
+    let line = just("//")
+        .ignore_then(filter(|c: &char| *c != '\n').repeated())
+        .collect::<String>()
+        .map(|s| format!("//{}", s));
+    let block = just("/*")
+        .ignore_then(take_until(just("*/")))
+        .map(|(body, _)| format!("/*{}*/", body.into_iter().collect::<String>()));
+    line.or(block)
+}
+
+/// Whitespaces and comments. If `kc` (keep comments) is true, comments are
+/// not skipped because they are parsed between statements or declarations
+fn ws(kc: bool) -> BoxedParser<'static, char, (), Simple<char>> {
+    let space = filter(|c: &char| c.is_whitespace()).ignored();
+    if kc {
+        space.repeated().ignored().boxed()
+    } else {
+        space
+            .or(comment_parser().ignored())
+            .repeated()
+            .ignored()
+            .boxed()
+    }
+}
+
+/// Comment between statements or declarations, which is recorded in `rec`
+fn kept_comment(rec: &Recorder) -> impl Parser<char, (), Error = Simple<char>> + Clone {
+    let rec = rec.clone();
+    comment_parser().map_with_span(move |text, span: Range<usize>| {
+        if let Some(t) = &rec {
+            t.borrow_mut().comments.insert(span.start, text);
+        }
+    })
+}
+
+/// `int`, `$FN((int) -> int)` (sync function) or `$ASYNC_FN((int) -> int)`
+/// (async function). Function types are only for the params of externs
 fn ty_parser() -> impl Parser<char, ast::Ty, Error = Simple<char>> {
-    recursive(|_ty| {
-        //let params = ty
-        //    .clone()
-        //    .padded()
-        //    .separated_by(just(','))
-        //    .delimited_by(just('('), just(')'));
-        //let sig = params
-        //    .then_ignore(just("->").padded())
-        //    .then(ty.clone())
-        //    .delimited_by(just('('), just(')'));
-        //let fn_ty = just("$FN")
-        //    .ignore_then(sig)
-        //    .map(|(param_tys, ret_ty)| ast::Ty::fun(param_tys, ret_ty));
-
-        let raw_ty = ident_parser().map(|name| ast::Ty::Raw(name));
-
-        raw_ty
+    recursive(|ty| {
+        let params = ty
+            .clone()
+            .padded()
+            .separated_by(just(','))
+            .padded()
+            .delimited_by(just('('), just(')'));
+        let sig = params
+            .then_ignore(just("->").padded())
+            .then(ty.clone())
+            .delimited_by(just('('), just(')'));
+        let fn_ty = just("$ASYNC_FN")
+            .to(true)
+            .or(just("$FN").to(false))
+            .then(sig)
+            .map(|(is_async, (param_tys, ret_ty))| {
+                ast::Ty::Fun(ast::FunTy {
+                    is_async,
+                    param_tys,
+                    ret_ty: Box::new(ret_ty),
+                })
+            });
+
+        fn_ty.or(ident_parser().map(ast::Ty::Raw))
     })
 }
 
@@ -26,12 +120,23 @@ fn ident_parser() -> impl Parser<char, String, Error = Simple<char>> {
     text::ident()
 }
 
+/// String literal
+fn string_parser() -> impl Parser<char, String, Error = Simple<char>> {
+    let escape = just('\\').ignore_then(just('\\').or(just('"')).or(just('n').to('\n')));
+    filter(|c: &char| *c != '\\' && *c != '"')
+        .or(escape)
+        .repeated()
+        .delimited_by(just('"'), just('"'))
+        .collect::<String>()
+}
+
 fn varref_parser() -> impl Parser<char, ast::Expr, Error = Simple<char>> {
     ident_parser().map(ast::Expr::VarRef)
 }
 
 fn atomic_parser(
     expr_parser: impl Parser<char, ast::Expr, Error = Simple<char>> + Clone,
+    kc: bool,
 ) -> impl Parser<char, ast::Expr, Error = Simple<char>> {
     let number = just('-')
         .or_not()
@@ -41,18 +146,80 @@ fn atomic_parser(
         .unwrapped()
         .map(ast::Expr::Number);
 
+    let str_lit = string_parser().map(ast::Expr::Str);
+
     let parenthesized = expr_parser.clone().delimited_by(just('('), just(')'));
 
+    let args = expr_parser
+        .clone()
+        .padded_by(ws(kc))
+        .separated_by(just(','))
+        .delimited_by(just('('), just(')'));
+
     let funcall = (varref_parser().or(parenthesized.clone()))
-        .then_ignore(just('('))
-        .then(expr_parser.clone().padded().separated_by(just(',')))
-        .then_ignore(just(')'))
+        .then(args.clone())
         .map(|(func_expr, args)| ast::Expr::FunCall(Box::new(func_expr), args));
 
-    funcall.or(parenthesized).or(varref_parser()).or(number)
+    let new = text::keyword("new")
+        .ignore_then(ident_parser().padded_by(ws(kc)))
+        .then(args.clone())
+        .map(|(name, args)| ast::Expr::New(name, args));
+
+    let array_lit = expr_parser
+        .clone()
+        .padded_by(ws(kc))
+        .separated_by(just(','))
+        .allow_trailing()
+        .padded_by(ws(kc))
+        .delimited_by(just('['), just(']'))
+        .map(ast::Expr::ArrayLit);
+
+    // `.foo`, `.foo(...)` or `[...]`
+    let method_call = just('.')
+        .ignore_then(ident_parser())
+        .then(args.clone())
+        .map(|(name, args)| Postfix::MethodCall(name, args));
+    let field = just('.').ignore_then(ident_parser()).map(Postfix::Field);
+    let index = expr_parser
+        .clone()
+        .padded_by(ws(kc))
+        .delimited_by(just('['), just(']'))
+        .map(Postfix::Index);
+    let try_ = just('?').to(Postfix::Try);
+    let postfix = method_call.or(field).or(index).or(try_);
+
+    let enum_new = ident_parser()
+        .then_ignore(just("::"))
+        .then(ident_parser())
+        .then(args.clone().or_not())
+        .map(|((name, variant), args)| ast::Expr::EnumNew(name, variant, args.unwrap_or_default()));
+
+    new.or(enum_new)
+        .or(funcall)
+        .or(parenthesized)
+        .or(array_lit)
+        .or(varref_parser())
+        .or(number)
+        .or(str_lit)
+        .then(postfix.repeated())
+        .foldl(|obj, postfix| match postfix {
+            Postfix::Field(name) => ast::Expr::FieldRef(Box::new(obj), name),
+            Postfix::MethodCall(name, args) => ast::Expr::MethodCall(Box::new(obj), name, args),
+            Postfix::Index(idx) => ast::Expr::Index(Box::new(obj), Box::new(idx)),
+            Postfix::Try => ast::Expr::Try(Box::new(obj)),
+        })
+}
+
+#[derive(Clone)]
+enum Postfix {
+    Field(String),
+    MethodCall(String, Vec<ast::Expr>),
+    Index(ast::Expr),
+    Try,
 }
 
-fn expr_parser() -> impl Parser<char, ast::Expr, Error = Simple<char>> {
+fn expr_parser(rec: &Recorder) -> impl Parser<char, ast::Expr, Error = Simple<char>> + Clone {
+    let kc = rec.is_some();
     recursive(|expr| {
         let bin_op = just("==")
             .or(just("!="))
@@ -62,68 +229,239 @@ fn expr_parser() -> impl Parser<char, ast::Expr, Error = Simple<char>> {
             .or(just(">"))
             .or(just("+"))
             .or(just("-"));
-        let sum = atomic_parser(expr.clone())
-            .then(bin_op.padded().then(atomic_parser(expr.clone())).repeated())
+        let sum = atomic_parser(expr.clone(), kc)
+            .then(
+                bin_op
+                    .padded_by(ws(kc))
+                    .then(atomic_parser(expr.clone(), kc))
+                    .repeated(),
+            )
             .foldl(|lhs, (op, rhs)| {
                 ast::Expr::OpCall(op.to_string(), Box::new(lhs), Box::new(rhs))
             });
 
+        // `alloc x` (int) or `alloc Point x`
         let alloc = just("alloc")
-            .padded()
+            .padded_by(ws(kc))
             .ignore_then(ident_parser())
-            .map(ast::Expr::Alloc);
+            .then(ident_parser().padded_by(ws(kc)).or_not())
+            .map(|(a, b)| match b {
+                Some(name) => ast::Expr::Alloc(name, ast::Ty::Raw(a)),
+                None => ast::Expr::Alloc(a, ast::Ty::raw("int")),
+            });
 
         let assign = ident_parser()
-            .padded()
-            .then_ignore(just('=').padded())
+            .padded_by(ws(kc))
+            .then_ignore(just('=').padded_by(ws(kc)))
             .then(expr.clone())
             .map(|(name, rhs)| ast::Expr::Assign(name, Box::new(rhs)));
 
-        alloc.or(assign).or(sum).or(atomic_parser(expr))
+        let field_set = atomic_parser(expr.clone(), kc)
+            .then_ignore(just('=').padded_by(ws(kc)))
+            .then(expr.clone())
+            .try_map(|(lhs, rhs), span| match lhs {
+                ast::Expr::FieldRef(obj, name) => Ok(ast::Expr::FieldSet(obj, name, Box::new(rhs))),
+                ast::Expr::Index(arr, idx) => Ok(ast::Expr::IndexSet(arr, idx, Box::new(rhs))),
+                _ => Err(Simple::custom(span, "invalid left-hand side of assignment")),
+            });
+
+        let block = stmts_parser(expr.clone(), rec).delimited_by(just('{'), just('}'));
+
+        let if_ = text::keyword("if")
+            .ignore_then(expr.clone().padded_by(ws(kc)))
+            .then(block.clone())
+            .then(
+                text::keyword("else")
+                    .padded_by(ws(kc))
+                    .ignore_then(block.clone())
+                    .or(empty().to(vec![]).map_with_span(record_missing_else(rec))),
+            )
+            .map(|((cond, then), els)| ast::Expr::If(Box::new(cond), then, els));
+
+        let pattern = ident_parser()
+            .then_ignore(just("::"))
+            .then(ident_parser())
+            .then(
+                ident_parser()
+                    .padded_by(ws(kc))
+                    .separated_by(just(','))
+                    .delimited_by(just('('), just(')'))
+                    .or_not(),
+            )
+            .map(|((name, variant), vars)| {
+                ast::Pattern::Variant(name, variant, vars.unwrap_or_default())
+            })
+            .or(just('_').to(ast::Pattern::Wildcard));
+        // Body of an arm of `match` or `select`
+        let arm_body = block
+            .clone()
+            .or(expr
+                .clone()
+                .map_with_span(record_item(rec))
+                .map(|e| vec![e])
+                .map_with_span(record_block(rec)))
+            .padded_by(ws(kc));
+        let arm = pattern
+            .padded_by(ws(kc))
+            .then_ignore(just("=>"))
+            .then(arm_body.clone())
+            .map(|(pattern, body)| ast::MatchArm { pattern, body });
+        let match_ = text::keyword("match")
+            .ignore_then(expr.clone().padded_by(ws(kc)))
+            .then(
+                arm.separated_by(just(','))
+                    .allow_trailing()
+                    .padded_by(ws(kc))
+                    .map_with_span(record_block(rec))
+                    .delimited_by(just('{'), just('}')),
+            )
+            .map(|(expr, arms)| ast::Expr::Match(Box::new(expr), arms));
+
+        let select_arm = ident_parser()
+            .padded_by(ws(kc))
+            .then_ignore(just('='))
+            .then(expr.clone().padded_by(ws(kc)))
+            .then_ignore(just("=>"))
+            .then(arm_body)
+            .map(|((var, call), body)| ast::SelectArm { var, call, body });
+        let select = text::keyword("select")
+            .ignore_then(ws(kc))
+            .ignore_then(
+                select_arm
+                    .separated_by(just(','))
+                    .allow_trailing()
+                    .padded_by(ws(kc))
+                    .map_with_span(record_block(rec))
+                    .delimited_by(just('{'), just('}')),
+            )
+            .map(ast::Expr::Select);
+
+        let scope = text::keyword("scope")
+            .ignore_then(ws(kc))
+            .ignore_then(block.clone())
+            .map(ast::Expr::Scope);
+
+        let for_ = text::keyword("for")
+            .ignore_then(ident_parser().padded_by(ws(kc)))
+            .then_ignore(text::keyword("in"))
+            .then(expr.clone().padded_by(ws(kc)))
+            .then(block)
+            .map(|((var, arr), body)| ast::Expr::For(var, Box::new(arr), body));
+
+        let return_ = text::keyword("return")
+            .ignore_then(expr.clone().padded_by(ws(kc)))
+            .map(|e| ast::Expr::Return(Box::new(e)));
+
+        if_.or(match_)
+            .or(select)
+            .or(scope)
+            .or(for_)
+            .or(return_)
+            .or(alloc)
+            .or(assign)
+            .or(field_set)
+            .or(sum)
+            .or(atomic_parser(expr, kc))
     })
 }
 
-fn stmts_parser() -> impl Parser<char, Vec<ast::Expr>, Error = Simple<char>> {
-    expr_parser()
-        .padded()
-        .separated_by(just(';'))
-        .allow_trailing()
+/// Statements separated by `;`. If `rec` is given, comments between them
+/// are recorded in it
+fn stmts_parser(
+    expr: impl Parser<char, ast::Expr, Error = Simple<char>> + Clone + 'static,
+    rec: &Recorder,
+) -> BoxedParser<'static, char, Vec<ast::Expr>, Simple<char>> {
+    let kc = rec.is_some();
+    if !kc {
+        return expr
+            .padded_by(ws(kc))
+            .separated_by(just(';'))
+            .allow_trailing()
+            .padded_by(ws(kc))
+            .boxed();
+    }
+    // Note that this accepts missing `;` too (the syntax is checked by the
+    // usual parser beforehand)
+    let stmt = expr
+        .map_with_span(record_item(rec))
+        .then_ignore(ws(kc))
+        .then_ignore(just(';').or_not())
+        .map(Some);
+    let comment = kept_comment(rec).to(None);
+    ws(kc)
+        .ignore_then(comment.or(stmt).then_ignore(ws(kc)).repeated())
+        .map(|xs| xs.into_iter().flatten().collect())
+        .map_with_span(record_block(rec))
+        .boxed()
 }
 
-fn param_parser() -> impl Parser<char, ast::Param, Error = Simple<char>> {
+fn param_parser(kc: bool) -> impl Parser<char, ast::Param, Error = Simple<char>> {
     ty_parser()
-        .padded()
+        .padded_by(ws(kc))
         .then(ident_parser())
         .map(|(ty, name)| ast::Param { ty, name })
 }
 
-fn params_parser() -> impl Parser<char, Vec<ast::Param>, Error = Simple<char>> {
-    param_parser().padded().separated_by(just(','))
+fn params_parser(kc: bool) -> impl Parser<char, Vec<ast::Param>, Error = Simple<char>> {
+    param_parser(kc).padded_by(ws(kc)).separated_by(just(','))
 }
 
-fn func_parser() -> impl Parser<char, ast::Function, Error = Simple<char>> {
-    just("fun")
-        .ignore_then(ident_parser().padded())
-        .then(params_parser().delimited_by(just('('), just(')')))
-        .then_ignore(just("->").padded())
-        .then(ty_parser().padded())
-        .then(stmts_parser().delimited_by(just('{'), just('}')))
-        .map(|(((name, params), ret_ty), body_stmts)| ast::Function {
+/// `#[name(key = value, ...)]`
+fn attr_parser(kc: bool) -> impl Parser<char, ast::Attribute, Error = Simple<char>> {
+    let value = text::int(10)
+        .from_str()
+        .unwrapped()
+        .map(ast::Expr::Number)
+        .or(string_parser().map(ast::Expr::Str));
+    let arg = ident_parser()
+        .then_ignore(just('=').padded_by(ws(kc)))
+        .then(value)
+        .padded_by(ws(kc));
+    just("#[")
+        .ignore_then(ident_parser().padded_by(ws(kc)))
+        .then(
+            arg.separated_by(just(','))
+                .delimited_by(just('('), just(')'))
+                .or_not(),
+        )
+        .then_ignore(ws(kc))
+        .then_ignore(just(']'))
+        .map(|(name, args)| ast::Attribute {
             name,
-            params,
-            ret_ty,
-            body_stmts,
+            args: args.unwrap_or_default(),
         })
 }
 
-fn extern_parser() -> impl Parser<char, ast::Extern, Error = Simple<char>> {
+fn func_parser(rec: &Recorder) -> impl Parser<char, ast::Function, Error = Simple<char>> {
+    let kc = rec.is_some();
+    attr_parser(kc)
+        .then_ignore(ws(kc))
+        .repeated()
+        .then_ignore(just("fun"))
+        .then(ident_parser().padded_by(ws(kc)))
+        .then(params_parser(kc).delimited_by(just('('), just(')')))
+        .then_ignore(just("->").padded_by(ws(kc)))
+        .then(ty_parser().padded_by(ws(kc)))
+        .then(stmts_parser(expr_parser(rec), rec).delimited_by(just('{'), just('}')))
+        .map(
+            |((((attrs, name), params), ret_ty), body_stmts)| ast::Function {
+                name,
+                params,
+                ret_ty,
+                body_stmts,
+                attrs,
+            },
+        )
+}
+
+fn extern_parser(kc: bool) -> impl Parser<char, ast::Extern, Error = Simple<char>> {
     just("extern_async")
         .or(just("extern"))
-        .then(ident_parser().padded())
-        .then(params_parser().delimited_by(just('('), just(')')))
-        .then_ignore(just("->").padded())
-        .then(ty_parser().padded())
-        .then_ignore(just(';').padded())
+        .then(ident_parser().padded_by(ws(kc)))
+        .then(params_parser(kc).delimited_by(just('('), just(')')))
+        .then_ignore(just("->").padded_by(ws(kc)))
+        .then(ty_parser().padded_by(ws(kc)))
+        .then_ignore(just(';').padded_by(ws(kc)))
         .map(|(((is_async, name), params), ret_ty)| ast::Extern {
             is_async: is_async == "extern_async",
             name,
@@ -132,12 +470,99 @@ fn extern_parser() -> impl Parser<char, ast::Extern, Error = Simple<char>> {
         })
 }
 
-fn decl_parser() -> impl Parser<char, ast::Declaration, Error = Simple<char>> {
-    func_parser()
+fn struct_parser(kc: bool) -> impl Parser<char, ast::Struct, Error = Simple<char>> {
+    just("struct")
+        .ignore_then(ident_parser().padded_by(ws(kc)))
+        .then(
+            param_parser(kc)
+                .padded_by(ws(kc))
+                .then_ignore(just(';'))
+                .repeated()
+                .padded_by(ws(kc))
+                .delimited_by(just('{'), just('}')),
+        )
+        .map(|(name, fields)| ast::Struct { name, fields })
+}
+
+fn enum_parser(kc: bool) -> impl Parser<char, ast::Enum, Error = Simple<char>> {
+    let variant = ident_parser()
+        .then(
+            ty_parser()
+                .padded_by(ws(kc))
+                .separated_by(just(','))
+                .delimited_by(just('('), just(')'))
+                .or_not(),
+        )
+        .map(|(name, param_tys)| ast::Variant {
+            name,
+            param_tys: param_tys.unwrap_or_default(),
+        });
+    just("enum")
+        .ignore_then(ident_parser().padded_by(ws(kc)))
+        .then(
+            variant
+                .padded_by(ws(kc))
+                .separated_by(just(','))
+                .allow_trailing()
+                .padded_by(ws(kc))
+                .delimited_by(just('{'), just('}')),
+        )
+        .map(|(name, variants)| ast::Enum { name, variants })
+}
+
+fn import_parser(kc: bool) -> impl Parser<char, String, Error = Simple<char>> {
+    text::keyword("import")
+        .ignore_then(string_parser().padded_by(ws(kc)))
+        .then_ignore(just(';'))
+}
+
+fn decl_parser(rec: &Recorder) -> impl Parser<char, ast::Declaration, Error = Simple<char>> {
+    let kc = rec.is_some();
+    func_parser(rec)
         .map(ast::Declaration::Function)
-        .or(extern_parser().map(ast::Declaration::Extern))
+        .or(import_parser(kc).map(ast::Declaration::Import))
+        .or(extern_parser(kc).map(ast::Declaration::Extern))
+        .or(struct_parser(kc).map(ast::Declaration::Struct))
+        .or(enum_parser(kc).map(ast::Declaration::Enum))
 }
 
 pub fn parser() -> impl Parser<char, Vec<ast::Declaration>, Error = Simple<char>> {
-    decl_parser().padded().repeated().then_ignore(end())
+    decl_parser(&None)
+        .padded_by(ws(false))
+        .repeated()
+        .then_ignore(end())
+}
+
+/// Parser of the statements given to the REPL
+pub fn repl_stmts_parser() -> impl Parser<char, Vec<ast::Expr>, Error = Simple<char>> {
+    stmts_parser(expr_parser(&None), &None).then_ignore(end())
+}
+
+/// Parser for tools which need the location of each declaration
+pub fn parser_with_spans(
+) -> impl Parser<char, Vec<(ast::Declaration, std::ops::Range<usize>)>, Error = Simple<char>> {
+    decl_parser(&None)
+        .map_with_span(|decl, span| (decl, span))
+        .padded_by(ws(false))
+        .repeated()
+        .then_ignore(end())
+}
+
+/// Parser for the formatter. Comments between declarations or statements
+/// are recorded in `trivia` (and comments elsewhere are syntax errors.)
+pub fn parser_with_comments(
+    trivia: Rc<RefCell<Trivia>>,
+) -> impl Parser<char, Vec<ast::Declaration>, Error = Simple<char>> {
+    let rec = Some(trivia);
+    let decl = decl_parser(&rec).map_with_span(record_item(&rec)).map(Some);
+    ws(true)
+        .ignore_then(
+            kept_comment(&rec)
+                .to(None)
+                .or(decl)
+                .then_ignore(ws(true))
+                .repeated(),
+        )
+        .then_ignore(end())
+        .map(|xs| xs.into_iter().flatten().collect())
 }
@@ -0,0 +1,190 @@
+//! REPL of chiika-2 (`chiika-2 repl`)
+//!
+//! Each input is either declarations or statements. Inputs are compiled
+//! together with the declarations so far to report errors of the compiler.
+//! With the `llvm` feature, statements whose value is an int are then
+//! JIT-compiled and run on chiika_runtime (see `jit`). Otherwise, or if LLVM
+//! is not available, they are evaluated by `interp::Session`. Both keep one
+//! tokio runtime for the whole session.
+use crate::{ast, asyncness_check, compiler, interp, parser};
+use anyhow::{anyhow, Result};
+use chumsky::Parser;
+
+/// Externs supported by the interpreter, declared at startup
+const PRELUDE: &str = "
+extern print(int n) -> int;
+extern print_str(string s) -> int;
+extern_async sleep_sec(int n) -> int;
+extern_async sleep_ms(int ms) -> int;
+extern now_ms() -> int;
+extern_async read_int(string path) -> Result;
+extern mutex_new() -> int;
+extern_async mutex_lock(int m) -> int;
+extern mutex_unlock(int m) -> int;
+extern mutex_free(int m) -> int;
+extern semaphore_new(int permits) -> int;
+extern_async semaphore_acquire(int s) -> int;
+extern semaphore_release(int s) -> int;
+extern semaphore_free(int s) -> int;
+";
+
+/// Name of the function which wraps statements given to the REPL
+const REPL_FUNC: &str = "chiika_repl";
+
+pub struct Repl {
+    decls: Vec<ast::Declaration>,
+    session: interp::Session,
+    #[cfg(feature = "llvm")]
+    jit: Option<crate::jit::Jit>,
+}
+
+impl Repl {
+    pub fn new() -> Result<Repl> {
+        let mut repl = Repl {
+            decls: vec![],
+            session: interp::Session::new()?,
+            #[cfg(feature = "llvm")]
+            jit: crate::jit::Jit::new()
+                .map_err(|e| eprintln!("JIT is not available ({}); using the interpreter", e))
+                .ok(),
+        };
+        repl.eval(PRELUDE)?;
+        Ok(repl)
+    }
+
+    /// Process an input and returns the message to show: the value of the
+    /// statements or what is declared
+    pub fn eval(&mut self, input: &str) -> Result<String> {
+        if input.trim().is_empty() {
+            return Ok(String::new());
+        }
+        match parser::parser().parse(input) {
+            Ok(decls) => return self.declare(decls),
+            Err(errs) if starts_with_decl(input) => return Err(parse_error(input, errs)),
+            Err(_) => (),
+        }
+        let stmts = parser::repl_stmts_parser()
+            .parse(input)
+            .map_err(|errs| parse_error(input, errs))?;
+        let wrapper = ast::Function {
+            name: REPL_FUNC.to_string(),
+            params: vec![],
+            ret_ty: ast::Ty::raw("int"),
+            body_stmts: stmts.clone(),
+            attrs: vec![],
+        };
+        let decls = with_main(merge(
+            &self.decls,
+            vec![ast::Declaration::Function(wrapper.clone())],
+        ));
+        compiler::compile(decls.clone())?;
+        // The interpreter shows the values of other types
+        #[cfg(feature = "llvm")]
+        if let Some(jit) = &self.jit {
+            let value_ty = compiler::infer_value_ty(&decls, &wrapper);
+            if matches!(value_ty, Ok(ty) if ty == ast::Ty::raw("int")) {
+                return Ok(jit.run(decls, REPL_FUNC)?.to_string());
+            }
+        }
+        self.session.eval(&stmts)
+    }
+
+    fn declare(&mut self, decls: Vec<ast::Declaration>) -> Result<String> {
+        let all = merge(&self.decls, decls.clone());
+        check(all.clone())?;
+        let sigs = asyncness_check::gather_sigs(&all)?;
+        let chapters = compiler::count_chapters(&all)?;
+        let msgs = decls
+            .iter()
+            .filter_map(|decl| match decl {
+                ast::Declaration::Function(x) if sigs[&x.name].is_async => Some(format!(
+                    "fun {} (async, {} chapters)",
+                    x.name, chapters[&x.name]
+                )),
+                ast::Declaration::Function(x) => Some(format!("fun {} (sync)", x.name)),
+                ast::Declaration::Extern(x) => Some(format!("extern {}", x.name)),
+                ast::Declaration::Struct(x) => Some(format!("struct {}", x.name)),
+                ast::Declaration::Enum(x) => Some(format!("enum {}", x.name)),
+                ast::Declaration::Comment(_) | ast::Declaration::Import(_) => None,
+            })
+            .collect::<Vec<_>>();
+        self.decls = all;
+        self.session.declare(decls);
+        Ok(msgs.join("\n"))
+    }
+}
+
+/// Returns true if the input has unclosed brackets and the REPL should read
+/// more lines
+pub fn is_incomplete(input: &str) -> bool {
+    let mut depth = 0;
+    let mut in_str = false;
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if in_str => {
+                chars.next();
+            }
+            '"' => in_str = !in_str,
+            '{' | '(' | '[' if !in_str => depth += 1,
+            '}' | ')' | ']' if !in_str => depth -= 1,
+            _ => (),
+        }
+    }
+    depth > 0
+}
+
+fn starts_with_decl(input: &str) -> bool {
+    let first = input.split_whitespace().next().unwrap_or("");
+    ["fun", "extern", "extern_async", "struct", "enum"].contains(&first)
+}
+
+fn parse_error(input: &str, errs: Vec<chumsky::error::Simple<char>>) -> anyhow::Error {
+    let mut s = String::new();
+    for e in errs {
+        s += &crate::render_parse_error(input, e.span(), e.to_string());
+    }
+    anyhow!(s)
+}
+
+/// Returns `decls` with `new_decls` added. Existing declarations of the same
+/// name are replaced
+fn merge(decls: &[ast::Declaration], new_decls: Vec<ast::Declaration>) -> Vec<ast::Declaration> {
+    let mut merged = decls
+        .iter()
+        .filter(|x| !new_decls.iter().any(|y| decl_name(x) == decl_name(y)))
+        .cloned()
+        .collect::<Vec<_>>();
+    merged.extend(new_decls);
+    merged
+}
+
+fn decl_name(decl: &ast::Declaration) -> Option<&str> {
+    match decl {
+        ast::Declaration::Function(x) => Some(&x.name),
+        ast::Declaration::Extern(x) => Some(&x.name),
+        ast::Declaration::Struct(x) => Some(&x.name),
+        ast::Declaration::Enum(x) => Some(&x.name),
+        ast::Declaration::Comment(_) | ast::Declaration::Import(_) => None,
+    }
+}
+
+/// Compile the program to report errors
+fn check(decls: Vec<ast::Declaration>) -> Result<()> {
+    compiler::compile(with_main(decls))?;
+    Ok(())
+}
+
+/// Returns `decls` with `chiika_main` added if missing
+fn with_main(mut decls: Vec<ast::Declaration>) -> Vec<ast::Declaration> {
+    if !decls.iter().any(|x| decl_name(x) == Some("chiika_main")) {
+        decls.push(ast::Declaration::Function(ast::Function {
+            name: "chiika_main".to_string(),
+            params: vec![],
+            ret_ty: ast::Ty::raw("int"),
+            body_stmts: vec![ast::Expr::Number(0)],
+            attrs: vec![],
+        }));
+    }
+    decls
+}
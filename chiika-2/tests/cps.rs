@@ -0,0 +1,532 @@
+//! Runs the output of chiika-2 with the interpreter of chiika-1 to check the
+//! CPS transformation.
+use chiika::{ast, interp, parser};
+use chumsky::Parser;
+
+const EXTERNS: &str = "
+extern print(int n) -> int;
+extern print_str(string s) -> int;
+extern_async sleep_sec(int n) -> int;
+extern_async chiika_exit(int code) -> int;
+";
+
+fn run(src: &str) -> String {
+    let (decls, chiika1_src) = compile(src);
+    interp::run_with_args(decls, &[])
+        .map(|x| x.0)
+        .unwrap_or_else(|e| panic!("{}\n{}", e, chiika1_src))
+}
+
+fn run_with_status(src: &str) -> (String, i64) {
+    let (decls, chiika1_src) = compile(src);
+    interp::run_with_args(decls, &[]).unwrap_or_else(|e| panic!("{}\n{}", e, chiika1_src))
+}
+
+fn compile(src: &str) -> (Vec<ast::Declaration>, String) {
+    let chiika1_src = chiika_2::compile_to_chiika1(&format!("{}{}", EXTERNS, src)).unwrap();
+    let decls = parser::parser()
+        .parse(chiika1_src.clone())
+        .unwrap_or_else(|e| panic!("{:?}\n{}", e, chiika1_src));
+    (decls, chiika1_src)
+}
+
+#[test]
+fn test_sync() {
+    let src = "
+      fun add(int a, int b) -> int { a + b }
+      fun chiika_main() -> int { print(add(1, 2)); 0 }
+    ";
+    assert_eq!(run(src), "3\n");
+}
+
+#[test]
+fn test_sequential_async_calls() {
+    let src = "
+      fun chiika_main() -> int {
+        print(1);
+        print(sleep_sec(2));
+        print(sleep_sec(1) + 10);
+        0
+      }
+    ";
+    assert_eq!(run(src), "1\n2\n11\n");
+}
+
+#[test]
+fn test_params_and_locals_across_chapters() {
+    let src = "
+      fun f(int a, int b) -> int {
+        alloc x;
+        x = a + sleep_sec(1);
+        sleep_sec(1);
+        x + b
+      }
+      fun chiika_main() -> int { print(f(10, 100)); print(f(1, 2)); 0 }
+    ";
+    assert_eq!(run(src), "111\n4\n");
+}
+
+#[test]
+fn test_async_if() {
+    let src = "
+      fun f(int n) -> int {
+        alloc y;
+        y = if n == 0 { sleep_sec(1) } else { n + 10 };
+        y + 1
+      }
+      fun chiika_main() -> int { print(f(0)); print(f(5)); 0 }
+    ";
+    assert_eq!(run(src), "2\n16\n");
+}
+
+#[test]
+fn test_async_match() {
+    let src = "
+      enum Shape { Circle(int), Rect(int, int), Empty }
+      fun area(Shape s) -> int {
+        match s {
+          Shape::Circle(r) => { sleep_sec(1); r + r + r },
+          Shape::Rect(w, h) => w + h,
+          _ => 0,
+        }
+      }
+      fun chiika_main() -> int {
+        print(area(Shape::Circle(2)));
+        print(area(Shape::Rect(3, 4)));
+        print(area(Shape::Empty));
+        0
+      }
+    ";
+    assert_eq!(run(src), "6\n7\n0\n");
+}
+
+#[test]
+fn test_match_arms_binding_same_name() {
+    let src = "
+      enum Value { Num(int), Text(string) }
+      fun show(Value v) -> int {
+        match v {
+          Value::Num(x) => { sleep_sec(1); print(x) },
+          Value::Text(x) => print_str(x),
+        }
+      }
+      fun chiika_main() -> int {
+        show(Value::Num(1));
+        show(Value::Text(\"a\"));
+        0
+      }
+    ";
+    assert_eq!(run(src), "1\na\n");
+}
+
+#[test]
+fn test_structs_and_arrays() {
+    let src = "
+      struct Point { int x; int y; }
+      fun chiika_main() -> int {
+        alloc Point p;
+        p = new Point(1, 2);
+        alloc array a;
+        a = [p.x, p.y];
+        a.push(sleep_sec(3));
+        p.x = 10;
+        for v in a { print(v) };
+        print(p.x + a.len());
+        0
+      }
+    ";
+    assert_eq!(run(src), "1\n2\n3\n13\n");
+}
+
+#[test]
+fn test_result_and_try() {
+    let src = "
+      fun check(int n) -> Result {
+        sleep_sec(1);
+        if n == 0 { Result::Err(\"zero\") } else { Result::Ok(n) }
+      }
+      fun sum(int a, int b) -> Result {
+        Result::Ok(check(a)? + check(b)?)
+      }
+      fun show(Result r) -> int {
+        match r {
+          Result::Ok(n) => print(n),
+          Result::Err(msg) => print_str(msg),
+        }
+      }
+      fun chiika_main() -> int {
+        show(sum(1, 2));
+        show(sum(0, 2));
+        0
+      }
+    ";
+    assert_eq!(run(src), "3\nzero\n");
+}
+
+#[test]
+fn test_return() {
+    let src = "
+      fun f(int n) -> int {
+        if n == 0 { return 100 };
+        sleep_sec(1);
+        if n == 1 { return sleep_sec(2) };
+        n
+      }
+      fun chiika_main() -> int {
+        print(f(0));
+        print(f(1));
+        print(f(5));
+        0
+      }
+    ";
+    assert_eq!(run(src), "100\n2\n5\n");
+}
+
+#[test]
+fn test_operands_before_async_call() {
+    let src = "
+      struct Point { int x; int y; }
+      fun bump(Point p) -> int { p.x = 10; sleep_sec(1) }
+      fun chiika_main() -> int {
+        alloc Point p;
+        p = new Point(1, 2);
+        print(p.x + bump(p));
+        0
+      }
+    ";
+    assert_eq!(run(src), "2\n");
+}
+
+#[test]
+fn test_array_items_before_async_call() {
+    let src = "
+      fun bump(array a) -> int { a[0] = 10; sleep_sec(1) }
+      fun chiika_main() -> int {
+        alloc array a;
+        a = [1];
+        alloc array b;
+        b = [a[0], bump(a)];
+        for v in b { print(v) };
+        0
+      }
+    ";
+    assert_eq!(run(src), "1\n1\n");
+}
+
+#[test]
+fn test_enum_args_before_async_call() {
+    let src = "
+      enum Shape { Circle(int), Rect(int, int), Empty }
+      fun bump(array a) -> int { a[0] = 10; sleep_sec(1) }
+      fun chiika_main() -> int {
+        alloc array a;
+        a = [3];
+        match Shape::Rect(a[0], bump(a)) {
+          Shape::Rect(w, h) => print(w + h),
+          _ => 0,
+        };
+        0
+      }
+    ";
+    assert_eq!(run(src), "4\n");
+}
+
+#[test]
+fn test_comments() {
+    let src = "
+      // line comment
+      fun chiika_main() -> int {
+        /* block
+           comment */
+        print(/* inline */ 1);
+        sleep_sec(1); // trailing
+        0
+      }
+    ";
+    assert_eq!(run(src), "1\n");
+}
+
+#[test]
+fn test_tokio_attribute() {
+    let src = "
+      #[tokio(flavor = \"multi_thread\", workers = 2, drivers = \"time\")]
+      fun chiika_main() -> int { sleep_sec(1); print(1); 0 }
+    ";
+    assert_eq!(run(src), "1\n");
+    let chiika1_src = chiika_2::compile_to_chiika1(&format!("{}{}", EXTERNS, src)).unwrap();
+    assert!(chiika1_src.contains(
+        "chiika_tokio_config(\"flavor\", \"multi_thread\");
+  chiika_tokio_config(\"workers\", \"2\");
+  chiika_tokio_config(\"drivers\", \"time\");
+  chiika_start_tokio(0)"
+    ));
+
+    for invalid in [
+        "#[tokio(flavor = \"single\")] fun chiika_main() -> int { 0 }",
+        "#[tokio(workers = 0)] fun chiika_main() -> int { 0 }",
+        "#[tokio(flavor = \"current_thread\", workers = 2)] fun chiika_main() -> int { 0 }",
+        "#[tokio(drivers = \"net\")] fun chiika_main() -> int { 0 }",
+        "#[tokio(clock = \"paused\")] fun chiika_main() -> int { 0 }",
+        "#[tokio(clock = \"stopped\", flavor = \"current_thread\")] fun chiika_main() -> int { 0 }",
+        "#[inline] fun chiika_main() -> int { 0 }",
+        "#[tokio] fun foo() -> int { 0 } fun chiika_main() -> int { 0 }",
+    ] {
+        assert!(
+            chiika_2::compile_to_chiika1(invalid).is_err(),
+            "{}",
+            invalid
+        );
+    }
+}
+
+#[test]
+fn test_exit_status() {
+    let src = "fun chiika_main() -> int { sleep_sec(1); 7 }";
+    assert_eq!(run_with_status(src), (String::new(), 7));
+
+    let src = "
+      fun chiika_main() -> int {
+        print(1);
+        chiika_exit(3);
+        print(2);
+        0
+      }
+    ";
+    assert_eq!(run_with_status(src), ("1\n".to_string(), 3));
+}
+
+#[test]
+fn test_function_args() {
+    let src = "
+      extern_async timeout(int ms, $ASYNC_FN(() -> int) f) -> int;
+      fun work() -> int { sleep_sec(1); 1 }
+      fun chiika_main() -> int { print(timeout(2000, work)); 0 }
+    ";
+    let chiika1_src = chiika_2::compile_to_chiika1(&format!("{}{}", EXTERNS, src)).unwrap();
+    assert!(chiika1_src.contains(
+        "extern timeout($ENV $env, $FN(($ENV, int) -> $FUTURE) $cont, int ms, \
+         $FN(($ENV, $FN(($ENV, int) -> $FUTURE)) -> $FUTURE) f) -> $FUTURE;"
+    ));
+
+    let decl = "extern_async timeout(int ms, $ASYNC_FN(() -> int) f) -> int;";
+    for invalid in [
+        // Not async
+        "fun work() -> int { 1 } fun chiika_main() -> int { timeout(10, work) }",
+        // Has a param
+        "fun work(int n) -> int { sleep_sec(n) } fun chiika_main() -> int { timeout(10, work) }",
+        "fun chiika_main() -> int { timeout(10, 1) }",
+    ] {
+        let src = format!("{}{}{}", EXTERNS, decl, invalid);
+        assert!(chiika_2::compile_to_chiika1(&src).is_err(), "{}", invalid);
+    }
+}
+
+const CHANNELS: &str = "
+extern spawn($ASYNC_FN((int) -> int) f, int arg) -> int;
+extern cancel(int task) -> int;
+extern chan_new(int capacity) -> int;
+extern_async chan_send(int ch, int value) -> Result;
+extern_async chan_recv(int ch) -> Result;
+extern chan_close(int ch) -> int;
+extern chan_free(int ch) -> int;
+extern oneshot_new() -> int;
+extern oneshot_send(int ch, int value) -> Result;
+";
+
+#[test]
+fn test_channels() {
+    // The buffer holds 1 value, so the producer waits for the consumer
+    let src = "
+      fun produce(int ch, int n) -> int {
+        if n == 4 { chan_close(ch); return 0 };
+        print(n);
+        chan_send(ch, n + 10);
+        produce(ch, n + 1)
+      }
+      fun producer(int ch) -> int { produce(ch, 0) }
+      fun consume(int ch) -> int {
+        match chan_recv(ch) {
+          Result::Ok(v) => { sleep_sec(1); print(v); consume(ch) },
+          Result::Err(msg) => print_str(msg),
+        }
+      }
+      fun chiika_main() -> int {
+        alloc int ch;
+        ch = chan_new(1);
+        spawn(producer, ch);
+        consume(ch);
+        chan_free(ch)
+      }
+    ";
+    assert_eq!(
+        run(&format!("{}{}", CHANNELS, src)),
+        "0\n1\n2\n10\n3\n11\n12\n13\nclosed\n"
+    );
+
+    let src = "
+      fun reply(int ch) -> int { sleep_sec(2); oneshot_send(ch, 42); 0 }
+      fun show(Result r) -> int {
+        match r {
+          Result::Ok(n) => print(n),
+          Result::Err(msg) => print_str(msg),
+        }
+      }
+      fun chiika_main() -> int {
+        alloc int ch;
+        ch = oneshot_new();
+        spawn(reply, ch);
+        show(chan_recv(ch));
+        show(chan_recv(ch));
+        show(oneshot_send(ch, 1));
+        chan_free(ch)
+      }
+    ";
+    assert_eq!(run(&format!("{}{}", CHANNELS, src)), "42\nclosed\nclosed\n");
+}
+
+#[test]
+fn test_select() {
+    let src = "
+      extern_async sleep_ms(int ms) -> int;
+      extern now_ms() -> int;
+      fun send_later(int ch) -> int { sleep_ms(50); chan_send(ch, 7); 0 }
+      fun slow(int ms) -> int { sleep_ms(ms); print(999); ms }
+      fun wait(int ch, int ms) -> int {
+        select {
+          r = chan_recv(ch) => match r {
+            Result::Ok(v) => v,
+            Result::Err(msg) => { print_str(msg); 0 },
+          },
+          n = sleep_ms(ms) => 0 - n,
+          _ = slow(200) => 1000,
+        }
+      }
+      fun chiika_main() -> int {
+        alloc int ch;
+        ch = chan_new(1);
+        spawn(send_later, ch);
+        print(wait(ch, 100));
+        print(wait(ch, 10));
+        // The receiver dropped above does not take the value
+        spawn(send_later, ch);
+        print(wait(ch, 100));
+        chan_close(ch);
+        print(wait(ch, 100));
+        print(now_ms());
+        0
+      }
+    ";
+    assert_eq!(
+        run(&format!("{}{}", CHANNELS, src)),
+        "7\n-10\n7\nclosed\n0\n110\n"
+    );
+
+    for invalid in [
+        // Not async
+        "fun chiika_main() -> int { select { x = print(1) => x } }",
+        "fun chiika_main() -> int { select { x = 1 => x } }",
+    ] {
+        let src = format!("{}{}", EXTERNS, invalid);
+        assert!(chiika_2::compile_to_chiika1(&src).is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_scope() {
+    let src = "
+      extern_async sleep_ms(int ms) -> int;
+      extern now_ms() -> int;
+      fun work(int ms) -> int { sleep_ms(ms); print(ms); 0 }
+      fun wait_all() -> int {
+        scope {
+          spawn(work, 30);
+          spawn(work, 10);
+          sleep_ms(20);
+          print(1)
+        }
+      }
+      // The tasks are cancelled by `return`
+      fun leave(int n) -> int {
+        scope {
+          spawn(work, 10);
+          if n == 0 { return 5 };
+          sleep_ms(20);
+          n
+        }
+      }
+      fun chiika_main() -> int {
+        print(wait_all());
+        print(now_ms());
+        print(leave(0));
+        sleep_ms(50);
+        print(leave(2));
+        alloc int task;
+        task = spawn(work, 10);
+        print(cancel(task));
+        print(cancel(task));
+        sleep_ms(50);
+        print(now_ms());
+        0
+      }
+    ";
+    assert_eq!(
+        run(&format!("{}{}", CHANNELS, src)),
+        "10\n1\n30\n0\n30\n5\n10\n2\n1\n0\n150\n"
+    );
+}
+
+#[test]
+fn test_mutex_and_semaphore() {
+    let src = "
+      extern_async sleep_ms(int ms) -> int;
+      extern now_ms() -> int;
+      extern mutex_new() -> int;
+      extern_async mutex_lock(int m) -> int;
+      extern mutex_unlock(int m) -> int;
+      extern semaphore_new(int permits) -> int;
+      extern_async semaphore_acquire(int s) -> int;
+      extern semaphore_release(int s) -> int;
+      // Without the lock, the prints of the tasks would interleave
+      fun critical(int m) -> int {
+        mutex_lock(m);
+        print(1);
+        sleep_ms(10);
+        print(2);
+        mutex_unlock(m)
+      }
+      fun limited(int s) -> int {
+        semaphore_acquire(s);
+        sleep_ms(10);
+        semaphore_release(s)
+      }
+      fun chiika_main() -> int {
+        alloc int m;
+        alloc int s;
+        m = mutex_new();
+        scope {
+          spawn(critical, m);
+          spawn(critical, m);
+          spawn(critical, m)
+        };
+        print(mutex_unlock(m));
+        print(now_ms());
+        // 2 of the 5 tasks run at a time
+        s = semaphore_new(2);
+        scope {
+          spawn(limited, s);
+          spawn(limited, s);
+          spawn(limited, s);
+          spawn(limited, s);
+          spawn(limited, s)
+        };
+        print(now_ms());
+        // All the permits are released
+        print(semaphore_release(s));
+        0
+      }
+    ";
+    assert_eq!(
+        run(&format!("{}{}", CHANNELS, src)),
+        "1\n2\n1\n2\n1\n2\n0\n30\n60\n0\n"
+    );
+}
@@ -0,0 +1,558 @@
+//! Differential tests: the output of the compiled program (run by the
+//! interpreter of chiika-1) must be the same as the reference interpreter of
+//! chiika-2. So must the exit status.
+use chiika::{interp, parser};
+use chumsky::Parser;
+
+const EXTERNS: &str = "
+extern print(int n) -> int;
+extern print_str(string s) -> int;
+extern_async sleep_sec(int n) -> int;
+extern_async chiika_exit(int code) -> int;
+";
+
+/// Runtime of the reference interpreter, with the paused clock
+fn paused_runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+        .unwrap()
+}
+
+/// Returns the output
+fn check(src: &str) -> String {
+    check_with_args(src, &[])
+}
+
+fn check_with_args(src: &str, args: &[&str]) -> String {
+    let src = format!("{}{}", EXTERNS, src);
+    let args = args.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    let expected =
+        chiika_2::interp::run_with_args(&paused_runtime(), chiika_2::parse(&src).unwrap(), &args)
+            .unwrap();
+    let chiika1_src = chiika_2::compile_to_chiika1(&src).unwrap();
+    let decls = parser::parser()
+        .parse(chiika1_src.clone())
+        .unwrap_or_else(|e| panic!("{:?}\n{}", e, chiika1_src));
+    let actual =
+        interp::run_with_args(decls, &args).unwrap_or_else(|e| panic!("{}\n{}", e, chiika1_src));
+    assert_eq!(actual, expected, "compiled:\n{}", chiika1_src);
+    actual.0
+}
+
+#[test]
+fn test_sync_only() {
+    check(
+        "
+        fun fib(int n) -> int { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } }
+        fun chiika_main() -> int { print(fib(10)); 0 }
+        ",
+    );
+}
+
+#[test]
+fn test_asyncness_propagates_through_callers() {
+    // `a` is async because it calls `b`, which calls `c`, which is async
+    check(
+        "
+        fun a(int n) -> int { print(n); b(n) + 1 }
+        fun b(int n) -> int { c(n) + 1 }
+        fun c(int n) -> int { sleep_sec(n) }
+        fun chiika_main() -> int { print(a(1)); 0 }
+        ",
+    );
+}
+
+#[test]
+fn test_recursive_async_function() {
+    check(
+        "
+        fun countdown(int n) -> int {
+          if n == 0 { 0 } else { print(n); sleep_sec(1); countdown(n - 1) }
+        }
+        fun chiika_main() -> int { countdown(3); 0 }
+        ",
+    );
+}
+
+#[test]
+fn test_mutually_recursive_async_functions() {
+    check(
+        "
+        fun ping(int n) -> int { if n == 0 { 0 } else { print(n); pong(n - 1) } }
+        fun pong(int n) -> int { if n == 0 { 0 } else { sleep_sec(1); ping(n - 1) } }
+        fun chiika_main() -> int { ping(4); 0 }
+        ",
+    );
+}
+
+#[test]
+fn test_mutually_recursive_sync_functions() {
+    check(
+        "
+        fun is_even(int n) -> int { if n == 0 { 1 } else { is_odd(n - 1) } }
+        fun is_odd(int n) -> int { if n == 0 { 0 } else { is_even(n - 1) } }
+        fun chiika_main() -> int { print(is_even(10)); print(is_odd(10)); 0 }
+        ",
+    );
+}
+
+#[test]
+fn test_async_call_in_arguments() {
+    check(
+        "
+        fun add3(int a, int b, int c) -> int { a + b + c }
+        fun chiika_main() -> int {
+          print(add3(sleep_sec(1), 10, sleep_sec(2)));
+          print(sleep_sec(1) + sleep_sec(2));
+          0
+        }
+        ",
+    );
+}
+
+#[test]
+fn test_variables_across_chapters() {
+    check(
+        "
+        fun f(int a) -> int {
+          alloc x;
+          alloc y;
+          x = a;
+          y = sleep_sec(1);
+          x = x + y;
+          sleep_sec(1);
+          y = x + a;
+          x + y
+        }
+        fun chiika_main() -> int { print(f(5)); 0 }
+        ",
+    );
+}
+
+#[test]
+fn test_nested_async_if() {
+    check(
+        "
+        fun f(int a, int b) -> int {
+          alloc r;
+          r = if a == 0 {
+            if b == 0 { sleep_sec(1) } else { b }
+          } else {
+            sleep_sec(2) + a
+          };
+          r + 100
+        }
+        fun chiika_main() -> int {
+          print(f(0, 0)); print(f(0, 7)); print(f(3, 0));
+          0
+        }
+        ",
+    );
+}
+
+#[test]
+fn test_match_with_bindings() {
+    check(
+        "
+        enum Op { Add(int, int), Neg(int), Nop }
+        fun eval(Op op) -> int {
+          match op {
+            Op::Add(a, b) => a + sleep_sec(b),
+            Op::Neg(a) => 0 - a,
+            Op::Nop => sleep_sec(0),
+          }
+        }
+        fun chiika_main() -> int {
+          print(eval(Op::Add(1, 2)));
+          print(eval(Op::Neg(5)));
+          print(eval(Op::Nop));
+          0
+        }
+        ",
+    );
+}
+
+#[test]
+fn test_structs_arrays_and_loops() {
+    check(
+        "
+        struct Acc { int total; array items; }
+        fun chiika_main() -> int {
+          alloc Acc acc;
+          acc = new Acc(0, []);
+          for x in [1, 2, 3] {
+            acc.total = acc.total + x;
+            acc.items.push(x + x);
+          };
+          sleep_sec(1);
+          for y in acc.items { print(y) };
+          acc.items[0] = acc.total;
+          print(acc.items[0]);
+          0
+        }
+        ",
+    );
+}
+
+#[test]
+fn test_result_and_early_return() {
+    check(
+        "
+        fun checked(int n) -> Result {
+          if n < 0 { return Result::Err(\"negative\") };
+          sleep_sec(1);
+          Result::Ok(n + 1)
+        }
+        fun twice(int n) -> Result {
+          alloc a;
+          a = checked(n)?;
+          Result::Ok(checked(a - 5)? + a)
+        }
+        fun show(Result r) -> int {
+          match r { Result::Ok(v) => print(v), Result::Err(e) => print_str(e) }
+        }
+        fun chiika_main() -> int {
+          show(twice(10));
+          show(twice(1));
+          show(twice(0 - 1));
+          0
+        }
+        ",
+    );
+}
+
+#[test]
+fn test_exit_status_of_sync_main() {
+    check("fun chiika_main() -> int { print(1); 3 }");
+}
+
+#[test]
+fn test_exit_status_of_async_main() {
+    check(
+        "
+        fun f(int n) -> int { sleep_sec(n); n + 1 }
+        fun chiika_main() -> int { print(f(1)); f(4) }
+        ",
+    );
+}
+
+#[test]
+fn test_exit() {
+    // Nothing is printed after `chiika_exit`, even by the caller
+    check(
+        "
+        fun check(int n) -> int {
+          if n > 2 { print_str(\"too large\"); chiika_exit(2) } else { 0 };
+          print(n);
+          0
+        }
+        fun chiika_main() -> int { check(1); sleep_sec(1); check(3); print(9); 0 }
+        ",
+    );
+}
+
+#[test]
+fn test_args_and_env_vars() {
+    check_with_args(
+        "
+        extern arg_count() -> int;
+        extern arg(int n) -> string;
+        extern env_var(string name) -> string;
+        fun show_args(int i) -> int {
+          if i < arg_count() { sleep_sec(1); print_str(arg(i)); show_args(i + 1) } else { 0 }
+        }
+        fun chiika_main() -> int {
+          show_args(0);
+          print_str(env_var(\"PATH\"));
+          print_str(env_var(\"CHIIKA_NO_SUCH_VARIABLE\"));
+          arg_count()
+        }
+        ",
+        &["prog", "a", "b c"],
+    );
+}
+
+#[test]
+fn test_tcp_loopback() {
+    // Ports are not printed since they differ between the runs
+    let output = check(
+        "
+        extern_async tcp_listen(string host, int port) -> Result;
+        extern tcp_local_port(int listener) -> int;
+        extern_async tcp_accept(int listener) -> Result;
+        extern_async tcp_connect(string host, int port) -> Result;
+        extern_async tcp_read(int conn, int max_len) -> Result;
+        extern tcp_received(int conn) -> string;
+        extern_async tcp_write(int conn, string data) -> Result;
+        extern tcp_close(int handle) -> int;
+        fun talk() -> Result {
+          alloc listener;
+          alloc client;
+          alloc server;
+          listener = tcp_listen(\"127.0.0.1\", 0)?;
+          client = tcp_connect(\"127.0.0.1\", tcp_local_port(listener))?;
+          server = tcp_accept(listener)?;
+          print(tcp_write(client, \"hello\")?);
+          tcp_read(server, 100)?;
+          print_str(tcp_received(server));
+          tcp_write(server, \"world\")?;
+          tcp_read(client, 100)?;
+          print_str(tcp_received(client));
+          tcp_close(client);
+          print(tcp_read(server, 100)?);
+          tcp_close(server);
+          tcp_close(listener);
+          Result::Ok(0)
+        }
+        fun closed_port() -> Result {
+          alloc listener;
+          alloc port;
+          listener = tcp_listen(\"127.0.0.1\", 0)?;
+          port = tcp_local_port(listener);
+          tcp_close(listener);
+          tcp_connect(\"127.0.0.1\", port)
+        }
+        fun chiika_main() -> int {
+          match talk() { Result::Ok(n) => print(n), Result::Err(e) => print_str(e) };
+          match closed_port() {
+            Result::Ok(_c) => print_str(\"connected\"),
+            Result::Err(_e) => print_str(\"refused\")
+          };
+          0
+        }
+        ",
+    );
+    assert_eq!(output, "5\nhello\nworld\n0\n0\nrefused\n");
+}
+
+#[test]
+fn test_sleep_ms_and_now_ms() {
+    let output = check(
+        "
+        extern_async sleep_ms(int ms) -> int;
+        extern now_ms() -> int;
+        fun chiika_main() -> int {
+          print(now_ms());
+          sleep_ms(250);
+          print(now_ms());
+          sleep_ms(10);
+          print(now_ms());
+          0
+        }
+        ",
+    );
+    assert_eq!(output, "0\n250\n260\n");
+}
+
+#[test]
+fn test_timeout() {
+    // The cancelled calls of `slow` never print and the frames they pushed
+    // to the env are removed
+    let output = check(
+        "
+        extern_async sleep_ms(int ms) -> int;
+        extern now_ms() -> int;
+        extern_async timeout(int ms, $ASYNC_FN(() -> int) f) -> int;
+        fun fast() -> int { sleep_ms(10); print(1); 10 }
+        fun slow() -> int { sleep_ms(100); print(2); 20 }
+        fun nested() -> int { timeout(500, slow) + timeout(50, slow) }
+        fun chiika_main() -> int {
+          alloc x;
+          x = 5;
+          print(timeout(50, fast));
+          print(timeout(50, slow));
+          print(x);
+          print(now_ms());
+          print(timeout(1000, nested));
+          sleep_ms(200);
+          print(now_ms());
+          0
+        }
+        ",
+    );
+    assert_eq!(output, "1\n10\n-1\n5\n60\n2\n19\n410\n");
+}
+
+#[test]
+fn test_interval() {
+    // Missed ticks complete immediately
+    let output = check(
+        "
+        extern_async sleep_ms(int ms) -> int;
+        extern now_ms() -> int;
+        extern interval_new(int ms) -> int;
+        extern_async interval_tick(int interval) -> int;
+        extern interval_free(int interval) -> int;
+        fun tick(int i) -> int { print(interval_tick(i)); print(now_ms()) }
+        fun chiika_main() -> int {
+          alloc i;
+          i = interval_new(100);
+          tick(i);
+          tick(i);
+          sleep_ms(250);
+          tick(i);
+          tick(i);
+          tick(i);
+          interval_free(i)
+        }
+        ",
+    );
+    assert_eq!(output, "1\n0\n2\n100\n3\n350\n4\n350\n5\n400\n");
+}
+
+#[test]
+fn test_select() {
+    // The losing arm is dropped before it prints
+    let output = check(
+        "
+        extern_async sleep_ms(int ms) -> int;
+        extern now_ms() -> int;
+        extern_async timeout(int ms, $ASYNC_FN(() -> int) f) -> int;
+        fun slow(int ms) -> int { print(ms); sleep_ms(ms); print(0 - ms); ms }
+        fun pick(int a, int b) -> int {
+          select {
+            x = slow(a) => x + 1000,
+            y = sleep_ms(b) => { print(now_ms()); y },
+          }
+        }
+        fun stuck() -> int { select { _ = slow(100) => 1, _ = sleep_ms(200) => 2 } }
+        fun chiika_main() -> int {
+          print(pick(10, 20));
+          print(pick(30, 20));
+          print(timeout(50, stuck));
+          sleep_ms(100);
+          print(now_ms());
+          0
+        }
+        ",
+    );
+    assert_eq!(output, "10\n-10\n1010\n30\n30\n20\n100\n-1\n180\n");
+}
+
+#[test]
+fn test_spawn_scope_and_cancel() {
+    let output = check(
+        "
+        extern_async sleep_ms(int ms) -> int;
+        extern now_ms() -> int;
+        extern spawn($ASYNC_FN((int) -> int) f, int arg) -> int;
+        extern cancel(int task) -> int;
+        fun work(int ms) -> int { sleep_ms(ms); print(ms); 0 }
+        fun wait_all() -> int {
+          scope {
+            spawn(work, 30);
+            spawn(work, 10);
+            sleep_ms(20);
+            print(1)
+          }
+        }
+        // The tasks are cancelled by `return`
+        fun leave(int n) -> int {
+          scope {
+            spawn(work, 10);
+            if n == 0 { return 5 };
+            sleep_ms(20);
+            n
+          }
+        }
+        fun chiika_main() -> int {
+          print(wait_all());
+          print(now_ms());
+          print(leave(0));
+          sleep_ms(50);
+          print(leave(2));
+          alloc int task;
+          task = spawn(work, 10);
+          print(cancel(task));
+          print(cancel(task));
+          // Not waited for
+          spawn(work, 5);
+          spawn(work, 1000);
+          sleep_ms(50);
+          print(now_ms());
+          0
+        }
+        ",
+    );
+    assert_eq!(output, "10\n1\n30\n0\n30\n5\n10\n2\n1\n0\n5\n150\n");
+}
+
+#[test]
+fn test_exit_in_task() {
+    let output = check(
+        "
+        extern_async sleep_ms(int ms) -> int;
+        extern spawn($ASYNC_FN((int) -> int) f, int arg) -> int;
+        fun quit(int code) -> int { sleep_ms(10); chiika_exit(code) }
+        fun chiika_main() -> int {
+          spawn(quit, 3);
+          sleep_ms(100);
+          print(1);
+          0
+        }
+        ",
+    );
+    assert_eq!(output, "");
+}
+
+#[test]
+fn test_mutex_and_semaphore() {
+    let output = check(
+        "
+        extern_async sleep_ms(int ms) -> int;
+        extern now_ms() -> int;
+        extern spawn($ASYNC_FN((int) -> int) f, int arg) -> int;
+        extern mutex_new() -> int;
+        extern_async mutex_lock(int m) -> int;
+        extern mutex_unlock(int m) -> int;
+        extern mutex_free(int m) -> int;
+        extern semaphore_new(int permits) -> int;
+        extern_async semaphore_acquire(int s) -> int;
+        extern semaphore_release(int s) -> int;
+        extern semaphore_free(int s) -> int;
+        fun critical(int m) -> int {
+          mutex_lock(m);
+          print(1);
+          sleep_ms(10);
+          print(2);
+          mutex_unlock(m)
+        }
+        // Never unlocks
+        fun hold(int m) -> int { mutex_lock(m); print(now_ms()); 0 }
+        fun limited(int s) -> int {
+          semaphore_acquire(s);
+          print(now_ms());
+          sleep_ms(10);
+          semaphore_release(s)
+        }
+        fun chiika_main() -> int {
+          alloc int m;
+          alloc int s;
+          m = mutex_new();
+          scope {
+            spawn(critical, m);
+            spawn(critical, m)
+          };
+          print(mutex_unlock(m));
+          // The second `hold` gets the lock when the mutex is freed
+          scope {
+            spawn(hold, m);
+            spawn(hold, m);
+            sleep_ms(10);
+            mutex_free(m)
+          };
+          s = semaphore_new(2);
+          scope {
+            spawn(limited, s);
+            spawn(limited, s);
+            spawn(limited, s)
+          };
+          print(semaphore_release(s));
+          semaphore_free(s)
+        }
+        ",
+    );
+    assert_eq!(output, "1\n2\n1\n2\n0\n20\n30\n30\n30\n40\n0\n");
+}
@@ -0,0 +1,40 @@
+use chiika_2::explain::explain;
+
+const SRC: &str = "extern print(int n) -> int;
+extern_async sleep_sec(int n) -> int;
+fun foo(int n) -> int {
+  alloc x;
+  x = n + 1;
+  sleep_sec(0);
+  print(x);
+  x + n
+}
+fun bar() -> int {
+  print(1)
+}
+fun chiika_main() -> int {
+  foo(1)
+}
+";
+
+#[test]
+fn test_explain_async() {
+    let out = explain(SRC, "foo").unwrap();
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], "foo: async (int) -> int (split into 2 chapters)");
+    assert_eq!(lines[1], "env frame: $cont, n, x");
+    assert!(lines
+        .iter()
+        .any(|x| x.starts_with("  sleep_sec(0);  <- chapter 1 |")));
+    assert!(out.contains("// env: push $cont, n, x; set x"));
+    assert!(out.contains("// chapter 1 (of 2): after async call of `sleep_sec' in statement 3"));
+    assert!(out.contains("// env: ref x, n; pop 3 items"));
+}
+
+#[test]
+fn test_explain_sync() {
+    let out = explain(SRC, "bar").unwrap();
+    assert!(out.starts_with("bar: sync () -> int (not split)\n"));
+    assert!(!out.contains("// chapter"));
+    assert!(explain(SRC, "baz").is_err());
+}
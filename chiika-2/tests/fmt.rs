@@ -0,0 +1,151 @@
+use chiika_2::formatter::format;
+
+fn test_files() -> Vec<std::path::PathBuf> {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let mut paths = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|x| x.unwrap().path())
+        .filter(|x| x.extension().is_some_and(|ext| ext == "chiika2"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+}
+
+#[test]
+fn test_keeps_meaning() {
+    for path in test_files() {
+        let src = std::fs::read_to_string(&path).unwrap();
+        let formatted = format(&src).unwrap();
+        assert_eq!(
+            chiika_2::parse(&formatted).unwrap(),
+            chiika_2::parse(&src).unwrap(),
+            "{}",
+            path.display()
+        );
+        assert_eq!(format(&formatted).unwrap(), formatted, "{}", path.display());
+    }
+}
+
+#[test]
+fn test_canonical_style() {
+    let src = "
+extern print(int n) -> int;
+    extern_async sleep_sec(int n)->int;
+fun foo(int a,int b)->int{alloc x;x=a-(b-1);
+  if x<0 {print(  0)}else{ print(x) };
+  x = if a < b { 1 } else { 2 };
+  (a - b) - x;}
+";
+    let expected = "extern print(int n) -> int;
+extern_async sleep_sec(int n) -> int;
+
+fun foo(int a, int b) -> int {
+  alloc x;
+  x = a - (b - 1);
+  if x < 0 {
+    print(0)
+  } else {
+    print(x)
+  };
+  x = if a < b { 1 } else { 2 };
+  a - b - x
+}
+";
+    assert_eq!(format(src).unwrap(), expected);
+}
+
+#[test]
+fn test_comments() {
+    let src = "// Header
+
+extern print(int n) -> int; // prints n
+
+// Entry point
+fun chiika_main() -> int {
+  // Say hello
+  print(1); // one
+  /* multi
+     line */
+  print(2); /* two */
+  if 1 < 2 {
+    // inner
+    print(2)
+  };
+  0
+  // end
+}
+";
+    assert_eq!(format(src).unwrap(), src);
+    // Comments are skipped by the compiler
+    assert_eq!(chiika_2::parse(src).unwrap().len(), 2);
+}
+
+#[test]
+fn test_comments_in_nested_blocks() {
+    let src = "fun f(int x) -> int {
+  match x {
+    _ => {
+      // arm
+      0
+    },
+  };
+  if x < 1 {
+    print(1)
+  } else {
+    // nothing yet
+  };
+  for v in [1, 2] {
+    print(v) // each
+  };
+  x = if x < 1 {
+    // one
+    1
+  } else { 2 };
+  0
+}
+";
+    assert_eq!(format(src).unwrap(), src);
+}
+
+#[test]
+fn test_comment_in_expression() {
+    let src = "fun f() -> int {
+  print(1, // here
+    2)
+}
+";
+    assert!(chiika_2::parse(src).is_ok());
+    assert!(format(src).is_err());
+}
+
+#[test]
+fn test_attributes() {
+    let src = "#[tokio( flavor=\"current_thread\" ,drivers = \"all\")]\n\nfun chiika_main() -> int { 0 }\n";
+    let expected =
+        "#[tokio(flavor = \"current_thread\", drivers = \"all\")]\nfun chiika_main() -> int {\n  0\n}\n";
+    assert_eq!(format(src).unwrap(), expected);
+}
+
+#[test]
+fn test_function_types() {
+    let src = "extern_async timeout(int ms,$ASYNC_FN(( )->int) f)->int;\nextern apply($FN((int,string) -> int) f) -> int;\n";
+    let expected = "extern_async timeout(int ms, $ASYNC_FN(() -> int) f) -> int;\nextern apply($FN((int, string) -> int) f) -> int;\n";
+    assert_eq!(format(src).unwrap(), expected);
+}
+
+#[test]
+fn test_select() {
+    let src = "fun f(int ch) -> int {\n  select { r=chan_recv(ch)=>{ print(1); 2 }, _ = sleep_ms(10) => 0 }\n}\n";
+    let expected = "fun f(int ch) -> int {\n  select {\n    r = chan_recv(ch) => {\n      print(1);\n      2\n    },\n    _ = sleep_ms(10) => 0,\n  }\n}\n";
+    assert_eq!(format(src).unwrap(), expected);
+    assert_eq!(format(expected).unwrap(), expected);
+}
+
+#[test]
+fn test_scope() {
+    let src = "fun f() -> int {\n  scope { spawn(g,1); sleep_ms(10) };\n  0\n}\n";
+    let expected =
+        "fun f() -> int {\n  scope {\n    spawn(g, 1);\n    sleep_ms(10)\n  };\n  0\n}\n";
+    assert_eq!(format(src).unwrap(), expected);
+    assert_eq!(format(expected).unwrap(), expected);
+}
@@ -0,0 +1,318 @@
+//! Property-based tests of the CPS transformation. Random programs are
+//! compiled and checked that
+//!
+//! - the output is well-formed chiika-1 (see `check_well_formed`)
+//! - the output behaves the same as the reference interpreter of chiika-2
+use chiika::{ast, interp, parser};
+use chumsky::Parser;
+use proptest::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Max number of local variables in a function
+const MAX_LOCALS: usize = 3;
+/// Max number of functions in a program
+const MAX_FUNCS: usize = 3;
+/// Number of programs to test unless `PROPTEST_CASES` is set. A program may
+/// take seconds to run, so the default is kept small
+const DEFAULT_CASES: u32 = 8;
+
+/// Expression of a generated program. All values are int
+#[derive(Debug, Clone)]
+enum GenExpr {
+    Num(i64),
+    /// `d` (the recursion depth) or `x`
+    Param(bool),
+    /// Index of a local variable (wrapped by the number of visible locals)
+    Local(usize),
+    Add(Box<GenExpr>, Box<GenExpr>),
+    Sub(Box<GenExpr>, Box<GenExpr>),
+    /// Index of a function (wrapped by the number of functions)
+    Call(usize, Box<GenExpr>),
+    Sleep,
+    Print(Box<GenExpr>),
+    If(Box<GenExpr>, Box<GenExpr>, Box<GenExpr>, Box<GenExpr>),
+}
+
+#[derive(Debug, Clone)]
+struct GenFunc {
+    locals: Vec<GenExpr>,
+    stmts: Vec<GenExpr>,
+    result: GenExpr,
+}
+
+/// A random program. Each function takes the recursion depth `d` and returns
+/// `x` when it reaches zero so that the program always terminates.
+#[derive(Debug, Clone)]
+struct GenProgram {
+    funcs: Vec<GenFunc>,
+}
+
+fn gen_expr() -> impl Strategy<Value = GenExpr> {
+    let leaf = prop_oneof![
+        (0..10i64).prop_map(GenExpr::Num),
+        any::<bool>().prop_map(GenExpr::Param),
+        (0..MAX_LOCALS).prop_map(GenExpr::Local),
+        Just(GenExpr::Sleep),
+    ];
+    leaf.prop_recursive(3, 12, 2, |inner| {
+        prop_oneof![
+            (inner.clone(), inner.clone())
+                .prop_map(|(l, r)| GenExpr::Add(Box::new(l), Box::new(r))),
+            (inner.clone(), inner.clone())
+                .prop_map(|(l, r)| GenExpr::Sub(Box::new(l), Box::new(r))),
+            (0..MAX_FUNCS, inner.clone()).prop_map(|(f, x)| GenExpr::Call(f, Box::new(x))),
+            inner.clone().prop_map(|x| GenExpr::Print(Box::new(x))),
+            (inner.clone(), inner.clone(), inner.clone(), inner).prop_map(|(a, b, c, d)| {
+                GenExpr::If(Box::new(a), Box::new(b), Box::new(c), Box::new(d))
+            }),
+        ]
+    })
+}
+
+fn gen_func() -> impl Strategy<Value = GenFunc> {
+    (
+        prop::collection::vec(gen_expr(), 0..=MAX_LOCALS),
+        prop::collection::vec(gen_expr(), 0..3),
+        gen_expr(),
+    )
+        .prop_map(|(locals, stmts, result)| GenFunc {
+            locals,
+            stmts,
+            result,
+        })
+}
+
+fn gen_program() -> impl Strategy<Value = GenProgram> {
+    prop::collection::vec(gen_func(), 1..=MAX_FUNCS).prop_map(|funcs| GenProgram { funcs })
+}
+
+/// Renders `GenExpr` with the number of functions and visible locals
+struct RenderExpr<'a>(&'a GenExpr, usize, usize);
+
+impl fmt::Display for RenderExpr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let RenderExpr(e, n_funcs, n_locals) = *self;
+        let r = |x| RenderExpr(x, n_funcs, n_locals);
+        match e {
+            GenExpr::Num(n) => write!(f, "{}", n),
+            GenExpr::Param(true) => write!(f, "d"),
+            GenExpr::Param(false) => write!(f, "x"),
+            GenExpr::Local(_) if n_locals == 0 => write!(f, "x"),
+            GenExpr::Local(i) => write!(f, "v{}", i % n_locals),
+            GenExpr::Add(l, rhs) => write!(f, "({} + {})", r(l), r(rhs)),
+            GenExpr::Sub(l, rhs) => write!(f, "({} - {})", r(l), r(rhs)),
+            GenExpr::Call(i, x) => write!(f, "f{}(d - 1, {})", i % n_funcs, r(x)),
+            GenExpr::Sleep => write!(f, "sleep_sec(0)"),
+            GenExpr::Print(x) => write!(f, "print({})", r(x)),
+            GenExpr::If(a, b, then, els) => write!(
+                f,
+                "(if {} < {} {{ {} }} else {{ {} }})",
+                r(a),
+                r(b),
+                r(then),
+                r(els)
+            ),
+        }
+    }
+}
+
+impl fmt::Display for GenProgram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "extern print(int n) -> int;")?;
+        writeln!(f, "extern_async sleep_sec(int n) -> int;")?;
+        let n_funcs = self.funcs.len();
+        for (i, func) in self.funcs.iter().enumerate() {
+            writeln!(f, "fun f{}(int d, int x) -> int {{", i)?;
+            writeln!(f, "  if d < 1 {{ x }} else {{")?;
+            for (j, e) in func.locals.iter().enumerate() {
+                writeln!(f, "    alloc v{};", j)?;
+                writeln!(f, "    v{} = {};", j, RenderExpr(e, n_funcs, j))?;
+            }
+            let n_locals = func.locals.len();
+            for e in &func.stmts {
+                writeln!(f, "    {};", RenderExpr(e, n_funcs, n_locals))?;
+            }
+            writeln!(f, "    {}", RenderExpr(&func.result, n_funcs, n_locals))?;
+            writeln!(f, "  }}")?;
+            writeln!(f, "}}")?;
+        }
+        writeln!(f, "fun chiika_main() -> int {{")?;
+        writeln!(f, "  print(f{}(2, 1));", n_funcs - 1)?;
+        writeln!(f, "  print(f0(1, 5));")?;
+        writeln!(f, "  0")?;
+        writeln!(f, "}}")
+    }
+}
+
+/// Check that
+///
+/// - every variable and function referenced exists (including chapter
+///   functions and `$async_result`)
+/// - chapters of an async function pop as many items as its first chapter
+///   pushes, and do not access the env beyond them
+fn check_well_formed(decls: &[ast::Declaration]) -> Result<(), String> {
+    let mut globals = HashSet::new();
+    let mut funcs = vec![];
+    for decl in decls {
+        match decl {
+            ast::Declaration::Extern(e) => {
+                globals.insert(e.name.clone());
+            }
+            ast::Declaration::Function(f) => {
+                globals.insert(f.name.clone());
+                funcs.push(f);
+            }
+            ast::Declaration::Struct(_) => (),
+        }
+    }
+
+    let mut n_pushes = HashMap::new();
+    for f in &funcs {
+        let mut scope = Scope {
+            locals: f.params.iter().map(|x| x.name.clone()).collect(),
+            env_ops: vec![],
+        };
+        for stmt in &f.body_stmts {
+            check_expr(stmt, &globals, &mut scope).map_err(|e| format!("in {}: {}", f.name, e))?;
+        }
+        n_pushes.insert(f.name.clone(), scope.env_ops);
+    }
+
+    for f in &funcs {
+        let base = chapter_base(&f.name, &globals);
+        let pushed = n_pushes[base]
+            .iter()
+            .filter(|(op, _)| op == "chiika_env_push")
+            .count() as i64;
+        for (op, n) in &n_pushes[&f.name] {
+            let ok = match &op[..] {
+                "chiika_env_push" => true,
+                "chiika_env_pop" => *n == pushed,
+                _ => *n < pushed,
+            };
+            if !ok {
+                return Err(format!(
+                    "in {}: {}(.., {}) but {} pushes {} items",
+                    f.name, op, n, base, pushed
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the name of the original function if `name` is a chapter
+/// function (`foo_1`, `foo_2`, etc.)
+fn chapter_base<'a>(name: &'a str, globals: &HashSet<String>) -> &'a str {
+    match name.rsplit_once('_') {
+        Some((base, idx)) if idx.parse::<usize>().is_ok() && globals.contains(base) => base,
+        _ => name,
+    }
+}
+
+struct Scope {
+    locals: HashSet<String>,
+    /// Name and the second argument of `chiika_env_xx` calls
+    env_ops: Vec<(String, i64)>,
+}
+
+/// Check `e` and collect env operations
+fn check_expr(e: &ast::Expr, globals: &HashSet<String>, scope: &mut Scope) -> Result<(), String> {
+    let check = |x: &ast::Expr, scope: &mut Scope| check_expr(x, globals, scope);
+    match e {
+        ast::Expr::Number(_) | ast::Expr::Str(_) => (),
+        ast::Expr::VarRef(name) => {
+            if !scope.locals.contains(name) && !globals.contains(name) {
+                return Err(format!("undefined variable `{}'", name));
+            }
+        }
+        ast::Expr::OpCall(_, l, r) => {
+            check(l, scope)?;
+            check(r, scope)?;
+        }
+        ast::Expr::FunCall(fexpr, args) => {
+            if let ast::Expr::VarRef(name) = &**fexpr {
+                if name.starts_with("chiika_env_") {
+                    let n = match args.get(1) {
+                        Some(ast::Expr::Number(n)) => *n,
+                        _ => 0,
+                    };
+                    scope.env_ops.push((name.clone(), n));
+                }
+            }
+            check(fexpr, scope)?;
+            for arg in args {
+                check(arg, scope)?;
+            }
+        }
+        ast::Expr::Cast(x, _) | ast::Expr::FieldRef(x, _) | ast::Expr::Return(x) => {
+            check(x, scope)?
+        }
+        ast::Expr::Alloc(name, _) => {
+            scope.locals.insert(name.clone());
+        }
+        ast::Expr::Assign(name, rhs) => {
+            if !scope.locals.contains(name) {
+                return Err(format!("assignment to undeclared variable `{}'", name));
+            }
+            check(rhs, scope)?;
+        }
+        ast::Expr::New(_, args) => {
+            for arg in args {
+                check(arg, scope)?;
+            }
+        }
+        ast::Expr::FieldSet(obj, _, rhs) => {
+            check(obj, scope)?;
+            check(rhs, scope)?;
+        }
+        ast::Expr::While(cond, body) => {
+            check(cond, scope)?;
+            for x in body {
+                check(x, scope)?;
+            }
+        }
+        ast::Expr::If(cond, then, els) => {
+            check(cond, scope)?;
+            for x in then.iter().chain(els) {
+                check(x, scope)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runtime of the reference interpreter, with the paused clock
+fn paused_runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+        .unwrap()
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig {
+        cases: std::env::var("PROPTEST_CASES")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(DEFAULT_CASES),
+        failure_persistence: None,
+        ..ProptestConfig::default()
+    })]
+    #[test]
+    fn test_cps_output(prog in gen_program()) {
+        let src = prog.to_string();
+        let expected = chiika_2::interp::run(&paused_runtime(), chiika_2::parse(&src).unwrap()).unwrap();
+        let chiika1_src = chiika_2::compile_to_chiika1(&src).unwrap();
+        let decls = parser::parser()
+            .parse(chiika1_src.clone())
+            .unwrap_or_else(|e| panic!("{:?}\n{}", e, chiika1_src));
+        if let Err(e) = check_well_formed(&decls) {
+            panic!("{}\nsource:\n{}\ncompiled:\n{}", e, src, chiika1_src);
+        }
+        let actual = interp::run_with_args(decls, &[]).map(|x| x.0).unwrap_or_else(|e| panic!("{}\n{}", e, chiika1_src));
+        prop_assert_eq!(actual, expected, "source:\n{}\ncompiled:\n{}", src, chiika1_src);
+    }
+}
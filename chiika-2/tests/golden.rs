@@ -0,0 +1,175 @@
+//! Golden-file tests. For each `tests/*.chiika2`,
+//!
+//! - the output of the compiler is compared with `tests/*.chiika1`
+//! - the stdout of the program is compared with `tests/*.out`. The program is
+//!   compiled by chiika-1 and linked with chiika_runtime if LLVM (and clang)
+//!   is available; otherwise it is run by the interpreter of chiika-1.
+//!
+//! Run `cargo test --test golden -- --bless` to update the snapshots.
+use anyhow::{anyhow, bail, Context, Result};
+use chiika::{interp, parser};
+use chumsky::Parser;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const CLANG: &str = if cfg!(target_os = "linux") {
+    "clang-16"
+} else {
+    "clang"
+};
+
+struct Options {
+    bless: bool,
+    filters: Vec<String>,
+}
+
+/// Tools needed to build native executables
+struct Native {
+    chiika1: PathBuf,
+    runtime_a: PathBuf,
+}
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let opts = Options {
+        bless: args.iter().any(|x| x == "--bless"),
+        filters: args.into_iter().filter(|x| !x.starts_with('-')).collect(),
+    };
+    let native = match prepare_native() {
+        Ok(x) => Some(x),
+        Err(e) => {
+            println!("note: running programs with the interpreter ({})", e);
+            None
+        }
+    };
+
+    let mut n_failed = 0;
+    let mut n_passed = 0;
+    for path in test_files() {
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+        if !opts.filters.is_empty() && !opts.filters.iter().any(|f| name.contains(f)) {
+            continue;
+        }
+        match run_test(&path, native.as_ref(), opts.bless) {
+            Ok(()) => {
+                println!("test {} ... ok", name);
+                n_passed += 1;
+            }
+            Err(e) => {
+                println!("test {} ... FAILED\n{}", name, e);
+                n_failed += 1;
+            }
+        }
+    }
+    println!("golden: {} passed; {} failed", n_passed, n_failed);
+    if n_failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+fn test_files() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let mut paths = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|x| x.unwrap().path())
+        .filter(|x| x.extension().is_some_and(|ext| ext == "chiika2"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+}
+
+fn run_test(path: &Path, native: Option<&Native>, bless: bool) -> Result<()> {
+    let src = std::fs::read_to_string(path)?;
+    let chiika1_src = chiika_2::compile_to_chiika1(&src)?;
+    check_snapshot(&path.with_extension("chiika1"), &chiika1_src, bless)?;
+
+    let stdout = match native {
+        Some(native) => run_native(native, &chiika1_src)?,
+        None => run_interp(&chiika1_src)?,
+    };
+    check_snapshot(&path.with_extension("out"), &stdout, bless)
+}
+
+fn check_snapshot(path: &Path, actual: &str, bless: bool) -> Result<()> {
+    if bless {
+        std::fs::write(path, actual)?;
+        return Ok(());
+    }
+    let expected = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {} (run with --bless)", path.display()))?;
+    if expected != actual {
+        bail!(
+            "{} does not match\n--- expected\n{}\n--- actual\n{}",
+            path.display(),
+            expected,
+            actual
+        );
+    }
+    Ok(())
+}
+
+fn run_interp(chiika1_src: &str) -> Result<String> {
+    let decls = parser::parser()
+        .parse(chiika1_src)
+        .map_err(|e| anyhow!("failed to parse chiika-1 source: {:?}", e))?;
+    interp::run_with_args(decls, &[]).map(|x| x.0)
+}
+
+/// Build chiika-1 and chiika_runtime
+fn prepare_native() -> Result<Native> {
+    if Command::new(CLANG).arg("--version").output().is_err() {
+        bail!("{} not found", CLANG);
+    }
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+    cargo_build(&root.join("chiika-1"))?;
+    cargo_build(&root.join("chiika_runtime"))?;
+    Ok(Native {
+        chiika1: root.join("chiika-1/target/debug/chiika"),
+        runtime_a: root.join("chiika_runtime/target/debug/libchiika_runtime.a"),
+    })
+}
+
+fn cargo_build(dir: &Path) -> Result<()> {
+    let output = Command::new("cargo")
+        .arg("build")
+        .current_dir(dir)
+        .output()?;
+    if !output.status.success() {
+        bail!("failed to build {}", dir.display());
+    }
+    Ok(())
+}
+
+fn run_native(native: &Native, chiika1_src: &str) -> Result<String> {
+    // chiika-1 writes `../a.bc`
+    let tmp = std::env::temp_dir().join(format!("chiika-golden-{}", std::process::id()));
+    let work = tmp.join("work");
+    std::fs::create_dir_all(&work)?;
+    std::fs::write(work.join("a.chiika1"), chiika1_src)?;
+    run_cmd(
+        Command::new(&native.chiika1)
+            .arg("a.chiika1")
+            .current_dir(&work),
+    )?;
+    run_cmd(
+        Command::new(CLANG)
+            .args(["-lm", "-ldl", "-lpthread", "-o", "a.out", "a.bc"])
+            .arg(&native.runtime_a)
+            .current_dir(&tmp),
+    )?;
+    let stdout = run_cmd(&mut Command::new(tmp.join("a.out")))?;
+    std::fs::remove_dir_all(&tmp)?;
+    Ok(stdout)
+}
+
+fn run_cmd(cmd: &mut Command) -> Result<String> {
+    let output = cmd.output()?;
+    if !output.status.success() {
+        bail!(
+            "{:?} failed:\n{}",
+            cmd,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
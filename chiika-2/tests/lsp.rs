@@ -0,0 +1,64 @@
+use chiika_2::lsp::Analysis;
+use lsp_types::Position;
+
+const SRC: &str = "extern print(int n) -> int;
+extern_async sleep_sec(int n) -> int;
+fun foo() -> int {
+  sleep_sec(1);
+  print(1)
+}
+fun chiika_main() -> int {
+  print(2);
+  foo()
+}
+";
+
+#[test]
+fn test_diagnostics() {
+    assert!(Analysis::new(SRC).diagnostics.is_empty());
+
+    let a = Analysis::new("fun chiika_main() -> int {\n  1 +\n}\n");
+    assert_eq!(a.diagnostics.len(), 1);
+    assert_eq!(a.diagnostics[0].range.start, Position::new(2, 0));
+
+    let a = Analysis::new("fun chiika_main() -> int { bar() }\n");
+    assert!(a.diagnostics[0].message.contains("unknown function: bar"));
+}
+
+#[test]
+fn test_hover() {
+    let a = Analysis::new(SRC);
+    // `foo` in `chiika_main`
+    let hover = a.hover(Position::new(8, 3)).unwrap();
+    assert!(hover.contains("fun foo() -> int"));
+    assert!(hover.contains("async (2 chapters)"));
+    // `print` in `chiika_main`
+    let hover = a.hover(Position::new(7, 7)).unwrap();
+    assert!(hover.contains("extern print(int n) -> int"));
+    assert!(hover.contains("sync"));
+    assert_eq!(a.hover(Position::new(7, 10)), None);
+}
+
+#[test]
+fn test_definition() {
+    let a = Analysis::new(SRC);
+    let range = a.definition(Position::new(3, 4)).unwrap();
+    assert_eq!(range.start, Position::new(1, 0));
+    let range = a.definition(Position::new(8, 2)).unwrap();
+    assert_eq!(range.start, Position::new(2, 0));
+    assert_eq!(range.end, Position::new(5, 1));
+}
+
+#[test]
+fn test_code_lenses() {
+    let a = Analysis::new(SRC);
+    let titles = a
+        .code_lenses()
+        .into_iter()
+        .map(|x| (x.range.start.line, x.command.unwrap().title))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        titles,
+        vec![(2, "2 chapters".to_string()), (6, "2 chapters".to_string())]
+    );
+}
@@ -0,0 +1,82 @@
+//! Tests of `import`. The programs are in `tests/modules/`
+use chiika::{interp, parser};
+use chumsky::Parser;
+use std::path::{Path, PathBuf};
+
+fn module_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/modules")
+        .join(name)
+}
+
+/// Runtime of the reference interpreter, with the paused clock
+fn paused_runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn test_import() {
+    let path = module_path("main.chiika2");
+    let expected =
+        chiika_2::interp::run(&paused_runtime(), chiika_2::module::load(&path).unwrap()).unwrap();
+    assert_eq!(expected, "6\n103\n10\n");
+
+    let chiika1_src = chiika_2::compile_file_to_chiika1(&path).unwrap();
+    // Asyncness is inferred across modules and names are not mixed up
+    assert!(chiika1_src.contains("func math__slow_add($ENV $env"));
+    assert!(chiika1_src.contains("func math__add(int a, int b)"));
+    assert!(chiika1_src.contains("func add(int a, int b)"));
+    assert_eq!(chiika1_src.matches("func util__twice(").count(), 1);
+    let decls = parser::parser()
+        .parse(chiika1_src.clone())
+        .unwrap_or_else(|e| panic!("{:?}\n{}", e, chiika1_src));
+    let actual = interp::run_with_args(decls, &[])
+        .map(|x| x.0)
+        .unwrap_or_else(|e| panic!("{}\n{}", e, chiika1_src));
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn test_import_cycle() {
+    let err = chiika_2::module::load(&module_path("cycle/a.chiika2")).unwrap_err();
+    let msg = err.to_string();
+    assert!(msg.starts_with("import cycle: "), "{}", msg);
+    assert!(msg.contains("b.chiika2 -> "), "{}", msg);
+    assert!(msg.ends_with("a.chiika2"), "{}", msg);
+}
+
+#[test]
+fn test_import_errors() {
+    let dir = std::env::temp_dir().join(format!("chiika-modules-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("m.chiika2"), "fun f() -> int { 1 }\n").unwrap();
+    let main = dir.join("main.chiika2");
+
+    std::fs::write(
+        &main,
+        "import \"m.chiika2\";\nfun chiika_main() -> int { m::g() }\n",
+    )
+    .unwrap();
+    let err = chiika_2::module::load(&main).unwrap_err();
+    assert!(format!("{:#}", err).contains("module `m' has no function `g'"));
+
+    std::fs::write(&main, "import \"none.chiika2\";\n").unwrap();
+    assert!(chiika_2::module::load(&main).is_err());
+
+    // Imports need the path of the file
+    let err = chiika_2::compile_to_chiika1("import \"m.chiika2\";\n").unwrap_err();
+    assert!(err.to_string().contains("without the path"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_formatting_imports() {
+    for name in ["main.chiika2", "lib/math.chiika2"] {
+        let src = std::fs::read_to_string(module_path(name)).unwrap();
+        assert_eq!(chiika_2::formatter::format(&src).unwrap(), src, "{}", name);
+    }
+}
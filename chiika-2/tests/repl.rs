@@ -0,0 +1,50 @@
+use chiika_2::repl::{is_incomplete, Repl};
+
+#[test]
+fn test_repl() {
+    let mut repl = Repl::new().unwrap();
+    assert_eq!(
+        repl.eval("fun foo(int n) -> int { sleep_sec(0); n + 1 }")
+            .unwrap(),
+        "fun foo (async, 2 chapters)"
+    );
+    assert_eq!(repl.eval("foo(1) + 2").unwrap(), "4");
+    // Redefinition
+    assert_eq!(
+        repl.eval("fun foo(int n) -> int { n }").unwrap(),
+        "fun foo (sync)"
+    );
+    assert_eq!(repl.eval("foo(1) + 2").unwrap(), "3");
+    assert_eq!(repl.eval("enum E { A, B(int) }").unwrap(), "enum E");
+    assert_eq!(repl.eval("E::B(foo(3))").unwrap(), "E::B(3)");
+    assert_eq!(repl.eval("alloc x; x = [1, 2]; x").unwrap(), "[1, 2]");
+    // Externs of the prelude
+    assert_eq!(
+        repl.eval("alloc m; m = mutex_new(); mutex_lock(m); mutex_free(m)")
+            .unwrap(),
+        "0"
+    );
+    assert!(repl.eval("bar(1)").is_err());
+    assert!(repl.eval("fun bar( -> int {}").is_err());
+}
+
+#[cfg(feature = "llvm")]
+#[test]
+fn test_repl_jit() {
+    let mut repl = Repl::new().unwrap();
+    repl.eval("fun foo(int n) -> int { sleep_ms(10); n + 1 }")
+        .unwrap();
+    assert_eq!(repl.eval("foo(1) + 2").unwrap(), "4");
+    // Runs on the same tokio runtime
+    assert_eq!(repl.eval("foo(2) + 2").unwrap(), "5");
+    // Not in chiika_runtime
+    repl.eval("extern bar(int n) -> int;").unwrap();
+    assert!(repl.eval("bar(1)").is_err());
+}
+
+#[test]
+fn test_is_incomplete() {
+    assert!(is_incomplete("fun foo() -> int {\n"));
+    assert!(is_incomplete("print(\"}\"\n"));
+    assert!(!is_incomplete("print(1)\n"));
+}
@@ -0,0 +1,54 @@
+//! Command-line arguments and environment variables.
+//!
+//! The generated `main` takes `argc` and `argv` of C and passes them to
+//! `chiika_set_args` before starting the tokio runtime.
+use crate::chiika_result::{from_chiika_string, to_chiika_string};
+use std::ffi::c_char;
+use std::sync::OnceLock;
+
+/// The arguments including the program name
+static ARGS: OnceLock<Vec<String>> = OnceLock::new();
+
+#[no_mangle]
+pub extern "C" fn chiika_set_args(argc: i64, argv: *const *const c_char) -> i64 {
+    // `argc` is `int` of C; the upper bits may be garbage
+    let argc = argc as i32;
+    let args = (0..argc.max(0) as usize)
+        .map(|i| from_chiika_string(unsafe { *argv.add(i) }))
+        .collect();
+    let _ = ARGS.set(args);
+    0
+}
+
+fn args() -> &'static [String] {
+    ARGS.get().map_or(&[], |x| &x[..])
+}
+
+/// Number of the arguments (including the program name).
+#[no_mangle]
+pub extern "C" fn arg_count() -> i64 {
+    args().len() as i64
+}
+
+/// Get the n-th argument (0 is the program name). Terminates the program if
+/// it is out of bounds.
+#[no_mangle]
+pub extern "C" fn arg(n: i64) -> *const c_char {
+    let args = args();
+    if n < 0 || n as usize >= args.len() {
+        eprintln!(
+            "[chiika] argument index out of bounds: the count is {} but the index is {}",
+            args.len(),
+            n
+        );
+        std::process::exit(1);
+    }
+    to_chiika_string(args[n as usize].clone())
+}
+
+/// Get the value of the environment variable. Returns an empty string if it
+/// is not set (or not valid unicode).
+#[no_mangle]
+pub extern "C" fn env_var(name: *const c_char) -> *const c_char {
+    to_chiika_string(std::env::var(from_chiika_string(name)).unwrap_or_default())
+}
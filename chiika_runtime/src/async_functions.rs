@@ -1,24 +1,300 @@
 use crate::chiika_env::ChiikaEnv;
-use crate::VoidFuture;
+use crate::chiika_result::{from_chiika_string, to_chiika_result};
+use crate::future::{call_cont, ChiikaCont, ChiikaFuture, Trampoline};
+use std::cell::RefCell;
+use std::ffi::c_char;
 use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::OnceLock;
 use std::task::Poll;
 use std::time::Duration;
 
-type ChiikaCont = extern "C" fn(env: &mut ChiikaEnv, value: i64);
+/// An async function of chiika-2 without params after the CPS transformation
+type ChiikaAsyncFn = extern "C" fn(env: &mut ChiikaEnv, cont: ChiikaCont) -> Box<ChiikaFuture>;
+
+/// Value of `timeout` when the function did not finish in time. It cannot be
+/// told from the same value returned by the function
+const TIMEOUT_SENTINEL: i64 = -1;
+
+/// When the runtime started (`tokio::time::Instant` to follow the paused
+/// clock)
+static START: OnceLock<tokio::time::Instant> = OnceLock::new();
+
+/// Must be called in the runtime
+pub(crate) fn start_clock() {
+    START.get_or_init(tokio::time::Instant::now);
+}
 
 #[no_mangle]
-#[allow(improper_ctypes_definitions)]
-pub extern "C" fn sleep_sec(env: &'static mut ChiikaEnv, cont: ChiikaCont, n: i64) -> VoidFuture {
-    async fn sleep_sec(n: i64) {
+pub extern "C" fn sleep_sec(
+    env: &'static mut ChiikaEnv,
+    cont: ChiikaCont,
+    n: i64,
+) -> Box<ChiikaFuture> {
+    async fn sleep_sec(n: i64) -> i64 {
         // Hand written part (all the rest will be macro-generated)
         tokio::time::sleep(Duration::from_secs(n as u64)).await;
+        n
     }
-    let mut future = Box::pin(sleep_sec(n));
-    Box::pin(poll_fn(move |ctx| match future.as_mut().poll(ctx) {
-        Poll::Ready(_) => {
-            cont(env, n);
-            Poll::Ready(())
+    call_cont(env, cont, sleep_sec(n))
+}
+
+#[no_mangle]
+pub extern "C" fn sleep_ms(
+    env: &'static mut ChiikaEnv,
+    cont: ChiikaCont,
+    n: i64,
+) -> Box<ChiikaFuture> {
+    call_cont(env, cont, async move {
+        tokio::time::sleep(Duration::from_millis(n.max(0) as u64)).await;
+        n
+    })
+}
+
+/// Milliseconds since the program started. Monotonic
+#[no_mangle]
+pub extern "C" fn now_ms() -> i64 {
+    let start = *START.get_or_init(tokio::time::Instant::now);
+    start.elapsed().as_millis() as i64
+}
+
+/// Result of `f` given to `timeout`. Pointed by the item of the env pushed
+/// before calling `f`
+struct TimeoutSlot {
+    value: Option<i64>,
+}
+
+/// Continuation given to `f` of `timeout`
+extern "C" fn chiika_timeout_done(env: &mut ChiikaEnv, value: i64) -> Box<ChiikaFuture> {
+    // `f` has popped its frame
+    let slot = env.last() as *mut TimeoutSlot;
+    unsafe { (*slot).value = Some(value) };
+    ChiikaFuture::done()
+}
+
+/// Run the async function `f` and call `cont` with its value, or with
+/// `TIMEOUT_SENTINEL` if it does not finish in `ms` milliseconds. In the
+/// latter case `f` is dropped and its frames are removed from the env
+#[no_mangle]
+pub extern "C" fn timeout(
+    env: &'static mut ChiikaEnv,
+    cont: ChiikaCont,
+    ms: i64,
+    f: ChiikaAsyncFn,
+) -> Box<ChiikaFuture> {
+    let depth = env.len();
+    let slot = Box::into_raw(Box::new(TimeoutSlot { value: None }));
+    env.push(slot as i64);
+    let env_ptr = env as *mut ChiikaEnv;
+    let mut inner = Some(Trampoline::new(f(
+        unsafe { &mut *env_ptr },
+        chiika_timeout_done,
+    )));
+    let mut timer = Box::pin(tokio::time::sleep(Duration::from_millis(ms.max(0) as u64)));
+    ChiikaFuture::new(poll_fn(move |ctx| {
+        let env = unsafe { &mut *env_ptr };
+        if let Some(future) = &mut inner {
+            if Pin::new(future).poll(ctx).is_ready() {
+                inner = None;
+            }
         }
-        Poll::Pending => Poll::Pending,
+        let value = if let Some(value) = unsafe { (*slot).value } {
+            env.truncate(depth);
+            value
+        } else if timer.as_mut().poll(ctx).is_ready() {
+            inner = None;
+            env.truncate(depth);
+            TIMEOUT_SENTINEL
+        } else {
+            return Poll::Pending;
+        };
+        drop(unsafe { Box::from_raw(slot) });
+        Poll::Ready(Some(cont(env, value)))
     }))
 }
+
+struct Interval {
+    interval: tokio::time::Interval,
+    ticks: i64,
+}
+
+/// The handle points to this. Shared with the ticks waiting so that
+/// `interval_free` does not free the interval under them
+type IntervalHandle = Rc<RefCell<Interval>>;
+
+fn interval(handle: i64) -> &'static IntervalHandle {
+    unsafe { &*(handle as *const IntervalHandle) }
+}
+
+/// Create an interval which ticks every `ms` milliseconds. The first tick
+/// completes immediately
+#[no_mangle]
+pub extern "C" fn interval_new(ms: i64) -> i64 {
+    let interval = tokio::time::interval(Duration::from_millis(ms.max(1) as u64));
+    let handle: IntervalHandle = Rc::new(RefCell::new(Interval { interval, ticks: 0 }));
+    Box::into_raw(Box::new(handle)) as i64
+}
+
+/// Wait for the next tick of the interval and returns the number of ticks so
+/// far. Missed ticks are completed immediately
+#[no_mangle]
+pub extern "C" fn interval_tick(
+    env: &'static mut ChiikaEnv,
+    cont: ChiikaCont,
+    handle: i64,
+) -> Box<ChiikaFuture> {
+    let interval = interval(handle).clone();
+    call_cont(env, cont, async move {
+        // Borrowed only while polled, as another task may tick it too
+        poll_fn(|ctx| interval.borrow_mut().interval.poll_tick(ctx)).await;
+        let mut interval = interval.borrow_mut();
+        interval.ticks += 1;
+        interval.ticks
+    })
+}
+
+/// Free the interval. The handle must not be used after this (the ticks
+/// already waiting still complete)
+#[no_mangle]
+pub extern "C" fn interval_free(handle: i64) -> i64 {
+    drop(unsafe { Box::from_raw(handle as *mut IntervalHandle) });
+    0
+}
+
+/// Finish the program with the exit status `code`. Never calls `cont`.
+/// (Not named `exit` to avoid clashing with the one of libc)
+#[no_mangle]
+pub extern "C" fn chiika_exit(
+    _env: &'static mut ChiikaEnv,
+    _cont: ChiikaCont,
+    code: i64,
+) -> Box<ChiikaFuture> {
+    crate::request_exit(code);
+    ChiikaFuture::new(std::future::pending())
+}
+
+/// Read an integer from the file. Fallible (returns `Result` of chiika-2)
+#[no_mangle]
+pub extern "C" fn read_int(
+    env: &'static mut ChiikaEnv,
+    cont: ChiikaCont,
+    path: *const c_char,
+) -> Box<ChiikaFuture> {
+    async fn read_int(path: String) -> Result<i64, String> {
+        // Hand written part (all the rest will be macro-generated)
+        let s = tokio::fs::read_to_string(&path)
+            .await
+            .map_err(|e| format!("{}: {}", path, e))?;
+        s.trim().parse().map_err(|e| format!("{}: {}", path, e))
+    }
+    let path = from_chiika_string(path);
+    call_cont(
+        env,
+        cont,
+        async move { to_chiika_result(read_int(path).await) },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use tokio::time::Instant;
+
+    thread_local! {
+        /// Values given to the continuations and when they are called
+        static LOG: RefCell<Vec<(i64, Instant)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn env(env: &mut ChiikaEnv) -> &'static mut ChiikaEnv {
+        unsafe { &mut *(env as *mut ChiikaEnv) }
+    }
+
+    fn log(value: i64) {
+        LOG.with(|x| x.borrow_mut().push((value, Instant::now())));
+    }
+
+    /// Run the chain made by `f` on a runtime with the paused clock and
+    /// returns the log with the milliseconds since the start
+    fn run(f: impl FnOnce(&'static mut ChiikaEnv) -> Box<ChiikaFuture>) -> Vec<(i64, u128)> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        let env = Box::leak(Box::new(ChiikaEnv::new()));
+        let start = runtime.block_on(async {
+            let start = Instant::now();
+            Trampoline::new(f(env)).await;
+            start
+        });
+        LOG.with(|x| {
+            x.borrow()
+                .iter()
+                .map(|(v, t)| (*v, (*t - start).as_millis()))
+                .collect()
+        })
+    }
+
+    extern "C" fn done(env: &mut ChiikaEnv, value: i64) -> Box<ChiikaFuture> {
+        // The slot of `timeout` is removed
+        assert_eq!(env.len(), 0);
+        log(value);
+        ChiikaFuture::done()
+    }
+
+    extern "C" fn sleep_50(e: &mut ChiikaEnv, cont: ChiikaCont) -> Box<ChiikaFuture> {
+        sleep_ms(env(e), cont, 50)
+    }
+
+    extern "C" fn sleep_200(e: &mut ChiikaEnv, cont: ChiikaCont) -> Box<ChiikaFuture> {
+        sleep_ms(env(e), cont, 200)
+    }
+
+    #[test]
+    fn test_timeout_finished() {
+        let log = run(|env| timeout(env, done, 100, sleep_50));
+        assert_eq!(log, vec![(50, 50)]);
+    }
+
+    #[test]
+    fn test_timeout_expired() {
+        let log = run(|env| timeout(env, done, 100, sleep_200));
+        assert_eq!(log, vec![(TIMEOUT_SENTINEL, 100)]);
+    }
+
+    thread_local! {
+        static INTERVAL: RefCell<i64> = const { RefCell::new(0) };
+    }
+
+    // Tick 3 times, sleeping 250ms after the second tick
+    extern "C" fn ticked(e: &mut ChiikaEnv, value: i64) -> Box<ChiikaFuture> {
+        log(value);
+        let handle = INTERVAL.with(|x| *x.borrow());
+        match value {
+            1 => interval_tick(env(e), ticked, handle),
+            2 => sleep_ms(env(e), slept, 250),
+            _ => {
+                interval_free(handle);
+                ChiikaFuture::done()
+            }
+        }
+    }
+
+    extern "C" fn slept(e: &mut ChiikaEnv, _value: i64) -> Box<ChiikaFuture> {
+        let handle = INTERVAL.with(|x| *x.borrow());
+        interval_tick(env(e), ticked, handle)
+    }
+
+    #[test]
+    fn test_interval() {
+        let log = run(|env| {
+            let handle = interval_new(100);
+            INTERVAL.with(|x| *x.borrow_mut() = handle);
+            interval_tick(env, ticked, handle)
+        });
+        // The tick missed while sleeping completes immediately
+        assert_eq!(log, vec![(1, 0), (2, 100), (3, 350)]);
+    }
+}
@@ -0,0 +1,278 @@
+//! Channels between tasks on `tokio::sync`.
+//!
+//! A channel is passed to chiika as an opaque handle (`$any`; `int` in
+//! chiika-2) which is used for both sending and receiving. Values are
+//! integers (or pointers). `send` and `recv` return `Result` of chiika-2,
+//! which is `Err("closed")` if the channel is closed.
+//!
+//! The handle is owned by the program, which frees it with `chan_free`. The
+//! sends and receives already called hold the channel until they finish, so
+//! it can be freed while other tasks are waiting on it.
+use crate::chiika_env::ChiikaEnv;
+use crate::chiika_result::to_chiika_result;
+use crate::future::{call_cont, ChiikaCont, ChiikaFuture};
+use std::cell::RefCell;
+use std::rc::Rc;
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+const CLOSED: &str = "closed";
+
+enum Channel {
+    Mpsc {
+        // `None` if closed
+        sender: RefCell<Option<mpsc::Sender<i64>>>,
+        // Locked while a task is waiting for a value
+        receiver: Mutex<mpsc::Receiver<i64>>,
+    },
+    Oneshot {
+        sender: RefCell<Option<oneshot::Sender<i64>>>,
+        receiver: Mutex<Option<oneshot::Receiver<i64>>>,
+    },
+}
+
+fn channel(handle: i64) -> &'static Rc<Channel> {
+    unsafe { &*(handle as *const Rc<Channel>) }
+}
+
+fn new_handle(ch: Channel) -> i64 {
+    Box::into_raw(Box::new(Rc::new(ch))) as i64
+}
+
+/// Create a channel which buffers at most `capacity` values
+#[no_mangle]
+pub extern "C" fn chan_new(capacity: i64) -> i64 {
+    let (sender, receiver) = mpsc::channel(capacity.max(1) as usize);
+    let ch = Channel::Mpsc {
+        sender: RefCell::new(Some(sender)),
+        receiver: Mutex::new(receiver),
+    };
+    new_handle(ch)
+}
+
+/// Create a channel which sends only one value
+#[no_mangle]
+pub extern "C" fn oneshot_new() -> i64 {
+    let (sender, receiver) = oneshot::channel();
+    let ch = Channel::Oneshot {
+        sender: RefCell::new(Some(sender)),
+        receiver: Mutex::new(Some(receiver)),
+    };
+    new_handle(ch)
+}
+
+/// Send a value. Waits while the buffer is full
+#[no_mangle]
+pub extern "C" fn chan_send(
+    env: &'static mut ChiikaEnv,
+    cont: ChiikaCont,
+    handle: i64,
+    value: i64,
+) -> Box<ChiikaFuture> {
+    let sender = match &**channel(handle) {
+        Channel::Mpsc { sender, .. } => sender.borrow().clone(),
+        Channel::Oneshot { .. } => invalid_handle(handle),
+    };
+    call_cont(env, cont, async move {
+        let result = match sender {
+            Some(s) => s.send(value).await.map(|_| 0).map_err(|_| CLOSED),
+            None => Err(CLOSED),
+        };
+        to_chiika_result(result.map_err(|x| x.to_string()))
+    })
+}
+
+/// Send the value of the oneshot channel. Never waits
+#[no_mangle]
+pub extern "C" fn oneshot_send(handle: i64, value: i64) -> i64 {
+    let Channel::Oneshot { sender, .. } = &**channel(handle) else {
+        invalid_handle(handle)
+    };
+    let result = match sender.borrow_mut().take() {
+        Some(s) => s.send(value).map(|_| 0).map_err(|_| CLOSED),
+        None => Err(CLOSED),
+    };
+    to_chiika_result(result.map_err(|x| x.to_string()))
+}
+
+/// Receive a value. Waits until a value is sent or the channel is closed
+#[no_mangle]
+pub extern "C" fn chan_recv(
+    env: &'static mut ChiikaEnv,
+    cont: ChiikaCont,
+    handle: i64,
+) -> Box<ChiikaFuture> {
+    let ch = channel(handle).clone();
+    call_cont(env, cont, async move {
+        let result = match &*ch {
+            Channel::Mpsc { receiver, .. } => receiver.lock().await.recv().await,
+            Channel::Oneshot { receiver, .. } => match receiver.lock().await.take() {
+                Some(r) => r.await.ok(),
+                None => None,
+            },
+        };
+        to_chiika_result(result.ok_or_else(|| CLOSED.to_string()))
+    })
+}
+
+/// Close the channel. Values already sent can still be received; after
+/// that `chan_recv` returns `Err("closed")`
+#[no_mangle]
+pub extern "C" fn chan_close(handle: i64) -> i64 {
+    match &**channel(handle) {
+        Channel::Mpsc { sender, .. } => drop(sender.borrow_mut().take()),
+        Channel::Oneshot { sender, .. } => drop(sender.borrow_mut().take()),
+    }
+    0
+}
+
+/// Close and free the channel. The handle must not be used after this
+#[no_mangle]
+pub extern "C" fn chan_free(handle: i64) -> i64 {
+    chan_close(handle);
+    drop(unsafe { Box::from_raw(handle as *mut Rc<Channel>) });
+    0
+}
+
+fn invalid_handle(handle: i64) -> ! {
+    eprintln!("[chiika] invalid channel: {:#x}", handle);
+    std::process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_functions::sleep_ms;
+    use crate::chiika_result::from_chiika_result;
+    use crate::future::Trampoline;
+    use std::future::{poll_fn, Future};
+    use std::pin::Pin;
+    use std::task::Poll;
+    use tokio::time::Instant;
+
+    thread_local! {
+        /// Values received and when
+        static LOG: RefCell<Vec<(Result<i64, String>, Instant)>> = const { RefCell::new(Vec::new()) };
+        static CHANNEL: RefCell<i64> = const { RefCell::new(0) };
+        /// Number of values sent
+        static SENT: RefCell<i64> = const { RefCell::new(0) };
+    }
+
+    type Chain = fn(&'static mut ChiikaEnv) -> Box<ChiikaFuture>;
+
+    fn env(env: &mut ChiikaEnv) -> &'static mut ChiikaEnv {
+        unsafe { &mut *(env as *mut ChiikaEnv) }
+    }
+
+    fn ch() -> i64 {
+        CHANNEL.with(|x| *x.borrow())
+    }
+
+    /// Run the chains concurrently on a runtime with the paused clock and
+    /// returns the log with the milliseconds since the start
+    fn run(new_channel: fn() -> i64, chains: &[Chain]) -> Vec<(Result<i64, String>, u128)> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        let start = runtime.block_on(async {
+            let start = Instant::now();
+            CHANNEL.with(|x| *x.borrow_mut() = new_channel());
+            let mut futures = chains
+                .iter()
+                .map(|f| Trampoline::new(f(Box::leak(Box::new(ChiikaEnv::new())))))
+                .collect::<Vec<_>>();
+            poll_fn(|ctx| {
+                futures.retain_mut(|f| Pin::new(f).poll(ctx).is_pending());
+                if futures.is_empty() {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            })
+            .await;
+            start
+        });
+        LOG.with(|x| {
+            x.borrow()
+                .iter()
+                .map(|(r, t)| (r.clone(), (*t - start).as_millis()))
+                .collect()
+        })
+    }
+
+    // Receive until the channel is closed, taking 10ms for each value
+    fn receiver(env: &'static mut ChiikaEnv) -> Box<ChiikaFuture> {
+        chan_recv(env, received, ch())
+    }
+
+    extern "C" fn received(e: &mut ChiikaEnv, result: i64) -> Box<ChiikaFuture> {
+        let result = from_chiika_result(result);
+        LOG.with(|x| x.borrow_mut().push((result.clone(), Instant::now())));
+        match result {
+            Ok(_) => sleep_ms(env(e), processed, 10),
+            Err(_) => ChiikaFuture::done(),
+        }
+    }
+
+    extern "C" fn processed(e: &mut ChiikaEnv, _value: i64) -> Box<ChiikaFuture> {
+        receiver(env(e))
+    }
+
+    // Send 1, 2 and 3 and then close the channel
+    fn sender(env: &'static mut ChiikaEnv) -> Box<ChiikaFuture> {
+        let n = SENT.with(|x| {
+            *x.borrow_mut() += 1;
+            *x.borrow()
+        });
+        chan_send(env, sent, ch(), n)
+    }
+
+    extern "C" fn sent(e: &mut ChiikaEnv, result: i64) -> Box<ChiikaFuture> {
+        assert_eq!(from_chiika_result(result), Ok(0));
+        if SENT.with(|x| *x.borrow()) == 3 {
+            chan_close(ch());
+            ChiikaFuture::done()
+        } else {
+            sender(env(e))
+        }
+    }
+
+    #[test]
+    fn test_send_and_recv() {
+        let log = run(|| chan_new(1), &[receiver, sender]);
+        let closed = Err(CLOSED.to_string());
+        // The values sent before closing are still received
+        assert_eq!(
+            log,
+            vec![(Ok(1), 0), (Ok(2), 10), (Ok(3), 20), (closed, 30)]
+        );
+    }
+
+    #[test]
+    fn test_oneshot() {
+        fn send(_env: &'static mut ChiikaEnv) -> Box<ChiikaFuture> {
+            assert_eq!(from_chiika_result(oneshot_send(ch(), 42)), Ok(0));
+            assert!(from_chiika_result(oneshot_send(ch(), 43)).is_err());
+            ChiikaFuture::done()
+        }
+        let log = run(|| oneshot_new(), &[receiver, send]);
+        assert_eq!(log, vec![(Ok(42), 0), (Err(CLOSED.to_string()), 10)]);
+    }
+
+    // Free the channel after 10ms, while `receiver` is waiting on it
+    fn freer(env: &'static mut ChiikaEnv) -> Box<ChiikaFuture> {
+        sleep_ms(env, freed, 10)
+    }
+
+    extern "C" fn freed(_e: &mut ChiikaEnv, _value: i64) -> Box<ChiikaFuture> {
+        chan_free(ch());
+        ChiikaFuture::done()
+    }
+
+    #[test]
+    fn test_free_while_receiving() {
+        let log = run(|| chan_new(1), &[receiver, freer]);
+        assert_eq!(log, vec![(Err(CLOSED.to_string()), 10)]);
+    }
+}
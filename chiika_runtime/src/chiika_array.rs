@@ -0,0 +1,57 @@
+#[derive(Debug)]
+pub struct ChiikaArray {
+    // Element is either 64-bit integer or 64-bit pointer.
+    items: Vec<i64>,
+}
+
+impl ChiikaArray {
+    /// Returns `idx` as usize or terminates the program if it is out of bounds.
+    fn check_index(&self, idx: i64) -> usize {
+        if idx < 0 || idx as usize >= self.items.len() {
+            eprintln!(
+                "[chiika] array index out of bounds: the len is {} but the index is {}",
+                self.items.len(),
+                idx
+            );
+            std::process::exit(1);
+        }
+        idx as usize
+    }
+}
+
+/// Create an empty array.
+#[no_mangle]
+pub extern "C" fn chiika_array_new() -> *mut ChiikaArray {
+    Box::into_raw(Box::new(ChiikaArray { items: vec![] }))
+}
+
+/// Append an item to the array and returns the array itself.
+#[no_mangle]
+pub extern "C" fn chiika_array_push(arr: *mut ChiikaArray, item: i64) -> *mut ChiikaArray {
+    unsafe {
+        (*arr).items.push(item);
+    }
+    arr
+}
+
+/// Get the idx-th item.
+#[no_mangle]
+pub extern "C" fn chiika_array_get(arr: *mut ChiikaArray, idx: i64) -> i64 {
+    let arr = unsafe { &*arr };
+    arr.items[arr.check_index(idx)]
+}
+
+/// Set the idx-th item.
+#[no_mangle]
+pub extern "C" fn chiika_array_set(arr: *mut ChiikaArray, idx: i64, item: i64) -> i64 {
+    let arr = unsafe { &mut *arr };
+    let i = arr.check_index(idx);
+    arr.items[i] = item;
+    0
+}
+
+/// Returns the number of the items.
+#[no_mangle]
+pub extern "C" fn chiika_array_len(arr: *mut ChiikaArray) -> i64 {
+    unsafe { (*arr).items.len() as i64 }
+}
@@ -9,6 +9,23 @@ impl ChiikaEnv {
     pub fn new() -> ChiikaEnv {
         ChiikaEnv { stack: vec![] }
     }
+
+    pub fn len(&self) -> usize {
+        self.stack.len()
+    }
+
+    pub fn push(&mut self, item: i64) {
+        self.stack.push(item);
+    }
+
+    pub fn last(&self) -> i64 {
+        *self.stack.last().unwrap()
+    }
+
+    /// Remove the items above the first `len` items
+    pub fn truncate(&mut self, len: usize) {
+        self.stack.truncate(len);
+    }
 }
 
 /// Push an item to the stack.
@@ -37,3 +54,12 @@ pub extern "C" fn chiika_env_ref(env: *mut ChiikaEnv, n: i64) -> i64 {
     let stack = unsafe { &(*env).stack };
     stack[stack.len() - 1 - (n as usize)]
 }
+
+/// Overwrite the n-th item (from the stack top)
+#[no_mangle]
+pub extern "C" fn chiika_env_set(env: *mut ChiikaEnv, n: i64, item: i64) -> i64 {
+    let stack = unsafe { &mut (*env).stack };
+    let len = stack.len();
+    stack[len - 1 - (n as usize)] = item;
+    0
+}
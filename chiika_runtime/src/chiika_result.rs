@@ -0,0 +1,55 @@
+use std::ffi::{c_char, CStr, CString};
+
+/// Memory layout of the values of chiika-2's built-in `Result` enum
+/// (`enum Result { Ok(int), Err(string) }`)
+#[repr(C)]
+#[derive(Debug)]
+pub struct ChiikaResult {
+    tag: i64,
+    value: i64,
+}
+
+const TAG_OK: i64 = 0;
+const TAG_ERR: i64 = 1;
+
+/// Convert the result of a fallible function into a `Result` value of chiika-2
+/// (returned as `$any`)
+pub fn to_chiika_result(result: Result<i64, String>) -> i64 {
+    let r = match result {
+        Ok(n) => ChiikaResult {
+            tag: TAG_OK,
+            value: n,
+        },
+        Err(msg) => ChiikaResult {
+            tag: TAG_ERR,
+            value: to_chiika_string(msg) as i64,
+        },
+    };
+    Box::into_raw(Box::new(r)) as i64
+}
+
+/// Convert a Rust string into `string` of chiika (NUL-terminated)
+pub fn to_chiika_string(s: String) -> *const c_char {
+    let bytes = s
+        .into_bytes()
+        .into_iter()
+        .filter(|b| *b != 0)
+        .collect::<Vec<_>>();
+    CString::new(bytes).unwrap().into_raw()
+}
+
+/// Copy `string` of chiika into a Rust string
+pub fn from_chiika_string(s: *const c_char) -> String {
+    unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned()
+}
+
+/// Read a `Result` value made by `to_chiika_result` (for tests; the value is
+/// not freed)
+#[cfg(test)]
+pub(crate) fn from_chiika_result(r: i64) -> Result<i64, String> {
+    let r = unsafe { &*(r as *const ChiikaResult) };
+    match r.tag {
+        TAG_OK => Ok(r.value),
+        _ => Err(from_chiika_string(r.value as *const c_char)),
+    }
+}
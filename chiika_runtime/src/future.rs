@@ -0,0 +1,133 @@
+//! The futures passed between chiika and the runtime.
+//!
+//! `$FUTURE` of chiika-1 is `Box<ChiikaFuture>`, a thin pointer. An async
+//! extern returns a future which waits for the operation and then calls the
+//! continuation. The continuation returns the future of the next async call
+//! (or of the caller's continuation), which is not polled there but yielded
+//! as the output; `Trampoline` runs it next. So the async calls of a chain
+//! are run one after another without nesting, however long the chain is.
+use crate::chiika_env::ChiikaEnv;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+pub struct ChiikaFuture(Pin<Box<dyn Future<Output = Option<Box<ChiikaFuture>>>>>);
+
+/// Continuation of an async call in the compiled code
+pub type ChiikaCont = extern "C" fn(env: &mut ChiikaEnv, value: i64) -> Box<ChiikaFuture>;
+
+impl ChiikaFuture {
+    /// `future` yields the future to run next, or `None` at the end of the
+    /// chain
+    pub fn new(future: impl Future<Output = Option<Box<ChiikaFuture>>> + 'static) -> Box<Self> {
+        Box::new(ChiikaFuture(Box::pin(future)))
+    }
+
+    /// The end of a chain (e.g. returned by the continuation of
+    /// `chiika_main`)
+    pub fn done() -> Box<Self> {
+        Self::new(std::future::ready(None))
+    }
+}
+
+/// Run `future` and call `cont` with the value
+pub(crate) fn call_cont(
+    env: &'static mut ChiikaEnv,
+    cont: ChiikaCont,
+    future: impl Future<Output = i64> + 'static,
+) -> Box<ChiikaFuture> {
+    ChiikaFuture::new(async move {
+        let value = future.await;
+        Some(cont(env, value))
+    })
+}
+
+/// Runs a chain of `ChiikaFuture`s until the end. Ready forever after that
+pub(crate) struct Trampoline {
+    current: Option<Box<ChiikaFuture>>,
+}
+
+impl Trampoline {
+    pub(crate) fn new(future: Box<ChiikaFuture>) -> Self {
+        Trampoline {
+            current: Some(future),
+        }
+    }
+}
+
+impl Future for Trampoline {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+        while let Some(future) = &mut self.current {
+            match future.0.as_mut().poll(ctx) {
+                Poll::Ready(next) => self.current = next,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    thread_local! {
+        /// Values given to the continuations and when they are called
+        static LOG: RefCell<Vec<(i64, Instant)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn sleep(env: &mut ChiikaEnv, cont: ChiikaCont, ms: i64) -> Box<ChiikaFuture> {
+        let env = unsafe { &mut *(env as *mut ChiikaEnv) };
+        call_cont(env, cont, async move {
+            tokio::time::sleep(Duration::from_millis(ms as u64)).await;
+            ms
+        })
+    }
+
+    fn log(value: i64) {
+        LOG.with(|x| x.borrow_mut().push((value, Instant::now())));
+    }
+
+    // `sleep(10); sleep(20); sleep(30)` after the CPS transformation
+    extern "C" fn step1(env: &mut ChiikaEnv, value: i64) -> Box<ChiikaFuture> {
+        log(value);
+        sleep(env, step2, 20)
+    }
+
+    extern "C" fn step2(env: &mut ChiikaEnv, value: i64) -> Box<ChiikaFuture> {
+        log(value);
+        sleep(env, step3, 30)
+    }
+
+    extern "C" fn step3(_env: &mut ChiikaEnv, value: i64) -> Box<ChiikaFuture> {
+        log(value);
+        ChiikaFuture::done()
+    }
+
+    #[test]
+    fn test_sequential_calls() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        let mut env = ChiikaEnv::new();
+        let start = runtime.block_on(async {
+            let start = Instant::now();
+            Trampoline::new(sleep(&mut env, step1, 10)).await;
+            start
+        });
+        let log: Vec<(i64, u128)> = LOG.with(|x| {
+            x.borrow()
+                .iter()
+                .map(|(v, t)| (*v, (*t - start).as_millis()))
+                .collect()
+        });
+        assert_eq!(log, vec![(10, 10), (20, 30), (30, 60)]);
+    }
+}
@@ -1,10 +1,23 @@
+mod args;
+mod channel;
+mod chiika_array;
 mod chiika_env;
+mod chiika_result;
+mod future;
+mod lock;
+pub mod repl;
+mod tokio_config;
 use crate::chiika_env::ChiikaEnv;
+use crate::future::{ChiikaFuture, Trampoline};
 mod async_functions;
+mod select;
 mod sync_functions;
+mod task;
+mod tcp;
 use std::ffi::c_void;
 use std::future::{poll_fn, Future};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::task::Poll;
 
 //async fn read(_: i64) -> i64 {
@@ -19,38 +32,83 @@ use std::task::Poll;
 //    0
 //}
 
-pub type VoidFuture = Pin<Box<dyn Future<Output = ()>>>;
-#[allow(improper_ctypes_definitions)]
-type ChiikaCont = extern "C" fn(env: *mut ChiikaEnv, value: *mut c_void) -> VoidFuture;
+type ChiikaCont = extern "C" fn(env: *mut ChiikaEnv, value: *mut c_void) -> Box<ChiikaFuture>;
 
+/// `chiika_start_user` of the program: starts `chiika_main` with the env and
+/// the continuation to call with its value
+pub type StartUser =
+    unsafe extern "C" fn(env: *mut ChiikaEnv, cont: ChiikaCont) -> Box<ChiikaFuture>;
+
+// Defined by the program linked with the staticlib. Not available when the
+// runtime is linked into the REPL of chiika-2, which gives the
+// `chiika_start_user` of each input to `repl::Session::run` instead
+#[cfg(not(feature = "repl"))]
 #[allow(improper_ctypes)]
 extern "C" {
-    fn chiika_start_user(env: *mut ChiikaEnv, cont: ChiikaCont) -> VoidFuture;
+    fn chiika_start_user(env: *mut ChiikaEnv, cont: ChiikaCont) -> Box<ChiikaFuture>;
+}
+
+/// Exit status of the process; the value of `chiika_main` or the argument of
+/// `chiika_exit`
+static EXIT_CODE: AtomicI64 = AtomicI64::new(0);
+/// Set by `chiika_exit` to stop the runtime
+static EXITING: AtomicBool = AtomicBool::new(false);
+
+/// Called with the value of `chiika_main`
+pub extern "C" fn chiika_finish(_env: *mut ChiikaEnv, value: *mut c_void) -> Box<ChiikaFuture> {
+    if !EXITING.load(Ordering::SeqCst) {
+        EXIT_CODE.store(value as i64, Ordering::SeqCst);
+    }
+    ChiikaFuture::done()
 }
 
-#[allow(improper_ctypes_definitions)]
-pub extern "C" fn chiika_finish(_env: *mut ChiikaEnv, _: *mut c_void) -> VoidFuture {
-    Box::pin(poll_fn(|_context| Poll::Ready(())))
+/// Stop the runtime with the exit status `code`. Pending futures are dropped
+/// without being polled again
+pub(crate) fn request_exit(code: i64) {
+    if !EXITING.swap(true, Ordering::SeqCst) {
+        EXIT_CODE.store(code, Ordering::SeqCst);
+    }
 }
 
+#[cfg(not(feature = "repl"))]
 #[no_mangle]
 pub extern "C" fn chiika_start_tokio(_: i64) -> i64 {
+    let config = tokio_config::TokioConfig::load().unwrap_or_else(|e| {
+        eprintln!("chiika: invalid tokio setting: {}", e);
+        std::process::exit(1)
+    });
+    let runtime = config.build().unwrap();
+    run_user(&runtime, chiika_start_user);
+    // Drop the tasks left (if any) before returning the status
+    drop(runtime);
+
+    EXIT_CODE.load(Ordering::SeqCst)
+}
+
+/// Run the program started by `start` on `runtime` until `chiika_main`
+/// finishes or `chiika_exit` is called. The status is left in `EXIT_CODE`
+fn run_user(runtime: &tokio::runtime::Runtime, start: StartUser) {
+    EXIT_CODE.store(0, Ordering::SeqCst);
+    EXITING.store(false, Ordering::SeqCst);
     let mut env = ChiikaEnv::new();
     let mut future: Option<_> = None;
+    let mut tasks = task::Tasks::default();
+    // Like `block_on`, the spawned tasks are dropped when `chiika_main`
+    // finishes
     let poller = poll_fn(move |context| {
         if future.is_none() {
-            future = Some(unsafe { chiika_start_user(&mut env, chiika_finish) });
+            async_functions::start_clock();
+            let f = unsafe { start(&mut env, chiika_finish) };
+            future = Some(Trampoline::new(f));
+        }
+        let poll = Pin::new(future.as_mut().unwrap()).poll(context);
+        if poll.is_pending() {
+            tasks.poll(context);
         }
-        future.as_mut().unwrap().as_mut().poll(context)
+        if EXITING.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        poll
     });
-    tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .unwrap()
-        .block_on(poller);
-
-    // Q: Need this?
-    // sleep(Duration::from_millis(50)).await;
-
-    0
+    runtime.block_on(poller);
 }
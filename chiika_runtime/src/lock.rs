@@ -0,0 +1,290 @@
+//! Mutexes and semaphores on `tokio::sync`.
+//!
+//! Like channels, they are opaque handles (`$any`; `int` in chiika-2).
+//! Locking and acquiring are async; unlocking and releasing are not, so a
+//! lock is not tied to the function which took it. Unlocking a mutex which
+//! is not locked, or releasing more permits than acquired, does nothing and
+//! returns 0.
+//!
+//! The handle is owned by the program, which frees it with `mutex_free` or
+//! `semaphore_free`. The locks and acquires already called hold the mutex
+//! or the semaphore until they finish.
+use crate::chiika_env::ChiikaEnv;
+use crate::future::{call_cont, ChiikaCont, ChiikaFuture};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::Arc;
+use tokio::sync::{Mutex, OwnedMutexGuard, Semaphore};
+
+struct ChiikaMutex {
+    mutex: Arc<Mutex<()>>,
+    // `Some` while locked
+    guard: RefCell<Option<OwnedMutexGuard<()>>>,
+}
+
+fn mutex(handle: i64) -> &'static Rc<ChiikaMutex> {
+    unsafe { &*(handle as *const Rc<ChiikaMutex>) }
+}
+
+struct ChiikaSemaphore {
+    semaphore: Semaphore,
+    // Number of the permits acquired and not released yet
+    acquired: Cell<i64>,
+}
+
+fn semaphore(handle: i64) -> &'static Rc<ChiikaSemaphore> {
+    unsafe { &*(handle as *const Rc<ChiikaSemaphore>) }
+}
+
+#[no_mangle]
+pub extern "C" fn mutex_new() -> i64 {
+    let m = ChiikaMutex {
+        mutex: Arc::new(Mutex::new(())),
+        guard: RefCell::new(None),
+    };
+    Box::into_raw(Box::new(Rc::new(m))) as i64
+}
+
+/// Wait until the mutex is unlocked and lock it
+#[no_mangle]
+pub extern "C" fn mutex_lock(
+    env: &'static mut ChiikaEnv,
+    cont: ChiikaCont,
+    handle: i64,
+) -> Box<ChiikaFuture> {
+    let m = mutex(handle).clone();
+    call_cont(env, cont, async move {
+        let guard = m.mutex.clone().lock_owned().await;
+        *m.guard.borrow_mut() = Some(guard);
+        0
+    })
+}
+
+/// Unlock the mutex. Returns 1 if it was locked, 0 otherwise
+#[no_mangle]
+pub extern "C" fn mutex_unlock(handle: i64) -> i64 {
+    mutex(handle).guard.borrow_mut().take().is_some() as i64
+}
+
+/// Unlock and free the mutex. The handle must not be used after this
+#[no_mangle]
+pub extern "C" fn mutex_free(handle: i64) -> i64 {
+    mutex_unlock(handle);
+    drop(unsafe { Box::from_raw(handle as *mut Rc<ChiikaMutex>) });
+    0
+}
+
+/// Create a semaphore with `permits` permits
+#[no_mangle]
+pub extern "C" fn semaphore_new(permits: i64) -> i64 {
+    let s = ChiikaSemaphore {
+        semaphore: Semaphore::new(permits.max(0) as usize),
+        acquired: Cell::new(0),
+    };
+    Box::into_raw(Box::new(Rc::new(s))) as i64
+}
+
+/// Wait until a permit is available and take it
+#[no_mangle]
+pub extern "C" fn semaphore_acquire(
+    env: &'static mut ChiikaEnv,
+    cont: ChiikaCont,
+    handle: i64,
+) -> Box<ChiikaFuture> {
+    let s = semaphore(handle).clone();
+    call_cont(env, cont, async move {
+        // The semaphore is never closed. The permit is given back by
+        // `semaphore_release`
+        s.semaphore.acquire().await.unwrap().forget();
+        s.acquired.set(s.acquired.get() + 1);
+        0
+    })
+}
+
+/// Give back a permit. Returns 1 if a permit was acquired, 0 otherwise
+#[no_mangle]
+pub extern "C" fn semaphore_release(handle: i64) -> i64 {
+    let s = semaphore(handle);
+    if s.acquired.get() == 0 {
+        return 0;
+    }
+    s.acquired.set(s.acquired.get() - 1);
+    s.semaphore.add_permits(1);
+    1
+}
+
+/// Give back the permits acquired and free the semaphore. The handle must
+/// not be used after this
+#[no_mangle]
+pub extern "C" fn semaphore_free(handle: i64) -> i64 {
+    let s = semaphore(handle);
+    s.semaphore.add_permits(s.acquired.replace(0) as usize);
+    drop(unsafe { Box::from_raw(handle as *mut Rc<ChiikaSemaphore>) });
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_functions::sleep_ms;
+    use crate::future::Trampoline;
+    use std::future::{poll_fn, Future};
+    use std::pin::Pin;
+    use std::task::Poll;
+    use tokio::time::Instant;
+
+    thread_local! {
+        /// When the lock or the permit is taken
+        static LOG: RefCell<Vec<Instant>> = const { RefCell::new(Vec::new()) };
+        static HANDLE: RefCell<i64> = const { RefCell::new(0) };
+        /// Whether `freer` frees a mutex or a semaphore
+        static HANDLE_IS_MUTEX: RefCell<bool> = const { RefCell::new(false) };
+    }
+
+    type Chain = fn(&'static mut ChiikaEnv) -> Box<ChiikaFuture>;
+
+    fn env(env: &mut ChiikaEnv) -> &'static mut ChiikaEnv {
+        unsafe { &mut *(env as *mut ChiikaEnv) }
+    }
+
+    fn handle() -> i64 {
+        HANDLE.with(|x| *x.borrow())
+    }
+
+    /// Run the chains concurrently on a runtime with the paused clock and
+    /// returns the log in milliseconds since the start
+    fn run(new_handle: fn() -> i64, chains: &[Chain]) -> Vec<u128> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        let start = runtime.block_on(async {
+            let start = Instant::now();
+            HANDLE.with(|x| *x.borrow_mut() = new_handle());
+            let mut futures = chains
+                .iter()
+                .map(|f| Trampoline::new(f(Box::leak(Box::new(ChiikaEnv::new())))))
+                .collect::<Vec<_>>();
+            poll_fn(|ctx| {
+                futures.retain_mut(|f| Pin::new(f).poll(ctx).is_pending());
+                if futures.is_empty() {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            })
+            .await;
+            start
+        });
+        LOG.with(|x| {
+            x.borrow()
+                .iter()
+                .map(|t| (*t - start).as_millis())
+                .collect()
+        })
+    }
+
+    // Lock the mutex for 10ms
+    fn critical(env: &'static mut ChiikaEnv) -> Box<ChiikaFuture> {
+        mutex_lock(env, locked, handle())
+    }
+
+    extern "C" fn locked(e: &mut ChiikaEnv, _value: i64) -> Box<ChiikaFuture> {
+        LOG.with(|x| x.borrow_mut().push(Instant::now()));
+        sleep_ms(env(e), unlock, 10)
+    }
+
+    extern "C" fn unlock(_e: &mut ChiikaEnv, _value: i64) -> Box<ChiikaFuture> {
+        assert_eq!(mutex_unlock(handle()), 1);
+        ChiikaFuture::done()
+    }
+
+    #[test]
+    fn test_mutex() {
+        let log = run(|| mutex_new(), &[critical, critical, critical]);
+        assert_eq!(log, vec![0, 10, 20]);
+        assert_eq!(mutex_unlock(handle()), 0);
+        mutex_free(handle());
+    }
+
+    // Lock the mutex and never unlock it
+    fn holder(env: &'static mut ChiikaEnv) -> Box<ChiikaFuture> {
+        mutex_lock(env, held, handle())
+    }
+
+    extern "C" fn held(_e: &mut ChiikaEnv, _value: i64) -> Box<ChiikaFuture> {
+        LOG.with(|x| x.borrow_mut().push(Instant::now()));
+        ChiikaFuture::done()
+    }
+
+    // Free the handle after 10ms, while the others are waiting on it
+    fn freer(env: &'static mut ChiikaEnv) -> Box<ChiikaFuture> {
+        sleep_ms(env, freed, 10)
+    }
+
+    extern "C" fn freed(_e: &mut ChiikaEnv, _value: i64) -> Box<ChiikaFuture> {
+        if HANDLE_IS_MUTEX.with(|x| *x.borrow()) {
+            mutex_free(handle());
+        } else {
+            semaphore_free(handle());
+        }
+        ChiikaFuture::done()
+    }
+
+    #[test]
+    fn test_mutex_free_while_locking() {
+        HANDLE_IS_MUTEX.with(|x| *x.borrow_mut() = true);
+        // The second lock is taken when the mutex is freed
+        let log = run(|| mutex_new(), &[holder, holder, freer]);
+        assert_eq!(log, vec![0, 10]);
+    }
+
+    // Hold a permit for 10ms
+    fn limited(env: &'static mut ChiikaEnv) -> Box<ChiikaFuture> {
+        semaphore_acquire(env, acquired, handle())
+    }
+
+    extern "C" fn acquired(e: &mut ChiikaEnv, _value: i64) -> Box<ChiikaFuture> {
+        LOG.with(|x| x.borrow_mut().push(Instant::now()));
+        sleep_ms(env(e), release, 10)
+    }
+
+    extern "C" fn release(_e: &mut ChiikaEnv, _value: i64) -> Box<ChiikaFuture> {
+        assert_eq!(semaphore_release(handle()), 1);
+        ChiikaFuture::done()
+    }
+
+    #[test]
+    fn test_semaphore() {
+        // Releasing without acquiring does not add permits
+        fn new_semaphore() -> i64 {
+            let s = semaphore_new(2);
+            assert_eq!(semaphore_release(s), 0);
+            s
+        }
+        let log = run(
+            new_semaphore,
+            &[limited, limited, limited, limited, limited],
+        );
+        assert_eq!(log, vec![0, 0, 10, 10, 20]);
+        assert_eq!(semaphore_release(handle()), 0);
+        semaphore_free(handle());
+    }
+
+    // Acquire a permit and never release it
+    fn acquirer(env: &'static mut ChiikaEnv) -> Box<ChiikaFuture> {
+        semaphore_acquire(env, held, handle())
+    }
+
+    #[test]
+    fn test_semaphore_free_while_acquiring() {
+        HANDLE_IS_MUTEX.with(|x| *x.borrow_mut() = false);
+        // The permits are given back when the semaphore is freed
+        let log = run(
+            || semaphore_new(2),
+            &[acquirer, acquirer, acquirer, acquirer, freer],
+        );
+        assert_eq!(log, vec![0, 0, 10, 10]);
+    }
+}
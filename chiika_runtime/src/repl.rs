@@ -0,0 +1,141 @@
+//! Entry points for the REPL of chiika-2.
+//!
+//! The REPL JIT-compiles each input with LLVM and links this crate as a Rust
+//! library (with the `repl` feature) instead of the staticlib. The functions
+//! of the runtime are given to the JIT by address (`symbols`), and the inputs
+//! run one after another on the tokio runtime kept by `Session`.
+use crate::{
+    args, async_functions, channel, chiika_array, chiika_env, lock, select, sync_functions, task,
+    tcp, tokio_config, StartUser, EXIT_CODE,
+};
+use std::sync::atomic::Ordering;
+
+/// Keeps one tokio runtime for the whole session, so that the inputs share
+/// the clock and the drivers. It is configured by the environment variables
+/// `CHIIKA_TOKIO_*` (see `tokio_config`)
+pub struct Session {
+    runtime: tokio::runtime::Runtime,
+}
+
+impl Session {
+    pub fn new() -> Result<Session, String> {
+        let runtime = tokio_config::TokioConfig::load()?
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Session { runtime })
+    }
+
+    /// Run the input whose `chiika_start_user` is `start` and returns the
+    /// value of the function it calls (or the code given to `chiika_exit`).
+    /// The tasks spawned by the input are dropped when it finishes
+    pub fn run(&self, start: StartUser) -> i64 {
+        crate::run_user(&self.runtime, start);
+        EXIT_CODE.load(Ordering::SeqCst)
+    }
+}
+
+/// Names and addresses of the functions of the runtime, to resolve the
+/// externs of the JIT-compiled code
+pub fn symbols() -> Vec<(&'static str, usize)> {
+    macro_rules! symbols {
+        ($($module:ident::$name:ident),* $(,)?) => {
+            vec![$((stringify!($name), $module::$name as *const () as usize)),*]
+        };
+    }
+    symbols![
+        args::chiika_set_args,
+        args::arg_count,
+        args::arg,
+        args::env_var,
+        async_functions::sleep_sec,
+        async_functions::sleep_ms,
+        async_functions::now_ms,
+        async_functions::timeout,
+        async_functions::interval_new,
+        async_functions::interval_tick,
+        async_functions::interval_free,
+        async_functions::chiika_exit,
+        async_functions::read_int,
+        channel::chan_new,
+        channel::oneshot_new,
+        channel::chan_send,
+        channel::oneshot_send,
+        channel::chan_recv,
+        channel::chan_close,
+        channel::chan_free,
+        chiika_array::chiika_array_new,
+        chiika_array::chiika_array_push,
+        chiika_array::chiika_array_get,
+        chiika_array::chiika_array_set,
+        chiika_array::chiika_array_len,
+        chiika_env::chiika_env_push,
+        chiika_env::chiika_env_pop,
+        chiika_env::chiika_env_ref,
+        chiika_env::chiika_env_set,
+        lock::mutex_new,
+        lock::mutex_lock,
+        lock::mutex_unlock,
+        lock::mutex_free,
+        lock::semaphore_new,
+        lock::semaphore_acquire,
+        lock::semaphore_release,
+        lock::semaphore_free,
+        select::chiika_select_new,
+        select::chiika_select_env,
+        select::chiika_select_done,
+        select::chiika_select_arm,
+        select::chiika_select,
+        sync_functions::print,
+        sync_functions::print_str,
+        task::spawn,
+        task::cancel,
+        task::chiika_scope_new,
+        task::chiika_scope_add,
+        task::chiika_scope_wait,
+        task::chiika_scope_cancel,
+        tcp::tcp_listen,
+        tcp::tcp_local_port,
+        tcp::tcp_accept,
+        tcp::tcp_connect,
+        tcp::tcp_read,
+        tcp::tcp_received,
+        tcp::tcp_write,
+        tcp::tcp_close,
+        tokio_config::chiika_tokio_config,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chiika_env::ChiikaEnv;
+    use crate::future::{ChiikaCont, ChiikaFuture};
+
+    fn cont(cont: crate::ChiikaCont) -> ChiikaCont {
+        unsafe { std::mem::transmute(cont) }
+    }
+
+    unsafe extern "C" fn sleep_10(env: *mut ChiikaEnv, k: crate::ChiikaCont) -> Box<ChiikaFuture> {
+        async_functions::sleep_ms(&mut *env, cont(k), 10)
+    }
+
+    unsafe extern "C" fn exit_3(env: *mut ChiikaEnv, k: crate::ChiikaCont) -> Box<ChiikaFuture> {
+        async_functions::chiika_exit(&mut *env, cont(k), 3)
+    }
+
+    #[test]
+    fn test_session() {
+        let session = Session::new().unwrap();
+        assert_eq!(session.run(sleep_10), 10);
+        // `chiika_exit` only finishes the input
+        assert_eq!(session.run(exit_3), 3);
+        assert_eq!(session.run(sleep_10), 10);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let symbols = symbols();
+        assert!(symbols.iter().any(|(name, _)| *name == "chiika_env_push"));
+        assert!(symbols.iter().all(|(_, addr)| *addr != 0));
+    }
+}
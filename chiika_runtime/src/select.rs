@@ -0,0 +1,197 @@
+//! Runtime support of `select` of chiika-2.
+//!
+//! The compiled code creates the state with `chiika_select_new` and starts
+//! the call of each arm with its own env (`chiika_select_env`) and the
+//! continuation `chiika_select_done`, then registers the future and the
+//! continuation of the arm with `chiika_select_arm`. The future returned by
+//! `chiika_select` polls them until one of the arms finishes, drops the
+//! others and calls the continuation of that arm with the original env.
+use crate::chiika_env::ChiikaEnv;
+use crate::future::{ChiikaCont, ChiikaFuture, Trampoline};
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::task::Poll;
+
+struct Select {
+    // Envs given to the calls of the arms. Each has a pointer to this
+    // struct at the bottom
+    envs: Vec<*mut ChiikaEnv>,
+    // `None` while being polled or if finished
+    futures: Vec<Option<Trampoline>>,
+    conts: Vec<ChiikaCont>,
+    // Index of the arm finished first and its value
+    winner: Option<(usize, i64)>,
+}
+
+impl Drop for Select {
+    fn drop(&mut self) {
+        // The futures of the arms refer to their envs
+        self.futures.clear();
+        for env in self.envs.drain(..) {
+            drop(unsafe { Box::from_raw(env) });
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn chiika_select_new() -> i64 {
+    let state = Select {
+        envs: vec![],
+        futures: vec![],
+        conts: vec![],
+        winner: None,
+    };
+    Box::into_raw(Box::new(state)) as i64
+}
+
+fn select(state: i64) -> &'static mut Select {
+    unsafe { &mut *(state as *mut Select) }
+}
+
+/// Create the env for the call of an arm
+#[no_mangle]
+pub extern "C" fn chiika_select_env(state: i64) -> *mut ChiikaEnv {
+    let mut env = ChiikaEnv::new();
+    env.push(state);
+    let env = Box::into_raw(Box::new(env));
+    select(state).envs.push(env);
+    env
+}
+
+/// Continuation given to the calls of the arms. Only the first one counts
+#[no_mangle]
+pub extern "C" fn chiika_select_done(env: &mut ChiikaEnv, value: i64) -> Box<ChiikaFuture> {
+    // The callee has popped its frame
+    let state = select(env.last());
+    if state.winner.is_none() {
+        let env = env as *mut ChiikaEnv;
+        if let Some(i) = state.envs.iter().position(|x| *x == env) {
+            state.winner = Some((i, value));
+        }
+    }
+    ChiikaFuture::done()
+}
+
+/// Register the future of the call of an arm and the continuation of the arm
+#[no_mangle]
+pub extern "C" fn chiika_select_arm(
+    state: i64,
+    future: Box<ChiikaFuture>,
+    cont: ChiikaCont,
+) -> i64 {
+    let state = select(state);
+    state.futures.push(Some(Trampoline::new(future)));
+    state.conts.push(cont);
+    0
+}
+
+/// Race the arms. The state is owned by the returned future and freed when
+/// an arm finishes or when the future is dropped (e.g. by `timeout`)
+#[no_mangle]
+pub extern "C" fn chiika_select(env: &'static mut ChiikaEnv, state: i64) -> Box<ChiikaFuture> {
+    let mut state = Some(unsafe { Box::from_raw(state as *mut Select) });
+    ChiikaFuture::new(poll_fn(move |ctx| {
+        let Some(owned) = &mut state else {
+            panic!("select polled after completion");
+        };
+        // `winner` is set by `chiika_select_done` while polling, so it is
+        // read through the pointer each time
+        let state_ptr: *mut Select = &mut **owned;
+        let n_arms = unsafe { (*state_ptr).futures.len() };
+        for i in 0..n_arms {
+            if unsafe { (*state_ptr).winner.is_some() } {
+                break;
+            }
+            let Some(mut future) = (unsafe { (&mut (*state_ptr).futures)[i].take() }) else {
+                continue;
+            };
+            if Pin::new(&mut future).poll(ctx).is_pending() {
+                unsafe { (&mut (*state_ptr).futures)[i] = Some(future) };
+            }
+        }
+        let Some((i, value)) = (unsafe { (*state_ptr).winner }) else {
+            return Poll::Pending;
+        };
+        let cont = owned.conts[i];
+        // Drop the other arms
+        state = None;
+        Poll::Ready(Some(cont(env, value)))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_functions::sleep_ms;
+    use std::cell::RefCell;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    thread_local! {
+        /// Arm, value and when the continuation is called
+        static LOG: RefCell<Vec<(usize, i64, Instant)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    extern "C" fn arm0(_env: &mut ChiikaEnv, value: i64) -> Box<ChiikaFuture> {
+        LOG.with(|x| x.borrow_mut().push((0, value, Instant::now())));
+        ChiikaFuture::done()
+    }
+
+    extern "C" fn arm1(_env: &mut ChiikaEnv, value: i64) -> Box<ChiikaFuture> {
+        LOG.with(|x| x.borrow_mut().push((1, value, Instant::now())));
+        ChiikaFuture::done()
+    }
+
+    // `select { n = sleep_ms(a) => ..., n = sleep_ms(b) => ... }` after the
+    // CPS transformation
+    fn race(env: &'static mut ChiikaEnv, a: i64, b: i64) -> Box<ChiikaFuture> {
+        let state = chiika_select_new();
+        for (ms, cont) in [(a, arm0 as ChiikaCont), (b, arm1)] {
+            let arm_env = unsafe { &mut *chiika_select_env(state) };
+            chiika_select_arm(state, sleep_ms(arm_env, chiika_select_done, ms), cont);
+        }
+        chiika_select(env, state)
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap()
+    }
+
+    fn log_since(start: Instant) -> Vec<(usize, i64, u128)> {
+        LOG.with(|x| {
+            x.borrow()
+                .iter()
+                .map(|(i, v, t)| (*i, *v, (*t - start).as_millis()))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn test_first_arm_wins() {
+        let env = Box::leak(Box::new(ChiikaEnv::new()));
+        let start = runtime().block_on(async {
+            let start = Instant::now();
+            Trampoline::new(race(env, 50, 20)).await;
+            // The other arm is dropped
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            start
+        });
+        assert_eq!(log_since(start), vec![(1, 20, 20)]);
+    }
+
+    #[test]
+    fn test_dropped_before_finish() {
+        let env = Box::leak(Box::new(ChiikaEnv::new()));
+        runtime().block_on(async {
+            let select = Trampoline::new(race(env, 50, 20));
+            let result = tokio::time::timeout(Duration::from_millis(10), select).await;
+            assert!(result.is_err());
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        });
+        assert_eq!(log_since(Instant::now()), vec![]);
+    }
+}
@@ -1,4 +1,13 @@
+use crate::chiika_result::from_chiika_string;
+use std::ffi::c_char;
+
 #[no_mangle]
 pub extern "C" fn print(n: i64) {
     println!("{}", n);
 }
+
+#[no_mangle]
+pub extern "C" fn print_str(s: *const c_char) -> i64 {
+    println!("{}", from_chiika_string(s));
+    0
+}
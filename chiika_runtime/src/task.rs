@@ -0,0 +1,316 @@
+//! Tasks spawned by chiika programs.
+//!
+//! The futures of chiika are not `Send`, so they cannot be given to
+//! `tokio::spawn`. Instead they are kept in `Tasks` and polled together with
+//! `chiika_main` by the future given to `block_on`. Each task has its own
+//! `ChiikaEnv`, which is freed when the task finishes or is cancelled.
+//!
+//! Tasks spawned in `scope { ... }` of chiika-2 are registered to the scope
+//! (`chiika_scope_add`), which waits for them at the end
+//! (`chiika_scope_wait`) or cancels them if exited by `return`
+//! (`chiika_scope_cancel`).
+use crate::chiika_env::ChiikaEnv;
+use crate::future::{ChiikaCont, ChiikaFuture, Trampoline};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+type ChiikaAsyncFn =
+    extern "C" fn(env: &mut ChiikaEnv, cont: ChiikaCont, arg: i64) -> Box<ChiikaFuture>;
+
+struct Task {
+    id: i64,
+    env: *mut ChiikaEnv,
+    // Called with `arg` on the first poll
+    f: ChiikaAsyncFn,
+    arg: i64,
+    future: Option<Trampoline>,
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        // The future may refer to the env
+        drop(self.future.take());
+        drop(unsafe { Box::from_raw(self.env) });
+    }
+}
+
+thread_local! {
+    /// Tasks spawned since the last poll
+    static SPAWNED: RefCell<Vec<Task>> = const { RefCell::new(Vec::new()) };
+    static LAST_ID: RefCell<i64> = const { RefCell::new(0) };
+    /// Ids of the tasks which are not finished nor cancelled
+    static LIVE: RefCell<HashSet<i64>> = RefCell::new(HashSet::new());
+    /// Tasks cancelled since the last poll
+    static CANCELLED: RefCell<Vec<i64>> = const { RefCell::new(Vec::new()) };
+    /// Wakers of `chiika_scope_wait`s by the id of the task they wait for
+    static SCOPE_WAKERS: RefCell<HashMap<i64, Vec<Waker>>> = RefCell::new(HashMap::new());
+}
+
+fn is_live(id: i64) -> bool {
+    LIVE.with(|x| x.borrow().contains(&id))
+}
+
+/// Called when the task is finished or cancelled. Returns false if it was
+/// already
+fn end_task(id: i64) -> bool {
+    if !LIVE.with(|x| x.borrow_mut().remove(&id)) {
+        return false;
+    }
+    let wakers = SCOPE_WAKERS.with(|x| x.borrow_mut().remove(&id));
+    for waker in wakers.into_iter().flatten() {
+        waker.wake();
+    }
+    true
+}
+
+/// Continuation of the spawned function. The value is discarded
+extern "C" fn chiika_task_finish(_env: &mut ChiikaEnv, _value: i64) -> Box<ChiikaFuture> {
+    ChiikaFuture::done()
+}
+
+/// Start running `f(arg)` concurrently and returns the id of the task. `f` is
+/// called after the current task waits for something
+#[no_mangle]
+pub extern "C" fn spawn(f: ChiikaAsyncFn, arg: i64) -> i64 {
+    let id = LAST_ID.with(|x| {
+        *x.borrow_mut() += 1;
+        *x.borrow()
+    });
+    let env = Box::into_raw(Box::new(ChiikaEnv::new()));
+    SPAWNED.with(|x| {
+        x.borrow_mut().push(Task {
+            id,
+            env,
+            f,
+            arg,
+            future: None,
+        })
+    });
+    LIVE.with(|x| x.borrow_mut().insert(id));
+    id
+}
+
+/// Cancel the task. Its future is dropped and its env is freed before it is
+/// polled again. Returns 1 if the task was running, 0 if already finished
+/// (or cancelled)
+#[no_mangle]
+pub extern "C" fn cancel(id: i64) -> i64 {
+    if !end_task(id) {
+        return 0;
+    }
+    CANCELLED.with(|x| x.borrow_mut().push(id));
+    1
+}
+
+/// The spawned tasks which are not finished yet
+#[derive(Default)]
+pub(crate) struct Tasks {
+    tasks: Vec<Task>,
+}
+
+impl Tasks {
+    /// Poll all the tasks (including the ones spawned while polling)
+    pub(crate) fn poll(&mut self, ctx: &mut Context) {
+        let mut i = 0;
+        loop {
+            self.tasks.append(&mut SPAWNED.with(|x| x.take()));
+            let cancelled = CANCELLED.with(|x| x.take());
+            if !cancelled.is_empty() {
+                // Keep `i` pointing to the same task
+                i -= self.tasks[..i]
+                    .iter()
+                    .filter(|t| cancelled.contains(&t.id))
+                    .count();
+                self.tasks.retain(|t| !cancelled.contains(&t.id));
+            }
+            if i == self.tasks.len() {
+                break;
+            }
+            let task = &mut self.tasks[i];
+            let future = task.future.get_or_insert_with(|| {
+                Trampoline::new((task.f)(
+                    unsafe { &mut *task.env },
+                    chiika_task_finish,
+                    task.arg,
+                ))
+            });
+            if Pin::new(future).poll(ctx).is_ready() {
+                let task = self.tasks.remove(i);
+                end_task(task.id);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Tasks spawned in a `scope`
+struct Scope {
+    ids: Vec<i64>,
+}
+
+#[no_mangle]
+pub extern "C" fn chiika_scope_new() -> i64 {
+    Box::into_raw(Box::new(Scope { ids: vec![] })) as i64
+}
+
+fn scope(handle: i64) -> &'static mut Scope {
+    unsafe { &mut *(handle as *mut Scope) }
+}
+
+/// Register the task to the scope. Returns the id
+#[no_mangle]
+pub extern "C" fn chiika_scope_add(handle: i64, id: i64) -> i64 {
+    scope(handle).ids.push(id);
+    id
+}
+
+/// Wait until all the tasks of the scope finish, then free the scope
+#[no_mangle]
+pub extern "C" fn chiika_scope_wait(
+    env: &'static mut ChiikaEnv,
+    cont: ChiikaCont,
+    handle: i64,
+) -> Box<ChiikaFuture> {
+    let env_ptr = env as *mut ChiikaEnv;
+    ChiikaFuture::new(poll_fn(move |ctx| {
+        if let Some(id) = scope(handle).ids.iter().find(|id| is_live(**id)) {
+            // Woken by `end_task` to check the rest
+            SCOPE_WAKERS.with(|x| {
+                let mut wakers = x.borrow_mut();
+                let wakers = wakers.entry(*id).or_default();
+                if !wakers.iter().any(|w| w.will_wake(ctx.waker())) {
+                    wakers.push(ctx.waker().clone());
+                }
+            });
+            return Poll::Pending;
+        }
+        drop(unsafe { Box::from_raw(handle as *mut Scope) });
+        Poll::Ready(Some(cont(unsafe { &mut *env_ptr }, 0)))
+    }))
+}
+
+/// Cancel the tasks of the scope which are still running and free the scope
+#[no_mangle]
+pub extern "C" fn chiika_scope_cancel(handle: i64) -> i64 {
+    let scope = unsafe { Box::from_raw(handle as *mut Scope) };
+    for id in scope.ids {
+        cancel(id);
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_functions::sleep_ms;
+    use tokio::time::Instant;
+
+    thread_local! {
+        /// Values given to the continuations of the main chain and when
+        static LOG: RefCell<Vec<(i64, Instant)>> = const { RefCell::new(Vec::new()) };
+        static TASK_IDS: RefCell<Vec<i64>> = const { RefCell::new(Vec::new()) };
+        static SCOPE: RefCell<i64> = const { RefCell::new(0) };
+    }
+
+    fn env(env: &mut ChiikaEnv) -> &'static mut ChiikaEnv {
+        unsafe { &mut *(env as *mut ChiikaEnv) }
+    }
+
+    /// Run the main chain with the spawned tasks like `chiika_start_tokio`
+    /// (with the paused clock) and returns the log with the milliseconds
+    /// since the start
+    fn run(main: fn(&'static mut ChiikaEnv) -> Box<ChiikaFuture>) -> Vec<(i64, u128)> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        let start = runtime.block_on(async {
+            let start = Instant::now();
+            let mut future = Trampoline::new(main(Box::leak(Box::new(ChiikaEnv::new()))));
+            let mut tasks = Tasks::default();
+            poll_fn(|ctx| {
+                let poll = Pin::new(&mut future).poll(ctx);
+                if poll.is_pending() {
+                    tasks.poll(ctx);
+                }
+                poll
+            })
+            .await;
+            start
+        });
+        LOG.with(|x| {
+            x.borrow()
+                .iter()
+                .map(|(v, t)| (*v, (*t - start).as_millis()))
+                .collect()
+        })
+    }
+
+    extern "C" fn work(e: &mut ChiikaEnv, cont: ChiikaCont, ms: i64) -> Box<ChiikaFuture> {
+        sleep_ms(env(e), cont, ms)
+    }
+
+    /// Spawn `work(ms)` for each in the scope
+    fn spawn_in_scope(ms: &[i64]) -> i64 {
+        let scope = chiika_scope_new();
+        for ms in ms {
+            let id = chiika_scope_add(scope, spawn(work, *ms));
+            TASK_IDS.with(|x| x.borrow_mut().push(id));
+        }
+        scope
+    }
+
+    extern "C" fn log(_env: &mut ChiikaEnv, value: i64) -> Box<ChiikaFuture> {
+        LOG.with(|x| x.borrow_mut().push((value, Instant::now())));
+        ChiikaFuture::done()
+    }
+
+    #[test]
+    fn test_scope_waits_for_tasks() {
+        let log = run(|env| chiika_scope_wait(env, log, spawn_in_scope(&[10, 30])));
+        assert_eq!(log, vec![(0, 30)]);
+        assert!(TASK_IDS.with(|x| x.borrow().iter().all(|id| !is_live(*id))));
+    }
+
+    // Cancel the first task after 10ms and then wait for the scope
+    fn cancel_first(env: &'static mut ChiikaEnv) -> Box<ChiikaFuture> {
+        SCOPE.with(|x| *x.borrow_mut() = spawn_in_scope(&[100, 20]));
+        sleep_ms(env, cancel_and_wait, 10)
+    }
+
+    extern "C" fn cancel_and_wait(e: &mut ChiikaEnv, _value: i64) -> Box<ChiikaFuture> {
+        let first = TASK_IDS.with(|x| x.borrow()[0]);
+        assert_eq!(cancel(first), 1);
+        assert_eq!(cancel(first), 0);
+        chiika_scope_wait(env(e), log, SCOPE.with(|x| *x.borrow()))
+    }
+
+    #[test]
+    fn test_cancel() {
+        let log = run(cancel_first);
+        assert_eq!(log, vec![(0, 20)]);
+    }
+
+    // Return from the scope after 10ms
+    fn leave_scope(env: &'static mut ChiikaEnv) -> Box<ChiikaFuture> {
+        SCOPE.with(|x| *x.borrow_mut() = spawn_in_scope(&[100, 20]));
+        sleep_ms(env, cancel_scope, 10)
+    }
+
+    extern "C" fn cancel_scope(e: &mut ChiikaEnv, _value: i64) -> Box<ChiikaFuture> {
+        chiika_scope_cancel(SCOPE.with(|x| *x.borrow()));
+        assert!(TASK_IDS.with(|x| x.borrow().iter().all(|id| !is_live(*id))));
+        sleep_ms(env(e), log, 200)
+    }
+
+    #[test]
+    fn test_scope_cancel() {
+        let log = run(leave_scope);
+        assert_eq!(log, vec![(200, 210)]);
+    }
+}
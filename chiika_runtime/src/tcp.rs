@@ -0,0 +1,370 @@
+//! TCP networking on `tokio::net`.
+//!
+//! Listeners and connections are passed to chiika as opaque handles
+//! (`$any`; `int` in chiika-2), which are pointers to `Rc<Socket>`. Fallible
+//! functions return `Result` of chiika-2 with the handle or the number of
+//! bytes read or written. `Ok` of `Result` can only hold an int, so the
+//! bytes read are kept by the connection until taken by `tcp_received`.
+//!
+//! The handle is owned by the program, which frees it with `tcp_close`.
+//! The accepts, reads and writes already called hold the socket until they
+//! finish, so it is closed after them.
+use crate::chiika_env::ChiikaEnv;
+use crate::chiika_result::{from_chiika_string, to_chiika_result, to_chiika_string};
+use crate::future::{call_cont, ChiikaCont, ChiikaFuture};
+use std::cell::RefCell;
+use std::ffi::c_char;
+use std::io;
+use std::rc::Rc;
+use tokio::net::{TcpListener, TcpStream};
+
+enum Socket {
+    Listener(TcpListener),
+    // With the bytes read but not taken yet
+    Stream(TcpStream, RefCell<Vec<u8>>),
+}
+
+fn new_handle(socket: Socket) -> i64 {
+    Box::into_raw(Box::new(Rc::new(socket))) as i64
+}
+
+fn socket(handle: i64) -> &'static Rc<Socket> {
+    unsafe { &*(handle as *const Rc<Socket>) }
+}
+
+fn listener(handle: i64) -> Rc<Socket> {
+    let socket = socket(handle);
+    match &**socket {
+        Socket::Listener(_) => socket.clone(),
+        Socket::Stream(..) => invalid_handle(handle, "listener"),
+    }
+}
+
+fn stream(handle: i64) -> Rc<Socket> {
+    let socket = socket(handle);
+    match &**socket {
+        Socket::Stream(..) => socket.clone(),
+        Socket::Listener(_) => invalid_handle(handle, "connection"),
+    }
+}
+
+impl Socket {
+    fn listener(&self) -> &TcpListener {
+        match self {
+            Socket::Listener(x) => x,
+            Socket::Stream(..) => unreachable!(),
+        }
+    }
+
+    fn stream(&self) -> (&TcpStream, &RefCell<Vec<u8>>) {
+        match self {
+            Socket::Stream(x, received) => (x, received),
+            Socket::Listener(_) => unreachable!(),
+        }
+    }
+}
+
+fn invalid_handle(handle: i64, expected: &str) -> ! {
+    eprintln!("[chiika] expected a TCP {} but got {:#x}", expected, handle);
+    std::process::exit(1);
+}
+
+/// Read at most `buf.len()` bytes. Only needs `&TcpStream`, so that a read
+/// and a write can wait on the same connection
+async fn read_some(stream: &TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        stream.readable().await?;
+        match stream.try_read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+async fn write_all(stream: &TcpStream, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        stream.writable().await?;
+        match stream.try_write(data) {
+            Ok(n) => data = &data[n..],
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Listen on `host:port`. If `port` is 0, a free port is chosen (see
+/// `tcp_local_port`)
+#[no_mangle]
+pub extern "C" fn tcp_listen(
+    env: &'static mut ChiikaEnv,
+    cont: ChiikaCont,
+    host: *const c_char,
+    port: i64,
+) -> Box<ChiikaFuture> {
+    let addr = format!("{}:{}", from_chiika_string(host), port);
+    call_cont(env, cont, async move {
+        let result = TcpListener::bind(&addr)
+            .await
+            .map(|x| new_handle(Socket::Listener(x)))
+            .map_err(|e| format!("{}: {}", addr, e));
+        to_chiika_result(result)
+    })
+}
+
+/// Port number the listener is bound to
+#[no_mangle]
+pub extern "C" fn tcp_local_port(handle: i64) -> i64 {
+    match listener(handle).listener().local_addr() {
+        Ok(addr) => addr.port() as i64,
+        Err(_) => 0,
+    }
+}
+
+/// Wait for a connection to the listener
+#[no_mangle]
+pub extern "C" fn tcp_accept(
+    env: &'static mut ChiikaEnv,
+    cont: ChiikaCont,
+    handle: i64,
+) -> Box<ChiikaFuture> {
+    let socket = listener(handle);
+    call_cont(env, cont, async move {
+        let result = socket
+            .listener()
+            .accept()
+            .await
+            .map(|(x, _)| new_handle(Socket::Stream(x, Default::default())))
+            .map_err(|e| e.to_string());
+        to_chiika_result(result)
+    })
+}
+
+/// Connect to `host:port`
+#[no_mangle]
+pub extern "C" fn tcp_connect(
+    env: &'static mut ChiikaEnv,
+    cont: ChiikaCont,
+    host: *const c_char,
+    port: i64,
+) -> Box<ChiikaFuture> {
+    let addr = format!("{}:{}", from_chiika_string(host), port);
+    call_cont(env, cont, async move {
+        let result = TcpStream::connect(&addr)
+            .await
+            .map(|x| new_handle(Socket::Stream(x, Default::default())))
+            .map_err(|e| format!("{}: {}", addr, e));
+        to_chiika_result(result)
+    })
+}
+
+/// Read at most `max_len` bytes and returns the number of bytes read (0 at
+/// EOF). The bytes are taken by `tcp_received`
+#[no_mangle]
+pub extern "C" fn tcp_read(
+    env: &'static mut ChiikaEnv,
+    cont: ChiikaCont,
+    handle: i64,
+    max_len: i64,
+) -> Box<ChiikaFuture> {
+    let socket = stream(handle);
+    call_cont(env, cont, async move {
+        let (stream, received) = socket.stream();
+        let mut buf = vec![0; max_len.max(0) as usize];
+        let result = read_some(stream, &mut buf).await.map_err(|e| e.to_string());
+        if let Ok(n) = result {
+            received.borrow_mut().extend_from_slice(&buf[..n]);
+        }
+        to_chiika_result(result.map(|n| n as i64))
+    })
+}
+
+/// Take the bytes read by `tcp_read` so far
+#[no_mangle]
+pub extern "C" fn tcp_received(handle: i64) -> *const c_char {
+    let socket = stream(handle);
+    let received = socket.stream().1.take();
+    to_chiika_string(String::from_utf8_lossy(&received).into_owned())
+}
+
+/// Write all of `data` and returns the number of bytes written
+#[no_mangle]
+pub extern "C" fn tcp_write(
+    env: &'static mut ChiikaEnv,
+    cont: ChiikaCont,
+    handle: i64,
+    data: *const c_char,
+) -> Box<ChiikaFuture> {
+    let socket = stream(handle);
+    let data = from_chiika_string(data);
+    call_cont(env, cont, async move {
+        let result = write_all(socket.stream().0, data.as_bytes())
+            .await
+            .map(|_| data.len() as i64)
+            .map_err(|e| e.to_string());
+        to_chiika_result(result)
+    })
+}
+
+/// Free the handle of the listener or the connection. The handle must not be
+/// used after this. The socket is closed when the calls waiting on it (if
+/// any) finish
+#[no_mangle]
+pub extern "C" fn tcp_close(handle: i64) -> i64 {
+    drop(unsafe { Box::from_raw(handle as *mut Rc<Socket>) });
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chiika_result::from_chiika_result;
+    use crate::future::Trampoline;
+    use std::future::{poll_fn, Future};
+    use std::pin::Pin;
+    use std::task::Poll;
+
+    thread_local! {
+        /// Handles and values given to the continuations
+        static LOG: RefCell<Vec<Result<i64, String>>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn env(env: &mut ChiikaEnv) -> &'static mut ChiikaEnv {
+        unsafe { &mut *(env as *mut ChiikaEnv) }
+    }
+
+    fn log(result: i64) -> i64 {
+        let result = from_chiika_result(result);
+        LOG.with(|x| x.borrow_mut().push(result.clone()));
+        result.unwrap()
+    }
+
+    fn logged(i: usize) -> i64 {
+        LOG.with(|x| x.borrow()[i].clone().unwrap())
+    }
+
+    fn localhost() -> *const c_char {
+        to_chiika_string("127.0.0.1".to_string())
+    }
+
+    fn run(future: impl FnOnce(&'static mut ChiikaEnv) -> Box<ChiikaFuture>) {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let env = Box::leak(Box::new(ChiikaEnv::new()));
+        runtime.block_on(Trampoline::new(future(env)));
+    }
+
+    // Listen, connect, accept, send "hello" from the client, receive it on
+    // the server and then read the EOF after the client closes
+    extern "C" fn listened(e: &mut ChiikaEnv, result: i64) -> Box<ChiikaFuture> {
+        let port = tcp_local_port(log(result));
+        tcp_connect(env(e), connected, localhost(), port)
+    }
+
+    extern "C" fn connected(e: &mut ChiikaEnv, result: i64) -> Box<ChiikaFuture> {
+        log(result);
+        tcp_accept(env(e), accepted, logged(0))
+    }
+
+    extern "C" fn accepted(e: &mut ChiikaEnv, result: i64) -> Box<ChiikaFuture> {
+        log(result);
+        let data = to_chiika_string("hello".to_string());
+        tcp_write(env(e), written, logged(1), data)
+    }
+
+    extern "C" fn written(e: &mut ChiikaEnv, result: i64) -> Box<ChiikaFuture> {
+        log(result);
+        tcp_read(env(e), read, logged(2), 100)
+    }
+
+    extern "C" fn read(e: &mut ChiikaEnv, result: i64) -> Box<ChiikaFuture> {
+        log(result);
+        let received = from_chiika_string(tcp_received(logged(2)));
+        assert_eq!(received, "hello");
+        assert_eq!(from_chiika_string(tcp_received(logged(2))), "");
+        tcp_close(logged(1));
+        tcp_read(env(e), read_eof, logged(2), 100)
+    }
+
+    extern "C" fn read_eof(_e: &mut ChiikaEnv, result: i64) -> Box<ChiikaFuture> {
+        log(result);
+        tcp_close(logged(2));
+        tcp_close(logged(0));
+        ChiikaFuture::done()
+    }
+
+    #[test]
+    fn test_loopback() {
+        run(|env| tcp_listen(env, listened, localhost(), 0));
+        let values: Vec<i64> =
+            LOG.with(|x| x.borrow().iter().map(|r| r.clone().unwrap()).collect());
+        // listener, client, server, bytes written, bytes read, EOF
+        assert_eq!(values.len(), 6);
+        assert_eq!(values[3..], [5, 5, 0]);
+    }
+
+    extern "C" fn refused(_e: &mut ChiikaEnv, result: i64) -> Box<ChiikaFuture> {
+        LOG.with(|x| x.borrow_mut().push(from_chiika_result(result)));
+        ChiikaFuture::done()
+    }
+
+    #[test]
+    fn test_connect_refused() {
+        // A port nobody listens on
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        run(|env| tcp_connect(env, refused, localhost(), port as i64));
+        let log = LOG.with(|x| x.borrow().clone());
+        assert!(matches!(&log[..], [Err(msg)] if msg.starts_with("127.0.0.1:")));
+    }
+
+    extern "C" fn read_after_close(_e: &mut ChiikaEnv, result: i64) -> Box<ChiikaFuture> {
+        log(result);
+        ChiikaFuture::done()
+    }
+
+    extern "C" fn written_after_close(_e: &mut ChiikaEnv, result: i64) -> Box<ChiikaFuture> {
+        log(result);
+        ChiikaFuture::done()
+    }
+
+    #[test]
+    fn test_close_during_read() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = TcpStream::connect(addr).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let client = new_handle(Socket::Stream(client, Default::default()));
+            let server = new_handle(Socket::Stream(server, Default::default()));
+            let env1 = Box::leak(Box::new(ChiikaEnv::new()));
+            let env2 = Box::leak(Box::new(ChiikaEnv::new()));
+            let mut read = Trampoline::new(tcp_read(env1, read_after_close, server, 100));
+            // Start the read before freeing the handle
+            poll_fn(|ctx| {
+                assert!(Pin::new(&mut read).poll(ctx).is_pending());
+                Poll::Ready(())
+            })
+            .await;
+            tcp_close(server);
+            let data = to_chiika_string("bye".to_string());
+            let write = Trampoline::new(tcp_write(env2, written_after_close, client, data));
+            tokio::join!(read, write);
+            tcp_close(client);
+        });
+        let mut values: Vec<i64> =
+            LOG.with(|x| x.borrow().iter().map(|r| r.clone().unwrap()).collect());
+        values.sort();
+        // The read still receives the bytes written
+        assert_eq!(values, [3, 3]);
+    }
+}
@@ -0,0 +1,133 @@
+//! Settings of the tokio runtime.
+//!
+//! The settings are given by `#[tokio(...)]` on `chiika_main` (the generated
+//! `main` calls `chiika_tokio_config` for each of them) and the environment
+//! variables `CHIIKA_TOKIO_FLAVOR`, `CHIIKA_TOKIO_WORKERS`,
+//! `CHIIKA_TOKIO_DRIVERS` and `CHIIKA_TOKIO_CLOCK`, which take precedence.
+use crate::chiika_result::from_chiika_string;
+use std::ffi::c_char;
+use std::sync::Mutex;
+
+/// Settings given to `chiika_tokio_config` (key and value)
+static SETTINGS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+const ENV_VARS: [(&str, &str); 4] = [
+    ("flavor", "CHIIKA_TOKIO_FLAVOR"),
+    ("workers", "CHIIKA_TOKIO_WORKERS"),
+    ("drivers", "CHIIKA_TOKIO_DRIVERS"),
+    ("clock", "CHIIKA_TOKIO_CLOCK"),
+];
+
+#[no_mangle]
+pub extern "C" fn chiika_tokio_config(key: *const c_char, value: *const c_char) -> i64 {
+    SETTINGS
+        .lock()
+        .unwrap()
+        .push((from_chiika_string(key), from_chiika_string(value)));
+    0
+}
+
+#[derive(Debug)]
+pub struct TokioConfig {
+    current_thread: bool,
+    // Default of tokio (the number of cores) if None. Chiika code is polled
+    // only by the thread of `block_on` (see `chiika_start_tokio`)
+    workers: Option<usize>,
+    enable_io: bool,
+    enable_time: bool,
+    // Time advances only when all tasks are waiting (for deterministic tests)
+    paused: bool,
+}
+
+impl TokioConfig {
+    pub fn load() -> Result<TokioConfig, String> {
+        let mut config = TokioConfig {
+            current_thread: false,
+            workers: None,
+            enable_io: true,
+            enable_time: true,
+            paused: false,
+        };
+        let settings = SETTINGS.lock().unwrap().clone();
+        for (key, value) in settings {
+            config.set(&key, &value)?;
+        }
+        for (key, var) in ENV_VARS {
+            if let Ok(value) = std::env::var(var) {
+                config
+                    .set(key, &value)
+                    .map_err(|e| format!("{}: {}", var, e))?;
+            }
+        }
+        if config.paused && !(config.current_thread && config.enable_time) {
+            return Err(
+                "the paused clock needs flavor `current_thread' and the time driver".to_string(),
+            );
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "flavor" => {
+                self.current_thread = match value {
+                    "current_thread" => true,
+                    "multi_thread" => false,
+                    _ => return Err(format!("unknown flavor `{}'", value)),
+                }
+            }
+            "workers" => match value.parse() {
+                Ok(n) if n > 0 => self.workers = Some(n),
+                _ => return Err(format!("invalid number of workers `{}'", value)),
+            },
+            "drivers" => {
+                self.enable_io = false;
+                self.enable_time = false;
+                for driver in value.split(',').map(|x| x.trim()) {
+                    match driver {
+                        "all" => {
+                            self.enable_io = true;
+                            self.enable_time = true;
+                        }
+                        "io" => self.enable_io = true,
+                        "time" => self.enable_time = true,
+                        "none" => (),
+                        _ => return Err(format!("unknown driver `{}'", driver)),
+                    }
+                }
+            }
+            "clock" => {
+                self.paused = match value {
+                    "paused" if cfg!(feature = "paused-clock") => true,
+                    "paused" => return Err("needs the feature `paused-clock'".to_string()),
+                    "real" => false,
+                    _ => return Err(format!("unknown clock `{}'", value)),
+                }
+            }
+            _ => return Err(format!("unknown setting `{}'", key)),
+        }
+        Ok(())
+    }
+
+    /// Build the runtime. `workers` is ignored for the current_thread flavor
+    pub fn build(&self) -> std::io::Result<tokio::runtime::Runtime> {
+        let mut builder = if self.current_thread {
+            tokio::runtime::Builder::new_current_thread()
+        } else {
+            let mut b = tokio::runtime::Builder::new_multi_thread();
+            if let Some(n) = self.workers {
+                b.worker_threads(n);
+            }
+            b
+        };
+        if self.enable_io {
+            builder.enable_io();
+        }
+        if self.enable_time {
+            builder.enable_time();
+        }
+        #[cfg(feature = "paused-clock")]
+        builder.start_paused(self.paused);
+        builder.build()
+    }
+}